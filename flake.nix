@@ -47,7 +47,7 @@
           export PATH="${pkgs.cargo}/bin:${pkgs.rustc}/bin:${pkgs.gcc}/bin:$PATH"
           export CARGO_TARGET_DIR=$(mktemp -d -t cargo-target-XXXXXX)
           cd ${toString ./.}
-          cargo bench --bench benchmark
+          cargo bench --bench benchmark --features testutil
         '';
         benchEnv = pkgs.buildEnv {
           name = "bench-env";
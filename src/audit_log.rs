@@ -0,0 +1,338 @@
+use blake3::{Hash, Hasher};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::pipeline::CHUNK_SIZE;
+use crate::plan::move_file;
+use crate::util::same_file;
+
+/// Suffix of the temporary copy created next to a link before it replaces the link.
+const UNDO_SUFFIX: &str = ".filematch-undo";
+
+/// An operation on the filesystem recorded in an audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// A file was copied from the source to the target.
+    Copy,
+    /// The target was removed, as the source has the same contents.
+    Remove,
+    /// The source was moved to the target in a trash directory.
+    Trash,
+    /// The target was replaced with a hard link to the source.
+    Hardlink,
+    /// The target was replaced with a symbolic link to the source.
+    Symlink,
+}
+
+impl Operation {
+    /// Returns the name of the operation, as recorded in the log.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Copy => "copy",
+            Self::Remove => "remove",
+            Self::Trash => "trash",
+            Self::Hardlink => "hardlink",
+            Self::Symlink => "symlink",
+        }
+    }
+
+    /// Looks up an operation by its name.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "copy" => Some(Self::Copy),
+            "remove" => Some(Self::Remove),
+            "trash" => Some(Self::Trash),
+            "hardlink" => Some(Self::Hardlink),
+            "symlink" => Some(Self::Symlink),
+            _ => None,
+        }
+    }
+}
+
+/// An operation recorded in an audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// When the operation finished.
+    pub time: SystemTime,
+    /// What was done.
+    pub operation: Operation,
+    /// The absolute path of the file with the contents, see [`Operation`].
+    pub source: PathBuf,
+    /// The absolute path of the file the operation wrote, removed or replaced, see
+    /// [`Operation`].
+    pub target: PathBuf,
+    /// The BLAKE3 hash of the contents.
+    pub hash: Hash,
+    /// The size of the contents in bytes.
+    pub size: u64,
+    /// Why the operation failed, or None if it succeeded.
+    pub error: Option<String>,
+}
+
+/// A log the operations on the filesystem are appended to as JSON lines, one per operation.
+///
+/// Every entry is written as soon as the operation finished, so the log is complete up to the
+/// last operation even if the process is interrupted.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    /// Opens an audit log for appending, creating it if it does not exist.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` naming the file if it cannot be opened.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+
+        Ok(Self { file })
+    }
+
+    /// Records an operation that just finished.
+    ///
+    /// Relative paths are made absolute, so the log can be undone from any working directory.
+    /// Paths that are not valid UTF-8 are recorded lossily.
+    ///
+    /// # Parameters
+    /// - `operation`: What was done.
+    /// - `source`: The file with the contents, see [`Operation`].
+    /// - `target`: The file the operation wrote, removed or replaced, see [`Operation`].
+    /// - `hash`: The BLAKE3 hash of the contents.
+    /// - `size`: The size of the contents in bytes.
+    /// - `error`: Why the operation failed, or None if it succeeded.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` if the entry cannot be written.
+    pub fn record(
+        &mut self,
+        operation: Operation,
+        source: &Path,
+        target: &Path,
+        hash: &Hash,
+        size: u64,
+        error: Option<&str>,
+    ) -> io::Result<()> {
+        let absolute = |path: &Path| {
+            std::path::absolute(path)
+                .unwrap_or_else(|_| path.to_path_buf())
+                .to_string_lossy()
+                .into_owned()
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let entry = json!({
+            "time": time,
+            "operation": operation.name(),
+            "source": absolute(source),
+            "target": absolute(target),
+            "hash": hash.to_hex().as_str(),
+            "size": size,
+            "outcome": if error.is_some() { "failed" } else { "done" },
+            "error": error,
+        });
+
+        // A single write per line keeps concurrent appends from interleaving
+        self.file.write_all(format!("{entry}\n").as_bytes())
+    }
+}
+
+/// Parses an audit log.
+///
+/// # Parameters
+/// - `reader`: The log contents.
+///
+/// # Returns
+/// The entries, in the order of the log.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, or an error of kind `InvalidData` if
+/// a line is not an entry.
+pub fn read_audit_log(reader: impl BufRead) -> io::Result<Vec<AuditEntry>> {
+    let mut entries = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("audit log line {}: expected an entry", index + 1),
+            )
+        };
+
+        let value: Value = serde_json::from_str(&line).map_err(|_| invalid())?;
+        let text = |key: &str| value.get(key).and_then(Value::as_str);
+        let number = |key: &str| value.get(key).and_then(Value::as_u64);
+        entries.push(AuditEntry {
+            time: UNIX_EPOCH + Duration::from_secs(number("time").ok_or_else(invalid)?),
+            operation: text("operation")
+                .and_then(Operation::from_name)
+                .ok_or_else(invalid)?,
+            source: text("source").map(PathBuf::from).ok_or_else(invalid)?,
+            target: text("target").map(PathBuf::from).ok_or_else(invalid)?,
+            hash: text("hash")
+                .and_then(|hex| Hash::from_hex(hex).ok())
+                .ok_or_else(invalid)?,
+            size: number("size").ok_or_else(invalid)?,
+            error: text("error").map(ToString::to_string),
+        });
+    }
+
+    Ok(entries)
+}
+
+/// The outcome of undoing the operations of an audit log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Undo {
+    /// Copies whose target was removed.
+    pub removed: Vec<PathBuf>,
+    /// Files removed, trashed or replaced by a link that were put back.
+    pub restored: Vec<PathBuf>,
+    /// Files modified since the operation, so they were kept.
+    pub modified: Vec<PathBuf>,
+    /// Files of operations that no longer exist.
+    pub gone: Vec<PathBuf>,
+    /// Files not put back, because another file took their path since.
+    pub occupied: Vec<PathBuf>,
+}
+
+/// Computes the BLAKE3 hash of a file.
+pub(crate) fn hash_file(path: &Path) -> io::Result<Hash> {
+    let mut reader = File::open(path)?;
+    let mut hasher = Hasher::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(hasher.finalize()),
+            Ok(bytes_read) => {
+                hasher.update(&buffer[..bytes_read]);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Checks that a file still has the contents of an operation.
+///
+/// # Returns
+/// True if the file has the hash of `entry`, false after adding it to the modified or gone files
+/// of `undo`.
+fn unchanged(path: &Path, entry: &AuditEntry, undo: &mut Undo) -> io::Result<bool> {
+    match hash_file(path) {
+        Ok(hash) if hash == entry.hash => Ok(true),
+        Ok(_) => {
+            undo.modified.push(path.to_path_buf());
+            Ok(false)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            undo.gone.push(path.to_path_buf());
+            Ok(false)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Checks that nothing took the path of a file that is to be put back.
+///
+/// # Returns
+/// True if the path is free, false after adding it to the occupied files of `undo`.
+fn free(path: &Path, undo: &mut Undo) -> bool {
+    if fs::symlink_metadata(path).is_ok() {
+        undo.occupied.push(path.to_path_buf());
+        return false;
+    }
+    true
+}
+
+/// Undoes a single operation.
+fn undo_entry(entry: &AuditEntry, undo: &mut Undo) -> io::Result<()> {
+    let (source, target) = (&entry.source, &entry.target);
+    match entry.operation {
+        Operation::Copy => {
+            if unchanged(target, entry, undo)? {
+                fs::remove_file(target)?;
+                undo.removed.push(target.clone());
+            }
+        }
+        Operation::Remove => {
+            if free(target, undo) && unchanged(source, entry, undo)? {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(source, target)?;
+                undo.restored.push(target.clone());
+            }
+        }
+        Operation::Trash => {
+            if free(source, undo) && unchanged(target, entry, undo)? {
+                move_file(target, source)?;
+                undo.restored.push(source.clone());
+            }
+        }
+        Operation::Hardlink | Operation::Symlink => {
+            // A link replaced by another file since is kept like any modified file
+            if !same_file(source, target) {
+                if fs::symlink_metadata(target).is_ok() {
+                    undo.modified.push(target.clone());
+                } else {
+                    undo.gone.push(target.clone());
+                }
+            } else if unchanged(source, entry, undo)? {
+                let mut copy = target.as_os_str().to_os_string();
+                copy.push(UNDO_SUFFIX);
+                let copy = PathBuf::from(copy);
+                fs::copy(source, &copy)?;
+                fs::rename(&copy, target).inspect_err(|_| {
+                    let _ = fs::remove_file(&copy);
+                })?;
+                undo.restored.push(target.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Undoes the operations of an audit log as far as possible, latest first.
+///
+/// The target of every successful copy is removed, unless it no longer has the contents that
+/// were copied. Removed files are copied back from the file with the same contents they were
+/// removed for, trashed files are moved back, and links are replaced by a copy of the file they
+/// link to, unless a file took their path or the contents changed since. Failed operations left
+/// nothing behind and are ignored. A target that a later operation not in the log relies on,
+/// such as a blob that a later export found already stored, is removed all the same, so undo
+/// the latest logs first.
+///
+/// # Parameters
+/// - `entries`: The entries of the log, see [`read_audit_log`].
+///
+/// # Returns
+/// The removed, restored, modified, gone and occupied files, in the order they were undone.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the file of the operation if a file cannot be
+/// read, removed, copied or moved.
+pub fn undo(entries: &[AuditEntry]) -> io::Result<Undo> {
+    let mut undo = Undo::default();
+
+    for entry in entries.iter().rev().filter(|entry| entry.error.is_none()) {
+        undo_entry(entry, &mut undo).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {err}", entry.target.display()))
+        })?;
+    }
+
+    Ok(undo)
+}
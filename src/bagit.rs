@@ -0,0 +1,330 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{digest_tree, Algorithm};
+use crate::compare_two_directories::partition_map_values;
+use crate::options::CompareOptions;
+use crate::result::{modified_paths, ComparisonResult, SumsCheck};
+
+/// Name of the bag declaration file every bag contains.
+pub const BAG_DECLARATION: &str = "bagit.txt";
+
+/// Name of the directory holding the payload of a bag.
+pub const PAYLOAD_DIRECTORY: &str = "data";
+
+/// Algorithms used to compare bags, strongest first.
+const PREFERRED_ALGORITHMS: [Algorithm; 4] = [
+    Algorithm::Sha512,
+    Algorithm::Sha256,
+    Algorithm::Sha1,
+    Algorithm::Md5,
+];
+
+/// A payload manifest of a bag, such as `manifest-sha256.txt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BagManifest {
+    /// The algorithm of the digests.
+    pub algorithm: Algorithm,
+    /// The lowercase hex digest of each payload file, keyed by its path relative to the bag.
+    pub entries: HashMap<PathBuf, String>,
+}
+
+/// Determines if a directory is a BagIt bag.
+///
+/// # Parameters
+/// - `directory`: The directory to check.
+///
+/// # Returns
+/// True if the directory contains a bag declaration, false otherwise.
+#[must_use]
+pub fn is_bag(directory: &Path) -> bool {
+    directory.join(BAG_DECLARATION).is_file()
+}
+
+/// Decodes the percent-encoded characters the BagIt specification allows in manifest paths.
+fn decode_path(path: &str) -> PathBuf {
+    PathBuf::from(
+        path.replace("%0A", "\n")
+            .replace("%0a", "\n")
+            .replace("%0D", "\r")
+            .replace("%0d", "\r")
+            .replace("%25", "%"),
+    )
+}
+
+/// Parses a single payload manifest.
+///
+/// # Parameters
+/// - `reader`: The manifest contents.
+/// - `algorithm`: The algorithm of the digests.
+///
+/// # Returns
+/// The parsed manifest.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails or a line is malformed.
+pub fn read_manifest(reader: impl BufRead, algorithm: Algorithm) -> io::Result<BagManifest> {
+    let mut entries = HashMap::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Some((digest, path)) = line.split_once([' ', '\t']) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("manifest line {}: expected a digest and a path", index + 1),
+            ));
+        };
+        entries.insert(
+            decode_path(path.trim_start_matches([' ', '\t'])),
+            digest.to_ascii_lowercase(),
+        );
+    }
+
+    Ok(BagManifest { algorithm, entries })
+}
+
+/// Reads all payload manifests of a bag.
+///
+/// Manifests of algorithms filematch does not support are ignored.
+///
+/// # Parameters
+/// - `bag`: The root directory of the bag.
+///
+/// # Returns
+/// The manifests, strongest algorithm first.
+///
+/// # Errors
+/// This function returns an `io::Error` if the directory is not a bag, a manifest cannot be
+/// read or parsed, or the bag has no manifest of a supported algorithm.
+pub fn read_manifests(bag: &Path) -> io::Result<Vec<BagManifest>> {
+    if !is_bag(bag) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "'{}' is not a bag: {BAG_DECLARATION} is missing",
+                bag.display()
+            ),
+        ));
+    }
+
+    let mut manifests = Vec::new();
+    for entry in fs::read_dir(bag)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(algorithm) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("manifest-"))
+            .and_then(|name| name.strip_suffix(".txt"))
+            .and_then(Algorithm::from_name)
+        else {
+            continue;
+        };
+
+        let file = File::open(entry.path())?;
+        let manifest = read_manifest(BufReader::new(file), algorithm).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {err}", entry.path().display()))
+        })?;
+        manifests.push(manifest);
+    }
+
+    if manifests.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' has no supported payload manifest", bag.display()),
+        ));
+    }
+    manifests.sort_by_key(|manifest| {
+        PREFERRED_ALGORITHMS
+            .iter()
+            .position(|algorithm| *algorithm == manifest.algorithm)
+            .unwrap_or(PREFERRED_ALGORITHMS.len())
+    });
+
+    Ok(manifests)
+}
+
+/// The outcome of verifying a bag against its payload manifests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BagReport {
+    /// Payload files matching every manifest.
+    pub valid: Vec<PathBuf>,
+    /// Payload files whose digest differs from a manifest.
+    pub mismatched: Vec<PathBuf>,
+    /// Files listed in a manifest but absent from the payload.
+    pub missing: Vec<PathBuf>,
+    /// Payload files not listed in every manifest.
+    pub unlisted: Vec<PathBuf>,
+}
+
+impl BagReport {
+    /// Returns true if the payload matches the manifests exactly.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.unlisted.is_empty()
+    }
+}
+
+/// Verifies the payload of a bag against all of its supported manifests.
+///
+/// Every payload file is read once, computing the digests of all manifests in a single pass.
+///
+/// # Parameters
+/// - `bag`: The root directory of the bag.
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The verification report, with all paths relative to the bag and sorted.
+///
+/// # Errors
+/// This function returns an `io::Error` if the manifests cannot be read or a payload file
+/// cannot be read.
+pub fn verify_bag(bag: &Path, options: &CompareOptions) -> io::Result<BagReport> {
+    let manifests = read_manifests(bag)?;
+    let algorithms: Vec<Algorithm> = manifests
+        .iter()
+        .map(|manifest| manifest.algorithm)
+        .collect();
+
+    let payload = bag.join(PAYLOAD_DIRECTORY);
+    let files = if payload.is_dir() {
+        digest_tree(&payload, &algorithms, options)?
+    } else {
+        Vec::new()
+    };
+
+    let mut report = BagReport::default();
+    let mut present = HashSet::new();
+    for file in files {
+        let path = file
+            .path
+            .strip_prefix(bag)
+            .map_or_else(|_| file.path.clone(), Path::to_path_buf);
+
+        let listed = manifests
+            .iter()
+            .all(|manifest| manifest.entries.contains_key(&path));
+        let matches = manifests
+            .iter()
+            .zip(&file.digests)
+            .all(|(manifest, digest)| manifest.entries.get(&path).is_none_or(|d| d == digest));
+
+        if !matches {
+            report.mismatched.push(path.clone());
+        } else if !listed {
+            report.unlisted.push(path.clone());
+        } else {
+            report.valid.push(path.clone());
+        }
+        present.insert(path);
+    }
+
+    report.missing = manifests
+        .iter()
+        .flat_map(|manifest| manifest.entries.keys())
+        .filter(|path| !present.contains(*path))
+        .cloned()
+        .collect();
+    report.missing.sort();
+    report.missing.dedup();
+
+    Ok(report)
+}
+
+/// Compares the payloads of two bags by content, using their manifests.
+///
+/// The payload files are not read: the digests of the strongest algorithm both bags have a
+/// manifest for are compared instead. Verify the bags first if their manifests may be stale.
+///
+/// # Parameters
+/// - `bag1`: The root directory of the first bag.
+/// - `bag2`: The root directory of the second bag.
+/// - `options`: The options selecting the result groups and sorting. Paths are relative to the
+///   bag when `options.relative` is set, and joined to the bag directory otherwise.
+///
+/// # Returns
+/// A Result containing the payload paths present in both bags, unique to `bag1`, and unique to
+/// `bag2` (each if requested).
+///
+/// # Errors
+/// This function returns an `io::Error` if the manifests cannot be read or the bags share no
+/// manifest algorithm.
+pub fn compare_bags(
+    bag1: &Path,
+    bag2: &Path,
+    options: &CompareOptions,
+) -> io::Result<ComparisonResult> {
+    let manifests1 = read_manifests(bag1)?;
+    let manifests2 = read_manifests(bag2)?;
+
+    let Some((manifest1, manifest2)) = manifests1.iter().find_map(|manifest1| {
+        manifests2
+            .iter()
+            .find(|manifest2| manifest2.algorithm == manifest1.algorithm)
+            .map(|manifest2| (manifest1, manifest2))
+    }) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the bags have no manifest algorithm in common",
+        ));
+    };
+
+    let group = |manifest: &BagManifest, bag: &Path| {
+        let mut map: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for (path, digest) in &manifest.entries {
+            let path = if options.relative {
+                path.clone()
+            } else {
+                bag.join(path)
+            };
+            map.entry(digest.clone()).or_default().push(path);
+        }
+        map
+    };
+
+    let (mut intersection, mut unique_dir1, mut unique_dir2) = partition_map_values(
+        &group(manifest1, bag1),
+        &group(manifest2, bag2),
+        options.include_intersection,
+        options.include_unique_dir1,
+        options.include_unique_dir2,
+    );
+
+    if options.sort {
+        for paths in [&mut intersection, &mut unique_dir1, &mut unique_dir2]
+            .into_iter()
+            .flatten()
+        {
+            paths.sort();
+        }
+    }
+
+    let modified = if options.relative {
+        modified_paths(unique_dir1.as_deref(), unique_dir2.as_deref())
+    } else {
+        None
+    };
+
+    Ok(ComparisonResult {
+        intersection,
+        unique_dir1,
+        unique_dir2,
+        modified,
+        errors: Vec::new(),
+        skipped: Vec::new(),
+        excluded: None,
+        walk_errors: Vec::new(),
+        read_errors: Vec::new(),
+        sums_check: SumsCheck::default(),
+        ranged_matches: Vec::new(),
+        slow_files: Vec::new(),
+        incomplete: Vec::new(),
+        groups: None,
+    })
+}
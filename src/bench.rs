@@ -0,0 +1,69 @@
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::compare_two_directories::compare_two_directories_with_options;
+use crate::options::CompareOptions;
+use crate::testutil::BenchmarkFiles;
+
+/// Compares two benchmark directories and measures how long it takes.
+///
+/// One warm up run is done first and not timed. Every run is checked against the expected
+/// files.
+///
+/// # Parameters
+/// - `dir_a`: Directory A.
+/// - `dir_b`: Directory B.
+/// - `expected`: The files the comparison is expected to report.
+/// - `runs`: The number of timed runs.
+/// - `options`: The options of the comparison. Paths are always absolute and sorted.
+/// - `on_run`: Called after each timed run with its number, starting at 1, and duration.
+///
+/// # Returns
+/// The duration of each timed run.
+///
+/// # Errors
+/// This function returns an `io::Error` if a file cannot be read, or an error of kind
+/// `InvalidData` if a comparison does not report the expected files.
+pub fn run_benchmark(
+    dir_a: &Path,
+    dir_b: &Path,
+    expected: &BenchmarkFiles,
+    runs: usize,
+    options: &CompareOptions,
+    mut on_run: impl FnMut(usize, Duration),
+) -> io::Result<Vec<Duration>> {
+    let options = CompareOptions {
+        relative: false,
+        sort: true,
+        include_intersection: true,
+        include_unique_dir1: true,
+        include_unique_dir2: true,
+        ..options.clone()
+    };
+
+    // Always do one warm up run
+    compare_two_directories_with_options(dir_a, dir_b, &options)?;
+
+    let mut durations = Vec::with_capacity(runs);
+    for run in 1..=runs {
+        let start = Instant::now();
+        let result = compare_two_directories_with_options(dir_a, dir_b, &options)?;
+        let elapsed = start.elapsed();
+
+        if result.intersection.as_ref() != Some(&expected.both)
+            || result.unique_dir1.as_ref() != Some(&expected.only_a)
+            || result.unique_dir2.as_ref() != Some(&expected.only_b)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("run #{run} did not report the expected files"),
+            ));
+        }
+
+        on_run(run, elapsed);
+        durations.push(elapsed);
+    }
+
+    Ok(durations)
+}
@@ -0,0 +1,328 @@
+use blake3::{Hash, Hasher};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::audit_log::{AuditLog, Operation};
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::options::CompareOptions;
+use crate::pipeline::CHUNK_SIZE;
+use crate::result::{SkippedFile, WalkError};
+
+/// Name of the directory holding the blobs, inside the store.
+pub const BLOBS_DIRECTORY: &str = "blobs";
+
+/// Name of the manifest written into the store by default.
+pub const MANIFEST_FILE_NAME: &str = "manifest.b3";
+
+/// The outcome of exporting a directory into a content-addressed store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CasExport {
+    /// Every exported file with the hash of its contents, with paths relative to the directory
+    /// and sorted.
+    pub files: Vec<(PathBuf, Hash)>,
+    /// The number of blobs copied into the store.
+    pub copied: usize,
+    /// The number of bytes copied into the store.
+    pub copied_bytes: u64,
+    /// The number of contents the store already held.
+    pub existing: usize,
+    /// Files that were found but not exported.
+    pub skipped: Vec<SkippedFile>,
+    /// Paths that could not be read while walking the directory.
+    pub walk_errors: Vec<WalkError>,
+}
+
+/// Returns the path of the blob holding a content.
+///
+/// Blobs are named after the hex hash of their content, in a directory named after its first
+/// two characters, e.g. `blobs/af/af1349b9…`, to keep directories small.
+///
+/// # Parameters
+/// - `store`: The root directory of the store.
+/// - `hash`: The BLAKE3 hash of the content.
+#[must_use]
+pub fn blob_path(store: &Path, hash: &Hash) -> PathBuf {
+    let hex = hash.to_hex();
+    store
+        .join(BLOBS_DIRECTORY)
+        .join(&hex[..2])
+        .join(hex.as_str())
+}
+
+/// Copies a file, checking that its contents have the expected hash.
+///
+/// The contents are written to a temporary file next to the target first and renamed once
+/// complete, so a target that exists is never partial.
+///
+/// # Returns
+/// True if the file was copied, false if its contents do not have the expected hash.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be read or the target cannot be
+/// written.
+fn copy_verified(source: &Path, target: &Path, hash: &Hash) -> io::Result<bool> {
+    fs::create_dir_all(target.parent().unwrap_or(target))?;
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temporary = target.with_file_name(name);
+
+    let mut reader = File::open(source)?;
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    let mut hasher = Hasher::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                let _ = fs::remove_file(&temporary);
+                return Err(err);
+            }
+        };
+        hasher.update(&buffer[..bytes_read]);
+        writer.write_all(&buffer[..bytes_read])?;
+    }
+    writer.flush()?;
+    drop(writer);
+
+    if hasher.finalize() != *hash {
+        fs::remove_file(&temporary)?;
+        return Ok(false);
+    }
+    fs::rename(&temporary, target)?;
+
+    Ok(true)
+}
+
+/// Copies a file with [`copy_verified`], recording the copy in `log`, if given.
+///
+/// # Parameters
+/// - `size`: The size of the contents, as recorded in the log.
+/// - `mismatch`: The error recorded if the contents do not have the expected hash.
+fn copy_logged(
+    source: &Path,
+    target: &Path,
+    hash: &Hash,
+    size: u64,
+    log: Option<&mut AuditLog>,
+    mismatch: &str,
+) -> io::Result<bool> {
+    let copied = copy_verified(source, target, hash);
+    if let Some(log) = log {
+        let error = match &copied {
+            Ok(true) => None,
+            Ok(false) => Some(mismatch.to_string()),
+            Err(err) => Some(err.to_string()),
+        };
+        log.record(
+            Operation::Copy,
+            source,
+            target,
+            hash,
+            size,
+            error.as_deref(),
+        )?;
+    }
+    copied
+}
+
+/// Exports a directory into a content-addressed store.
+///
+/// Every file is hashed by the comparison pipeline, and each content the store does not hold yet
+/// is copied once into a blob named after its hash, see [`blob_path`]. Identical files are thus
+/// stored once, and exporting several directories into the same store only adds the new
+/// contents. Files are stored as they are, ignoring `options.decompress`,
+/// `options.strip_metadata` and `options.transform`.
+///
+/// # Parameters
+/// - `directory`: The directory to export.
+/// - `store`: The root directory of the store. It is created if it does not exist.
+/// - `options`: The options controlling which files are included and the thread counts.
+/// - `log`: The audit log every copy into the store is recorded in, if any.
+///
+/// # Returns
+/// The exported files with their hashes, to be written as the manifest of the export, see
+/// [`crate::sums::write_checksum_lines`].
+///
+/// # Errors
+/// This function returns an `io::Error` if the directory cannot be hashed, see
+/// [`compare_multiple_roots`], a blob cannot be written, or every copy of a content changed
+/// while it was exported. Failing to write the audit log is an error as well.
+pub fn export_cas(
+    directory: &Path,
+    store: &Path,
+    options: &CompareOptions,
+    mut log: Option<&mut AuditLog>,
+) -> io::Result<CasExport> {
+    let scanned = compare_multiple_roots(
+        std::slice::from_ref(&directory.to_path_buf()),
+        &[],
+        &CompareOptions {
+            relative: true,
+            absolute: false,
+            relative_to1: None,
+            include_intersection: false,
+            include_unique_dir1: false,
+            include_unique_dir2: false,
+            include_groups: true,
+            decompress: Vec::new(),
+            strip_metadata: false,
+            transform: None,
+            ..options.clone()
+        },
+    )?;
+
+    let mut export = CasExport {
+        skipped: scanned.skipped,
+        walk_errors: scanned.walk_errors,
+        ..CasExport::default()
+    };
+    for group in scanned.groups.unwrap_or_default() {
+        let blob = blob_path(store, &group.hash);
+        if blob.is_file() {
+            export.existing += 1;
+        } else {
+            // Any copy will do, as long as it did not change since it was hashed
+            let mut copied = false;
+            for path in &group.dir1 {
+                let copied_now = copy_logged(
+                    &directory.join(path),
+                    &blob,
+                    &group.hash,
+                    group.size,
+                    log.as_deref_mut(),
+                    "the file changed while it was exported",
+                )?;
+                if copied_now {
+                    copied = true;
+                    break;
+                }
+            }
+            if !copied {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "'{}' changed while it was exported",
+                        directory.join(&group.dir1[0]).display()
+                    ),
+                ));
+            }
+            export.copied += 1;
+            export.copied_bytes += group.size;
+        }
+
+        export
+            .files
+            .extend(group.dir1.into_iter().map(|path| (path, group.hash)));
+    }
+    export.files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(export)
+}
+
+/// The outcome of restoring a tree from a content-addressed store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CasRestore {
+    /// The files restored, with paths relative to the destination and sorted.
+    pub restored: Vec<PathBuf>,
+    /// The files whose blob is not in the store, sorted.
+    pub missing: Vec<PathBuf>,
+    /// The files whose blob does not have the hash it is named after, sorted.
+    pub corrupt: Vec<PathBuf>,
+}
+
+impl CasRestore {
+    /// Returns true if every file was restored.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+/// Restores a tree from a content-addressed store.
+///
+/// Each file of the manifest is copied from the blob of its hash, see [`blob_path`], and the
+/// contents are hashed on the way out, so a blob damaged in the store is reported as corrupt
+/// instead of being restored. Files whose blob is missing or corrupt are left out, and the others
+/// are restored regardless.
+///
+/// # Parameters
+/// - `store`: The root directory of the store.
+/// - `manifest`: The paths of the files and their lowercase hex BLAKE3 hashes, as read by
+///   [`crate::sums::read_checksum_lines`].
+/// - `destination`: The directory to restore the files into. It is created if it does not exist.
+/// - `log`: The audit log every restored file is recorded in, if any.
+///
+/// # Returns
+/// The restored, missing and corrupt files.
+///
+/// # Errors
+/// This function returns an `io::Error` if a file cannot be written, an error of kind
+/// `AlreadyExists` if a file to restore already exists, or one of kind `InvalidData` if a path
+/// of the manifest is absolute or leaves the destination, or a hash is malformed. Failing to
+/// write the audit log is an error as well.
+pub fn restore_cas(
+    store: &Path,
+    manifest: &[(PathBuf, String)],
+    destination: &Path,
+    mut log: Option<&mut AuditLog>,
+) -> io::Result<CasRestore> {
+    // Check the whole manifest before writing anything
+    let mut files = Vec::with_capacity(manifest.len());
+    for (path, hex) in manifest {
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' is not a path inside the destination", path.display()),
+            ));
+        }
+        let hash = Hash::from_hex(hex).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' has no valid BLAKE3 hash", path.display()),
+            )
+        })?;
+
+        let target = destination.join(path);
+        if target.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("'{}' already exists", target.display()),
+            ));
+        }
+        files.push((path, hash, target));
+    }
+
+    let mut restore = CasRestore::default();
+    for (path, hash, target) in files {
+        let blob = blob_path(store, &hash);
+        if !blob.is_file() {
+            restore.missing.push(path.clone());
+        } else if copy_logged(
+            &blob,
+            &target,
+            &hash,
+            fs::metadata(&blob).map_or(0, |metadata| metadata.len()),
+            log.as_deref_mut(),
+            "the blob is corrupt",
+        )? {
+            restore.restored.push(path.clone());
+        } else {
+            restore.corrupt.push(path.clone());
+        }
+    }
+    for paths in [
+        &mut restore.restored,
+        &mut restore.missing,
+        &mut restore.corrupt,
+    ] {
+        paths.sort();
+    }
+
+    Ok(restore)
+}
@@ -0,0 +1,330 @@
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::options::CompareOptions;
+use crate::pipeline::{channel, CHUNK_SIZE};
+use crate::util::{send_file_paths, walk_error_to_io};
+
+/// A checksum algorithm used by external checksum formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    /// Returns the lowercase name of the algorithm, e.g. `sha256`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Returns the length of the digests of the algorithm in bytes.
+    #[must_use]
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 | Self::Blake3 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+
+    /// Looks up an algorithm by name, ignoring case and dashes (`SHA-256` and `sha256` both work).
+    ///
+    /// # Parameters
+    /// - `name`: The name of the algorithm.
+    ///
+    /// # Returns
+    /// The algorithm, or None if the name is unknown.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "md5" => Some(Self::Md5),
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            "blake3" | "b3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+}
+
+/// The running state of a single algorithm.
+enum State {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Computes several digests of the same data in a single pass.
+pub struct MultiHasher {
+    states: Vec<State>,
+}
+
+impl MultiHasher {
+    /// Creates a hasher computing the given algorithms.
+    #[must_use]
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        let states = algorithms
+            .iter()
+            .map(|algorithm| match algorithm {
+                Algorithm::Md5 => State::Md5(Md5::new()),
+                Algorithm::Sha1 => State::Sha1(Sha1::new()),
+                Algorithm::Sha256 => State::Sha256(Sha256::new()),
+                Algorithm::Sha512 => State::Sha512(Sha512::new()),
+                Algorithm::Blake3 => State::Blake3(Box::default()),
+            })
+            .collect();
+
+        Self { states }
+    }
+
+    /// Feeds data to all algorithms.
+    pub fn update(&mut self, data: &[u8]) {
+        for state in &mut self.states {
+            match state {
+                State::Md5(hasher) => hasher.update(data),
+                State::Sha1(hasher) => hasher.update(data),
+                State::Sha256(hasher) => hasher.update(data),
+                State::Sha512(hasher) => hasher.update(data),
+                State::Blake3(hasher) => {
+                    hasher.update(data);
+                }
+            }
+        }
+    }
+
+    /// Returns the lowercase hex digests, in the order the algorithms were given.
+    #[must_use]
+    pub fn finalize(self) -> Vec<String> {
+        self.states
+            .into_iter()
+            .map(|state| match state {
+                State::Md5(hasher) => to_hex(&hasher.finalize()),
+                State::Sha1(hasher) => to_hex(&hasher.finalize()),
+                State::Sha256(hasher) => to_hex(&hasher.finalize()),
+                State::Sha512(hasher) => to_hex(&hasher.finalize()),
+                State::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Formats bytes as a lowercase hex string.
+#[must_use]
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Parses a hex string, in upper or lower case, into bytes.
+///
+/// # Returns
+/// The bytes, or None if the string has an odd length or a character that is not a hex digit.
+#[must_use]
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The digests computed for a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDigest {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The lowercase hex digests, in the order the algorithms were requested.
+    pub digests: Vec<String>,
+}
+
+/// Computes the digests of everything read from `reader`.
+///
+/// # Parameters
+/// - `reader`: The data to digest.
+/// - `algorithms`: The algorithms to compute.
+///
+/// # Returns
+/// The number of bytes read and the lowercase hex digests.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails.
+pub fn digest_reader(
+    mut reader: impl Read,
+    algorithms: &[Algorithm],
+) -> io::Result<(u64, Vec<String>)> {
+    let mut hasher = MultiHasher::new(algorithms);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+
+    loop {
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hasher.update(&buffer[..bytes_read]);
+        size += bytes_read as u64;
+    }
+
+    Ok((size, hasher.finalize()))
+}
+
+/// Computes the digests of the file at the given path.
+///
+/// # Parameters
+/// - `path`: The file to digest.
+/// - `algorithms`: The algorithms to compute.
+///
+/// # Returns
+/// The size of the file and the lowercase hex digests.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be opened or read.
+pub fn digest_file(path: &Path, algorithms: &[Algorithm]) -> io::Result<(u64, Vec<String>)> {
+    digest_reader(File::open(path)?, algorithms)
+}
+
+/// Computes the digests of every file in a directory tree in parallel.
+///
+/// The tree is walked like a comparison root, honoring `skip_hidden` and `use_ignore_file`,
+/// and the files are digested by `io_threads` threads. Since the digests are meant to cover the
+/// whole tree, a directory that cannot be read is an error.
+///
+/// # Parameters
+/// - `directory`: The root directory of the tree.
+/// - `algorithms`: The algorithms to compute.
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The digests of all files, sorted by path.
+///
+/// # Panics
+/// This function may panic if a thread panics.
+///
+/// # Errors
+/// This function returns the first `io::Error` encountered while reading a file, or an
+/// `io::Error` naming the first path that could not be read while walking the tree.
+pub fn digest_tree(
+    directory: &Path,
+    algorithms: &[Algorithm],
+    options: &CompareOptions,
+) -> io::Result<Vec<FileDigest>> {
+    let mut digests = Vec::new();
+    for_each_digest(directory, algorithms, options, |file| {
+        digests.push(file);
+        Ok(())
+    })?;
+    digests.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(digests)
+}
+
+/// Computes the digests of every file in a directory tree in parallel, handing each file to
+/// `on_file` as soon as it is digested.
+///
+/// Unlike [`digest_tree`], nothing is collected: the files arrive in the order they are
+/// finished, and the queues of paths and digests hold at most `max_in_flight` files, or four
+/// per thread by default. Memory use thus stays the same however large the tree is, which
+/// suits writing the digests of enormous trees straight to a file.
+///
+/// After the first error, `on_file` is no longer called, but the walk still runs to its end.
+///
+/// # Parameters
+/// - `directory`: The root directory of the tree.
+/// - `algorithms`: The algorithms to compute.
+/// - `options`: The options controlling which files are included and the thread count.
+/// - `on_file`: Called on the calling thread with the digests of each file.
+///
+/// # Returns
+/// The number of files digested.
+///
+/// # Panics
+/// This function may panic if a thread panics.
+///
+/// # Errors
+/// This function returns the first `io::Error` encountered while reading a file or returned
+/// by `on_file`, or an `io::Error` naming the first path that could not be read while walking
+/// the tree.
+pub fn for_each_digest(
+    directory: &Path,
+    algorithms: &[Algorithm],
+    options: &CompareOptions,
+    mut on_file: impl FnMut(FileDigest) -> io::Result<()>,
+) -> io::Result<usize> {
+    let threads = options.io_threads.max(1);
+    let capacity = Some(options.max_in_flight.unwrap_or(4 * threads));
+    let (sender, receiver) = channel::<PathBuf>(capacity);
+    let (digest_sender, digests) = channel::<io::Result<FileDigest>>(capacity);
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let paths = receiver.clone();
+            let digest_sender = digest_sender.clone();
+            scope.spawn(move || {
+                for path in paths {
+                    let digest = digest_file(&path, algorithms).map(|(size, hex)| FileDigest {
+                        path,
+                        size,
+                        digests: hex,
+                    });
+                    if digest_sender.send(digest).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop((receiver, digest_sender));
+
+        let walker = scope.spawn(move || send_file_paths(directory, &sender, options, &[], None));
+
+        let mut files = 0;
+        let mut first_error = None;
+        for digest in digests {
+            if first_error.is_some() {
+                continue;
+            }
+            match digest.and_then(&mut on_file) {
+                Ok(()) => files += 1,
+                Err(err) => first_error = Some(err),
+            }
+        }
+
+        let walk_errors = walker.join().expect("Thread panicked");
+        if let Some(err) = first_error {
+            return Err(err);
+        }
+        if let Some(err) = walk_errors.first() {
+            return Err(walk_error_to_io(err));
+        }
+        Ok(files)
+    })
+}
@@ -1,11 +1,31 @@
 use blake3::Hash;
-use crossbeam_channel::{select, unbounded, Receiver};
+use crossbeam_channel::{bounded, unbounded};
 use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
 use std::io::{self};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 
-use crate::util::{compute_file_hash_and_insert_path, send_file_paths};
+use crate::checksum::{digest_reader, Algorithm};
+use crate::error::FileMatchError;
+use crate::filesystem::{StorageKind, HDD_CHUNK_SIZE, HDD_IO_THREADS};
+use crate::options::{CompareOptions, ProgressEvent, ProgressHook, SumsPolicy};
+use crate::pipeline::{
+    channel, hash_files, read_files, sampled, size_mismatch, ProgressCounter, ReadGate, RootWatch,
+    Side, CHUNK_SIZE, SMALL_FILE_SIZE,
+};
+use crate::result::{
+    error_paths, keep_slowest, modified_paths, ComparisonResult, HashGroup, ReadError, Recheck,
+    ResultDifference, SkipReason, SkippedFile, SumsCheck, WalkError,
+};
+use crate::sums::{HashJournal, SumsStore};
+use crate::util::{
+    calculate_file_hash, find_overlapping_roots, matching_root, open_file, open_raw_file,
+    relative_path, send_file_paths, split_case_aliases, split_link_aliases,
+};
 
 /// Partitions values from two hash maps based on key occurrence.
 ///
@@ -18,6 +38,10 @@ use crate::util::{compute_file_hash_and_insert_path, send_file_paths};
 /// The function accepts boolean flags to control which groups are computed.
 /// If a flag is false, the corresponding result is returned as None.
 ///
+/// Each group is ordered by key, and the values of a key are sorted. For keys in both maps, the
+/// values of `map1` come first. The order therefore does not depend on the iteration order of
+/// the maps.
+///
 /// # Parameters
 /// - `map1`: The first hash map.
 /// - `map2`: The second hash map.
@@ -31,111 +55,1127 @@ use crate::util::{compute_file_hash_and_insert_path, send_file_paths};
 /// - The second vector holds values unique to `map1` (if requested).
 /// - The third vector holds values unique to `map2` (if requested).
 #[allow(clippy::type_complexity)]
-fn partition_map_values<K: Eq + std::hash::Hash + Clone, V: Clone>(
+pub(crate) fn partition_map_values<K: Ord + std::hash::Hash, V: Ord + Clone>(
     map1: &HashMap<K, Vec<V>>,
     map2: &HashMap<K, Vec<V>>,
     include_intersection: bool,
     include_unique_dir1: bool,
     include_unique_dir2: bool,
 ) -> (Option<Vec<V>>, Option<Vec<V>>, Option<Vec<V>>) {
-    let keys1: HashSet<_> = map1.keys().cloned().collect();
-    let keys2: HashSet<_> = map2.keys().cloned().collect();
-    let keys_intersection: HashSet<_> = keys1.intersection(&keys2).cloned().collect();
+    let mut keys1: Vec<&K> = map1.keys().collect();
+    let mut keys2: Vec<&K> = map2.keys().collect();
+    keys1.sort_unstable();
+    keys2.sort_unstable();
+
+    let sorted_values = |values: &Vec<V>| {
+        let mut values = values.clone();
+        values.sort();
+        values
+    };
 
     let intersection = include_intersection.then(|| {
-        keys_intersection
-            .into_iter()
-            .flat_map(|key| {
-                map1.get(&key)
+        keys1
+            .iter()
+            .filter_map(|key| Some((&map1[*key], map2.get(*key)?)))
+            .flat_map(|(values1, values2)| {
+                sorted_values(values1)
                     .into_iter()
-                    .chain(map2.get(&key))
-                    .flat_map(|values| values.iter().cloned())
+                    .chain(sorted_values(values2))
             })
             .collect()
     });
 
     let unique_dir1 = include_unique_dir1.then(|| {
-        map1.iter()
-            .filter(|(key, _)| !keys2.contains(*key))
-            .flat_map(|(_, values)| values.iter().cloned())
+        keys1
+            .iter()
+            .filter(|key| !map2.contains_key(**key))
+            .flat_map(|key| sorted_values(&map1[*key]))
             .collect()
     });
 
     let unique_dir2 = include_unique_dir2.then(|| {
-        map2.iter()
-            .filter(|(key, _)| !keys1.contains(*key))
-            .flat_map(|(_, values)| values.iter().cloned())
+        keys2
+            .iter()
+            .filter(|key| !map1.contains_key(**key))
+            .flat_map(|key| sorted_values(&map2[*key]))
             .collect()
     });
 
     (intersection, unique_dir1, unique_dir2)
 }
 
-/// Receives file paths from two channels, computes their hash, and groups them by hash.
+/// Returns the directories the paths of one side are made relative to.
+///
+/// # Errors
+/// This function returns an error of kind `InvalidInput` if a root is not inside `relative_to`.
+fn relative_bases(
+    roots: &[PathBuf],
+    relative_to: Option<&PathBuf>,
+    relative: bool,
+) -> io::Result<Vec<PathBuf>> {
+    match relative_to {
+        Some(base) => match roots.iter().find(|root| !root.starts_with(base)) {
+            Some(root) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' is not inside '{}'", root.display(), base.display()),
+            )),
+            None => Ok(vec![base.clone()]),
+        },
+        None if relative => Ok(roots.to_vec()),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Compares two sets of directories by grouping files according to their hashes.
+///
+/// Each side of the comparison may consist of several root directories, whose union is treated
+/// as one logical set of files. This is handy when a dataset is split across multiple drives.
+/// The directories are scanned concurrently, the hash of each file is computed, and the file
+/// paths are grouped based on their hash values. The two groups are then compared to determine:
+/// - File paths common to both sides.
+/// - File paths unique to the first side.
+/// - File paths unique to the second side.
+///
+/// Reading and hashing run in separate thread pools: reader threads stream file contents from
+/// disk while hasher threads consume them. The size of both pools is taken from `options`, which
+/// allows tuning for storage where latency rather than CPU limits the throughput. With
+/// `options.adaptive_io` set, the number of files read at once is adjusted to the observed
+/// throughput instead, up to the number of reader threads. With `options.io_threads1` or
+/// `options.io_threads2` set, each side is read by a pool of its own, so storage devices of
+/// different speeds can each be read at their best parallelism. A side on a hard disk by
+/// `options.storage` is read by a single thread of its own in large reads unless set otherwise.
+/// With `options.max_open_files` set, at most that many files are read at once. With
+/// `options.max_in_flight` set, the queues between the stages are bounded, so the directory walk
+/// and the readers wait for the hashers instead of queueing files without limit.
+///
+/// When `options.relative` is set, each path is made relative to the root it was found in.
+/// `options.relative_to1` and `options.relative_to2` make the paths of a side relative to an
+/// ancestor of its roots instead. With `options.absolute` set, the roots are canonicalized
+/// before walking them, so the paths are absolute and free of `..` and linked roots.
+///
+/// The output order is the same across runs. With `options.sort` set, each group is sorted by
+/// path. Otherwise the paths are grouped by hash, in order of the hash, and sorted by path
+/// within a hash, with the paths of the first side first. Files with the same content are
+/// therefore listed next to each other. Skipped files and walk errors are always sorted by path.
+///
+/// Files with the same hash are checked to have the same size. A mismatch means a hash
+/// collision or a bug, and fails the comparison.
+///
+/// Paths that cannot be read while walking the directories, such as directories without
+/// permission, are reported in the result, or fail the comparison if
+/// `options.fail_on_walk_error` is set.
+///
+/// A root directory that becomes unavailable while its files are read, such as an unmounted
+/// network share, does not fail the comparison: the files that could not be read are reported
+/// as read errors, and the side of the root is marked as incomplete in the result.
+///
+/// With `options.sums` set, files whose size and modification time match their entry in the
+/// `.filematch.sums` file of their directory are not read, and with `SumsPolicy::Update` the
+/// sums files are updated with the hashes of the files that were read. With `options.journal`
+/// set, the hashes are likewise taken from and appended to the journal, so an interrupted
+/// comparison can be resumed.
 ///
-/// This function listens on two channels, each providing file paths. File paths from the first channel
-/// are grouped into the first hash map, while file paths from the second channel are grouped into the
-/// second hash map. When one channel is closed, it drains the other channel.
+/// With `options.reference_set` set, files found in the reference set are left out of both
+/// sides.
+///
+/// With `options.prefilter_sizes` set, the directories are walked completely before any file is
+/// read, and files of a size not found on the other side are reported as unique without being
+/// read, after the files grouped by hash. The prefilter is not applied when the hash of every
+/// file is needed: for the groups by content, ignored contents, a reference set, decompression,
+/// metadata stripping, or a content transform.
+///
+/// Root directories inside, or the same as, another root are rejected unless
+/// `options.allow_overlapping_roots` is set. A nested root is then left out of the walk of the
+/// root containing it.
 ///
 /// # Parameters
-/// - `r1`: Receiver for file paths for the first group.
-/// - `r2`: Receiver for file paths for the second group.
-/// - `base1`: An optional base directory for file paths from the first channel.
-/// - `base2`: An optional base directory for file paths from the second channel.
+/// - `dirs1`: The root directories of the first side.
+/// - `dirs2`: The root directories of the second side.
+/// - `options`: The options controlling the comparison.
 ///
 /// # Returns
-/// A Result containing a tuple of two hash maps:
-/// - The first hash map groups file paths (from `r1`) by their computed hash.
-/// - The second hash map groups file paths (from `r2`) by their computed hash.
-#[allow(clippy::type_complexity)]
-#[allow(clippy::needless_pass_by_value)] // TODO: This can most likely be fixed
-fn group_files_by_hash(
-    r1: &Receiver<PathBuf>,
-    r2: &Receiver<PathBuf>,
-    base1: Option<PathBuf>,
-    base2: Option<PathBuf>,
-) -> Result<(HashMap<Hash, Vec<PathBuf>>, HashMap<Hash, Vec<PathBuf>>), io::Error> {
-    let mut map1 = HashMap::new();
-    let mut map2 = HashMap::new();
-
-    loop {
-        select! {
-            recv(r1) -> msg => {
-                if let Ok(path) = msg {
-                    compute_file_hash_and_insert_path(&mut map1, path, base1.as_ref())?;
-                } else {
-                    for path in r2 {
-                        compute_file_hash_and_insert_path(&mut map2, path, base2.as_ref())?;
-                    }
-                    break;
+/// A Result containing the file paths present on both sides, unique to `dirs1`, and unique to
+/// `dirs2` (each if requested), the files that were skipped, the paths that could not be
+/// read, and the files grouped by content (if requested).
+///
+/// # Errors
+/// This function returns `FileMatchError::Hash` if a file cannot be read, `FileMatchError::Walk`
+/// if a path cannot be read while walking the directories and `options.fail_on_walk_error` is
+/// set, and `FileMatchError::Join` if a thread panicked. Other errors are `FileMatchError::Io`:
+/// one of kind `InvalidInput` if roots overlap and `options.allow_overlapping_roots` is not set,
+/// if a root is not inside the directory its side is made relative to, or if `options.sums` or
+/// `options.reference_set` is combined with decompression or metadata stripping, one of kind
+/// `PermissionDenied` if `options.read_only` is set with `SumsPolicy::Update` or a journal, and
+/// one of kind `InvalidData` if two files with the same hash differ in size. Failing to
+/// canonicalize a root while `options.absolute` is set, to open the journal, or to write a sums
+/// file or the journal is an error as well.
+pub fn compare_multiple_roots(
+    dirs1: &[PathBuf],
+    dirs2: &[PathBuf],
+    options: &CompareOptions,
+) -> Result<ComparisonResult, FileMatchError> {
+    // Walk the canonical roots, so every path found is absolute and canonical up to its root.
+    let canonical = |dirs: &[PathBuf]| {
+        dirs.iter()
+            .map(std::fs::canonicalize)
+            .collect::<io::Result<Vec<_>>>()
+    };
+    let (canonical1, canonical2);
+    let (dirs1, dirs2) = if options.absolute {
+        canonical1 = canonical(dirs1)?;
+        canonical2 = canonical(dirs2)?;
+        (&canonical1[..], &canonical2[..])
+    } else {
+        (dirs1, dirs2)
+    };
+
+    // Find roots inside other roots, to leave them out of the walk of the outer root.
+    let roots: Vec<PathBuf> = dirs1.iter().chain(dirs2).cloned().collect();
+    let mut excluded: HashMap<&Path, Vec<PathBuf>> = HashMap::new();
+    for overlap in find_overlapping_roots(&roots)? {
+        if !options.allow_overlapping_roots {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, overlap.to_string()).into());
+        }
+        if let Some(nested) = overlap.nested {
+            let outer = roots.iter().find(|root| **root == overlap.outer);
+            if let Some(outer) = outer {
+                excluded.entry(outer.as_path()).or_default().push(nested);
+            }
+        }
+    }
+
+    if options.reference_set.is_some()
+        && (!options.decompress.is_empty() || options.strip_metadata || options.transform.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a reference set cannot be used when decompressing, stripping metadata or \
+             transforming contents",
+        )
+        .into());
+    }
+
+    if options.range_hash.is_some()
+        && (options.sums != SumsPolicy::Ignore
+            || options.journal.is_some()
+            || !options.ignore_hashes.is_empty()
+            || options.reference_set.is_some()
+            || !options.decompress.is_empty()
+            || options.strip_metadata
+            || options.transform.is_some())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "hashing by ranges cannot be combined with sums files, a journal, ignored contents, a \
+             reference set, decompressing, stripping metadata or transforming contents",
+        )
+        .into());
+    }
+
+    if options.read_only && (options.sums == SumsPolicy::Update || options.journal.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "sums files and journals cannot be written in read-only mode",
+        )
+        .into());
+    }
+
+    let journal = options
+        .journal
+        .as_deref()
+        .map(HashJournal::open)
+        .transpose()?;
+    let sums = match (options.sums, journal) {
+        (SumsPolicy::Ignore, None) => None,
+        (SumsPolicy::Ignore, Some(journal)) => Some(Arc::new(SumsStore::journal_only(journal))),
+        _ if !options.decompress.is_empty()
+            || options.strip_metadata
+            || options.transform.is_some() =>
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sums files cannot be used when decompressing, stripping metadata or transforming \
+                 contents",
+            )
+            .into());
+        }
+        (policy, journal) => {
+            let sums = SumsStore::new(policy == SumsPolicy::Update);
+            Some(Arc::new(match journal {
+                Some(journal) => sums.with_journal(journal),
+                None => sums,
+            }))
+        }
+    };
+
+    // Hard disks are read one file at a time in large reads, unless set otherwise
+    let on_hdd = options.storage.map(|kind| kind == Some(StorageKind::Hdd));
+    let hdd_options;
+    let options = if on_hdd.contains(&true) && options.chunk_size == CHUNK_SIZE {
+        hdd_options = CompareOptions {
+            chunk_size: HDD_CHUNK_SIZE,
+            ..options.clone()
+        };
+        &hdd_options
+    } else {
+        options
+    };
+    let io_threads1 = options.io_threads1.or(on_hdd[0].then_some(HDD_IO_THREADS));
+    let io_threads2 = options.io_threads2.or(on_hdd[1].then_some(HDD_IO_THREADS));
+
+    // Readers either serve both sides, or each side has readers of its own
+    let reader_sides = if io_threads1.is_some() || io_threads2.is_some() {
+        let count = |threads: Option<usize>| threads.unwrap_or(options.io_threads).max(1);
+        let mut sides = vec![Some(Side::Dir1); count(io_threads1)];
+        sides.extend(vec![Some(Side::Dir2); count(io_threads2)]);
+        sides
+    } else {
+        vec![None; options.io_threads.max(1)]
+    };
+    let io_threads = reader_sides.len();
+    let hash_threads = options.hash_threads.max(1);
+    let mut readers = Vec::with_capacity(io_threads);
+    let mut hashers = Vec::with_capacity(hash_threads);
+
+    // Create channels for sending file paths from both directories.
+    let (sender1, receiver1) = channel(options.max_in_flight);
+    let (sender2, receiver2) = channel(options.max_in_flight);
+
+    // Create the channel connecting readers and hashers, and one to recycle read buffers.
+    let (job_sender, job_receiver) = channel(options.max_in_flight);
+    let (buffer_sender, buffer_receiver) = unbounded();
+
+    let bases1 = relative_bases(dirs1, options.relative_to1.as_ref(), options.relative)?;
+    let bases2 = relative_bases(dirs2, options.relative_to2.as_ref(), options.relative)?;
+
+    // Spawn hasher threads, sharing the count of files hashed and the watch of the roots.
+    let watch = Arc::new(RootWatch::new(dirs1, dirs2));
+    let progress = options
+        .on_progress
+        .clone()
+        .map(|hook| Arc::new(ProgressCounter::new(hook)));
+    for _ in 0..hash_threads {
+        let jobs = job_receiver.clone();
+        let buffers = buffer_sender.clone();
+        let b1 = bases1.clone();
+        let b2 = bases2.clone();
+        let sums = sums.clone();
+        let reference = options.reference_set.clone();
+        let errors = options.read_errors;
+        let watch = watch.clone();
+        let progress = progress.clone();
+        let slow_files = options.slow_files;
+
+        let handle = thread::spawn(move || {
+            hash_files(
+                &jobs,
+                &buffers,
+                &b1,
+                &b2,
+                sums.as_deref(),
+                reference.as_deref(),
+                errors,
+                Some(&watch),
+                progress.as_deref(),
+                slow_files,
+            )
+        });
+        hashers.push(handle);
+    }
+    drop(job_receiver);
+    drop(buffer_sender);
+
+    // Optionally adjust the number of files read at once, starting at one per physical core,
+    // and keep it within the limit of open files.
+    let max_open = options
+        .max_open_files
+        .map_or(io_threads, |max| max.clamp(1, io_threads));
+    let gate = if options.adaptive_io {
+        Some(ReadGate::new(num_cpus::get_physical(), max_open))
+    } else {
+        (max_open < io_threads).then(|| ReadGate::new(max_open, max_open))
+    }
+    .map(Arc::new);
+    let (stop_sender, stop_receiver) = bounded::<()>(0);
+    let controller = gate
+        .clone()
+        .filter(|_| options.adaptive_io)
+        .map(|gate| thread::spawn(move || gate.control(&stop_receiver)));
+
+    // Spawn reader threads. A reader of a single side gets a closed channel for the other side.
+    let (_, closed) = unbounded();
+    for side in reader_sides {
+        let r1 = match side {
+            Some(Side::Dir2) => closed.clone(),
+            _ => receiver1.clone(),
+        };
+        let r2 = match side {
+            Some(Side::Dir1) => closed.clone(),
+            _ => receiver2.clone(),
+        };
+        let jobs = job_sender.clone();
+        let buffers = buffer_receiver.clone();
+        let opts = options.clone();
+        let sums = sums.clone();
+        let gate = gate.clone();
+
+        let handle = thread::spawn(move || {
+            read_files(
+                &r1,
+                &r2,
+                &jobs,
+                &buffers,
+                &opts,
+                sums.as_deref(),
+                gate.as_deref(),
+            );
+        });
+        readers.push(handle);
+    }
+    // Only the readers receive the paths, so the walk stops once they are gone, such as after
+    // every hasher failed.
+    drop(receiver1);
+    drop(receiver2);
+    drop(job_sender);
+
+    // The size of every file is only enough to tell unique files if no hash of them is needed
+    let prefilter = options.prefilter_sizes
+        && !options.include_groups
+        && options.ignore_hashes.is_empty()
+        && options.reference_set.is_none()
+        && options.decompress.is_empty()
+        && !options.strip_metadata
+        && options.transform.is_none();
+    let collect = prefilter || options.collapse_case_aliases || options.collapse_link_aliases;
+    let (found_sender, found_receiver) = unbounded();
+    let mut found: [Vec<PathBuf>; 2] = Default::default();
+
+    // Send file paths from each directory into the respective channels, collecting the paths
+    // that cannot be read. With the size prefilter or case aliases collapsed, the paths are
+    // collected first instead.
+    let mut walk_errors = Vec::new();
+    let mut left_out = Vec::new();
+    for (side, dirs, sender, bases) in [
+        (Side::Dir1, dirs1, &sender1, &bases1),
+        (Side::Dir2, dirs2, &sender2, &bases2),
+    ] {
+        for dir in dirs {
+            let exclude = excluded.get(dir.as_path()).map_or(&[][..], Vec::as_slice);
+            let mut paths = Vec::new();
+            let errors = send_file_paths(
+                dir,
+                if collect { &found_sender } else { sender },
+                options,
+                exclude,
+                options.include_excluded.then_some(&mut paths),
+            );
+            let index = match side {
+                Side::Dir1 => 0,
+                Side::Dir2 => 1,
+            };
+            found[index].extend(found_receiver.try_iter());
+            left_out.extend(paths.into_iter().map(|(path, reason)| {
+                let base = matching_root(&path, bases);
+                SkippedFile {
+                    side,
+                    path: relative_path(path, base),
+                    reason,
                 }
-            },
-            recv(r2) -> msg => {
-                if let Ok(path) = msg {
-                    compute_file_hash_and_insert_path(&mut map2, path, base2.as_ref())?;
-                } else {
-                    for path in r1 {
-                        compute_file_hash_and_insert_path(&mut map1, path, base1.as_ref())?;
+            }));
+            for err in errors {
+                if options.fail_on_walk_error {
+                    let path = err.path().unwrap_or(dir).to_path_buf();
+                    let message = err.to_string();
+                    let source = err
+                        .into_io_error()
+                        .unwrap_or_else(|| io::Error::other(message));
+                    return Err(FileMatchError::Walk { path, source });
+                }
+                let path = err.path().unwrap_or(dir).to_path_buf();
+                let base = matching_root(&path, bases);
+                walk_errors.push(WalkError {
+                    side,
+                    path: relative_path(path, base),
+                    message: err
+                        .io_error()
+                        .map_or_else(|| err.to_string(), ToString::to_string),
+                });
+            }
+        }
+    }
+
+    // Paths through links, or differing only in case on case-insensitive filesystems, may lead
+    // to a file found already
+    type Split = fn(Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>);
+    let collapse: [(bool, Split, SkipReason); 2] = [
+        (
+            options.collapse_link_aliases,
+            split_link_aliases,
+            SkipReason::LinkAlias,
+        ),
+        (
+            options.collapse_case_aliases,
+            split_case_aliases,
+            SkipReason::CaseAlias,
+        ),
+    ];
+    for (_, split, reason) in collapse.into_iter().filter(|(enabled, ..)| *enabled) {
+        for (index, (side, bases)) in [(Side::Dir1, &bases1), (Side::Dir2, &bases2)]
+            .into_iter()
+            .enumerate()
+        {
+            let (kept, aliases) = split(std::mem::take(&mut found[index]));
+            found[index] = kept;
+            if options.include_excluded {
+                left_out.extend(aliases.into_iter().map(|path| {
+                    let base = matching_root(&path, bases);
+                    SkippedFile {
+                        side,
+                        path: relative_path(path, base),
+                        reason,
+                    }
+                }));
+            }
+        }
+    }
+
+    // Only files of a size found on the other side can match it, so the others are unique
+    // without being read. Files whose size cannot be read are read to report the error.
+    let mut unread: [Vec<PathBuf>; 2] = Default::default();
+    if prefilter {
+        let sized = found.map(|paths| {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let size = fs::metadata(&path).map(|metadata| metadata.len()).ok();
+                    (path, size)
+                })
+                .collect::<Vec<_>>()
+        });
+        let sizes: [HashSet<u64>; 2] = [&sized[0], &sized[1]]
+            .map(|files| files.iter().filter_map(|(_, size)| *size).collect());
+        for (index, (files, sender)) in sized.into_iter().zip([&sender1, &sender2]).enumerate() {
+            for (path, size) in files {
+                match size {
+                    Some(size) if !sizes[1 - index].contains(&size) => unread[index].push(path),
+                    // Nothing receives the paths anymore once the readers stopped
+                    _ => {
+                        let _ = sender.send(path);
                     }
-                    break;
                 }
             }
         }
+    } else if collect {
+        for (paths, sender) in found.into_iter().zip([&sender1, &sender2]) {
+            for path in paths {
+                let _ = sender.send(path);
+            }
+        }
+    }
+
+    // Close the channels so that threads can finish processing.
+    drop(sender1);
+    drop(sender2);
+
+    for handle in readers {
+        handle
+            .join()
+            .map_err(|_| FileMatchError::Join { task: "reading" })?;
+    }
+    drop(stop_sender);
+    if let Some(controller) = controller {
+        controller
+            .join()
+            .map_err(|_| FileMatchError::Join { task: "pacing" })?;
+    }
+
+    // Combine the results from all hashers, keyed by the hash bytes to order the groups.
+    let mut combined1: HashMap<[u8; 32], Vec<PathBuf>> = HashMap::new();
+    let mut combined2: HashMap<[u8; 32], Vec<PathBuf>> = HashMap::new();
+    let mut sizes: HashMap<[u8; 32], u64> = HashMap::new();
+    let mut skipped = Vec::new();
+    let mut read_errors = Vec::new();
+    let mut sums_check = SumsCheck::default();
+    let mut ranged: HashMap<[u8; 32], Vec<(Side, PathBuf)>> = HashMap::new();
+    let mut slow_files = Vec::new();
+
+    for handle in hashers {
+        let hashed = handle
+            .join()
+            .map_err(|_| FileMatchError::Join { task: "hashing" })??;
+
+        // Files with the same hash found by different hashers must have the same size too.
+        for (key, size) in &hashed.sizes {
+            let known_size = *sizes.entry(*key.as_bytes()).or_insert(*size);
+            if known_size == *size {
+                continue;
+            }
+            let known_path = combined1
+                .get(key.as_bytes())
+                .or_else(|| combined2.get(key.as_bytes()))
+                .and_then(|paths| paths.first().cloned())
+                .unwrap_or_default();
+            let path = hashed
+                .map1
+                .get(key)
+                .or_else(|| hashed.map2.get(key))
+                .and_then(|paths| paths.first().cloned())
+                .unwrap_or_default();
+            return Err(size_mismatch(key, [(&known_path, known_size), (&path, *size)]).into());
+        }
+
+        for (key, paths) in hashed.map1 {
+            combined1.entry(*key.as_bytes()).or_default().extend(paths);
+        }
+        for (key, paths) in hashed.map2 {
+            combined2.entry(*key.as_bytes()).or_default().extend(paths);
+        }
+        skipped.extend(hashed.skipped);
+        left_out.extend(hashed.excluded);
+        read_errors.extend(hashed.read_errors);
+        sums_check.checked += hashed.sums_check.checked;
+        sums_check.stale.extend(hashed.sums_check.stale);
+        for (key, files) in hashed.ranged {
+            ranged.entry(*key.as_bytes()).or_default().extend(files);
+        }
+        slow_files.extend(hashed.slow_files);
+    }
+    keep_slowest(&mut slow_files, options.slow_files);
+
+    // Only contents matched by ranges on both sides may not match in full
+    ranged.retain(|key, _| combined1.contains_key(key) && combined2.contains_key(key));
+    if options.range_hash.is_some_and(|ranges| ranges.verify) {
+        for (key, files) in std::mem::take(&mut ranged) {
+            let size = sizes[&key];
+            combined1.remove(&key);
+            combined2.remove(&key);
+            for ((side, path), hash) in files.iter().zip(hash_in_full(&files, options)?) {
+                let (combined, bases) = match side {
+                    Side::Dir1 => (&mut combined1, &bases1),
+                    Side::Dir2 => (&mut combined2, &bases2),
+                };
+                let path = relative_path(path.clone(), matching_root(path, bases));
+                combined.entry(*hash.as_bytes()).or_default().push(path);
+                sizes.insert(*hash.as_bytes(), size);
+            }
+        }
+    }
+    let mut ranged_matches: Vec<PathBuf> = ranged
+        .keys()
+        .flat_map(|key| combined1[key].iter().chain(&combined2[key]).cloned())
+        .collect();
+    ranged_matches.sort();
+
+    if let Some(sums) = &sums {
+        sums.save()?;
+    }
+
+    // Leave out ignored contents before anything is derived from the hashes
+    if !options.ignore_hashes.is_empty() {
+        for (side, combined) in [(Side::Dir1, &mut combined1), (Side::Dir2, &mut combined2)] {
+            combined.retain(|key, paths| {
+                if !options.ignore_hashes.contains(&Hash::from_bytes(*key)) {
+                    return true;
+                }
+                left_out.extend(paths.drain(..).map(|path| SkippedFile {
+                    side,
+                    path,
+                    reason: SkipReason::IgnoredHash,
+                }));
+                false
+            });
+        }
     }
 
-    Ok((map1, map2))
+    // Partition the file paths into intersection and unique groups.
+    let (mut intersection_paths, mut unique_dir1_paths, mut unique_dir2_paths) =
+        partition_map_values(
+            &combined1,
+            &combined2,
+            options.include_intersection,
+            options.include_unique_dir1,
+            options.include_unique_dir2,
+        );
+
+    // Group the files of both sides by content, in the same order as the partitions.
+    let groups = options.include_groups.then(|| {
+        let mut keys: Vec<&[u8; 32]> = combined1.keys().chain(combined2.keys()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+
+        keys.into_iter()
+            .map(|key| {
+                let sorted = |map: &HashMap<[u8; 32], Vec<PathBuf>>| {
+                    let mut paths = map.get(key).cloned().unwrap_or_default();
+                    paths.sort();
+                    paths
+                };
+                HashGroup {
+                    hash: Hash::from_bytes(*key),
+                    size: sizes[key],
+                    dir1: sorted(&combined1),
+                    dir2: sorted(&combined2),
+                }
+            })
+            .collect()
+    });
+
+    // Files left unread come after those grouped by hash, by path
+    for ((paths, mut unread), bases) in [&mut unique_dir1_paths, &mut unique_dir2_paths]
+        .into_iter()
+        .zip(unread)
+        .zip([&bases1, &bases2])
+    {
+        if let Some(paths) = paths {
+            unread.sort();
+            paths.extend(
+                unread
+                    .into_iter()
+                    .map(|path| relative_path(path.clone(), matching_root(&path, bases))),
+            );
+        }
+    }
+
+    // Optionally sort the file paths. They are grouped by hash otherwise.
+    if options.sort {
+        if let Some(ref mut paths) = intersection_paths {
+            paths.sort();
+        }
+        if let Some(ref mut paths) = unique_dir1_paths {
+            paths.sort();
+        }
+        if let Some(ref mut paths) = unique_dir2_paths {
+            paths.sort();
+        }
+    }
+    skipped.sort_by(|a, b| a.path.cmp(&b.path));
+    left_out.sort_by(|a, b| a.path.cmp(&b.path));
+    walk_errors.sort_by(|a, b| a.path.cmp(&b.path));
+    read_errors.sort_by(|a: &ReadError, b| a.path.cmp(&b.path));
+    sums_check.stale.sort();
+
+    // Changed files are unique to both sides at the same path below their roots, so paths as
+    // found are made relative to their root to match them
+    let below_roots = |paths: &Option<Vec<PathBuf>>, bases: &[PathBuf], roots: &[PathBuf]| {
+        let paths = paths.as_ref()?;
+        if !bases.is_empty() {
+            return Some(paths.clone());
+        }
+        let relative = paths
+            .iter()
+            .map(|path| relative_path(path.clone(), matching_root(path, roots)));
+        Some(relative.collect::<Vec<_>>())
+    };
+    let modified = modified_paths(
+        below_roots(&unique_dir1_paths, &bases1, dirs1).as_deref(),
+        below_roots(&unique_dir2_paths, &bases2, dirs2).as_deref(),
+    );
+
+    Ok(ComparisonResult {
+        intersection: intersection_paths,
+        unique_dir1: unique_dir1_paths,
+        unique_dir2: unique_dir2_paths,
+        modified,
+        errors: error_paths(&skipped, &walk_errors, &read_errors),
+        skipped,
+        excluded: options.include_excluded.then_some(left_out),
+        walk_errors,
+        read_errors,
+        sums_check,
+        ranged_matches,
+        slow_files,
+        incomplete: watch.lost_sides(),
+        groups,
+    })
+}
+
+/// Hashes files in full by `options.io_threads` threads, for files matched by ranges only.
+///
+/// # Returns
+/// The hashes of the files, in the order of the files.
+///
+/// # Errors
+/// This function returns `FileMatchError::Hash` with the first file that cannot be read.
+fn hash_in_full(
+    files: &[(Side, PathBuf)],
+    options: &CompareOptions,
+) -> Result<Vec<Hash>, FileMatchError> {
+    let next = AtomicUsize::new(0);
+    let hash = |path: &Path| {
+        let mut hasher = blake3::Hasher::new();
+        open_raw_file(path, options)
+            .and_then(|mut file| io::copy(&mut file, &mut hasher))
+            .map(|_| hasher.finalize())
+            .map_err(|source| FileMatchError::Hash {
+                path: path.to_path_buf(),
+                source,
+            })
+    };
+    let mut hashes: Vec<(usize, Hash)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.io_threads.clamp(1, files.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| -> Result<_, FileMatchError> {
+                    let mut hashes = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((_, path)) = files.get(index) else {
+                            return Ok(hashes);
+                        };
+                        hashes.push((index, hash(path)?));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| FileMatchError::Join { task: "hashing" })?
+            })
+            .collect::<Result<Vec<Vec<_>>, _>>()
+            .map(|hashes| hashes.into_iter().flatten().collect())
+    })?;
+
+    hashes.sort_by_key(|(index, _)| *index);
+    Ok(hashes.into_iter().map(|(_, hash)| hash).collect())
 }
 
 /// Compares two directories by grouping files according to their hashes.
 ///
-/// This function scans two directories concurrently, computes the hash of each file, and
-/// groups the file paths based on their hash values. It then compares the two groups to determine:
-/// - File paths common to both directories.
-/// - File paths unique to the first directory.
-/// - File paths unique to the second directory.
+/// This is a convenience wrapper around [`compare_multiple_roots`] with a single root directory
+/// on each side.
+///
+/// # Parameters
+/// - `dir1`: The first directory to compare.
+/// - `dir2`: The second directory to compare.
+/// - `options`: The options controlling the comparison.
+///
+/// # Returns
+/// A Result containing the file paths present in both directories, unique to `dir1`, and
+/// unique to `dir2` (each if requested), and the files that were skipped.
+///
+/// # Errors
+/// This function returns the errors of [`compare_multiple_roots`].
+pub fn compare_two_directories_with_options(
+    dir1: &Path,
+    dir2: &Path,
+    options: &CompareOptions,
+) -> Result<ComparisonResult, FileMatchError> {
+    compare_multiple_roots(&[dir1.to_path_buf()], &[dir2.to_path_buf()], options)
+}
+
+/// Digests the files present in both directories with a second algorithm, as a guard against
+/// hash collisions and bugs before acting on the matches.
+///
+/// Every file of each content found on both sides is read again, the same way as during the
+/// comparison, by `options.io_threads` threads, and all files of a content must have the same
+/// digest with `algorithm` too.
+///
+/// # Parameters
+/// - `groups`: The contents found by the comparison, with paths as found (not relative).
+/// - `algorithm`: The second algorithm, other than BLAKE3.
+/// - `options`: The options the comparison ran with.
+///
+/// # Returns
+/// The number of files digested.
+///
+/// # Errors
+/// This function returns a [`FileMatchError::Hash`] naming the file if a file cannot be read,
+/// a [`FileMatchError::Join`] if a thread panics, or an `io::Error` of kind `InvalidInput` if
+/// `algorithm` is BLAKE3, and one of kind `InvalidData` naming two files of a content whose
+/// digests differ.
+pub fn double_check(
+    groups: &[HashGroup],
+    algorithm: Algorithm,
+    options: &CompareOptions,
+) -> Result<usize, FileMatchError> {
+    if algorithm == Algorithm::Blake3 {
+        return Err(FileMatchError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the double check needs an algorithm other than BLAKE3",
+        )));
+    }
+    let shared: Vec<&HashGroup> = groups.iter().filter(|group| group.is_shared()).collect();
+    let files: Vec<(usize, &PathBuf)> = shared
+        .iter()
+        .enumerate()
+        .flat_map(|(index, group)| {
+            group
+                .dir1
+                .iter()
+                .chain(&group.dir2)
+                .map(move |path| (index, path))
+        })
+        .collect();
+
+    let next = AtomicUsize::new(0);
+    let digest = |path: &Path| {
+        open_file(path, options)
+            .and_then(|file| digest_reader(file, &[algorithm]))
+            .map(|(_, mut digests)| digests.remove(0))
+            .map_err(|source| FileMatchError::Hash {
+                path: path.to_path_buf(),
+                source,
+            })
+    };
+    let digests: Vec<(usize, String)> = thread::scope(|scope| -> Result<_, FileMatchError> {
+        let workers: Vec<_> = (0..options.io_threads.clamp(1, files.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut digests = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((_, path)) = files.get(index) else {
+                            return Ok(digests);
+                        };
+                        digests.push((index, digest(path)?));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .map_err(|_| FileMatchError::Join { task: "digesting" })?
+            })
+            .collect::<Result<Vec<Vec<_>>, _>>()
+            .map(|digests| digests.into_iter().flatten().collect())
+    })?;
+
+    let mut first: HashMap<usize, (&PathBuf, &str)> = HashMap::new();
+    for (index, digest) in &digests {
+        let (group, path) = files[*index];
+        let (first_path, first_digest) = *first.entry(group).or_insert((path, digest));
+        if first_digest != digest {
+            return Err(FileMatchError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "'{}' and '{}' have the same hash {} but different {} digests; this is a \
+                     hash collision or a bug in filematch",
+                    first_path.display(),
+                    path.display(),
+                    shared[group].hash,
+                    algorithm.name()
+                ),
+            )));
+        }
+    }
+    Ok(files.len())
+}
+
+/// Runs a comparison once more without reusing anything from an earlier run, and lists how the
+/// results differ.
+///
+/// Every file is read again: hashes recorded in sums files or a journal are not used, and
+/// nothing is recorded. Any difference means the comparison is not reproducible, because files
+/// changed between the runs, the storage returned different data, or filematch has a bug.
+///
+/// # Parameters
+/// - `dirs1`: The root directories of the first side.
+/// - `dirs2`: The root directories of the second side.
+/// - `first`: The result of the earlier run.
+/// - `options`: The options the earlier run used.
+///
+/// # Returns
+/// The paths of each category listed by only one of the runs, empty if the runs agree.
+///
+/// # Errors
+/// This function returns the errors of [`compare_multiple_roots`].
+pub fn reproducibility_check(
+    dirs1: &[PathBuf],
+    dirs2: &[PathBuf],
+    first: &ComparisonResult,
+    options: &CompareOptions,
+) -> Result<Vec<ResultDifference>, FileMatchError> {
+    let options = CompareOptions {
+        sums: SumsPolicy::Ignore,
+        journal: None,
+        verify_sums: 0.0,
+        ..options.clone()
+    };
+    let second = compare_multiple_roots(dirs1, dirs2, &options)?;
+    Ok(first.differences(&second))
+}
+
+/// A comparison of two directories, configured one option at a time.
+///
+/// Options not set keep their defaults, see [`CompareOptions`]. For example,
+/// `Comparison::new(dir1, dir2).relative(true).sort(true).run()` compares two directories
+/// with relative, sorted paths.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    roots1: Vec<PathBuf>,
+    roots2: Vec<PathBuf>,
+    options: CompareOptions,
+}
+
+impl Comparison {
+    /// Creates a comparison of two directories with the default options.
+    #[must_use]
+    pub fn new(dir1: impl Into<PathBuf>, dir2: impl Into<PathBuf>) -> Self {
+        Self {
+            roots1: vec![dir1.into()],
+            roots2: vec![dir2.into()],
+            options: CompareOptions::default(),
+        }
+    }
+
+    /// Adds a directory treated as part of the first directory.
+    #[must_use]
+    pub fn root1(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.roots1.push(dir.into());
+        self
+    }
+
+    /// Adds a directory treated as part of the second directory.
+    #[must_use]
+    pub fn root2(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.roots2.push(dir.into());
+        self
+    }
+
+    /// Sets whether paths are made relative to the root they were found in.
+    #[must_use]
+    pub fn relative(mut self, relative: bool) -> Self {
+        self.options.relative = relative;
+        self
+    }
+
+    /// Sets whether hidden files and directories are skipped.
+    #[must_use]
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.options.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Sets whether `.gitignore` and `.ignore` files and the global git excludes are honored.
+    #[must_use]
+    pub fn respect_gitignore(mut self, respect_gitignore: bool) -> Self {
+        self.options.respect_gitignore = respect_gitignore;
+        self
+    }
+
+    /// Sets the size in bytes below which files are left out of the comparison.
+    #[must_use]
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.options.min_size = Some(min_size);
+        self
+    }
+
+    /// Sets the size in bytes above which files are left out of the comparison.
+    #[must_use]
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.options.max_size = Some(max_size);
+        self
+    }
+
+    /// Sets whether linked directories are walked.
+    #[must_use]
+    pub fn follow_links(mut self, follow_links: bool) -> Self {
+        self.options.follow_links = follow_links;
+        self
+    }
+
+    /// Sets whether the paths of each result group are sorted.
+    #[must_use]
+    pub fn sort(mut self, sort: bool) -> Self {
+        self.options.sort = sort;
+        self
+    }
+
+    /// Sets whether the paths present in both directories are computed.
+    #[must_use]
+    pub fn intersection(mut self, include: bool) -> Self {
+        self.options.include_intersection = include;
+        self
+    }
+
+    /// Sets whether the paths unique to the first directory are computed.
+    #[must_use]
+    pub fn unique_dir1(mut self, include: bool) -> Self {
+        self.options.include_unique_dir1 = include;
+        self
+    }
+
+    /// Sets whether the paths unique to the second directory are computed.
+    #[must_use]
+    pub fn unique_dir2(mut self, include: bool) -> Self {
+        self.options.include_unique_dir2 = include;
+        self
+    }
+
+    /// Sets whether the files of each side are grouped by content.
+    #[must_use]
+    pub fn groups(mut self, include: bool) -> Self {
+        self.options.include_groups = include;
+        self
+    }
+
+    /// Sets the number of reader threads.
+    #[must_use]
+    pub fn io_threads(mut self, threads: usize) -> Self {
+        self.options.io_threads = threads;
+        self
+    }
+
+    /// Sets the number of hasher threads.
+    #[must_use]
+    pub fn hash_threads(mut self, threads: usize) -> Self {
+        self.options.hash_threads = threads;
+        self
+    }
+
+    /// Sets the number of reader threads and of hasher threads, such as 1 for a hard disk.
+    #[must_use]
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.io_threads = threads;
+        self.options.hash_threads = threads;
+        self
+    }
+
+    /// Reports the progress of the comparison to a function each time a file has been hashed,
+    /// see [`ProgressHook`].
+    #[must_use]
+    pub fn on_progress(
+        mut self,
+        on_progress: impl Fn(&ProgressEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.options.on_progress = Some(ProgressHook::new(on_progress));
+        self
+    }
+
+    /// Sets the number of bytes read from a file at once, see [`CompareOptions::chunk_size`].
+    #[must_use]
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.options.chunk_size = chunk_size;
+        self
+    }
+
+    /// Replaces all options, for those without a method of their own.
+    #[must_use]
+    pub fn options(mut self, options: CompareOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Runs the comparison, see [`compare_multiple_roots`].
+    ///
+    /// # Returns
+    /// The result of the comparison.
+    ///
+    /// # Errors
+    /// This function returns the errors of [`compare_multiple_roots`].
+    pub fn run(&self) -> Result<ComparisonResult, FileMatchError> {
+        compare_multiple_roots(&self.roots1, &self.roots2, &self.options)
+    }
+}
+
+/// Compares two directories by grouping files according to their hashes.
 ///
-/// The caller may choose whether to return paths as relative to the provided directories,
-/// skip hidden files, or sort the results.
+/// This is a convenience wrapper around [`Comparison`] using the default thread pool sizes.
 ///
 /// # Parameters
 /// - `dir1`: The first directory to compare.
@@ -148,20 +1188,14 @@ fn group_files_by_hash(
 /// - `include_unique_dir2`: If true, includes file paths unique to `dir2`.
 ///
 /// # Returns
-/// A tuple containing three optional vectors:
-/// - The first vector holds file paths present in both directories (if requested).
-/// - The second vector holds file paths unique to `dir1` (if requested).
-/// - The third vector holds file paths unique to `dir2` (if requested).
-///
-/// # Panics
-/// This function may panic if a thread panics or when the channel sends a message.
-/// 
+/// The result of the comparison, with the requested categories of paths and the files that were
+/// skipped.
+///
 /// # Errors
-/// This function does not return any errors directly but may panic.
+/// This function returns the errors of [`compare_multiple_roots`], such as
+/// `FileMatchError::Hash` naming a file that cannot be read.
 #[allow(clippy::fn_params_excessive_bools)]
 #[allow(clippy::too_many_arguments)]
-#[allow(clippy::type_complexity)]
-#[must_use]
 pub fn compare_two_directories(
     dir1: &Path,
     dir2: &Path,
@@ -171,87 +1205,110 @@ pub fn compare_two_directories(
     include_intersection: bool,
     include_unique_dir1: bool,
     include_unique_dir2: bool,
-) -> (
-    Option<Vec<PathBuf>>,
-    Option<Vec<PathBuf>>,
-    Option<Vec<PathBuf>>,
-) {
-    // Determine the number of threads based on available physical cores.
-    let num_threads = num_cpus::get_physical();
-    let mut handles = Vec::with_capacity(num_threads);
-
-    // Create channels for sending file paths from both directories.
-    let (sender1, receiver1) = unbounded();
-    let (sender2, receiver2) = unbounded();
+) -> Result<ComparisonResult, FileMatchError> {
+    Comparison::new(dir1, dir2)
+        .relative(relative)
+        .skip_hidden(skip_hidden)
+        .sort(sort)
+        .intersection(include_intersection)
+        .unique_dir1(include_unique_dir1)
+        .unique_dir2(include_unique_dir2)
+        .run()
+}
 
-    let base1: Option<PathBuf> = if relative {
-        Some(dir1.to_path_buf())
-    } else {
-        None
+/// Determines if two files have the same contents.
+///
+/// Files of different sizes differ without being read. Small files are read and compared
+/// directly, while larger ones are hashed in parallel, one thread per file, so files on
+/// different disks are read at the same time. The contents are compared as they are, without
+/// any decompression or transformation.
+///
+/// # Parameters
+/// - `path1`: The first file.
+/// - `path2`: The second file.
+///
+/// # Returns
+/// True if both files have the same contents, false otherwise.
+///
+/// # Errors
+/// This function returns a [`FileMatchError::Hash`] naming the file if either file cannot be
+/// read, or a [`FileMatchError::Join`] if the thread hashing the second file panics.
+pub fn files_identical(path1: &Path, path2: &Path) -> Result<bool, FileMatchError> {
+    let named = |path: &Path, source: io::Error| FileMatchError::Hash {
+        path: path.to_path_buf(),
+        source,
     };
-    
-    let base2: Option<PathBuf> = if relative {
-        Some(dir2.to_path_buf())
-    } else {
-        None
+    let size = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .map_err(|err| named(path, err))
     };
+    let read = |path: &Path| fs::read(path).map_err(|err| named(path, err));
+    let hash = |path: &Path| calculate_file_hash(path).map_err(|err| named(path, err));
 
-    // Spawn threads.
-    for _ in 0..num_threads {
-        let r1 = receiver1.clone();
-        let r2 = receiver2.clone();
-        let b1 = base1.clone();
-        let b2 = base2.clone();
-
-        let handle = thread::spawn(move || group_files_by_hash(&r1, &r2, b1, b2));
-        handles.push(handle);
+    let size1 = size(path1)?;
+    if size1 != size(path2)? {
+        return Ok(false);
+    }
+    if size1 <= SMALL_FILE_SIZE as u64 {
+        return Ok(read(path1)? == read(path2)?);
     }
 
-    // Send file paths from each directory into the respective channels.
-    send_file_paths(dir1, &sender1, skip_hidden);
-    send_file_paths(dir2, &sender2, skip_hidden);
-
-    // Close the channels so that threads can finish processing.
-    drop(sender1);
-    drop(sender2);
-
-    // Combine the results from all threads.
-    let mut combined1: HashMap<Hash, Vec<PathBuf>> = HashMap::new();
-    let mut combined2: HashMap<Hash, Vec<PathBuf>> = HashMap::new();
+    let (hash1, hash2) = thread::scope(|scope| {
+        let hasher = scope.spawn(|| hash(path2));
+        (hash(path1), hasher.join())
+    });
+    let hash2 = hash2.map_err(|_| FileMatchError::Join { task: "hashing" })?;
 
-    for handle in handles {
-        let (map1, map2) = handle.join().expect("Thread panicked").unwrap();
+    Ok(hash1? == hash2?)
+}
 
-        for (key, paths) in map1 {
-            combined1.entry(key).or_default().extend(paths);
-        }
-        for (key, paths) in map2 {
-            combined2.entry(key).or_default().extend(paths);
-        }
+/// Reads a random sample of matched files once more to confirm that the match is reproducible.
+///
+/// For a random `fraction` of the contents present on both sides, the first file of each side
+/// is read again and hashed with a key drawn at random for this run. The salted hashes cannot
+/// come from a cache or from an earlier run, so matches that hold only because of a stale
+/// recorded hash or because flaky storage returned different data on the first read show up
+/// as mismatches. Files are read the same way as during the comparison, with `options`.
+///
+/// # Parameters
+/// - `groups`: The contents found by the comparison, with paths as found (not relative).
+/// - `fraction`: The fraction of matches to check, from 0 to 1.
+/// - `options`: The options the comparison ran with.
+///
+/// # Returns
+/// The number of matches checked and the pairs of files that differed.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the file if a file cannot be read.
+pub fn recheck_matches(
+    groups: &[HashGroup],
+    fraction: f64,
+    options: &CompareOptions,
+) -> io::Result<Recheck> {
+    let state = RandomState::new();
+    let mut key = [0; 32];
+    for (index, bytes) in key.chunks_exact_mut(8).enumerate() {
+        bytes.copy_from_slice(&state.hash_one(index).to_le_bytes());
     }
+    let salted_hash = |path: &Path| -> io::Result<Hash> {
+        let mut hasher = blake3::Hasher::new_keyed(&key);
+        open_file(path, options)
+            .and_then(|mut file| io::copy(&mut file, &mut hasher))
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        Ok(hasher.finalize())
+    };
 
-    // Partition the file paths into intersection and unique groups.
-    let (mut intersection_paths, mut unique_dir1_paths, mut unique_dir2_paths) =
-        partition_map_values(
-            &combined1,
-            &combined2,
-            include_intersection,
-            include_unique_dir1,
-            include_unique_dir2,
-        );
-
-    // Optionally sort the file paths.
-    if sort {
-        if let Some(ref mut paths) = intersection_paths {
-            paths.sort();
-        }
-        if let Some(ref mut paths) = unique_dir1_paths {
-            paths.sort();
+    let mut recheck = Recheck::default();
+    for group in groups.iter().filter(|group| group.is_shared()) {
+        let (path1, path2) = (&group.dir1[0], &group.dir2[0]);
+        if !sampled(path1, fraction) {
+            continue;
         }
-        if let Some(ref mut paths) = unique_dir2_paths {
-            paths.sort();
+        recheck.checked += 1;
+        if salted_hash(path1)? != salted_hash(path2)? {
+            recheck.mismatched.push([path1.clone(), path2.clone()]);
         }
     }
-
-    (intersection_paths, unique_dir1_paths, unique_dir2_paths)
+    Ok(recheck)
 }
@@ -0,0 +1,288 @@
+use blake3::Hash;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::audit_log::AuditLog;
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::error::FileMatchError;
+use crate::options::CompareOptions;
+use crate::plan::{apply_plan, Plan, PlanStep, StepOutcome};
+use crate::result::{HashGroup, ReadError, SkippedFile, WalkError};
+use crate::util::{matching_root, relative_path};
+
+/// What is done with a file of the second directory whose content is in the first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupeAction {
+    /// Delete the file
+    Remove,
+    /// Move the file into the trash directory, keeping its path below its root
+    Trash,
+    /// Replace the file with a hard link to the file in directory1
+    Hardlink,
+    /// Replace the file with a symbolic link to the file in directory1
+    Symlink,
+}
+
+impl DedupeAction {
+    /// Returns the name of the action, as given on the command line.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Remove => "remove",
+            Self::Trash => "trash",
+            Self::Hardlink => "hardlink",
+            Self::Symlink => "symlink",
+        }
+    }
+}
+
+/// A file of the second directory whose content is also in the first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicate {
+    /// The file in the second directory.
+    pub path: PathBuf,
+    /// The file with the same content in the first directory.
+    pub original: PathBuf,
+    /// The size of the content in bytes.
+    pub size: u64,
+}
+
+/// The outcome of deduplicating the second directory against the first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dedupe {
+    /// The duplicates removed, trashed or replaced by a link, or that would be in a dry run.
+    pub done: Vec<Duplicate>,
+    /// The duplicates left alone because they no longer have the contents of the original, or no
+    /// longer exist.
+    pub changed: Vec<Duplicate>,
+    /// The duplicates left alone because they are the original itself, or a symbolic link to it.
+    pub same_file: Vec<Duplicate>,
+}
+
+impl Dedupe {
+    /// Returns the number of bytes freed in the second directory.
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        self.done.iter().map(|duplicate| duplicate.size).sum()
+    }
+}
+
+/// Lists the files of the second directory whose content is also in the first.
+///
+/// Every file of the second directory in a content shared by both directories is a duplicate of
+/// the first file of that content in the first directory.
+///
+/// # Parameters
+/// - `groups`: The contents found by a comparison, with paths as found (not relative).
+///
+/// # Returns
+/// The duplicates, in the order of the groups.
+#[must_use]
+pub fn cross_side_duplicates(groups: &[HashGroup]) -> Vec<Duplicate> {
+    groups
+        .iter()
+        .filter(|group| group.is_shared())
+        .flat_map(|group| {
+            group.dir2.iter().map(|path| Duplicate {
+                path: path.clone(),
+                original: group.dir1[0].clone(),
+                size: group.size,
+            })
+        })
+        .collect()
+}
+
+/// A content found in more than one file of a single directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// The BLAKE3 hash of the content.
+    pub hash: Hash,
+    /// The size of the content in bytes.
+    pub size: u64,
+    /// The paths of the files with the content, as found, sorted.
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// Returns the number of bytes freed by keeping a single copy of the content.
+    #[must_use]
+    pub fn savings(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+}
+
+/// The duplicates found within a single directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Duplicates {
+    /// The contents found in more than one file.
+    pub groups: Vec<DuplicateGroup>,
+    /// The files left out of the search.
+    pub skipped: Vec<SkippedFile>,
+    /// The paths that could not be read while walking the directory.
+    pub walk_errors: Vec<WalkError>,
+    /// The files that could not be read, with `ErrorPolicy::CollectAndContinue`.
+    pub read_errors: Vec<ReadError>,
+}
+
+impl Duplicates {
+    /// Returns the number of bytes freed by keeping a single copy of every content.
+    #[must_use]
+    pub fn savings(&self) -> u64 {
+        self.groups.iter().map(DuplicateGroup::savings).sum()
+    }
+}
+
+/// Finds the files of a directory sharing their content with another file of the directory.
+///
+/// The walk and the reading of the files follow `options`; the sections of the comparison
+/// are ignored, as only the groups by content are needed.
+///
+/// # Parameters
+/// - `dir`: The directory to search.
+/// - `options`: The options controlling the walk and the reading of the files.
+///
+/// # Returns
+/// The contents found in more than one file, the ones freeing the most space first, then by
+/// first path, and the files that were skipped or could not be read.
+///
+/// # Errors
+/// This function returns the errors of [`compare_multiple_roots`].
+pub fn find_duplicates(dir: &Path, options: &CompareOptions) -> Result<Duplicates, FileMatchError> {
+    let options = CompareOptions {
+        include_intersection: false,
+        include_unique_dir1: false,
+        include_unique_dir2: false,
+        include_groups: true,
+        ..options.clone()
+    };
+    let result = compare_multiple_roots(&[dir.to_path_buf()], &[], &options)?;
+
+    let mut groups: Vec<_> = result
+        .groups
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|group| group.dir1.len() > 1)
+        .map(|group| DuplicateGroup {
+            hash: group.hash,
+            size: group.size,
+            paths: group.dir1,
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.savings()
+            .cmp(&a.savings())
+            .then_with(|| a.paths.cmp(&b.paths))
+    });
+    Ok(Duplicates {
+        groups,
+        skipped: result.skipped,
+        walk_errors: result.walk_errors,
+        read_errors: result.read_errors,
+    })
+}
+
+/// Plans removing, trashing or linking the duplicates in the second directory of files in the
+/// first, see [`apply_plan`].
+///
+/// Trashed files are moved to `trash` at their path below the root of `roots2` they are in.
+/// Paths are made absolute, so the plan can be applied from any directory.
+///
+/// # Parameters
+/// - `duplicates`: The duplicates, see [`cross_side_duplicates`].
+/// - `action`: What to do with each duplicate.
+/// - `roots2`: The roots of the second directory.
+/// - `trash`: The directory trashed files are moved to, required for `DedupeAction::Trash`.
+///
+/// # Returns
+/// The plan, with a step for each duplicate.
+///
+/// # Errors
+/// This function returns an `io::Error` of kind `InvalidInput` if no trash directory is given
+/// to trash files, or if a path cannot be made absolute.
+pub fn dedupe_plan(
+    duplicates: &[Duplicate],
+    action: DedupeAction,
+    roots2: &[PathBuf],
+    trash: Option<&Path>,
+) -> io::Result<Plan> {
+    if action == DedupeAction::Trash && trash.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "trashing files requires a trash directory",
+        ));
+    }
+
+    let steps = duplicates
+        .iter()
+        .map(|duplicate| {
+            let path = &duplicate.path;
+            let target = trash
+                .filter(|_| action == DedupeAction::Trash)
+                .map(|trash| {
+                    std::path::absolute(
+                        trash.join(relative_path(path.clone(), matching_root(path, roots2))),
+                    )
+                })
+                .transpose()?;
+            Ok(PlanStep {
+                action,
+                path: std::path::absolute(path)?,
+                original: std::path::absolute(&duplicate.original)?,
+                size: duplicate.size,
+                target,
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(Plan { steps })
+}
+
+/// Removes, trashes or links the duplicates in the second directory of files in the first.
+///
+/// The duplicates are planned with [`dedupe_plan`] and the plan is applied with [`apply_plan`]
+/// without a journal, which checks every duplicate against its original before touching it.
+/// Every change is recorded in `log`, if given. A dry run checks, changes and records nothing.
+///
+/// # Parameters
+/// - `duplicates`: The duplicates, see [`cross_side_duplicates`].
+/// - `action`: What to do with each duplicate.
+/// - `roots2`: The roots of the second directory.
+/// - `trash`: The directory trashed files are moved to, required for `DedupeAction::Trash`.
+/// - `dry_run`: If set, only report what would be done.
+/// - `log`: The audit log every change is recorded in, if any.
+///
+/// # Returns
+/// The duplicates dealt with, and those that changed.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the file if a file cannot be read, removed,
+/// moved or linked, and one of kind `InvalidInput` if no trash directory is given to trash
+/// files. Duplicates dealt with before the error stay so. Failing to write the audit log is an
+/// error as well.
+pub fn dedupe(
+    duplicates: &[Duplicate],
+    action: DedupeAction,
+    roots2: &[PathBuf],
+    trash: Option<&Path>,
+    dry_run: bool,
+    log: Option<&mut AuditLog>,
+) -> io::Result<Dedupe> {
+    let plan = dedupe_plan(duplicates, action, roots2, trash)?;
+    if dry_run {
+        return Ok(Dedupe {
+            done: duplicates.to_vec(),
+            ..Dedupe::default()
+        });
+    }
+
+    let applied = apply_plan(&plan, None, log)?;
+    let mut dedupe = Dedupe::default();
+    for ((step, outcome), duplicate) in applied.steps.iter().zip(duplicates) {
+        debug_assert_eq!(step.size, duplicate.size);
+        match outcome {
+            StepOutcome::Done => dedupe.done.push(duplicate.clone()),
+            StepOutcome::Changed | StepOutcome::Gone => dedupe.changed.push(duplicate.clone()),
+            StepOutcome::SameFile => dedupe.same_file.push(duplicate.clone()),
+        }
+    }
+    Ok(dedupe)
+}
@@ -0,0 +1,90 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Why a comparison failed.
+#[derive(Debug)]
+pub enum FileMatchError {
+    /// A directory could not be walked, and walk errors fail the comparison.
+    Walk {
+        /// The path that could not be read.
+        path: PathBuf,
+        /// The error reading it.
+        source: io::Error,
+    },
+    /// A file could not be read or hashed.
+    Hash {
+        /// The file that could not be read.
+        path: PathBuf,
+        /// The error reading it.
+        source: io::Error,
+    },
+    /// A worker thread panicked.
+    Join {
+        /// The work of the thread, such as `reading` or `hashing`.
+        task: &'static str,
+    },
+    /// Any other error, such as invalid options or a sums file that cannot be written.
+    Io(io::Error),
+}
+
+impl FileMatchError {
+    /// Returns the kind of the underlying I/O error, or `Other` if a thread panicked.
+    #[must_use]
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Walk { source, .. } | Self::Hash { source, .. } | Self::Io(source) => {
+                source.kind()
+            }
+            Self::Join { .. } => io::ErrorKind::Other,
+        }
+    }
+
+    /// Returns the file or directory the error is about, if any.
+    #[must_use]
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Walk { path, .. } | Self::Hash { path, .. } => Some(path),
+            Self::Join { .. } | Self::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for FileMatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Walk { path, source } | Self::Hash { path, source } => {
+                write!(f, "{}: {source}", path.display())
+            }
+            Self::Join { task } => write!(f, "a thread {task} files panicked"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for FileMatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Walk { source, .. } | Self::Hash { source, .. } | Self::Io(source) => {
+                Some(source)
+            }
+            Self::Join { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for FileMatchError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<FileMatchError> for io::Error {
+    fn from(err: FileMatchError) -> Self {
+        match err {
+            FileMatchError::Io(err) => err,
+            err => io::Error::new(err.kind(), err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::error::FileMatchError;
+
+/// The placeholder replaced by the path of the file in a command template.
+pub const PLACEHOLDER: &str = "{}";
+
+/// A command run for each file of a category, such as `cp {} /backup`.
+///
+/// The command is run directly, not through a shell, so paths are passed as they are whatever
+/// characters they contain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecTemplate {
+    /// The words of the command, the first being the program.
+    words: Vec<String>,
+}
+
+impl ExecTemplate {
+    /// Parses a command template.
+    ///
+    /// The template is split into words at whitespace. Single quotes keep everything up to the
+    /// next single quote in a word, double quotes everything up to the next unescaped double
+    /// quote, and a backslash outside single quotes keeps the next character. Every `{}` in a
+    /// word is replaced by the path of the file; without any, the path is passed as the last
+    /// argument.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` of kind `InvalidInput` if the template is empty or
+    /// has an unterminated quote.
+    pub fn parse(template: &str) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() => words.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => {
+                                return Err(invalid("unterminated single quote in the command"))
+                            }
+                        }
+                    }
+                }
+                '"' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c @ ('"' | '\\')) => word.push(c),
+                                Some(c) => {
+                                    word.push('\\');
+                                    word.push(c);
+                                }
+                                None => {
+                                    return Err(invalid("unterminated double quote in the command"))
+                                }
+                            },
+                            Some(c) => word.push(c),
+                            None => {
+                                return Err(invalid("unterminated double quote in the command"))
+                            }
+                        }
+                    }
+                }
+                '\\' => {
+                    let word = word.get_or_insert_with(String::new);
+                    word.extend(chars.next());
+                }
+                c => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+
+        if words.is_empty() {
+            return Err(invalid("no command given"));
+        }
+        if !words.iter().any(|word| word.contains(PLACEHOLDER)) {
+            words.push(PLACEHOLDER.to_string());
+        }
+        Ok(Self { words })
+    }
+
+    /// Returns the command to run for a file.
+    ///
+    /// Relative paths starting with `-` are prefixed with `./`, so they are not taken for
+    /// options.
+    #[must_use]
+    pub fn command(&self, path: &Path) -> Command {
+        let path = if path.is_relative() && path.to_string_lossy().starts_with('-') {
+            Path::new(".").join(path)
+        } else {
+            path.to_path_buf()
+        };
+        let expand = |word: &str| {
+            let mut expanded = OsString::new();
+            for (i, part) in word.split(PLACEHOLDER).enumerate() {
+                if i > 0 {
+                    expanded.push(path.as_os_str());
+                }
+                expanded.push(part);
+            }
+            expanded
+        };
+
+        let mut command = Command::new(expand(&self.words[0]));
+        command.args(self.words[1..].iter().map(|word| expand(word)));
+        command
+    }
+}
+
+/// A file the command failed for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecFailure {
+    /// The file.
+    pub path: PathBuf,
+    /// Why the command failed, such as its exit status.
+    pub message: String,
+}
+
+/// The outcome of running a command for each file of a category.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecReport {
+    /// The number of files the command succeeded for.
+    pub succeeded: usize,
+    /// The files the command could not be run for or failed for, in the order of the files.
+    pub failed: Vec<ExecFailure>,
+}
+
+/// Runs a command for each file, at most `jobs` at once.
+///
+/// The commands inherit the standard streams, so their output may interleave when several
+/// run at once. A command that cannot be started or exits unsuccessfully is recorded as
+/// failed, and the others still run.
+///
+/// # Parameters
+/// - `template`: The command to run for each file.
+/// - `paths`: The files, as found so the commands can open them.
+/// - `jobs`: The number of commands run at once, at least 1.
+///
+/// # Returns
+/// The number of commands that succeeded, and the files they failed for.
+///
+/// # Errors
+/// This function returns a [`FileMatchError::Join`] if a thread running the commands panics.
+pub fn run_for_each(
+    template: &ExecTemplate,
+    paths: &[PathBuf],
+    jobs: usize,
+) -> Result<ExecReport, FileMatchError> {
+    let next = AtomicUsize::new(0);
+    let mut failed: Vec<(usize, ExecFailure)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, paths.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut failed = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(path) = paths.get(index) else {
+                            return failed;
+                        };
+                        let message = match template.command(path).status() {
+                            Ok(status) if status.success() => continue,
+                            Ok(status) => status.to_string(),
+                            Err(err) => err.to_string(),
+                        };
+                        let path = path.clone();
+                        failed.push((index, ExecFailure { path, message }));
+                    }
+                })
+            })
+            .collect();
+        // Join every worker, as the scope panics if a panicked thread is left unjoined
+        let mut failed = Vec::new();
+        let mut panicked = false;
+        for worker in workers {
+            match worker.join() {
+                Ok(worker_failed) => failed.extend(worker_failed),
+                Err(_) => panicked = true,
+            }
+        }
+        if panicked {
+            return Err(FileMatchError::Join {
+                task: "running commands for",
+            });
+        }
+        Ok(failed)
+    })?;
+
+    failed.sort_by_key(|(index, _)| *index);
+    Ok(ExecReport {
+        succeeded: paths.len() - failed.len(),
+        failed: failed.into_iter().map(|(_, failure)| failure).collect(),
+    })
+}
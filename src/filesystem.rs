@@ -0,0 +1,312 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::selftest::open_uncached;
+
+/// Number of bytes read from a file at once on network filesystems, where every request has a
+/// high latency.
+pub const NETWORK_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of threads reading a side on a network filesystem, as file servers slow down when
+/// many files are opened at once.
+pub const NETWORK_IO_THREADS: usize = 4;
+
+/// Number of retries after a transient I/O error on network filesystems, where connections drop
+/// now and then.
+pub const NETWORK_RETRIES: u32 = 3;
+
+/// Number of bytes read from a file at once on hard disks, so the head reads long stretches
+/// between seeks.
+pub const HDD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of threads reading a side on a hard disk, as a disk reading several files at once
+/// spends its time seeking between them.
+pub const HDD_IO_THREADS: usize = 1;
+
+/// Reads taking longer than this come from a disk that seeks: a few milliseconds per seek,
+/// against well under one on solid-state storage.
+const SEEK_LATENCY: Duration = Duration::from_millis(2);
+
+/// Number of reads at scattered offsets timed by the storage probe.
+const PROBE_READS: u64 = 9;
+
+/// Number of bytes read by each read of the storage probe.
+const PROBE_READ_SIZE: usize = 4096;
+
+/// Smallest file the storage probe reads, so its reads are far enough apart to need seeks.
+const PROBE_MIN_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Number of directory entries the storage probe looks at to find a file to read.
+const PROBE_ENTRIES: usize = 1000;
+
+/// Filesystem types accessed over the network, as named by Linux and macOS.
+const NETWORK_KINDS: [&str; 14] = [
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "afpfs",
+    "webdav",
+    "afs",
+    "9p",
+    "ceph",
+    "glusterfs",
+    "fuse.sshfs",
+    "fuse.rclone",
+    "fuse.s3fs",
+];
+
+/// The filesystem a directory is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filesystem {
+    /// The type of the filesystem as named by the operating system, e.g. `ext4` or `nfs4`.
+    pub kind: String,
+    /// True if the filesystem is accessed over the network.
+    pub network: bool,
+}
+
+impl Filesystem {
+    /// Creates the description of a filesystem of the given type.
+    fn of_kind(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            network: NETWORK_KINDS.contains(&kind),
+        }
+    }
+}
+
+/// The kind of storage device a directory is on, deciding how its files are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StorageKind {
+    /// Solid-state storage, read by many threads at once.
+    Ssd,
+    /// A spinning hard disk, read one file at a time in large reads.
+    Hdd,
+}
+
+impl StorageKind {
+    /// Returns the lowercase name of the kind, e.g. `hdd`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ssd => "ssd",
+            Self::Hdd => "hdd",
+        }
+    }
+}
+
+/// Unescapes the characters `/proc/self/mountinfo` escapes in mount points.
+fn unescape_mount_point(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+/// Finds the type of the filesystem a path is on in the mount table of Linux.
+///
+/// # Parameters
+/// - `mountinfo`: The contents of `/proc/self/mountinfo`.
+/// - `path`: The canonical path to look up.
+///
+/// # Returns
+/// The type of the innermost filesystem mounted at the path or one of its ancestors, or None
+/// if no mount point contains the path.
+#[must_use]
+pub fn mount_kind(mountinfo: &str, path: &Path) -> Option<String> {
+    mount_entry(mountinfo, path).map(|(kind, _)| kind)
+}
+
+/// Finds the source of the filesystem a path is on in the mount table of Linux, such as the
+/// device `/dev/sda1`.
+///
+/// # Parameters
+/// - `mountinfo`: The contents of `/proc/self/mountinfo`.
+/// - `path`: The canonical path to look up.
+///
+/// # Returns
+/// The source of the innermost filesystem mounted at the path or one of its ancestors, or None
+/// if no mount point contains the path.
+#[must_use]
+pub fn mount_source(mountinfo: &str, path: &Path) -> Option<String> {
+    mount_entry(mountinfo, path).map(|(_, source)| source)
+}
+
+/// Finds the type and source of the innermost filesystem mounted at a path or one of its
+/// ancestors.
+fn mount_entry(mountinfo: &str, path: &Path) -> Option<(String, String)> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let mount_point = unescape_mount_point(mount.split_whitespace().nth(4)?);
+            let mut fields = filesystem.split_whitespace();
+            let kind = fields.next()?.to_string();
+            let source = unescape_mount_point(fields.next()?);
+            path.starts_with(&mount_point)
+                .then_some((mount_point.len(), (kind, source)))
+        })
+        // Later mounts hide earlier ones at the same mount point
+        .max_by_key(|(length, _)| *length)
+        .map(|(_, entry)| entry)
+}
+
+/// Detects the filesystem a directory is on.
+///
+/// Linux reads the mount table, and macOS asks the kernel. Windows only recognizes UNC paths
+/// such as `\\server\share` as network filesystems, as mapped network drives cannot be told
+/// apart from local drives by their path.
+///
+/// # Parameters
+/// - `path`: The directory.
+///
+/// # Returns
+/// The filesystem, or None if it cannot be determined.
+#[must_use]
+pub fn detect_filesystem(path: &Path) -> Option<Filesystem> {
+    let path = std::fs::canonicalize(path).ok()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+        mount_kind(&mountinfo, &path).map(|kind| Filesystem::of_kind(&kind))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::{CStr, CString};
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        let kind = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+        Some(Filesystem::of_kind(&kind.to_string_lossy()))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::path::{Component, Prefix};
+
+        match path.components().next() {
+            Some(Component::Prefix(prefix))
+                if matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..)) =>
+            {
+                Some(Filesystem {
+                    kind: "smb".to_string(),
+                    network: true,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Detects the kind of storage device a directory is on.
+///
+/// Linux reads whether the device behind the filesystem is rotational from `/sys/block`.
+/// Elsewhere, and where Linux cannot tell, as for filesystems spanning several devices, the
+/// storage is probed by timing reads at scattered offsets of an existing file, see
+/// [`probe_storage`]. Directories on network filesystems have no storage kind of their own.
+///
+/// # Parameters
+/// - `path`: The directory.
+///
+/// # Returns
+/// The kind of storage, or None if it cannot be determined.
+#[must_use]
+pub fn detect_storage(path: &Path) -> Option<StorageKind> {
+    if detect_filesystem(path).is_some_and(|fs| fs.network) {
+        return None;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let rotational = || {
+            let path = std::fs::canonicalize(path).ok()?;
+            let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+            // Resolves names such as /dev/mapper/root to the device, such as /dev/dm-0
+            let device = std::fs::canonicalize(mount_source(&mountinfo, &path)?).ok()?;
+            let name = device.strip_prefix("/dev").ok()?.file_name()?.to_owned();
+            let block = std::fs::canonicalize(Path::new("/sys/class/block").join(name)).ok()?;
+            // Partitions share the queue of the disk they are on
+            [
+                block.join("queue/rotational"),
+                block.parent()?.join("queue/rotational"),
+            ]
+            .iter()
+            .find_map(|queue| std::fs::read_to_string(queue).ok())
+            .map(|rotational| rotational.trim() == "1")
+        };
+        if let Some(rotational) = rotational() {
+            return Some(if rotational {
+                StorageKind::Hdd
+            } else {
+                StorageKind::Ssd
+            });
+        }
+    }
+
+    probe_storage(path)
+}
+
+/// Probes the kind of storage a directory is on by timing reads of an existing file.
+///
+/// The largest file of at least 1 MiB among the first entries of the directory tree is read
+/// in small pieces at scattered offsets, past the page cache on Linux and macOS. A median time
+/// per read above 2 ms means a disk seeking between the pieces. Nothing is written, so the
+/// probe works on read-only directories. On other platforms the reads may be served from the
+/// page cache, making any storage look solid-state.
+///
+/// # Parameters
+/// - `directory`: The directory to probe.
+///
+/// # Returns
+/// The kind of storage, or None if the directory has no file large enough or it cannot be
+/// read.
+#[must_use]
+pub fn probe_storage(directory: &Path) -> Option<StorageKind> {
+    let (size, path) = walkdir::WalkDir::new(directory)
+        .into_iter()
+        .take(PROBE_ENTRIES)
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| Some((entry.metadata().ok()?.len(), entry.into_path())))
+        .filter(|(size, _)| *size >= PROBE_MIN_FILE_SIZE)
+        .max()?;
+
+    let mut file = open_uncached(&path).ok()?;
+    let mut buffer = vec![0; PROBE_READ_SIZE];
+    let stride = (size - PROBE_READ_SIZE as u64) / (PROBE_READS - 1);
+    let mut latencies = Vec::new();
+    // Alternate between the front and the back of the file, so no read follows the last one
+    for i in 0..PROBE_READS {
+        let index = if i % 2 == 0 {
+            i / 2
+        } else {
+            PROBE_READS - 1 - i / 2
+        };
+        let start = Instant::now();
+        file.seek(SeekFrom::Start(index * stride)).ok()?;
+        file.read_exact(&mut buffer).ok()?;
+        latencies.push(start.elapsed());
+    }
+    latencies.sort();
+
+    Some(if latencies[latencies.len() / 2] > SEEK_LATENCY {
+        StorageKind::Hdd
+    } else {
+        StorageKind::Ssd
+    })
+}
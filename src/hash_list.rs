@@ -0,0 +1,120 @@
+use blake3::Hash;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{digest_tree, Algorithm};
+use crate::options::CompareOptions;
+
+/// Parses a list of BLAKE3 hashes.
+///
+/// Each line holds a hex hash, optionally followed by whitespace and anything else, so the
+/// output of `b3sum` and filematch sums exports can be used as they are. Empty lines and lines
+/// starting with `#` are ignored.
+///
+/// # Parameters
+/// - `reader`: The list contents.
+///
+/// # Returns
+/// The hashes of the list.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, or an error of kind `InvalidData` if
+/// a line does not start with a BLAKE3 hash.
+pub fn read_hash_list(reader: impl BufRead) -> io::Result<HashSet<Hash>> {
+    let mut hashes = HashSet::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Escaped checksum lines start with a backslash
+        let hex = line
+            .trim_start_matches('\\')
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        let hash = Hash::from_hex(hex).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hash list line {}: expected a BLAKE3 hash", index + 1),
+            )
+        })?;
+        hashes.insert(hash);
+    }
+
+    Ok(hashes)
+}
+
+/// Reads a list of BLAKE3 hashes from a file, see [`read_hash_list`].
+///
+/// # Parameters
+/// - `path`: The file to read.
+///
+/// # Returns
+/// The hashes of the list.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the file if it cannot be read or parsed.
+pub fn load_hash_list(path: &Path) -> io::Result<HashSet<Hash>> {
+    File::open(path)
+        .and_then(|file| read_hash_list(BufReader::new(file)))
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))
+}
+
+/// The outcome of checking a directory against a set of known hashes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownCheck {
+    /// Files whose content is in the set.
+    pub known: Vec<PathBuf>,
+    /// Files whose content is not in the set.
+    pub unknown: Vec<PathBuf>,
+}
+
+impl KnownCheck {
+    /// Returns true if the content of every file is in the set.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.unknown.is_empty()
+    }
+}
+
+/// Checks every file of a directory against a set of known hashes, such as the hashes of
+/// approved software.
+///
+/// Unlike an audit, the paths of the files do not matter and known contents missing from the
+/// directory are not reported. Since every file must be checked, a directory that cannot be read
+/// is an error.
+///
+/// # Parameters
+/// - `directory`: The directory to check.
+/// - `known`: The BLAKE3 hashes of the known contents, see [`read_hash_list`].
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The known and unknown files, sorted by path.
+///
+/// # Errors
+/// This function returns an `io::Error` if a file or directory cannot be read.
+pub fn check_known(
+    directory: &Path,
+    known: &HashSet<Hash>,
+    options: &CompareOptions,
+) -> io::Result<KnownCheck> {
+    let mut check = KnownCheck::default();
+
+    for file in digest_tree(directory, &[Algorithm::Blake3], options)? {
+        let is_known = Hash::from_hex(&file.digests[0]).is_ok_and(|hash| known.contains(&hash));
+        if is_known {
+            check.known.push(file.path);
+        } else {
+            check.unknown.push(file.path);
+        }
+    }
+
+    Ok(check)
+}
@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{digest_tree, Algorithm, FileDigest};
+use crate::options::CompareOptions;
+use crate::util::invalid_data;
+
+/// First line of every hashdeep file.
+const HEADER: &str = "%%%% HASHDEEP-1.0";
+
+/// The algorithms filematch can read from and write to hashdeep files.
+pub const HASHDEEP_ALGORITHMS: [Algorithm; 3] =
+    [Algorithm::Md5, Algorithm::Sha1, Algorithm::Sha256];
+
+/// A single file recorded in a hashdeep file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashdeepEntry {
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The lowercase hex digests, in the order of [`HashdeepFile::algorithms`].
+    pub digests: Vec<String>,
+    /// The path of the file as recorded.
+    pub path: PathBuf,
+}
+
+/// The contents of a hashdeep file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashdeepFile {
+    /// The supported algorithms present in the file.
+    pub algorithms: Vec<Algorithm>,
+    /// The recorded files.
+    pub entries: Vec<HashdeepEntry>,
+}
+
+/// Parses a hashdeep file.
+///
+/// Columns of algorithms filematch does not support (tiger, whirlpool) are ignored.
+///
+/// # Parameters
+/// - `reader`: The hashdeep file contents.
+///
+/// # Returns
+/// The parsed hashdeep file.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, the headers are missing, the file
+/// contains no supported algorithm, or a line is malformed.
+pub fn read_hashdeep(reader: impl BufRead) -> io::Result<HashdeepFile> {
+    let mut lines = reader.lines().enumerate();
+
+    let header = lines.next().map(|(_, line)| line).transpose()?;
+    if header.as_deref().map(str::trim_end) != Some(HEADER) {
+        return Err(invalid_data("hashdeep", 1, "missing HASHDEEP-1.0 header"));
+    }
+
+    let columns: Vec<String> = match lines.next() {
+        Some((_, line)) => {
+            let line = line?;
+            let Some(columns) = line.trim_end().strip_prefix("%%%% ") else {
+                return Err(invalid_data("hashdeep", 2, "missing column header"));
+            };
+            columns.split(',').map(str::to_string).collect()
+        }
+        None => return Err(invalid_data("hashdeep", 2, "missing column header")),
+    };
+    if columns.len() < 3
+        || columns.first().map(String::as_str) != Some("size")
+        || columns.last().map(String::as_str) != Some("filename")
+    {
+        return Err(invalid_data(
+            "hashdeep",
+            2,
+            "columns must start with size and end with filename",
+        ));
+    }
+
+    // Indices of the supported algorithm columns, skipping size and filename
+    let supported: Vec<(usize, Algorithm)> = columns[1..columns.len() - 1]
+        .iter()
+        .enumerate()
+        .filter_map(|(index, name)| {
+            Algorithm::from_name(name)
+                .filter(|algorithm| HASHDEEP_ALGORITHMS.contains(algorithm))
+                .map(|algorithm| (index + 1, algorithm))
+        })
+        .collect();
+    if supported.is_empty() {
+        return Err(invalid_data("hashdeep", 2, "no supported hash algorithm"));
+    }
+
+    let mut entries = Vec::new();
+    for (index, line) in lines {
+        let line = line?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // The filename is the last column and may itself contain commas
+        let fields: Vec<&str> = line.splitn(columns.len(), ',').collect();
+        if fields.len() != columns.len() {
+            return Err(invalid_data(
+                "hashdeep",
+                index + 1,
+                "wrong number of columns",
+            ));
+        }
+        let size = fields[0]
+            .parse()
+            .map_err(|_| invalid_data("hashdeep", index + 1, "invalid size"))?;
+
+        entries.push(HashdeepEntry {
+            size,
+            digests: supported
+                .iter()
+                .map(|&(column, _)| fields[column].to_ascii_lowercase())
+                .collect(),
+            path: PathBuf::from(fields[columns.len() - 1]),
+        });
+    }
+
+    Ok(HashdeepFile {
+        algorithms: supported
+            .into_iter()
+            .map(|(_, algorithm)| algorithm)
+            .collect(),
+        entries,
+    })
+}
+
+/// Writes files and their digests in hashdeep format.
+///
+/// # Parameters
+/// - `writer`: Where to write the hashdeep file.
+/// - `algorithms`: The algorithms of the digests. Each must be one of [`HASHDEEP_ALGORITHMS`].
+/// - `files`: The files to record, with digests in the order of `algorithms`.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails or an algorithm is not supported by
+/// hashdeep.
+pub fn write_hashdeep(
+    mut writer: impl Write,
+    algorithms: &[Algorithm],
+    files: &[FileDigest],
+) -> io::Result<()> {
+    write_hashdeep_header(&mut writer, algorithms)?;
+    for file in files {
+        write_hashdeep_entry(&mut writer, file)?;
+    }
+
+    writer.flush()
+}
+
+/// Writes the header of a hashdeep file, for files written one at a time with
+/// [`write_hashdeep_entry`].
+///
+/// # Parameters
+/// - `writer`: Where to write the hashdeep file.
+/// - `algorithms`: The algorithms of the digests. Each must be one of [`HASHDEEP_ALGORITHMS`].
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails or an algorithm is not supported by
+/// hashdeep.
+pub fn write_hashdeep_header(mut writer: impl Write, algorithms: &[Algorithm]) -> io::Result<()> {
+    if let Some(algorithm) = algorithms
+        .iter()
+        .find(|algorithm| !HASHDEEP_ALGORITHMS.contains(algorithm))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("hashdeep does not support {}", algorithm.name()),
+        ));
+    }
+
+    let names: Vec<&str> = algorithms
+        .iter()
+        .map(|algorithm| algorithm.name())
+        .collect();
+    writeln!(writer, "{HEADER}")?;
+    writeln!(writer, "%%%% size,{},filename", names.join(","))?;
+    writeln!(writer, "## Written by filematch")?;
+    writeln!(writer, "##")
+}
+
+/// Writes the line of a single file of a hashdeep file, after [`write_hashdeep_header`].
+///
+/// # Parameters
+/// - `writer`: Where to write the hashdeep file.
+/// - `file`: The file, with digests in the order of the algorithms of the header.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails.
+pub fn write_hashdeep_entry(mut writer: impl Write, file: &FileDigest) -> io::Result<()> {
+    writeln!(
+        writer,
+        "{},{},{}",
+        file.size,
+        file.digests.join(","),
+        file.path.display()
+    )
+}
+
+/// The outcome of auditing a directory against a hashdeep file.
+///
+/// The categories follow hashdeep's audit mode.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    /// Files whose content and path match a known entry.
+    pub matched: Vec<PathBuf>,
+    /// Files whose content is known under a different path, as (current path, known path).
+    pub moved: Vec<(PathBuf, PathBuf)>,
+    /// Files whose content is not known.
+    pub new: Vec<PathBuf>,
+    /// Known entries whose content was not found in the directory.
+    pub missing: Vec<PathBuf>,
+}
+
+impl AuditReport {
+    /// Returns true if every file matched a known entry and no known entry is missing.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.moved.is_empty() && self.new.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Audits a directory against a hashdeep file, like `hashdeep -a -k`.
+///
+/// Files are identified by their size and all digests present in the hashdeep file. Paths are
+/// compared as recorded, so the directory should be given the same way it was when the
+/// hashdeep file was created.
+///
+/// # Parameters
+/// - `directory`: The directory to audit.
+/// - `known`: The known files.
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The audit report, with all lists sorted by path.
+///
+/// # Errors
+/// This function returns an `io::Error` if a file cannot be read.
+pub fn audit(
+    directory: &Path,
+    known: &HashdeepFile,
+    options: &CompareOptions,
+) -> io::Result<AuditReport> {
+    let mut known_paths: HashMap<(u64, &[String]), Vec<&Path>> = HashMap::new();
+    for entry in &known.entries {
+        known_paths
+            .entry((entry.size, entry.digests.as_slice()))
+            .or_default()
+            .push(&entry.path);
+    }
+
+    let mut report = AuditReport::default();
+    let mut found = HashSet::new();
+
+    for file in digest_tree(directory, &known.algorithms, options)? {
+        let key = (file.size, file.digests.as_slice());
+        match known_paths.get(&key) {
+            Some(paths) if paths.contains(&file.path.as_path()) => {
+                report.matched.push(file.path);
+            }
+            Some(paths) => {
+                report.moved.push((file.path, paths[0].to_path_buf()));
+            }
+            None => report.new.push(file.path),
+        }
+        found.insert((file.size, file.digests));
+    }
+
+    report.missing = known
+        .entries
+        .iter()
+        .filter(|entry| !found.contains(&(entry.size, entry.digests.clone())))
+        .map(|entry| entry.path.clone())
+        .collect();
+    report.missing.sort();
+
+    Ok(report)
+}
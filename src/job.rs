@@ -0,0 +1,365 @@
+use serde_json::{json, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::options::CompareOptions;
+use crate::result::ComparisonResult;
+
+/// Name of the file describing a job, inside the directory of the job.
+pub const JOB_FILE_NAME: &str = "job.json";
+
+/// Name of the journal recording the hashes computed by a job, inside the directory of the job.
+pub const JOURNAL_FILE_NAME: &str = "journal.sums";
+
+/// The comparison a job runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobSpec {
+    /// The canonical root directories of the first side.
+    pub directories1: Vec<PathBuf>,
+    /// The canonical root directories of the second side.
+    pub directories2: Vec<PathBuf>,
+    /// Return file paths relative to the directory they were found in.
+    pub relative: bool,
+    /// Sort the resulting file paths.
+    pub sort: bool,
+    /// Skip hidden files and directories.
+    pub skip_hidden: bool,
+    /// Skip files matched by a `.filematchignore` file at the root of each directory.
+    pub use_ignore_file: bool,
+    /// Skip files matched by `.gitignore` and `.ignore` files, or by the global git excludes.
+    pub respect_gitignore: bool,
+    /// Descend into symbolic links to directories.
+    pub follow_links: bool,
+    /// Compare even if a root directory is inside another root directory.
+    pub allow_overlapping_roots: bool,
+}
+
+impl JobSpec {
+    /// Returns the options of the comparison.
+    ///
+    /// # Parameters
+    /// - `options`: The options not recorded in the job, such as the thread counts.
+    #[must_use]
+    pub fn options(&self, options: &CompareOptions) -> CompareOptions {
+        CompareOptions {
+            relative: self.relative,
+            sort: self.sort,
+            skip_hidden: self.skip_hidden,
+            use_ignore_file: self.use_ignore_file,
+            respect_gitignore: self.respect_gitignore,
+            follow_links: self.follow_links,
+            allow_overlapping_roots: self.allow_overlapping_roots,
+            ..options.clone()
+        }
+    }
+}
+
+/// How far a job got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// The job was never run.
+    Created,
+    /// The job was started but did not finish, so it can be resumed.
+    Running,
+    /// The job ran to completion.
+    Finished,
+}
+
+impl JobState {
+    /// Returns the name of the state, as recorded in the job file.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Running => "running",
+            Self::Finished => "finished",
+        }
+    }
+
+    /// Looks up a state by its name.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "created" => Some(Self::Created),
+            "running" => Some(Self::Running),
+            "finished" => Some(Self::Finished),
+            _ => None,
+        }
+    }
+}
+
+/// A comparison that can be interrupted and resumed, stored in a directory of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// The name of the directory of the job.
+    pub id: String,
+    /// The comparison the job runs.
+    pub spec: JobSpec,
+    /// How far the job got.
+    pub state: JobState,
+    /// When the last run that finished was started, if any. Files modified before then were
+    /// compared by that run.
+    pub last_run: Option<SystemTime>,
+}
+
+/// Returns the directory jobs are stored in by default.
+///
+/// This is `filematch/jobs` in `$XDG_STATE_HOME`, `~/.local/state`, or `%LOCALAPPDATA%` on
+/// Windows.
+///
+/// # Returns
+/// The directory, or None if the home directory is unknown.
+#[must_use]
+pub fn default_jobs_directory() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+
+    Some(state.join("filematch").join("jobs"))
+}
+
+/// Creates the error reported for job files that cannot be understood.
+fn invalid(id: &str, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("job {id}: {message}"))
+}
+
+/// Converts the paths of a side to JSON strings.
+fn paths_to_json(paths: &[PathBuf]) -> io::Result<Value> {
+    paths
+        .iter()
+        .map(|path| {
+            path.to_str().map(|path| json!(path)).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' is not valid UTF-8", path.display()),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()
+        .map(Value::Array)
+}
+
+/// Writes the job file of a job, replacing it atomically.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be written, or an error of kind
+/// `InvalidInput` if a directory of the job is not valid UTF-8.
+fn save_job(jobs: &Path, job: &Job) -> io::Result<()> {
+    let spec = &job.spec;
+    let value = json!({
+        "directories1": paths_to_json(&spec.directories1)?,
+        "directories2": paths_to_json(&spec.directories2)?,
+        "relative": spec.relative,
+        "sort": spec.sort,
+        "skip_hidden": spec.skip_hidden,
+        "use_ignore_file": spec.use_ignore_file,
+        "respect_gitignore": spec.respect_gitignore,
+        "follow_links": spec.follow_links,
+        "allow_overlapping_roots": spec.allow_overlapping_roots,
+        "state": job.state.name(),
+        "last_run": job
+            .last_run
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs()),
+    });
+
+    let directory = jobs.join(&job.id);
+    let temporary = directory.join(format!("{JOB_FILE_NAME}.tmp"));
+    let contents = serde_json::to_string_pretty(&value).map_err(io::Error::from)? + "\n";
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, directory.join(JOB_FILE_NAME))
+}
+
+/// Creates a job, assigning it the next free number as its id.
+///
+/// The directories are canonicalized, so the job can be run from any working directory.
+///
+/// # Parameters
+/// - `jobs`: The directory the jobs are stored in. It is created if it does not exist.
+/// - `spec`: The comparison to run.
+///
+/// # Returns
+/// The created job.
+///
+/// # Errors
+/// This function returns an `io::Error` if a directory cannot be canonicalized or the job
+/// cannot be written, or an error of kind `InvalidInput` if a directory is not valid UTF-8.
+pub fn create_job(jobs: &Path, spec: &JobSpec) -> io::Result<Job> {
+    let canonical = |dirs: &[PathBuf]| {
+        dirs.iter()
+            .map(fs::canonicalize)
+            .collect::<io::Result<Vec<_>>>()
+    };
+    let spec = JobSpec {
+        directories1: canonical(&spec.directories1)?,
+        directories2: canonical(&spec.directories2)?,
+        ..spec.clone()
+    };
+    fs::create_dir_all(jobs)?;
+
+    // Creating the directory claims the id, even if another job is created at the same time
+    let mut number = 1;
+    let id = loop {
+        let id = number.to_string();
+        match fs::create_dir(jobs.join(&id)) {
+            Ok(()) => break id,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => number += 1,
+            Err(err) => return Err(err),
+        }
+    };
+
+    let job = Job {
+        id,
+        spec,
+        state: JobState::Created,
+        last_run: None,
+    };
+    save_job(jobs, &job)?;
+
+    Ok(job)
+}
+
+/// Loads a job.
+///
+/// # Parameters
+/// - `jobs`: The directory the jobs are stored in.
+/// - `id`: The id of the job.
+///
+/// # Returns
+/// The job.
+///
+/// # Errors
+/// This function returns an `io::Error` if the job file cannot be read, or an error of kind
+/// `InvalidData` if it is malformed.
+pub fn load_job(jobs: &Path, id: &str) -> io::Result<Job> {
+    let contents = fs::read_to_string(jobs.join(id).join(JOB_FILE_NAME))
+        .map_err(|err| io::Error::new(err.kind(), format!("job {id}: {err}")))?;
+    let value: Value =
+        serde_json::from_str(&contents).map_err(|err| invalid(id, &err.to_string()))?;
+
+    let paths = |key: &str| {
+        value
+            .get(key)
+            .and_then(Value::as_array)
+            .and_then(|paths| {
+                paths
+                    .iter()
+                    .map(|path| path.as_str().map(PathBuf::from))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid(id, &format!("'{key}' must be a list of paths")))
+    };
+    let flag = |key: &str| {
+        value
+            .get(key)
+            .and_then(Value::as_bool)
+            .ok_or_else(|| invalid(id, &format!("'{key}' must be true or false")))
+    };
+
+    Ok(Job {
+        id: id.to_string(),
+        spec: JobSpec {
+            directories1: paths("directories1")?,
+            directories2: paths("directories2")?,
+            relative: flag("relative")?,
+            sort: flag("sort")?,
+            skip_hidden: flag("skip_hidden")?,
+            use_ignore_file: flag("use_ignore_file")?,
+            // Job files written before ignore files were honored have none
+            respect_gitignore: match value.get("respect_gitignore") {
+                None => false,
+                Some(_) => flag("respect_gitignore")?,
+            },
+            follow_links: flag("follow_links")?,
+            allow_overlapping_roots: flag("allow_overlapping_roots")?,
+        },
+        state: value
+            .get("state")
+            .and_then(Value::as_str)
+            .and_then(JobState::from_name)
+            .ok_or_else(|| invalid(id, "unknown state"))?,
+        // Job files written before the last run was recorded have none
+        last_run: match value.get("last_run") {
+            None | Some(Value::Null) => None,
+            Some(seconds) => Some(
+                seconds
+                    .as_u64()
+                    .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+                    .ok_or_else(|| invalid(id, "'last_run' must be a number of seconds"))?,
+            ),
+        },
+    })
+}
+
+/// Loads all jobs.
+///
+/// # Parameters
+/// - `jobs`: The directory the jobs are stored in.
+///
+/// # Returns
+/// The jobs, ordered by id. Nothing if the directory does not exist.
+///
+/// # Errors
+/// This function returns an `io::Error` if the directory or a job cannot be read.
+pub fn list_jobs(jobs: &Path) -> io::Result<Vec<Job>> {
+    let entries = match fs::read_dir(jobs) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut list = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(id) = entry.file_name().to_str() {
+            if entry.path().join(JOB_FILE_NAME).is_file() {
+                list.push(load_job(jobs, id)?);
+            }
+        }
+    }
+    // Numeric ids sort by number
+    list.sort_by(|a, b| (a.id.len(), &a.id).cmp(&(b.id.len(), &b.id)));
+
+    Ok(list)
+}
+
+/// Runs a job, resuming it if it was interrupted.
+///
+/// The hash of every file read is appended to the journal of the job as soon as it is known.
+/// Running the job again, after an interruption or once it finished, only reads the files that
+/// are not in the journal or changed since. When the run finishes, its start time is recorded as
+/// the last run of the job, so a later run can leave out the files modified before, see
+/// [`CompareOptions::modified_since`].
+///
+/// # Parameters
+/// - `jobs`: The directory the jobs are stored in.
+/// - `id`: The id of the job.
+/// - `options`: The options not recorded in the job, such as the thread counts.
+///
+/// # Returns
+/// The result of the comparison.
+///
+/// # Errors
+/// This function returns an `io::Error` if the job cannot be loaded or saved, or if the
+/// comparison fails, see [`compare_multiple_roots`].
+pub fn run_job(jobs: &Path, id: &str, options: &CompareOptions) -> io::Result<ComparisonResult> {
+    let mut job = load_job(jobs, id)?;
+    let started = SystemTime::now();
+    job.state = JobState::Running;
+    save_job(jobs, &job)?;
+
+    let options = CompareOptions {
+        journal: Some(jobs.join(id).join(JOURNAL_FILE_NAME)),
+        ..job.spec.options(options)
+    };
+    let result = compare_multiple_roots(&job.spec.directories1, &job.spec.directories2, &options)?;
+
+    job.state = JobState::Finished;
+    job.last_run = Some(started);
+    save_job(jobs, &job)?;
+
+    Ok(result)
+}
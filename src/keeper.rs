@@ -0,0 +1,298 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use crate::pipeline::Side;
+use crate::result::HashGroup;
+
+/// A rule preferring some files of a group over others when picking the one to keep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepRule {
+    /// Prefer the files of one directory.
+    Side(Side),
+    /// Prefer the files whose path matches a pattern, see [`KeepRule::matches`].
+    Matching(String),
+    /// Prefer the files whose path does not match a pattern.
+    NotMatching(String),
+    /// Prefer the files with the shortest path.
+    ShortestPath,
+    /// Prefer the files with the longest path.
+    LongestPath,
+    /// Prefer the files modified first.
+    Oldest,
+    /// Prefer the files modified last.
+    Newest,
+}
+
+/// Matches a single path component against a pattern component with `*` and `?` wildcards.
+fn component_matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            component_matches(&pattern[1..], name)
+                || (!name.is_empty() && component_matches(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => component_matches(&pattern[1..], &name[1..]),
+        (Some(expected), Some(found)) => {
+            expected == found && component_matches(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+/// Matches path components against pattern components, where `**` matches any number of them.
+fn components_match(pattern: &[Vec<char>], components: &[Vec<char>]) -> bool {
+    match pattern.first() {
+        None => components.is_empty(),
+        Some(first) if first.iter().eq(['*', '*'].iter()) => {
+            components_match(&pattern[1..], components)
+                || (!components.is_empty() && components_match(pattern, &components[1..]))
+        }
+        Some(first) => components.first().is_some_and(|component| {
+            component_matches(first, component) && components_match(&pattern[1..], &components[1..])
+        }),
+    }
+}
+
+impl KeepRule {
+    /// Determines if a path matches a pattern.
+    ///
+    /// In the pattern, `*` matches any characters within a path component, `?` matches a single
+    /// one, and `**` matches any number of components. The pattern matches if it matches the
+    /// whole path or its last components, so `*.jpg` matches every JPEG file and
+    /// `originals/*` every file directly in a directory named `originals`.
+    ///
+    /// # Parameters
+    /// - `pattern`: The pattern, with components separated by `/`.
+    /// - `path`: The path to match.
+    ///
+    /// # Returns
+    /// True if the path matches, false otherwise.
+    #[must_use]
+    pub fn matches(pattern: &str, path: &Path) -> bool {
+        let pattern: Vec<Vec<char>> = pattern
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(|component| component.chars().collect())
+            .collect();
+        let components: Vec<Vec<char>> = path
+            .iter()
+            .map(|component| component.to_string_lossy().chars().collect())
+            .collect();
+
+        (0..=components.len()).any(|start| components_match(&pattern, &components[start..]))
+    }
+
+    /// Computes the rank of a file under this rule, lower ranks being preferred.
+    fn rank(&self, side: Side, path: &Path) -> u128 {
+        let modified = || {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_nanos())
+        };
+
+        match self {
+            Self::Side(preferred) => u128::from(side != *preferred),
+            Self::Matching(pattern) => u128::from(!Self::matches(pattern, path)),
+            Self::NotMatching(pattern) => u128::from(Self::matches(pattern, path)),
+            Self::ShortestPath => path.as_os_str().len() as u128,
+            Self::LongestPath => u128::MAX - path.as_os_str().len() as u128,
+            // Files whose modification time cannot be read are preferred last
+            Self::Oldest => modified().unwrap_or(u128::MAX),
+            Self::Newest => modified().map_or(u128::MAX, |modified| u128::MAX - 1 - modified),
+        }
+    }
+}
+
+impl fmt::Display for KeepRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Side(Side::Dir1) => write!(f, "dir1"),
+            Self::Side(Side::Dir2) => write!(f, "dir2"),
+            Self::Matching(pattern) => write!(f, "match:{pattern}"),
+            Self::NotMatching(pattern) => write!(f, "avoid:{pattern}"),
+            Self::ShortestPath => write!(f, "shortest"),
+            Self::LongestPath => write!(f, "longest"),
+            Self::Oldest => write!(f, "oldest"),
+            Self::Newest => write!(f, "newest"),
+        }
+    }
+}
+
+impl FromStr for KeepRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let rule = rule.trim();
+        let pattern = |pattern: &str| {
+            if pattern.is_empty() {
+                Err(format!("'{rule}' has no pattern"))
+            } else {
+                Ok(pattern.to_string())
+            }
+        };
+
+        match rule.split_once(':') {
+            Some(("match", rest)) => Ok(Self::Matching(pattern(rest)?)),
+            Some(("avoid", rest)) => Ok(Self::NotMatching(pattern(rest)?)),
+            _ => match rule {
+                "dir1" => Ok(Self::Side(Side::Dir1)),
+                "dir2" => Ok(Self::Side(Side::Dir2)),
+                "shortest" => Ok(Self::ShortestPath),
+                "longest" => Ok(Self::LongestPath),
+                "oldest" => Ok(Self::Oldest),
+                "newest" => Ok(Self::Newest),
+                _ => Err(format!(
+                    "unknown rule '{rule}', expected dir1, dir2, match:PATTERN, avoid:PATTERN, \
+                     shortest, longest, oldest or newest"
+                )),
+            },
+        }
+    }
+}
+
+/// A file of a group of files with the same content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupFile {
+    /// The directory the file belongs to.
+    pub side: Side,
+    /// The path of the file.
+    pub path: PathBuf,
+}
+
+/// The file to keep of a group of files with the same content, and its duplicates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepDecision {
+    /// The file to keep.
+    pub keep: GroupFile,
+    /// The other files of the group, in the order of the group.
+    pub duplicates: Vec<GroupFile>,
+}
+
+/// Rules picking the file to keep of each group of duplicates, evaluated in order.
+///
+/// Each rule narrows the files of a group down to those it prefers most, and the next rule only
+/// decides between those, so the first rule has the final say. Files still tied after the last
+/// rule are decided by their order in the group: the files of the first directory first, then
+/// by path. The rules can be built one by one, or parsed from a comma-separated list such as
+/// `dir1,avoid:**/tmp/**,shortest`, with the rules:
+///
+/// - `dir1`, `dir2`: prefer the files of a directory;
+/// - `match:PATTERN`, `avoid:PATTERN`: prefer the files whose path matches, or does not match,
+///   a pattern, see [`KeepRule::matches`];
+/// - `shortest`, `longest`: prefer the files with the shortest or longest path;
+/// - `oldest`, `newest`: prefer the files modified first or last.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeepRules {
+    rules: Vec<KeepRule>,
+}
+
+impl KeepRules {
+    /// Creates an empty list of rules, keeping the first file of each group.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, which only decides between the files the previous rules tied on.
+    #[must_use]
+    pub fn then(mut self, rule: KeepRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Adds a rule preferring the files of one directory.
+    #[must_use]
+    pub fn prefer_side(self, side: Side) -> Self {
+        self.then(KeepRule::Side(side))
+    }
+
+    /// Adds a rule preferring the files whose path matches a pattern.
+    #[must_use]
+    pub fn prefer_matching(self, pattern: &str) -> Self {
+        self.then(KeepRule::Matching(pattern.to_string()))
+    }
+
+    /// Adds a rule preferring the files whose path does not match a pattern.
+    #[must_use]
+    pub fn avoid_matching(self, pattern: &str) -> Self {
+        self.then(KeepRule::NotMatching(pattern.to_string()))
+    }
+
+    /// Adds a rule preferring the files with the shortest path.
+    #[must_use]
+    pub fn shortest_path(self) -> Self {
+        self.then(KeepRule::ShortestPath)
+    }
+
+    /// Returns the rules, in the order they are evaluated.
+    #[must_use]
+    pub fn rules(&self) -> &[KeepRule] {
+        &self.rules
+    }
+
+    /// Picks the file to keep of a group of files with the same content.
+    ///
+    /// The modification times of the files are read for the `oldest` and `newest` rules, so the
+    /// paths of the group must be valid from the working directory.
+    ///
+    /// # Parameters
+    /// - `group`: The files with the same content, of both directories.
+    ///
+    /// # Returns
+    /// The file to keep and its duplicates, or None if the group has fewer than two files.
+    #[must_use]
+    pub fn decide(&self, group: &HashGroup) -> Option<KeepDecision> {
+        let files: Vec<GroupFile> = group
+            .dir1
+            .iter()
+            .map(|path| (Side::Dir1, path))
+            .chain(group.dir2.iter().map(|path| (Side::Dir2, path)))
+            .map(|(side, path)| GroupFile {
+                side,
+                path: path.clone(),
+            })
+            .collect();
+        if files.len() < 2 {
+            return None;
+        }
+
+        let mut candidates: Vec<usize> = (0..files.len()).collect();
+        for rule in &self.rules {
+            let ranks: Vec<u128> = candidates
+                .iter()
+                .map(|&index| rule.rank(files[index].side, &files[index].path))
+                .collect();
+            let best = ranks.iter().min().copied().unwrap_or_default();
+            candidates = candidates
+                .into_iter()
+                .zip(ranks)
+                .filter(|(_, rank)| *rank == best)
+                .map(|(index, _)| index)
+                .collect();
+        }
+
+        let keep = candidates[0];
+        let mut duplicates = files;
+        let keep = duplicates.remove(keep);
+        Some(KeepDecision { keep, duplicates })
+    }
+}
+
+impl FromStr for KeepRules {
+    type Err = String;
+
+    fn from_str(rules: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            rules: rules
+                .split(',')
+                .filter(|rule| !rule.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
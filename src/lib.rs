@@ -1,2 +1,37 @@
+pub mod audit_log;
+pub mod bagit;
+#[cfg(feature = "testutil")]
+pub mod bench;
+pub mod cas;
+pub mod checksum;
 pub mod compare_two_directories;
+pub mod dedupe;
+pub mod error;
+pub mod exec;
+pub mod filesystem;
+pub mod hash_list;
+pub mod hashdeep;
+pub mod job;
+pub mod keeper;
+pub mod limits;
+pub mod manifest;
+pub mod manifest_diff;
+pub mod media;
+pub mod notify;
+pub mod options;
+pub mod pipeline;
+pub mod plan;
+pub mod previous;
+pub mod priority;
+pub mod profile;
+pub mod reference;
+pub mod result;
+pub mod result_cache;
+pub mod selftest;
+pub mod snapshot;
+pub mod sums;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+pub mod three_way;
 pub mod util;
+pub mod watch;
@@ -0,0 +1,194 @@
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+/// Number of file descriptors kept free for directory walks, sums files, the journal and the
+/// standard streams when capping the files read at once.
+pub const RESERVED_FILES: u64 = 64;
+
+/// Highest soft limit of open files macOS accepts with an unlimited hard limit when the
+/// per-process limit cannot be read, `OPEN_MAX` of `<sys/syslimits.h>`.
+#[cfg(target_os = "macos")]
+const MACOS_OPEN_MAX: libc::rlim_t = 10240;
+
+/// Returns the highest soft limit of open files macOS accepts: the `kern.maxfilesperproc`
+/// sysctl, or `OPEN_MAX` if it cannot be read.
+#[cfg(target_os = "macos")]
+fn macos_max_files() -> libc::rlim_t {
+    let mut value: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>();
+    // SAFETY: The name is a null-terminated string, and `value` and `size` describe a writable
+    // buffer of the size of the integer the sysctl holds. No new value is given.
+    let read = unsafe {
+        libc::sysctlbyname(
+            c"kern.maxfilesperproc".as_ptr(),
+            std::ptr::addr_of_mut!(value).cast(),
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    match libc::rlim_t::try_from(value) {
+        Ok(value) if read == 0 && value > 0 => value,
+        _ => MACOS_OPEN_MAX,
+    }
+}
+
+/// Raises the limit of open files of the process as far as permitted, like `ulimit -n`.
+///
+/// On Unix the soft limit is raised to the hard limit, which many systems set far higher. macOS
+/// rejects soft limits above the number of files a process may open, `kern.maxfilesperproc`,
+/// even when the hard limit is unlimited, so the soft limit is raised to that number at most,
+/// and to `OPEN_MAX` if that fails. A limit that cannot be raised is kept. Windows has no such
+/// limit for the handles filematch uses.
+///
+/// # Returns
+/// The limit in effect afterwards, or None if the platform has no limit or it cannot be read.
+#[must_use]
+pub fn raise_open_file_limit() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a valid, writable `rlimit` for the call to fill.
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return None;
+        }
+
+        #[cfg(target_os = "macos")]
+        let targets = [
+            limit.rlim_max.min(macos_max_files()),
+            limit.rlim_max.min(MACOS_OPEN_MAX),
+        ];
+        #[cfg(not(target_os = "macos"))]
+        let targets = [limit.rlim_max];
+        for target in targets {
+            if limit.rlim_cur >= target {
+                break;
+            }
+            let raised = libc::rlimit {
+                rlim_cur: target,
+                rlim_max: limit.rlim_max,
+            };
+            // SAFETY: `raised` is a valid `rlimit`, which the call only reads.
+            if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+                limit.rlim_cur = target;
+                break;
+            }
+        }
+
+        #[allow(clippy::useless_conversion)]
+        Some(u64::from(limit.rlim_cur))
+    }
+
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+/// Returns the number of files that may be read at once under a limit of open files.
+///
+/// # Parameters
+/// - `limit`: The limit of open files of the process.
+///
+/// # Returns
+/// The limit less `RESERVED_FILES`, and at least one.
+#[must_use]
+pub fn max_open_files(limit: u64) -> usize {
+    usize::try_from(limit.saturating_sub(RESERVED_FILES))
+        .unwrap_or(usize::MAX)
+        .max(1)
+}
+
+/// Longest name of a single file or directory on common filesystems (NTFS, ext4, APFS).
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// A platform whose limits on the length of paths are checked before files are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathPlatform {
+    /// Windows without long path support: 259 UTF-16 characters (MAX_PATH of 260 with the null)
+    Windows,
+    /// Linux: 4095 bytes (PATH_MAX of 4096 with the null)
+    Linux,
+    /// macOS: 1023 bytes (PATH_MAX of 1024 with the null)
+    Macos,
+}
+
+impl PathPlatform {
+    /// Returns the length of the longest path the platform accepts, without the terminating
+    /// null.
+    #[must_use]
+    pub fn max_path(self) -> usize {
+        match self {
+            PathPlatform::Windows => 259,
+            PathPlatform::Linux => 4095,
+            PathPlatform::Macos => 1023,
+        }
+    }
+
+    /// Returns the length of a path or name as the platform counts it: in UTF-16 characters on
+    /// Windows and in bytes elsewhere.
+    #[must_use]
+    pub fn length(self, path: &OsStr) -> usize {
+        match self {
+            PathPlatform::Windows => path.to_string_lossy().encode_utf16().count(),
+            PathPlatform::Linux | PathPlatform::Macos => path.as_encoded_bytes().len(),
+        }
+    }
+}
+
+/// A path that a platform would not accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LongPath {
+    /// The path.
+    pub path: PathBuf,
+    /// The length of the path, or of its longest name if only that is too long.
+    pub length: usize,
+    /// The longest length accepted.
+    pub limit: usize,
+    /// True if a single file or directory name is too long rather than the whole path.
+    pub name: bool,
+}
+
+/// Finds the paths that are too long for a platform, or that have a name too long for it.
+///
+/// # Parameters
+/// - `paths`: The paths to check, as they would be written on the platform.
+/// - `platform`: The platform whose limits apply.
+///
+/// # Returns
+/// The paths exceeding a limit, in the order given.
+pub fn long_paths<'a>(
+    paths: impl IntoIterator<Item = &'a Path>,
+    platform: PathPlatform,
+) -> Vec<LongPath> {
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let length = platform.length(path.as_os_str());
+            if length > platform.max_path() {
+                return Some(LongPath {
+                    path: path.to_path_buf(),
+                    length,
+                    limit: platform.max_path(),
+                    name: false,
+                });
+            }
+            let longest_name = path
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(platform.length(name)),
+                    _ => None,
+                })
+                .max()?;
+            (longest_name > MAX_NAME_LENGTH).then(|| LongPath {
+                path: path.to_path_buf(),
+                length: longest_name,
+                limit: MAX_NAME_LENGTH,
+                name: true,
+            })
+        })
+        .collect()
+}
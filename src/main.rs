@@ -1,167 +1,4097 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use serde_json::json;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-mod compare_two_directories;
-mod util;
+use filematch::audit_log::{read_audit_log, undo, AuditLog};
+use filematch::bagit::{compare_bags, verify_bag};
+#[cfg(feature = "testutil")]
+use filematch::bench::run_benchmark;
+use filematch::cas::{export_cas, restore_cas, MANIFEST_FILE_NAME};
+use filematch::checksum::{digest_tree, for_each_digest, Algorithm};
+use filematch::compare_two_directories::{
+    compare_multiple_roots, double_check, recheck_matches, reproducibility_check,
+};
+use filematch::dedupe::{
+    cross_side_duplicates, dedupe, dedupe_plan, find_duplicates, Dedupe, DedupeAction, Duplicate,
+};
+use filematch::error::FileMatchError;
+use filematch::exec::{run_for_each, ExecTemplate};
+use filematch::filesystem::{
+    detect_filesystem, detect_storage, Filesystem, StorageKind, HDD_CHUNK_SIZE, HDD_IO_THREADS,
+    NETWORK_CHUNK_SIZE, NETWORK_IO_THREADS, NETWORK_RETRIES,
+};
+use filematch::hash_list::{check_known, load_hash_list};
+use filematch::hashdeep::{
+    audit, read_hashdeep, write_hashdeep, write_hashdeep_entry, write_hashdeep_header, HashdeepFile,
+};
+use filematch::job::{
+    create_job, default_jobs_directory, list_jobs, load_job, run_job, JobSpec, JobState,
+};
+use filematch::keeper::{GroupFile, KeepDecision, KeepRules};
+use filematch::limits::{long_paths, max_open_files, raise_open_file_limit, PathPlatform};
+use filematch::manifest::{
+    generate_checksum_list, generate_manifest_with_options, read_checksum_list, read_manifest,
+    verify_checksum_list, verify_manifest, write_checksum_list, write_manifest, MANIFEST_HEADER,
+};
+use filematch::manifest_diff::{compare_sorted_manifests, diff_sorted_manifests};
+use filematch::notify::{post_webhook, summarize, SUMMARY_PATHS};
+use filematch::options::{
+    CompareOptions, Compression, ErrorPolicy, LockedPolicy, RangeHash, SumsPolicy,
+};
+use filematch::pipeline::Side;
+use filematch::plan::{apply_plan, Plan, PlanStep, StepOutcome};
+use filematch::previous::{compare_against, read_grouped_json};
+use filematch::priority::{set_io_priority, set_nice, IoPriority};
+use filematch::profile::{default_profiles_file, Profiles};
+use filematch::reference::{build_reference_index, ReferenceSet};
+use filematch::result::{
+    rollup_by_directory, rollup_by_extension, usage_by_directory, Category, ComparisonResult,
+    DirectoryRollup, DirectoryUsage, ExtensionRollup, HashGroup, ReadError, SkippedFile, WalkError,
+};
+use filematch::result_cache::{compare_cached, default_cache_directory, ResultCache};
+use filematch::selftest::{run_self_test, TEST_FILE_NAME};
+use filematch::snapshot::compare_snapshots;
+use filematch::sums::{
+    export_sums, import_sums, precompute, read_checksum_lines, write_checksum_lines,
+};
+#[cfg(feature = "testutil")]
+use filematch::testutil::setup_benchmark_files;
+use filematch::three_way::{three_way_diff, Change, ThreeWayChange, ThreeWayStatus};
+use filematch::util::{
+    find_overlapping_roots, matching_root, parse_duration, parse_size, parse_timestamp,
+    relative_path, PathStyle,
+};
+use filematch::watch::{watch, WatchEvent, WatchOptions};
 
-use crate::compare_two_directories::compare_two_directories;
+// Compares files between two directories by hash
+#[derive(Parser)]
+#[command(
+    version = env!("CARGO_PKG_VERSION"),
+    author = env!("CARGO_PKG_AUTHORS"),
+    about = "Compares files between two directories by hash",
+    after_help = "If none of --intersection, --dir1, or --dir2 are set, then all are displayed",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    args_override_self = true,
+)]
+#[allow(clippy::struct_excessive_bools)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The first directory to compare
+    #[arg(required = true)]
+    directory1: Option<PathBuf>,
+
+    /// The second directory to compare
+    #[arg(required = true)]
+    directory2: Option<PathBuf>,
+
+    /// Additional directory treated as part of directory1 (may be repeated)
+    #[arg(long, value_name = "DIR")]
+    root1: Vec<PathBuf>,
+
+    /// Additional directory treated as part of directory2 (may be repeated)
+    #[arg(long, value_name = "DIR")]
+    root2: Vec<PathBuf>,
+
+    /// Use the options of a profile, overridden by options given after it: photos, code, backup-verify, or one defined in the profiles file (may be repeated)
+    #[arg(long, value_name = "NAME")]
+    profile: Vec<String>,
+
+    /// Sort output paths
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    sort: bool,
+
+    /// Skip hidden files and directories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    skip_hidden: bool,
+
+    /// Do not honor .filematchignore files at the root of the directories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_ignore_file: bool,
+
+    /// Honor .ignore files in every directory, and .gitignore files and the global git excludes inside git repositories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    gitignore: bool,
+
+    /// Descend into symbolic links to directories, and junctions and mount points on Windows
+    #[arg(long, visible_alias = "follow-junctions", action = clap::ArgAction::SetTrue)]
+    follow_links: bool,
+
+    /// Display output paths relative to argument directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    relative: bool,
+
+    /// Display canonical absolute paths, resolving relative directories, .. and symbolic links to the directories
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["relative", "relative_to1", "relative_to2"])]
+    absolute: bool,
+
+    /// Display the paths of directory1 relative to this ancestor of it instead
+    #[arg(long, value_name = "PATH")]
+    relative_to1: Option<PathBuf>,
+
+    /// Display the paths of directory2 relative to this ancestor of it instead
+    #[arg(long, value_name = "PATH")]
+    relative_to2: Option<PathBuf>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+
+    /// Output format. json-grouped lists every content with its files and their metadata
+    #[arg(long, value_enum, conflicts_with_all = ["json", "prefix", "counts"])]
+    format: Option<OutputFormat>,
+
+    /// Display files both in directory1 and directory2
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "sections")]
+    intersection: bool,
+
+    /// Display unique files in dir1
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "sections")]
+    dir1: bool,
+
+    /// Display unique files in dir2
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "sections")]
+    dir2: bool,
+
+    /// Display each content found in both directories with its number of copies on each side
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["prefix", "intersection_side"])]
+    counts: bool,
+
+    /// Display the share of files and bytes matched on the other side for each subdirectory of the roots
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["prefix", "counts", "format", "intersection_side"])]
+    rollup: bool,
+
+    /// Display the share of files and bytes matched on the other side for each file extension
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["prefix", "counts", "format", "rollup", "mtime_delta", "intersection_side"])]
+    by_extension: bool,
+
+    /// Display how much later the copy in directory2 was modified than the one in directory1, listing pairs
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    mtime_delta: bool,
+
+    /// Display the file to keep of each content with several files, picked by these comma-separated rules: dir1, dir2, match:PATTERN, avoid:PATTERN, shortest, longest, oldest, newest
+    #[arg(long, value_name = "RULES", conflicts_with_all = ["format", "counts", "rollup", "by_extension", "mtime_delta", "intersection_side", "sections", "snapshots"])]
+    keep: Option<KeepRules>,
+
+    /// Deal with the files of directory2 whose content is in directory1, after comparing them byte by byte once more
+    #[arg(long, value_enum, value_name = "ACTION", conflicts_with_all = ["format", "counts", "rollup", "by_extension", "mtime_delta", "keep", "intersection_side", "sections", "intersection", "dir1", "dir2", "snapshots"])]
+    dedupe: Option<DedupeAction>,
+
+    /// With --dedupe, list the files that would be dealt with and change nothing
+    #[arg(long, requires = "dedupe", action = clap::ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// With --dedupe, write the changes to this plan file for `filematch apply` instead of making them
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "dedupe",
+        conflicts_with = "dry_run"
+    )]
+    write_plan: Option<PathBuf>,
+
+    /// The directory --dedupe trash moves files to, keeping their paths below directory2
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "dedupe",
+        required_if_eq("dedupe", "trash")
+    )]
+    trash_dir: Option<PathBuf>,
+
+    /// With --dedupe, append every file removed, trashed or replaced by a link to this audit log, as JSON lines, for `filematch undo`
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "dedupe",
+        conflicts_with_all = ["dry_run", "write_plan"]
+    )]
+    audit_log: Option<PathBuf>,
+
+    /// Run this command for each file both in directory1 and directory2, {} standing for its path as found, e.g. 'cp {} /backup'
+    #[arg(long, value_name = "COMMAND", value_parser = parse_exec, conflicts_with_all = ["format", "counts", "rollup", "by_extension", "keep", "dedupe", "snapshots"])]
+    exec_intersection: Option<ExecTemplate>,
+
+    /// Run this command for each file unique to directory1, {} standing for its path as found
+    #[arg(long, value_name = "COMMAND", value_parser = parse_exec, conflicts_with_all = ["format", "counts", "rollup", "by_extension", "keep", "dedupe", "snapshots"])]
+    exec_unique_dir1: Option<ExecTemplate>,
+
+    /// Run this command for each file unique to directory2, {} standing for its path as found
+    #[arg(long, value_name = "COMMAND", value_parser = parse_exec, conflicts_with_all = ["format", "counts", "rollup", "by_extension", "keep", "dedupe", "snapshots"])]
+    exec_unique_dir2: Option<ExecTemplate>,
+
+    /// Post the summary of --format summary to this webhook URL, such as a Slack incoming webhook, using curl
+    #[arg(long, value_name = "URL", value_parser = parse_webhook_url)]
+    notify_webhook: Option<String>,
+
+    /// Number of --exec commands run at once
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    exec_jobs: u16,
+
+    /// With --format json-grouped, --counts or --keep, skip the first N contents
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    offset: usize,
+
+    /// With --format json-grouped, --counts or --keep, display at most N contents
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Which paths to display for files both in directory1 and directory2
+    #[arg(long, value_name = "SIDE", value_enum, default_value_t = IntersectionSide::Both)]
+    intersection_side: IntersectionSide,
+
+    /// Number of threads reading files and of threads hashing them, where not set separately, e.g. 1 on a hard disk [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    threads: Option<u16>,
+
+    /// Number of threads reading files [default: --threads, or physical cores, 4 × with --adaptive-io]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads: Option<u16>,
+
+    /// Number of threads reading only the files of directory1 [default: 4 on network filesystems, 1 on hard disks]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads1: Option<u16>,
+
+    /// Number of threads reading only the files of directory2 [default: 4 on network filesystems, 1 on hard disks]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads2: Option<u16>,
+
+    /// Kind of storage both directories are on, read in parallel on ssd and one file at a time on hdd [default: detected for each directory]
+    #[arg(long, value_name = "KIND", value_enum)]
+    storage: Option<StorageKind>,
+
+    /// Adjust the number of files read at once to the observed throughput, up to --io-threads
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    adaptive_io: bool,
+
+    /// Number of threads hashing file contents [default: --threads, or physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: Option<u16>,
+
+    /// Retry reading a file this many times after a transient I/O error [default: 0, 3 on network filesystems]
+    #[arg(long, value_name = "N")]
+    retries: Option<u32>,
+
+    /// Delay before the first retry, doubling with each further attempt
+    #[arg(long, value_name = "DURATION", default_value = "500ms", value_parser = parse_duration)]
+    retry_delay: Duration,
+
+    /// What to do with files locked by another process
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = LockedPolicy::Error)]
+    locked: LockedPolicy,
+
+    /// With --locked wait, how long to wait for a locked file before handling it as unreadable
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_duration)]
+    lock_timeout: Duration,
+
+    /// Read files modified while they were read once more before skipping them as unstable
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    retry_unstable: bool,
+
+    /// Compare even if a directory is inside another, leaving it out of the outer directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    allow_overlap: bool,
+
+    /// Fail if a directory cannot be read instead of reporting it and comparing the rest
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    fail_on_walk_error: bool,
+
+    /// Report files that cannot be read, e.g. without permission or removed meanwhile, and compare the rest instead of failing
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    ignore_errors: bool,
+
+    /// Compare files with these extensions by their decompressed contents (gz, xz, zst)
+    #[arg(long, value_name = "FORMATS", value_enum, value_delimiter = ',')]
+    decompress: Vec<Compression>,
+
+    /// Maximum number of files queued between the pipeline stages, bounding memory use
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_in_flight: Option<u32>,
+
+    /// Maximum number of files read at once [default: the limit of open files less 64]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_open_files: Option<u32>,
+
+    /// Number of bytes read from a file at once, e.g. 4M for fast storage [default: 64K, 1M on network filesystems and hard disks]
+    #[arg(long, visible_alias = "buffer-size", value_name = "SIZE", value_parser = parse_chunk_size)]
+    chunk_size: Option<usize>,
+
+    /// Do not adjust the defaults of reads, readers and retries to network filesystems
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_network_tuning: bool,
+
+    /// Walk both directories first, and only read the files of a size found on both sides, unless every hash is needed
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    prefilter_sizes: bool,
+
+    /// Hash only the first and last --range-size bytes and the size of files larger than this, e.g. 1G, as a quick triage of huge files; their matches are probable, not certain
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    range_hash: Option<u64>,
+
+    /// Number of bytes hashed at the start and at the end of files hashed by ranges [default: 16M]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "range_hash")]
+    range_size: Option<u64>,
+
+    /// Read the files matched by ranges in full after the comparison, so only certain matches remain
+    #[arg(long, requires = "range_hash")]
+    verify_ranges: bool,
+
+    /// Time the hashing of every file and report the N slowest on stderr, often a sign of a failing disk or cold network storage
+    #[arg(long, value_name = "N")]
+    slow_report: Option<usize>,
+
+    /// List a file reachable by paths differing only in case, as on case-insensitive filesystems, once per directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    collapse_case_aliases: bool,
+
+    /// Read a file reachable by several paths through links or junctions once per directory, listing it under one path
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    collapse_link_aliases: bool,
+
+    /// Print the profiles used, the filesystem of each directory, the tuning applied, the limit of open files, and the time taken on stderr
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    summary: bool,
+
+    /// Only compare files modified after this UTC time, e.g. 2024-05-01T12:30:00Z or @1714566600
+    #[arg(long, value_name = "TIMESTAMP", value_parser = parse_timestamp)]
+    since: Option<SystemTime>,
+
+    /// Leave out files smaller than this, e.g. 4K, before reading them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Leave out files larger than this, e.g. 10G, before reading them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Lower the CPU priority to this niceness from 1 to 19 [default: 10]
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        global = true,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10",
+        value_parser = clap::value_parser!(u8).range(1..=19),
+    )]
+    nice: Option<u8>,
+
+    /// Lower the disk priority (background mode on macOS and Windows)
+    #[arg(long, value_name = "CLASS", value_enum, global = true)]
+    ionice: Option<IoPriority>,
+
+    /// Refuse to run anything that writes to the filesystem besides the requested output, such as --dedupe or --sums update, and check every file is opened for reading only
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    assert_read_only: bool,
+
+    /// Compare JPEG, PNG, MP3 and FLAC files without their embedded metadata (EXIF, ID3, tags)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    strip_metadata: bool,
+
+    /// Reuse the hashes recorded in .filematch.sums files for unchanged files (trust), and also record the hashes of the files read (update)
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = SumsPolicy::Ignore, conflicts_with_all = ["decompress", "strip_metadata"])]
+    sums: SumsPolicy,
+
+    /// Read this share of the files with a recorded hash anyway, e.g. 5%, and report stale hashes
+    #[arg(long, value_name = "PERCENT", visible_alias = "verify-cache", value_parser = parse_percent)]
+    verify_sums: Option<f64>,
+
+    /// Before reporting or acting on matches, digest the files found in both directories with this second algorithm too, and fail if they disagree
+    #[arg(long, value_enum, value_name = "ALGORITHM")]
+    double_check: Option<Algorithm>,
+
+    /// At the end of the run, read this share of the matches again with a salted hash, e.g. 1%, and report those that differ
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    recheck: Option<f64>,
+
+    /// Run the comparison a second time, reading every file again, and report any difference between the two results
+    #[arg(long)]
+    repro_check: bool,
+
+    /// Cache the result of the comparison, and use the result of an identical comparison of the unchanged directories cached within --result-cache-ttl instead of comparing the files
+    #[arg(long)]
+    result_cache: bool,
+
+    /// How long the result of a comparison is cached, for identical comparisons of the unchanged directories
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_duration, requires = "result_cache")]
+    result_cache_ttl: Duration,
+
+    /// Leave out the files whose content has a BLAKE3 hash listed in this file, one per line
+    #[arg(long, value_name = "FILE")]
+    ignore_hashes: Vec<PathBuf>,
+
+    /// Leave out the files found in this reference index of known files, written by reference-index
+    #[arg(long, value_name = "INDEX", conflicts_with_all = ["decompress", "strip_metadata"])]
+    reference: Option<PathBuf>,
+
+    /// Only read the files changed between two Btrfs or ZFS snapshots of the same data, matching the others by path
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with_all = ["root1", "root2", "relative_to1", "relative_to2", "format", "counts", "rollup", "by_extension", "mtime_delta", "intersection_side"])]
+    snapshots: bool,
+}
+
+impl Cli {
+    /// Returns the --exec commands of the intersection, directory1 and directory2, each with a
+    /// description of its files.
+    fn exec_hooks(&self) -> impl Iterator<Item = (Option<&ExecTemplate>, &'static str)> {
+        [
+            (
+                self.exec_intersection.as_ref(),
+                "both in directory1 and directory2",
+            ),
+            (self.exec_unique_dir1.as_ref(), "unique to directory1"),
+            (self.exec_unique_dir2.as_ref(), "unique to directory2"),
+        ]
+        .into_iter()
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write a hashdeep file for a directory
+    Hashdeep(HashdeepArgs),
+    /// Record the hash of every file in .filematch.sums files, for comparisons with --sums trust
+    Precompute(PrecomputeArgs),
+    /// Print the hashes recorded in .filematch.sums files as b3sum checksum lines
+    Export(ExportArgs),
+    /// Write a manifest with the BLAKE3 hash, size and modification time of every file of a directory
+    Manifest(ManifestArgs),
+    /// Check a directory against a manifest, listing changed, missing and added files
+    Verify(VerifyArgs),
+    /// Record the BLAKE3 hashes of b3sum checksum files in .filematch.sums files
+    Import(ImportArgs),
+    /// Copy each distinct content of a directory once into a store of hash-named blobs
+    ExportCas(ExportCasArgs),
+    /// Restore the files of a manifest from a store written by export-cas
+    Restore(RestoreArgs),
+    /// Undo the operations recorded in an audit log, as far as possible
+    Undo(UndoArgs),
+    /// Apply a plan written by --write-plan, resuming where an earlier run stopped
+    Apply(ApplyArgs),
+    /// Compare two checksum manifests sorted by digest, streaming them
+    DiffManifests(DiffManifestsArgs),
+    /// Audit a directory against hashdeep files (matched, moved, new, missing)
+    Audit(AuditArgs),
+    /// Compare a directory against the grouped JSON output of a previous run, or two copies with their common ancestor
+    Compare(CompareArgs),
+    /// Check every file of a directory against known-good BLAKE3 hashes and list unknown files
+    CheckKnown(CheckKnownArgs),
+    /// Index a reference list of known files, such as the NSRL, for use with --reference
+    ReferenceIndex(ReferenceIndexArgs),
+    /// Verify or compare BagIt bags using their payload manifests
+    #[command(subcommand)]
+    Bag(BagCommand),
+    /// Create, run and resume long comparisons stored as jobs
+    #[command(subcommand)]
+    Job(JobCommand),
+    /// Report the size of each subdirectory next to the size of its distinct contents
+    Usage(UsageArgs),
+    /// List the files of a directory with the same content, and the space removing the copies would free
+    Dedupe(DedupeArgs),
+    /// Compare two mirrored directories periodically, printing JSON lines when they drift apart
+    Watch(WatchArgs),
+    /// Measure hashing and read speeds, and recommend thread settings for a directory
+    Selftest(SelftestArgs),
+    /// Measure comparison speed on generated files
+    #[cfg(feature = "testutil")]
+    Bench(BenchArgs),
+}
+
+impl Command {
+    /// Returns the name of the subcommand, as given on the command line.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Hashdeep(_) => "hashdeep",
+            Self::Precompute(_) => "precompute",
+            Self::Export(_) => "export",
+            Self::Manifest(_) => "manifest",
+            Self::Verify(_) => "verify",
+            Self::Import(_) => "import",
+            Self::ExportCas(_) => "export-cas",
+            Self::Restore(_) => "restore",
+            Self::Undo(_) => "undo",
+            Self::Apply(_) => "apply",
+            Self::DiffManifests(_) => "diff-manifests",
+            Self::Audit(_) => "audit",
+            Self::Compare(_) => "compare",
+            Self::CheckKnown(_) => "check-known",
+            Self::ReferenceIndex(_) => "reference-index",
+            Self::Bag(_) => "bag",
+            Self::Job(_) => "job",
+            Self::Usage(_) => "usage",
+            Self::Dedupe(_) => "dedupe",
+            Self::Watch(_) => "watch",
+            Self::Selftest(_) => "selftest",
+            #[cfg(feature = "testutil")]
+            Self::Bench(_) => "bench",
+        }
+    }
+
+    /// Returns whether the subcommand writes to the filesystem besides its requested output.
+    fn writes(&self) -> bool {
+        match self {
+            Self::Precompute(_)
+            | Self::Import(_)
+            | Self::ExportCas(_)
+            | Self::Restore(_)
+            | Self::Undo(_)
+            | Self::Apply(_)
+            | Self::ReferenceIndex(_)
+            | Self::Job(JobCommand::Create(_) | JobCommand::Run(_))
+            | Self::Selftest(_) => true,
+            #[cfg(feature = "testutil")]
+            Self::Bench(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the options of the directory walk, for subcommands scanning a directory.
+    fn walk_mut(&mut self) -> Option<&mut WalkArgs> {
+        match self {
+            Self::Hashdeep(args) => Some(&mut args.walk),
+            Self::Precompute(args) => Some(&mut args.walk),
+            Self::Export(args) => Some(&mut args.walk),
+            Self::Manifest(args) => Some(&mut args.walk),
+            Self::Verify(args) => Some(&mut args.walk),
+            Self::ExportCas(args) => Some(&mut args.walk),
+            Self::Audit(args) => Some(&mut args.walk),
+            Self::CheckKnown(args) => Some(&mut args.walk),
+            Self::Compare(args) => Some(&mut args.walk),
+            Self::Usage(args) => Some(&mut args.walk),
+            Self::Dedupe(args) => Some(&mut args.walk),
+            Self::Watch(args) => Some(&mut args.walk),
+            Self::Bag(BagCommand::Verify(args)) => Some(&mut args.walk),
+            Self::Job(JobCommand::Create(args)) => Some(&mut args.walk),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum BagCommand {
+    /// Verify the payload of a bag against its manifests
+    Verify(BagVerifyArgs),
+    /// Compare the payloads of two bags by the digests in their manifests
+    Compare(BagCompareArgs),
+}
+
+#[derive(Subcommand)]
+enum JobCommand {
+    /// Create a job comparing two directories, and print its id
+    Create(JobCreateArgs),
+    /// Run a job, resuming it where it stopped if it was interrupted
+    Run(JobRunArgs),
+    /// List the jobs and their state
+    List(JobsDirArgs),
+}
+
+/// The location of the jobs
+#[derive(Args)]
+struct JobsDirArgs {
+    /// Directory the jobs are stored in [default: ~/.local/state/filematch/jobs]
+    #[arg(long, value_name = "DIR")]
+    jobs_dir: Option<PathBuf>,
+}
+
+impl JobsDirArgs {
+    /// Returns the directory the jobs are stored in.
+    fn jobs_dir(&self) -> PathBuf {
+        self.jobs_dir
+            .clone()
+            .or_else(default_jobs_directory)
+            .unwrap_or_else(|| exit_with_error("cannot find the home directory; use --jobs-dir"))
+    }
+}
+
+#[derive(Args)]
+#[allow(clippy::struct_excessive_bools)]
+struct JobCreateArgs {
+    /// The first directory to compare
+    directory1: PathBuf,
+
+    /// The second directory to compare
+    directory2: PathBuf,
+
+    /// Additional directory treated as part of directory1 (may be repeated)
+    #[arg(long, value_name = "DIR")]
+    root1: Vec<PathBuf>,
+
+    /// Additional directory treated as part of directory2 (may be repeated)
+    #[arg(long, value_name = "DIR")]
+    root2: Vec<PathBuf>,
+
+    /// Sort output paths
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    sort: bool,
+
+    /// Display output paths relative to argument directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    relative: bool,
+
+    /// Compare even if a directory is inside another, leaving it out of the outer directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    allow_overlap: bool,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+
+    #[command(flatten)]
+    jobs: JobsDirArgs,
+}
+
+#[derive(Args)]
+struct JobRunArgs {
+    /// The id of the job
+    id: String,
+
+    /// Number of threads hashing file contents [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: Option<u16>,
+
+    /// Number of threads reading files [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads: Option<u16>,
+
+    /// Only compare files modified since the last finished run of the job started
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    incremental: bool,
+
+    #[command(flatten)]
+    jobs: JobsDirArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+/// Which paths to list for content found in both directories
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum IntersectionSide {
+    /// Every path from both directories
+    Both,
+    /// The first path in directory1 of each content
+    Dir1,
+    /// The first path in directory2 of each content
+    Dir2,
+    /// Every pair of a path in directory1 and a path in directory2 with the same content
+    Pairs,
+}
+
+/// The format of the comparison output
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Sections of paths, the default
+    Text,
+    /// An object of path lists, the same as --json
+    Json,
+    /// An array of contents, each with its hash, size, and files per directory
+    JsonGrouped,
+    /// The whole result with counts, skipped files and walk errors, as the library returns it
+    JsonResult,
+    /// The counts and the first files unique to each directory with a one-line text, for notifications such as mail
+    Summary,
+}
+
+/// A section of the comparison output
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Section {
+    /// Files both in directory1 and directory2
+    Intersection,
+    /// Files unique in directory1
+    Dir1,
+    /// Files unique in directory2
+    Dir2,
+    /// Files and directories left out of the comparison, with the reason (not shown by default)
+    Skipped,
+}
+
+/// Options controlling how the result of a comparison is displayed
+#[derive(Args, Clone)]
+struct OutputArgs {
+    /// Sections to display, in this order [default: intersection,dir1,dir2]
+    #[arg(long, value_name = "SECTIONS", value_enum, value_delimiter = ',')]
+    sections: Vec<Section>,
+
+    /// Display as json
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    json: bool,
+
+    /// Do not print the section headers
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "json")]
+    no_header: bool,
+
+    /// Prefix each path with its section (BOTH, ONLY1, ONLY2, SKIPPED) and a tab instead of printing sections
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "json")]
+    prefix: bool,
+
+    /// Separator of relative paths in the output, e.g. windows for results read on Windows [default: as found]
+    #[arg(long, value_name = "STYLE", value_enum)]
+    path_style: Option<PathStyle>,
+
+    /// Name of directory1 in the output instead of its path, e.g. NAS
+    #[arg(long, value_name = "NAME")]
+    label1: Option<String>,
+
+    /// Name of directory2 in the output instead of its path, e.g. USB-backup
+    #[arg(long, value_name = "NAME")]
+    label2: Option<String>,
+}
+
+impl OutputArgs {
+    /// Returns the sections to display, in order.
+    fn sections(&self) -> &[Section] {
+        if self.sections.is_empty() {
+            &[Section::Intersection, Section::Dir1, Section::Dir2]
+        } else {
+            &self.sections
+        }
+    }
+
+    /// Returns the label of a side, if given.
+    fn label(&self, side: Side) -> Option<&str> {
+        match side {
+            Side::Dir1 => self.label1.as_deref(),
+            Side::Dir2 => self.label2.as_deref(),
+        }
+    }
+
+    /// Returns the name of a side in headers and messages: its label in quotes, or else `name`.
+    fn side_name(&self, side: Side, name: &str) -> String {
+        self.label(side)
+            .map_or_else(|| name.to_string(), |label| format!("'{label}'"))
+    }
+
+    /// Formats a path for display in the requested path style.
+    fn display(&self, path: &Path) -> String {
+        match self.path_style {
+            Some(style) => style.display(path),
+            None => path.display().to_string(),
+        }
+    }
+}
+
+/// Options controlling the directory walk of subcommands scanning a single directory
+#[derive(Args)]
+struct WalkArgs {
+    /// Skip hidden files and directories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    skip_hidden: bool,
+
+    /// Do not honor a .filematchignore file at the root of the directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_ignore_file: bool,
+
+    /// Honor .ignore files in every directory, and .gitignore files and the global git excludes inside git repositories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    gitignore: bool,
+
+    /// Descend into symbolic links to directories, and junctions and mount points on Windows
+    #[arg(long, visible_alias = "follow-junctions", action = clap::ArgAction::SetTrue)]
+    follow_links: bool,
+
+    /// Number of threads reading files [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads: Option<u16>,
+
+    /// Leave out files smaller than this, e.g. 4K, before reading them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Leave out files larger than this, e.g. 10G, before reading them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Set by --assert-read-only
+    #[arg(skip)]
+    read_only: bool,
+}
+
+impl WalkArgs {
+    fn options(&self) -> CompareOptions {
+        let defaults = CompareOptions::default();
+        CompareOptions {
+            skip_hidden: self.skip_hidden,
+            use_ignore_file: !self.no_ignore_file,
+            respect_gitignore: self.gitignore,
+            follow_links: self.follow_links,
+            io_threads: self.io_threads.map_or(defaults.io_threads, usize::from),
+            min_size: self.min_size,
+            max_size: self.max_size,
+            read_only: self.read_only,
+            ..defaults
+        }
+    }
+}
+
+#[derive(Args)]
+struct PrecomputeArgs {
+    /// The directories to record the hashes of
+    #[arg(required = true)]
+    directories: Vec<PathBuf>,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+
+    /// Number of threads hashing file contents [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: Option<u16>,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// The directory to export the hashes of
+    directory: PathBuf,
+
+    /// Write to this file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct ManifestArgs {
+    /// The directory to hash
+    directory: PathBuf,
+
+    /// Write to this file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Write checksum lines of this algorithm, as sha256sum and md5sum do, instead of a manifest
+    #[arg(short, long, value_enum)]
+    algorithm: Option<Algorithm>,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// The directory to check
+    directory: PathBuf,
+
+    /// The manifest of the directory, written by filematch manifest, or a checksum file such as SHA256SUMS
+    manifest: PathBuf,
+
+    /// The algorithm of a checksum file [default: from its name, its tagged lines or the length of its digests]
+    #[arg(short, long, value_enum)]
+    algorithm: Option<Algorithm>,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct ExportCasArgs {
+    /// The directory to export
+    directory: PathBuf,
+
+    /// The store to copy the contents into, created if it does not exist
+    store: PathBuf,
+
+    /// Write the manifest mapping paths to hashes to this file [default: STORE/manifest.b3]
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Append every copy into the store to this audit log, as JSON lines
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    /// The store written by export-cas
+    store: PathBuf,
+
+    /// The manifest of the files to restore
+    manifest: PathBuf,
+
+    /// The directory to restore the files into, created if it does not exist
+    destination: PathBuf,
+
+    /// Append every restored file to this audit log, as JSON lines
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Check the paths of the restored files against the limits of this platform first, and restore nothing if one is too long
+    #[arg(long, value_enum, value_name = "PLATFORM")]
+    path_limit: Option<PathPlatform>,
+}
+
+#[derive(Args)]
+struct DiffManifestsArgs {
+    /// The first manifest, sorted by digest
+    manifest1: PathBuf,
+
+    /// The second manifest, sorted by digest
+    manifest2: PathBuf,
+
+    /// Display files whose digest is in both manifests
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "grouped")]
+    intersection: bool,
+
+    /// Display files whose digest is only in manifest1
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "grouped")]
+    dir1: bool,
+
+    /// Display files whose digest is only in manifest2
+    #[arg(long, action = clap::ArgAction::SetTrue, conflicts_with = "grouped")]
+    dir2: bool,
+
+    /// Display the files by section once both manifests are read, like comparing the directories with --relative, instead of each file as soon as its digest is compared
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    grouped: bool,
+
+    /// Print the whole grouped result as JSON, with the modified files, as --format json-result does
+    #[arg(long, action = clap::ArgAction::SetTrue, requires = "grouped", conflicts_with_all = ["json", "prefix", "sections"])]
+    json_result: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct UndoArgs {
+    /// The audit log of the operations to undo
+    log: PathBuf,
+}
+
+#[derive(Args)]
+struct ApplyArgs {
+    /// The plan to apply
+    #[arg(long, value_name = "FILE")]
+    plan: PathBuf,
+
+    /// The journal recording the steps applied [default: the plan with .journal appended]
+    #[arg(long, value_name = "FILE")]
+    journal: Option<PathBuf>,
+
+    /// Append every file removed, trashed or replaced by a link to this audit log, as JSON lines, for `filematch undo`
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ImportArgs {
+    /// The directory the paths in the checksum files are relative to
+    directory: PathBuf,
+
+    /// Checksum files with lines of a hash, two spaces, and a path
+    #[arg(required = true)]
+    checksums: Vec<PathBuf>,
+
+    /// Algorithm of the checksums. Sums files record BLAKE3 hashes, so only blake3 can be imported
+    #[arg(short = 'c', long, value_name = "ALGORITHM", value_enum, default_value_t = Algorithm::Blake3)]
+    algorithm: Algorithm,
+}
+
+#[derive(Args)]
+struct HashdeepArgs {
+    /// The directory to hash
+    directory: PathBuf,
+
+    /// Write to this file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Algorithms to record (md5, sha1, sha256)
+    #[arg(
+        short = 'c',
+        long,
+        value_name = "ALGORITHMS",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "md5,sha256"
+    )]
+    algorithms: Vec<Algorithm>,
+
+    /// Write each file as soon as it is hashed, in no particular order, so memory use stays the same on enormous trees
+    #[arg(long)]
+    stream: bool,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct AuditArgs {
+    /// The directory to audit
+    directory: PathBuf,
+
+    /// Hashdeep file with the known files (may be repeated)
+    #[arg(short = 'k', long = "known", value_name = "FILE", required = true)]
+    known: Vec<PathBuf>,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct CheckKnownArgs {
+    /// The directory to check
+    directory: PathBuf,
+
+    /// File listing known-good BLAKE3 hashes, one per line (may be repeated)
+    #[arg(short = 'k', long = "known", value_name = "FILE", required = true)]
+    known: Vec<PathBuf>,
+
+    /// Also list the known files
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    show_known: bool,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct ReferenceIndexArgs {
+    /// The reference list: an NSRL file, another CSV file with a header, or one digest per line
+    list: PathBuf,
+
+    /// The index file to write
+    output: PathBuf,
+
+    /// The algorithm of the digests to index
+    #[arg(short, long, value_enum, default_value_t = Algorithm::Sha1)]
+    algorithm: Algorithm,
+}
+
+#[derive(Args)]
+struct CompareArgs {
+    /// The directory to compare, or the first copy with --base
+    directory: PathBuf,
+
+    /// The second copy to compare with --base
+    #[arg(requires = "base")]
+    directory2: Option<PathBuf>,
+
+    /// Output of a previous run written with --format json-grouped
+    #[arg(long, value_name = "FILE", required_unless_present = "base")]
+    against: Option<PathBuf>,
+
+    /// Compare against the files of directory2 of the previous run instead of directory1
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    against_dir2: bool,
+
+    /// Classify the changes of the two copies since this common ancestor, matching files by path
+    #[arg(long, value_name = "ANCESTOR", requires = "directory2", conflicts_with_all = ["against", "against_dir2", "sort", "relative", "sections"])]
+    base: Option<PathBuf>,
+
+    /// Sort output paths
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    sort: bool,
+
+    /// Display output paths relative to the directory
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    relative: bool,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct UsageArgs {
+    /// The directory to report the usage of
+    directory: PathBuf,
+
+    /// Display as json
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    json: bool,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct DedupeArgs {
+    /// The directory to search for duplicates
+    directory: PathBuf,
+
+    /// Display as json
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    json: bool,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// The first directory to watch
+    directory1: PathBuf,
+
+    /// The second directory to watch
+    directory2: PathBuf,
+
+    /// Time between two comparisons
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = parse_duration)]
+    interval: Duration,
+
+    /// Time to wait before comparing again to confirm a change
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+    debounce: Duration,
+
+    /// Also print a summary of the current state this often
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    summary_every: Option<Duration>,
+
+    /// Also print the files that drifted apart or matched again since the previous change
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    changes: bool,
+
+    /// Reuse the hashes recorded in .filematch.sums files for unchanged files (trust), and also record the hashes of the files read (update)
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = SumsPolicy::Ignore)]
+    sums: SumsPolicy,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct BagVerifyArgs {
+    /// The root directory of the bag
+    bag: PathBuf,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+#[derive(Args)]
+struct BagCompareArgs {
+    /// The root directory of the first bag
+    bag1: PathBuf,
+
+    /// The root directory of the second bag
+    bag2: PathBuf,
+
+    /// Sort output paths
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    sort: bool,
+
+    /// Display output paths relative to the bag
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    relative: bool,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Args)]
+struct SelftestArgs {
+    /// A directory on the storage to measure, where a temporary test file is written
+    directory: PathBuf,
+
+    /// Size of the test file
+    #[arg(long, value_name = "SIZE", default_value = "256M", value_parser = parse_size)]
+    size: u64,
+
+    /// Number of bytes read from the test file at once
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = parse_chunk_size)]
+    chunk_size: usize,
+
+    /// Number of threads hashing at once [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: Option<u16>,
+}
+
+#[cfg(feature = "testutil")]
+#[derive(Args)]
+struct BenchArgs {
+    /// Directory to generate the files in, reused across runs [default: <temp dir>/filematch-bench]
+    #[arg(long, value_name = "DIR")]
+    dir: Option<PathBuf>,
+
+    /// Total size of the files in each of the two directories
+    #[arg(long, value_name = "SIZE", default_value = "1G", value_parser = parse_size)]
+    total: u64,
+
+    /// File sizes to generate, each getting an equal share of the total
+    #[arg(
+        long,
+        value_name = "SIZES",
+        value_delimiter = ',',
+        default_value = "1G,100M,10M,1M",
+        value_parser = parse_size
+    )]
+    sizes: Vec<u64>,
+
+    /// Seed of the generated file names and contents
+    #[arg(long, value_name = "N", default_value_t = 3_346_523)]
+    seed: u64,
+
+    /// Number of timed runs, after one warm up run
+    #[arg(long, value_name = "N", default_value_t = 3, value_parser = clap::value_parser!(u16).range(1..))]
+    runs: u16,
+
+    /// Number of threads reading files [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    io_threads: Option<u16>,
+
+    /// Number of threads hashing file contents [default: physical cores]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    hash_threads: Option<u16>,
+
+    /// Maximum number of files queued between the pipeline stages
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_in_flight: Option<u32>,
+}
+
+/// Parses a command template for --exec-intersection and --exec-unique-dir1/2.
+fn parse_exec(value: &str) -> Result<ExecTemplate, String> {
+    ExecTemplate::parse(value).map_err(|err| err.to_string())
+}
+
+/// Parses the URL of --notify-webhook, which must be an http or https URL.
+fn parse_webhook_url(value: &str) -> Result<String, String> {
+    if value.starts_with("http://") || value.starts_with("https://") {
+        Ok(value.to_string())
+    } else {
+        Err("the webhook must be an http or https URL".to_string())
+    }
+}
+
+/// Parses a percentage such as `5%` into a fraction from 0 to 1.
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent: f64 = value
+        .trim()
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("invalid percentage '{value}'"))?;
+    if (0.0..=100.0).contains(&percent) {
+        Ok(percent / 100.0)
+    } else {
+        Err("the percentage must be between 0 and 100".to_string())
+    }
+}
+
+/// Parses the size of the chunks read from files, which must not be zero.
+fn parse_chunk_size(value: &str) -> Result<usize, String> {
+    match parse_size(value)? {
+        0 => Err("the chunk size must not be zero".to_string()),
+        size => usize::try_from(size).map_err(|err| err.to_string()),
+    }
+}
+
+/// Prints an error message and exits with a failure code.
+fn exit_with_error(message: impl Display) -> ! {
+    eprintln!("Error: {message}");
+    std::process::exit(1);
+}
+
+/// Prints the error of a failed comparison, naming the file that failed, and exits with a
+/// failure code.
+fn exit_with_comparison_error(err: FileMatchError) -> ! {
+    match &err {
+        FileMatchError::Walk { path, source } => exit_with_error(format!(
+            "cannot read directory {}: {source}",
+            path.display()
+        )),
+        FileMatchError::Hash { path, source } => {
+            exit_with_error(format!("cannot read file {}: {source}", path.display()))
+        }
+        FileMatchError::Join { .. } | FileMatchError::Io(_) => exit_with_error(err),
+    }
+}
+
+/// Exits with an error unless the path is an existing directory.
+fn require_directory(path: &std::path::Path) {
+    if !path.is_dir() {
+        exit_with_error(format!(
+            "'{}' does not exist or is not a directory.",
+            path.display()
+        ));
+    }
+}
+
+/// Formats the root directories of one side for display, e.g. `'a', 'b'`.
+fn display_roots(roots: &[PathBuf]) -> String {
+    roots
+        .iter()
+        .map(|root| format!("'{}'", root.display()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn main() {
+    let mut args = Cli::parse_from(with_profiles(std::env::args_os()));
+    if args.assert_read_only {
+        refuse_writes(&mut args);
+    }
+    // Many reader threads may otherwise run into the default limit of 1024 open files
+    let open_file_limit = raise_open_file_limit();
+
+    // Before any thread is spawned, as Linux sets priorities per thread
+    if let Some(level) = args.nice {
+        set_nice(level)
+            .unwrap_or_else(|err| exit_with_error(format!("cannot lower the priority: {err}")));
+    }
+    if let Some(priority) = args.ionice {
+        set_io_priority(priority).unwrap_or_else(|err| {
+            exit_with_error(format!("cannot lower the disk priority: {err}"))
+        });
+    }
+
+    match &args.command {
+        Some(Command::Hashdeep(hashdeep_args)) => hashdeep(hashdeep_args),
+        Some(Command::Precompute(precompute_args)) => run_precompute(precompute_args),
+        Some(Command::Export(export_args)) => export(export_args),
+        Some(Command::Manifest(manifest_args)) => manifest(manifest_args),
+        Some(Command::Verify(verify_args)) => verify_against_manifest(verify_args),
+        Some(Command::Import(import_args)) => import(import_args),
+        Some(Command::ExportCas(export_cas_args)) => export_to_store(export_cas_args),
+        Some(Command::Restore(restore_args)) => restore(restore_args),
+        Some(Command::Undo(undo_args)) => undo_log(undo_args),
+        Some(Command::Apply(apply_args)) => apply(apply_args),
+        Some(Command::DiffManifests(diff_args)) => diff_manifests(diff_args),
+        Some(Command::Audit(audit_args)) => run_audit(audit_args),
+        Some(Command::Compare(compare_args)) => compare_previous(compare_args),
+        Some(Command::CheckKnown(check_args)) => run_check_known(check_args),
+        Some(Command::ReferenceIndex(index_args)) => reference_index(index_args),
+        Some(Command::Bag(BagCommand::Verify(verify_args))) => verify(verify_args),
+        Some(Command::Bag(BagCommand::Compare(compare_args))) => compare_bag_payloads(compare_args),
+        Some(Command::Job(JobCommand::Create(create_args))) => create(create_args),
+        Some(Command::Job(JobCommand::Run(run_args))) => run(run_args),
+        Some(Command::Job(JobCommand::List(list_args))) => list(list_args),
+        Some(Command::Usage(usage_args)) => usage(usage_args),
+        Some(Command::Dedupe(dedupe_args)) => find_duplicates_in(dedupe_args),
+        Some(Command::Watch(watch_args)) => watch_directories(watch_args),
+        Some(Command::Selftest(selftest_args)) => selftest(selftest_args),
+        #[cfg(feature = "testutil")]
+        Some(Command::Bench(bench_args)) => bench(bench_args),
+        None => compare(&args, open_file_limit),
+    }
+}
+
+/// Exits if the command line asks for anything writing to the filesystem besides its output,
+/// and makes the directory walks check that files are opened for reading only.
+fn refuse_writes(args: &mut Cli) {
+    let refused = match &args.command {
+        Some(Command::Watch(watch)) if watch.sums == SumsPolicy::Update => {
+            Some("--sums update".to_string())
+        }
+        Some(command) if command.writes() => Some(format!("filematch {}", command.name())),
+        Some(_) => None,
+        None if args.dedupe.is_some() && !args.dry_run && args.write_plan.is_none() => {
+            Some("--dedupe without --dry-run or --write-plan".to_string())
+        }
+        None if args.sums == SumsPolicy::Update => Some("--sums update".to_string()),
+        None if args.exec_hooks().any(|(template, _)| template.is_some()) => {
+            Some("--exec-intersection or --exec-unique-dir1/2 may run a command that".to_string())
+        }
+        None => None,
+    };
+    if let Some(refused) = refused {
+        exit_with_error(format!(
+            "{refused} writes to the filesystem, which --assert-read-only forbids"
+        ));
+    }
+
+    if let Some(walk) = args.command.as_mut().and_then(Command::walk_mut) {
+        walk.read_only = true;
+    }
+}
+
+/// Writes a hashdeep file for a directory.
+fn hashdeep(args: &HashdeepArgs) {
+    require_directory(&args.directory);
+
+    if args.stream {
+        let result = match &args.output {
+            Some(path) => {
+                File::create(path).and_then(|file| stream_hashdeep(BufWriter::new(file), args))
+            }
+            None => stream_hashdeep(io::stdout().lock(), args),
+        };
+        if let Err(err) = result {
+            exit_with_error(err);
+        }
+        return;
+    }
+
+    let files = digest_tree(&args.directory, &args.algorithms, &args.walk.options())
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    let result = match &args.output {
+        Some(path) => File::create(path)
+            .and_then(|file| write_hashdeep(BufWriter::new(file), &args.algorithms, &files)),
+        None => write_hashdeep(io::stdout().lock(), &args.algorithms, &files),
+    };
+    if let Err(err) = result {
+        exit_with_error(err);
+    }
+}
+
+/// Writes a hashdeep file line by line while the files are hashed.
+fn stream_hashdeep(mut writer: impl Write, args: &HashdeepArgs) -> io::Result<()> {
+    write_hashdeep_header(&mut writer, &args.algorithms)?;
+    for_each_digest(
+        &args.directory,
+        &args.algorithms,
+        &args.walk.options(),
+        |file| write_hashdeep_entry(&mut writer, &file),
+    )?;
+    writer.flush()
+}
+
+/// Brings the sums files of directories up to date.
+fn run_precompute(args: &PrecomputeArgs) {
+    for directory in &args.directories {
+        require_directory(directory);
+    }
+
+    let defaults = args.walk.options();
+    let options = CompareOptions {
+        hash_threads: args.hash_threads.map_or(defaults.hash_threads, usize::from),
+        ..defaults
+    };
+    let report = precompute(&args.directories, &options).unwrap_or_else(|err| exit_with_error(err));
+
+    println!("Recorded the hashes of {} files", report.files);
+    report_problems(&report.skipped, &report.walk_errors, &[]);
+}
+
+/// Writes the recorded hashes of a directory as checksum lines, warning about files without one.
+fn export(args: &ExportArgs) {
+    require_directory(&args.directory);
+
+    let exported = export_sums(&args.directory, &args.walk.options())
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    let result = match &args.output {
+        Some(path) => File::create(path)
+            .and_then(|file| write_checksum_lines(BufWriter::new(file), &exported.hashes)),
+        None => write_checksum_lines(io::stdout().lock(), &exported.hashes),
+    };
+    if let Err(err) = result {
+        exit_with_error(err);
+    }
+
+    if !exported.missing.is_empty() {
+        eprintln!(
+            "Warning: {} files have no up to date hash, run filematch precompute first:",
+            exported.missing.len()
+        );
+        for path in &exported.missing {
+            eprintln!("{}", path.display());
+        }
+    }
+}
+
+/// Writes the manifest of a directory.
+fn manifest(args: &ManifestArgs) {
+    require_directory(&args.directory);
+
+    let options = args.walk.options();
+    let write = |writer: &mut dyn Write| match args.algorithm {
+        Some(algorithm) => generate_checksum_list(&args.directory, algorithm, &options)
+            .and_then(|list| write_checksum_list(writer, &list)),
+        None => generate_manifest_with_options(&args.directory, &options)
+            .and_then(|manifest| write_manifest(writer, &manifest)),
+    };
+
+    let result = match &args.output {
+        Some(path) => File::create(path).and_then(|file| write(&mut BufWriter::new(file))),
+        None => write(&mut io::stdout().lock()),
+    };
+    if let Err(err) = result {
+        exit_with_error(err);
+    }
+}
+
+/// Guesses the algorithm of a checksum file from its name, such as `SHA256SUMS` or `photos.b3`.
+fn algorithm_from_file_name(path: &Path) -> Option<Algorithm> {
+    let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .map(|word| word.trim_end_matches("sums").trim_end_matches("sum"))
+        .find_map(Algorithm::from_name)
+}
+
+/// Checks a directory against its manifest or checksum file and exits with a failure code if
+/// they differ.
+fn verify_against_manifest(args: &VerifyArgs) {
+    require_directory(&args.directory);
+
+    let options = args.walk.options();
+    let contents = fs::read(&args.manifest)
+        .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", args.manifest.display())));
+    let (check, kind) = if contents.starts_with(MANIFEST_HEADER.as_bytes()) {
+        let manifest = read_manifest(contents.as_slice())
+            .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", args.manifest.display())));
+        let check = verify_manifest(&args.directory, &manifest, &options);
+        (check, "manifest")
+    } else {
+        let algorithm = args
+            .algorithm
+            .or_else(|| algorithm_from_file_name(&args.manifest));
+        let list = read_checksum_list(contents.as_slice(), algorithm)
+            .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", args.manifest.display())));
+        let check = verify_checksum_list(&args.directory, &list, &options);
+        (check, "checksum file")
+    };
+    let check = check.unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let sections = [
+        ("Changed files", &check.changed),
+        ("Missing files", &check.missing),
+        ("Added files", &check.added),
+    ];
+    for (title, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for path in paths {
+            let _ = writeln!(out, "{}", path.display());
+        }
+        let _ = writeln!(out);
+    }
+
+    if check.passed() {
+        let _ = writeln!(
+            out,
+            "Directory matches the {kind} ({} files)",
+            check.unchanged.len()
+        );
+    } else {
+        let _ = writeln!(out, "Directory does not match the {kind}");
+        std::process::exit(1);
+    }
+}
+
+/// Exports a directory into a content-addressed store and writes its manifest.
+fn export_to_store(args: &ExportCasArgs) {
+    require_directory(&args.directory);
+
+    let mut log = open_audit_log(args.audit_log.as_deref());
+    let export = export_cas(
+        &args.directory,
+        &args.store,
+        &args.walk.options(),
+        log.as_mut(),
+    )
+    .unwrap_or_else(|err| exit_with_error(err));
+    let manifest = args
+        .manifest
+        .clone()
+        .unwrap_or_else(|| args.store.join(MANIFEST_FILE_NAME));
+    File::create(&manifest)
+        .and_then(|file| write_checksum_lines(BufWriter::new(file), &export.files))
+        .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", manifest.display())));
+
+    eprintln!(
+        "Exported {} files: copied {} new contents ({} bytes), {} already stored",
+        export.files.len(),
+        export.copied,
+        export.copied_bytes,
+        export.existing
+    );
+    report_problems(&export.skipped, &export.walk_errors, &[]);
+}
+
+/// Restores the files of a manifest from a content-addressed store.
+fn restore(args: &RestoreArgs) {
+    require_directory(&args.store);
+
+    let manifest = File::open(&args.manifest)
+        .and_then(|file| read_checksum_lines(BufReader::new(file)))
+        .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", args.manifest.display())));
+    if let Some(platform) = args.path_limit {
+        let destination = std::path::absolute(&args.destination).unwrap_or_else(|err| {
+            exit_with_error(format!("{}: {err}", args.destination.display()))
+        });
+        let targets: Vec<PathBuf> = manifest
+            .iter()
+            .map(|(path, _)| destination.join(path))
+            .collect();
+        let long = long_paths(targets.iter().map(PathBuf::as_path), platform);
+        if !long.is_empty() {
+            eprintln!("Paths too long to restore:");
+            for path in &long {
+                let what = if path.name { "name" } else { "path" };
+                eprintln!(
+                    "{} ({what} of {}, at most {})",
+                    path.path.display(),
+                    path.length,
+                    path.limit
+                );
+            }
+            exit_with_error(format!(
+                "{} paths are too long, nothing was restored",
+                long.len()
+            ));
+        }
+    }
+    let mut log = open_audit_log(args.audit_log.as_deref());
+    let report = restore_cas(&args.store, &manifest, &args.destination, log.as_mut())
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let sections = [
+        ("Files missing from the store", &report.missing),
+        ("Files with a corrupt blob", &report.corrupt),
+    ];
+    for (title, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for path in paths {
+            let _ = writeln!(out, "{}", path.display());
+        }
+        let _ = writeln!(out);
+    }
+
+    let _ = writeln!(out, "Restored {} files", report.restored.len());
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
+
+/// Opens the audit log to append to, if any.
+fn open_audit_log(path: Option<&Path>) -> Option<AuditLog> {
+    path.map(|path| AuditLog::open(path).unwrap_or_else(|err| exit_with_error(err)))
+}
+
+/// Compares two sorted manifests, printing each file as soon as its category is known, or the
+/// grouped result once both manifests are read with --grouped.
+///
+/// Text lines start with `=` for files whose digest is in both manifests, `<` for files only in
+/// the first, and `>` for files only in the second.
+fn diff_manifests(args: &DiffManifestsArgs) {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", path.display())))
+    };
+    let output = &args.output;
+    let [name1, name2] =
+        [&args.manifest1, &args.manifest2].map(|path| format!("'{}'", path.display()));
+
+    if args.grouped {
+        let result = compare_sorted_manifests(open(&args.manifest1), open(&args.manifest2))
+            .unwrap_or_else(|err| exit_with_error(err));
+        if args.json_result {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&result.to_json()).unwrap()
+            );
+            return;
+        }
+        print_comparison(
+            result,
+            None,
+            &display_roots(std::slice::from_ref(&args.manifest1)),
+            &display_roots(std::slice::from_ref(&args.manifest2)),
+            output,
+        );
+        return;
+    }
+
+    let all = !args.intersection && !args.dir1 && !args.dir2;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let diff = diff_sorted_manifests(
+        open(&args.manifest1),
+        open(&args.manifest2),
+        |category, path, digest| {
+            let (shown, marker, name) = match category {
+                Category::Intersection => (args.intersection, '=', "intersection"),
+                Category::UniqueDir1 => (args.dir1, '<', "directory1"),
+                _ => (args.dir2, '>', "directory2"),
+            };
+            if !all && !shown {
+                return Ok(());
+            }
+            if output.json {
+                let line = json!({
+                    "category": name,
+                    "path": output.display(path),
+                    "hash": digest,
+                });
+                writeln!(out, "{line}")
+            } else {
+                writeln!(out, "{marker} {}", output.display(path))
+            }
+        },
+    )
+    .and_then(|diff| out.flush().map(|()| diff))
+    .unwrap_or_else(|err| exit_with_error(err));
+
+    eprintln!(
+        "{} files in both, {} only in {}, {} only in {}",
+        diff.intersection,
+        diff.unique1,
+        output.side_name(Side::Dir1, &name1),
+        diff.unique2,
+        output.side_name(Side::Dir2, &name2)
+    );
+}
+
+/// Undoes the operations recorded in an audit log.
+fn undo_log(args: &UndoArgs) {
+    let entries = File::open(&args.log)
+        .and_then(|file| read_audit_log(BufReader::new(file)))
+        .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", args.log.display())));
+    let undone = undo(&entries).unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let sections = [
+        ("Modified since, kept", &undone.modified),
+        ("Already gone", &undone.gone),
+        ("Not put back, another file took the path", &undone.occupied),
+    ];
+    for (title, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for path in paths {
+            let _ = writeln!(out, "{}", path.display());
+        }
+        let _ = writeln!(out);
+    }
+
+    let _ = writeln!(
+        out,
+        "Removed {} copied files, put back {} files",
+        undone.removed.len(),
+        undone.restored.len()
+    );
+}
+
+/// Applies a plan, recording the steps in its journal and the changes in the audit log, if
+/// given.
+fn apply(args: &ApplyArgs) {
+    let plan = Plan::read(&args.plan).unwrap_or_else(|err| exit_with_error(err));
+    let journal = args.journal.clone().unwrap_or_else(|| {
+        let mut journal = args.plan.clone().into_os_string();
+        journal.push(".journal");
+        PathBuf::from(journal)
+    });
+    let mut log = open_audit_log(args.audit_log.as_deref());
+    let applied =
+        apply_plan(&plan, Some(&journal), log.as_mut()).unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let sections = [
+        ("Left alone, changed since the plan", StepOutcome::Changed),
+        (
+            "Left alone, the same file as the original",
+            StepOutcome::SameFile,
+        ),
+        ("Already gone", StepOutcome::Gone),
+    ];
+    for (title, outcome) in sections {
+        let mut steps = applied.with_outcome(outcome).peekable();
+        if steps.peek().is_none() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for step in steps {
+            let _ = writeln!(out, "{}", step.path.display());
+        }
+        let _ = writeln!(out);
+    }
+
+    let done: Vec<&PlanStep> = applied.with_outcome(StepOutcome::Done).collect();
+    let bytes: u64 = done.iter().map(|step| step.size).sum();
+    let _ = write!(out, "Applied {} steps ({bytes} bytes)", done.len());
+    if applied.resumed > 0 {
+        let _ = write!(out, ", {} applied by an earlier run", applied.resumed);
+    }
+    let _ = writeln!(out);
+}
+
+/// Records the hashes of checksum files in the sums files of a directory.
+fn import(args: &ImportArgs) {
+    require_directory(&args.directory);
+    if args.algorithm != Algorithm::Blake3 {
+        exit_with_error(format!(
+            "{} checksums cannot be imported, as sums files record BLAKE3 hashes",
+            args.algorithm.name()
+        ));
+    }
+
+    let mut checksums = Vec::new();
+    for path in &args.checksums {
+        let lines = File::open(path)
+            .and_then(|file| read_checksum_lines(BufReader::new(file)))
+            .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", path.display())));
+        checksums.extend(lines);
+    }
+
+    let report =
+        import_sums(&args.directory, &checksums).unwrap_or_else(|err| exit_with_error(err));
+
+    println!("Recorded the hashes of {} files", report.imported);
+    if !report.missing.is_empty() {
+        eprintln!(
+            "Warning: {} listed files do not exist:",
+            report.missing.len()
+        );
+        for path in &report.missing {
+            eprintln!("{}", path.display());
+        }
+    }
+}
+
+/// Audits a directory against hashdeep files and exits with a failure code if the audit fails.
+fn run_audit(args: &AuditArgs) {
+    require_directory(&args.directory);
+
+    let mut known: Option<HashdeepFile> = None;
+    for path in &args.known {
+        let file = File::open(path)
+            .and_then(|file| read_hashdeep(BufReader::new(file)))
+            .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", path.display())));
+
+        match &mut known {
+            Some(known) if known.algorithms != file.algorithms => exit_with_error(format!(
+                "{}: all known files must use the same algorithms",
+                path.display()
+            )),
+            Some(known) => known.entries.extend(file.entries),
+            None => known = Some(file),
+        }
+    }
+    let known = known.expect("at least one known file is required by clap");
+
+    let report = audit(&args.directory, &known, &args.walk.options())
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let sections: [(&str, Vec<String>); 4] = [
+        (
+            "Matched files",
+            report
+                .matched
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect(),
+        ),
+        (
+            "Moved files",
+            report
+                .moved
+                .iter()
+                .map(|(path, known)| format!("{} (known as {})", path.display(), known.display()))
+                .collect(),
+        ),
+        (
+            "New files",
+            report.new.iter().map(|p| p.display().to_string()).collect(),
+        ),
+        (
+            "Missing known files",
+            report
+                .missing
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect(),
+        ),
+    ];
+    for (title, lines) in &sections {
+        if lines.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for line in lines {
+            let _ = writeln!(out, "{line}");
+        }
+        let _ = writeln!(out);
+    }
+
+    if report.passed() {
+        let _ = writeln!(out, "Audit passed");
+    } else {
+        let _ = writeln!(out, "Audit failed");
+        std::process::exit(1);
+    }
+}
+
+/// Lists the files of a directory with unknown contents and exits with a failure code if there
+/// are any.
+fn run_check_known(args: &CheckKnownArgs) {
+    require_directory(&args.directory);
+
+    let known: HashSet<_> = args
+        .known
+        .iter()
+        .flat_map(|path| load_hash_list(path).unwrap_or_else(|err| exit_with_error(err)))
+        .collect();
+    let check = check_known(&args.directory, &known, &args.walk.options())
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let mut sections = vec![("Unknown files", &check.unknown)];
+    if args.show_known {
+        sections.insert(0, ("Known files", &check.known));
+    }
+    for (title, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for path in paths {
+            let _ = writeln!(out, "{}", path.display());
+        }
+        let _ = writeln!(out);
+    }
+
+    let total = check.known.len() + check.unknown.len();
+    if check.passed() {
+        let _ = writeln!(out, "All {total} files are known");
+    } else {
+        let _ = writeln!(out, "{} of {total} files are unknown", check.unknown.len());
+        std::process::exit(1);
+    }
+}
+
+/// Builds a reference index from a reference list.
+fn reference_index(args: &ReferenceIndexArgs) {
+    let count = build_reference_index(&args.list, args.algorithm, &args.output)
+        .unwrap_or_else(|err| exit_with_error(err));
+    println!("Indexed {count} {} digests", args.algorithm.name());
+}
+
+/// Verifies a bag and exits with a failure code if it is invalid.
+fn verify(args: &BagVerifyArgs) {
+    require_directory(&args.bag);
+
+    let report =
+        verify_bag(&args.bag, &args.walk.options()).unwrap_or_else(|err| exit_with_error(err));
+
+    let mut out = io::stdout().lock();
+    let sections = [
+        ("Mismatched files", &report.mismatched),
+        ("Missing files", &report.missing),
+        ("Files not in every manifest", &report.unlisted),
+    ];
+    for (title, paths) in sections {
+        if paths.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{title}:");
+        for path in paths {
+            let _ = writeln!(out, "{}", path.display());
+        }
+        let _ = writeln!(out);
+    }
+
+    if report.passed() {
+        let _ = writeln!(out, "Bag is valid ({} files)", report.valid.len());
+    } else {
+        let _ = writeln!(out, "Bag is invalid");
+        std::process::exit(1);
+    }
+}
+
+/// Compares a directory against the output of a previous run and prints the result.
+fn compare_previous(args: &CompareArgs) {
+    require_directory(&args.directory);
+    if let (Some(base), Some(directory2)) = (&args.base, &args.directory2) {
+        compare_three_way(args, base, directory2);
+        return;
+    }
+    let Some(against) = &args.against else {
+        unreachable!("--against is required by clap without --base");
+    };
+
+    let side = if args.against_dir2 {
+        Side::Dir2
+    } else {
+        Side::Dir1
+    };
+    let previous = File::open(against)
+        .and_then(|file| read_grouped_json(BufReader::new(file), side))
+        .unwrap_or_else(|err| exit_with_error(format!("{}: {err}", against.display())));
+
+    let options = CompareOptions {
+        relative: args.relative,
+        sort: args.sort,
+        ..args.walk.options()
+    };
+    let result = compare_against(&args.directory, &previous, &options)
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    print_comparison(
+        result,
+        None,
+        &display_roots(std::slice::from_ref(&args.directory)),
+        &format!("the previous run '{}'", against.display()),
+        &args.output,
+    );
+}
+
+/// Compares two copies of a directory with their common ancestor and prints the changes.
+fn compare_three_way(args: &CompareArgs, base: &Path, directory2: &Path) {
+    for directory in [base, &args.directory, directory2] {
+        require_directory(directory);
+    }
+
+    let diff = three_way_diff(base, &args.directory, directory2, &args.walk.options())
+        .unwrap_or_else(|err| exit_with_error(err));
+    let (label1, label2) = (
+        args.output.side_name(
+            Side::Dir1,
+            &display_roots(std::slice::from_ref(&args.directory)),
+        ),
+        args.output
+            .side_name(Side::Dir2, &display_roots(&[directory2.to_path_buf()])),
+    );
+    let describe = |change: &ThreeWayChange| {
+        match change.status() {
+            ThreeWayStatus::ChangedInA | ThreeWayStatus::ChangedInBoth => {
+                change.change_a().map(|change| change.to_string())
+            }
+            ThreeWayStatus::ChangedInB => change.change_b().map(|change| change.to_string()),
+            ThreeWayStatus::Conflict => {
+                let side = |change: Option<Change>| {
+                    change.map_or("unchanged".to_string(), |change| change.to_string())
+                };
+                Some(format!(
+                    "{} in {label1}, {} in {label2}",
+                    side(change.change_a()),
+                    side(change.change_b())
+                ))
+            }
+        }
+        .unwrap_or_default()
+    };
+    let sections = [
+        (
+            ThreeWayStatus::ChangedInA,
+            "A",
+            "changed_in_a",
+            format!("Changed in {label1}:"),
+        ),
+        (
+            ThreeWayStatus::ChangedInB,
+            "B",
+            "changed_in_b",
+            format!("Changed in {label2}:"),
+        ),
+        (
+            ThreeWayStatus::ChangedInBoth,
+            "BOTH",
+            "changed_in_both",
+            "Changed the same way in both:".to_string(),
+        ),
+        (
+            ThreeWayStatus::Conflict,
+            "CONFLICT",
+            "conflicts",
+            "Conflicts:".to_string(),
+        ),
+    ];
+
+    if args.output.json {
+        let mut output = serde_json::Map::new();
+        for (status, _, key, _) in &sections {
+            let changes: Vec<serde_json::Value> = diff
+                .with_status(*status)
+                .map(|change| {
+                    let side = |change: Option<Change>| change.map(|change| change.to_string());
+                    json!({
+                        "path": args.output.display(&change.path),
+                        "directory1": side(change.change_a()),
+                        "directory2": side(change.change_b()),
+                    })
+                })
+                .collect();
+            output.insert((*key).to_string(), json!(changes));
+        }
+        output.insert("unchanged".to_string(), json!(diff.unchanged));
+        output.insert(
+            "errors".to_string(),
+            json!(diff
+                .errors
+                .iter()
+                .map(|path| args.output.display(path))
+                .collect::<Vec<_>>()),
+        );
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else {
+        let mut first = true;
+        for (status, prefix, _, header) in &sections {
+            if args.output.prefix {
+                // Print one line per path, tagged with its section
+                for change in diff.with_status(*status) {
+                    println!(
+                        "{prefix}\t{}\t{}",
+                        args.output.display(&change.path),
+                        describe(change)
+                    );
+                }
+                continue;
+            }
+            if !first {
+                println!();
+            }
+            first = false;
+            if !args.output.no_header {
+                println!("{header}");
+            }
+            for change in diff.with_status(*status) {
+                println!(
+                    "{} ({})",
+                    args.output.display(&change.path),
+                    describe(change)
+                );
+            }
+        }
+        if !diff.errors.is_empty() {
+            eprintln!(
+                "Warning: could not compare {} paths in all three directories:",
+                diff.errors.len()
+            );
+            for path in &diff.errors {
+                eprintln!("{}", path.display());
+            }
+        }
+    }
+}
+
+/// Creates a job and prints its id.
+fn create(args: &JobCreateArgs) {
+    let directories1: Vec<PathBuf> = std::iter::once(&args.directory1)
+        .chain(&args.root1)
+        .cloned()
+        .collect();
+    let directories2: Vec<PathBuf> = std::iter::once(&args.directory2)
+        .chain(&args.root2)
+        .cloned()
+        .collect();
+    for directory in directories1.iter().chain(&directories2) {
+        require_directory(directory);
+    }
+
+    let spec = JobSpec {
+        directories1,
+        directories2,
+        relative: args.relative,
+        sort: args.sort,
+        skip_hidden: args.walk.skip_hidden,
+        use_ignore_file: !args.walk.no_ignore_file,
+        respect_gitignore: args.walk.gitignore,
+        follow_links: args.walk.follow_links,
+        allow_overlapping_roots: args.allow_overlap,
+    };
+    let job = create_job(&args.jobs.jobs_dir(), &spec).unwrap_or_else(|err| exit_with_error(err));
+
+    println!("{}", job.id);
+}
+
+/// Runs or resumes a job and prints the result.
+fn run(args: &JobRunArgs) {
+    let jobs = args.jobs.jobs_dir();
+    let job = load_job(&jobs, &args.id).unwrap_or_else(|err| exit_with_error(err));
+    if job.state == JobState::Running {
+        eprintln!("Resuming job {}", job.id);
+    }
+
+    let defaults = CompareOptions::default();
+    let options = CompareOptions {
+        io_threads: args.io_threads.map_or(defaults.io_threads, usize::from),
+        hash_threads: args.hash_threads.map_or(defaults.hash_threads, usize::from),
+        modified_since: job.last_run.filter(|_| args.incremental),
+        ..defaults
+    };
+    let result = run_job(&jobs, &args.id, &options).unwrap_or_else(|err| exit_with_error(err));
+
+    print_comparison(
+        result,
+        None,
+        &display_roots(&job.spec.directories1),
+        &display_roots(&job.spec.directories2),
+        &args.output,
+    );
+}
+
+/// Lists the jobs with their state and directories.
+fn list(args: &JobsDirArgs) {
+    let jobs = list_jobs(&args.jobs_dir()).unwrap_or_else(|err| exit_with_error(err));
+
+    for job in jobs {
+        println!(
+            "{}\t{}\t{}\t{}",
+            job.id,
+            job.state.name(),
+            display_roots(&job.spec.directories1),
+            display_roots(&job.spec.directories2)
+        );
+    }
+}
+
+/// Prints the apparent and distinct size of each subdirectory of a directory.
+fn usage(args: &UsageArgs) {
+    require_directory(&args.directory);
+
+    let roots = std::slice::from_ref(&args.directory);
+    let options = CompareOptions {
+        include_intersection: false,
+        include_unique_dir1: false,
+        include_unique_dir2: false,
+        include_groups: true,
+        ..args.walk.options()
+    };
+    let result = compare_multiple_roots(roots, &[], &options)
+        .unwrap_or_else(|err| exit_with_comparison_error(err));
+    let groups = result.groups.unwrap_or_default();
+    let usages = usage_by_directory(&groups, roots);
+    let total = DirectoryUsage {
+        directory: args.directory.clone(),
+        files: usages.iter().map(|usage| usage.files).sum(),
+        bytes: usages.iter().map(|usage| usage.bytes).sum(),
+        distinct_bytes: groups.iter().map(|group| group.size).sum(),
+        exclusive_bytes: groups.iter().map(|group| group.size).sum(),
+    };
+
+    let display = |usage: &DirectoryUsage| {
+        let path = relative_path(usage.directory.clone(), Some(&args.directory));
+        if path.as_os_str().is_empty() {
+            ".".to_string()
+        } else {
+            path.display().to_string()
+        }
+    };
+
+    if args.json {
+        let entry = |usage: &DirectoryUsage| {
+            json!({
+                "directory": display(usage),
+                "files": usage.files,
+                "bytes": usage.bytes,
+                "distinct_bytes": usage.distinct_bytes,
+                "exclusive_bytes": usage.exclusive_bytes,
+            })
+        };
+        let value = json!({
+            "directories": usages.iter().map(entry).collect::<Vec<_>>(),
+            "total": entry(&total),
+        });
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+    } else {
+        println!(
+            "Usage of the directories in '{}':",
+            args.directory.display()
+        );
+        let lines = usages.iter().map(|usage| (display(usage), usage));
+        for (name, usage) in lines.chain([("total".to_string(), &total)]) {
+            println!(
+                "{name}: {} files, {} bytes, {} distinct ({}), {} found nowhere else ({})",
+                usage.files,
+                usage.bytes,
+                usage.distinct_bytes,
+                percentage(usage.distinct_bytes, usage.bytes),
+                usage.exclusive_bytes,
+                percentage(usage.exclusive_bytes, usage.bytes)
+            );
+        }
+    }
+    report_problems(&result.skipped, &result.walk_errors, &result.read_errors);
+}
+
+/// Prints the files of a directory with the same content, grouped, with the space they take up.
+fn find_duplicates_in(args: &DedupeArgs) {
+    require_directory(&args.directory);
+
+    let duplicates = find_duplicates(&args.directory, &args.walk.options())
+        .unwrap_or_else(|err| exit_with_comparison_error(err));
+    let display = |path: &PathBuf| {
+        relative_path(path.clone(), Some(&args.directory))
+            .display()
+            .to_string()
+    };
+
+    if args.json {
+        let groups = duplicates
+            .groups
+            .iter()
+            .map(|group| {
+                json!({
+                    "hash": group.hash.to_hex().as_str(),
+                    "size": group.size,
+                    "savings": group.savings(),
+                    "files": group.paths.iter().map(display).collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let value = json!({
+            "groups": groups,
+            "savings": duplicates.savings(),
+        });
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+    } else {
+        for group in &duplicates.groups {
+            println!(
+                "{} files of {} bytes, {} bytes in copies:",
+                group.paths.len(),
+                group.size,
+                group.savings()
+            );
+            for path in &group.paths {
+                println!("  {}", display(path));
+            }
+        }
+        let copies: usize = duplicates
+            .groups
+            .iter()
+            .map(|group| group.paths.len() - 1)
+            .sum();
+        println!(
+            "{} contents found more than once in '{}'; removing the {copies} copies would free {} bytes",
+            duplicates.groups.len(),
+            args.directory.display(),
+            duplicates.savings()
+        );
+    }
+    report_problems(
+        &duplicates.skipped,
+        &duplicates.walk_errors,
+        &duplicates.read_errors,
+    );
+}
+
+/// Watches two directories, printing each event as a JSON line until stdout is closed.
+fn watch_directories(args: &WatchArgs) {
+    require_directory(&args.directory1);
+    require_directory(&args.directory2);
+
+    let options = CompareOptions {
+        sums: args.sums,
+        ..args.walk.options()
+    };
+    let watch_options = WatchOptions {
+        interval: args.interval,
+        debounce: args.debounce,
+        summary_interval: args.summary_every,
+        changes: args.changes,
+    };
+
+    let result = watch(
+        std::slice::from_ref(&args.directory1),
+        std::slice::from_ref(&args.directory2),
+        &options,
+        &watch_options,
+        |event| {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            let mut line = json!({ "event": event.name(), "time": time });
+            let paths = |paths: &[PathBuf]| {
+                paths
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+            };
+            match event {
+                WatchEvent::Diverged {
+                    unique_dir1,
+                    unique_dir2,
+                } => {
+                    line["unique_dir1"] = json!(unique_dir1);
+                    line["unique_dir2"] = json!(unique_dir2);
+                }
+                WatchEvent::Reconverged => {}
+                WatchEvent::Changed {
+                    diverged_dir1,
+                    diverged_dir2,
+                    converged_dir1,
+                    converged_dir2,
+                } => {
+                    line["diverged_dir1"] = json!(paths(diverged_dir1));
+                    line["diverged_dir2"] = json!(paths(diverged_dir2));
+                    line["converged_dir1"] = json!(paths(converged_dir1));
+                    line["converged_dir2"] = json!(paths(converged_dir2));
+                }
+                WatchEvent::Summary {
+                    diverged,
+                    checks,
+                    unique_dir1,
+                    unique_dir2,
+                } => {
+                    line["diverged"] = json!(diverged);
+                    line["checks"] = json!(checks);
+                    line["unique_dir1"] = json!(unique_dir1);
+                    line["unique_dir2"] = json!(unique_dir2);
+                }
+            }
+
+            // Stop once nobody reads the events anymore
+            let mut out = io::stdout().lock();
+            writeln!(out, "{line}").and_then(|()| out.flush()).is_ok()
+        },
+    );
+    if let Err(err) = result {
+        exit_with_error(err);
+    }
+}
+
+/// Compares the payloads of two bags and prints the result.
+fn compare_bag_payloads(args: &BagCompareArgs) {
+    require_directory(&args.bag1);
+    require_directory(&args.bag2);
+
+    let options = CompareOptions {
+        relative: args.relative,
+        sort: args.sort,
+        ..CompareOptions::default()
+    };
+    let result =
+        compare_bags(&args.bag1, &args.bag2, &options).unwrap_or_else(|err| exit_with_error(err));
+
+    print_comparison(
+        result,
+        None,
+        &display_roots(std::slice::from_ref(&args.bag1)),
+        &display_roots(std::slice::from_ref(&args.bag2)),
+        &args.output,
+    );
+}
+
+/// Measures the hash and read speeds and prints the settings they suggest.
+fn selftest(args: &SelftestArgs) {
+    require_directory(&args.directory);
+
+    let hash_threads = args
+        .hash_threads
+        .map_or(CompareOptions::default().hash_threads, usize::from);
+    eprintln!(
+        "Measuring for a few seconds, writing {} bytes to '{}'...",
+        args.size,
+        args.directory.join(TEST_FILE_NAME).display()
+    );
+    let test = run_self_test(&args.directory, args.size, args.chunk_size, hash_threads)
+        .unwrap_or_else(|err| exit_with_error(err));
+
+    #[allow(clippy::cast_precision_loss)]
+    let mib = |bytes_per_second: f64| bytes_per_second / (1 << 20) as f64;
+    println!("Hashing, 1 thread: {:.1} MiB/s", mib(test.hash_single));
+    if test.hash_threads > 1 {
+        println!(
+            "Hashing, {} threads: {:.1} MiB/s",
+            test.hash_threads,
+            mib(test.hash_multi)
+        );
+    }
+    println!("Sequential reads: {:.1} MiB/s", mib(test.sequential_read));
+    println!(
+        "Random reads of {} bytes: {:.1} MiB/s",
+        args.chunk_size,
+        mib(test.random_read)
+    );
+
+    let recommendation = test.recommendation();
+    println!();
+    if recommendation.seeks_slowly {
+        println!("Random reads are slow, as on a hard disk: read one file at a time.");
+    } else {
+        println!("Random reads are fast, as on an SSD: read many files at once.");
+    }
+    let adaptive = if recommendation.adaptive_io {
+        " --adaptive-io"
+    } else {
+        ""
+    };
+    println!(
+        "Recommended options: --io-threads {} --hash-threads {}{adaptive}",
+        recommendation.io_threads, recommendation.hash_threads
+    );
+}
+
+/// Generates benchmark files and times comparing them.
+#[cfg(feature = "testutil")]
+fn bench(args: &BenchArgs) {
+    let base = args
+        .dir
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("filematch-bench"));
+    let dir_a = base.join("a");
+    let dir_b = base.join("b");
+
+    print!(
+        "Setting up benchmark environment in '{}'...",
+        base.display()
+    );
+    let _ = io::stdout().flush();
+    let files = setup_benchmark_files(&dir_a, &dir_b, args.total, &args.sizes, args.seed)
+        .unwrap_or_else(|err| exit_with_error(err));
+    println!(" DONE!");
+
+    let bytes: u64 = files
+        .both
+        .iter()
+        .chain(&files.only_a)
+        .chain(&files.only_b)
+        .filter_map(|path| path.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+    let defaults = CompareOptions::default();
+    let options = CompareOptions {
+        io_threads: args.io_threads.map_or(defaults.io_threads, usize::from),
+        hash_threads: args.hash_threads.map_or(defaults.hash_threads, usize::from),
+        max_in_flight: args.max_in_flight.map(|n| n as usize),
+        ..defaults
+    };
+    println!(
+        "Comparing {} files ({} MiB) with {} reader and {} hasher threads",
+        files.both.len() + files.only_a.len() + files.only_b.len(),
+        bytes >> 20,
+        options.io_threads,
+        options.hash_threads
+    );
+
+    let durations = run_benchmark(
+        &dir_a,
+        &dir_b,
+        &files,
+        usize::from(args.runs),
+        &options,
+        |run, elapsed| println!("Run #{run} took: {elapsed:.3?}"),
+    )
+    .unwrap_or_else(|err| exit_with_error(err));
+
+    let best = durations.into_iter().min().unwrap_or_default();
+    #[allow(clippy::cast_precision_loss)]
+    let throughput = bytes as f64 / (1 << 20) as f64 / best.as_secs_f64();
+    println!("\nBest run: {best:.3?} ({throughput:.1} MiB/s)");
+}
+
+/// How the result of a comparison is shown, as set on the command line.
+struct View {
+    /// Whether the intersection is shown.
+    intersection: bool,
+    /// Whether the files unique to directory1 are shown.
+    dir1: bool,
+    /// Whether the files unique to directory2 are shown.
+    dir2: bool,
+    /// Whether the files left out of the comparison are shown.
+    skipped: bool,
+    format: OutputFormat,
+    intersection_side: IntersectionSide,
+    /// Whether the paths are made relative after the comparison, because the output needs the
+    /// roots of the files.
+    late_relative: bool,
+}
+
+impl View {
+    /// Reads the view from the command line, exiting on conflicting options.
+    fn new(args: &Cli) -> Self {
+        // If no selective directory is set all are true
+        let all = !args.intersection && !args.dir1 && !args.dir2;
+        let shows = |section| args.output.sections().contains(&section);
+
+        let format = args.format.unwrap_or(if args.output.json {
+            OutputFormat::Json
+        } else {
+            OutputFormat::Text
+        });
+        let late_relative = format == OutputFormat::JsonGrouped
+            || args.rollup
+            || args.mtime_delta
+            || args.keep.is_some()
+            || args.dedupe.is_some()
+            || args.double_check.is_some()
+            || args.recheck.is_some()
+            || args.exec_hooks().any(|(template, _)| template.is_some());
+
+        if (args.offset > 0 || args.limit.is_some())
+            && !(args.counts || args.keep.is_some() || format == OutputFormat::JsonGrouped)
+        {
+            exit_with_error(
+                "--offset and --limit only apply to --format json-grouped, --counts and --keep",
+            );
+        }
+
+        let intersection_side = match args.intersection_side {
+            IntersectionSide::Dir1 | IntersectionSide::Dir2 if args.mtime_delta => {
+                exit_with_error("--mtime-delta lists pairs and cannot show a single side")
+            }
+            _ if args.mtime_delta => IntersectionSide::Pairs,
+            side => side,
+        };
+
+        Self {
+            intersection: (all || args.intersection) && shows(Section::Intersection),
+            dir1: (all || args.dir1) && shows(Section::Dir1),
+            dir2: (all || args.dir2) && shows(Section::Dir2),
+            skipped: shows(Section::Skipped),
+            format,
+            intersection_side,
+            late_relative,
+        }
+    }
+
+    /// Returns whether the files of a group are in a shown section.
+    fn shows(&self, group: &HashGroup) -> bool {
+        match (group.dir1.is_empty(), group.dir2.is_empty()) {
+            (false, false) => self.intersection,
+            (false, true) => self.dir1,
+            _ => self.dir2,
+        }
+    }
+}
+
+/// Compares two directories and prints the result.
+///
+/// # Parameters
+/// - `args`: The command line arguments.
+/// - `open_file_limit`: The limit of open files of the process, if any.
+fn compare(args: &Cli, open_file_limit: Option<u64>) {
+    let (Some(directory1), Some(directory2)) = (&args.directory1, &args.directory2) else {
+        unreachable!("both directories are required by clap");
+    };
+
+    let mut roots1: Vec<PathBuf> = std::iter::once(directory1.clone())
+        .chain(args.root1.iter().cloned())
+        .collect();
+    let mut roots2: Vec<PathBuf> = std::iter::once(directory2.clone())
+        .chain(args.root2.iter().cloned())
+        .collect();
+
+    // Validate directories
+    for root in roots1.iter().chain(&roots2) {
+        require_directory(root);
+    }
+    if matches!((args.min_size, args.max_size), (Some(min), Some(max)) if min > max) {
+        exit_with_error("--min-size is larger than --max-size, no file would be compared");
+    }
+
+    // Show the canonical directories in the headers too
+    if args.absolute {
+        for root in roots1.iter_mut().chain(&mut roots2) {
+            *root = fs::canonicalize(&*root).unwrap_or_else(|err| exit_with_error(err));
+        }
+    }
+
+    // Overlapping directories would list the files of the nested one twice
+    let roots: Vec<PathBuf> = roots1.iter().chain(&roots2).cloned().collect();
+    let overlaps = find_overlapping_roots(&roots).unwrap_or_else(|err| exit_with_error(err));
+    if let Some(overlap) = overlaps.first() {
+        if !args.allow_overlap {
+            exit_with_error(format!(
+                "{overlap}. Pass --allow-overlap to compare anyway."
+            ));
+        }
+    }
+    for overlap in &overlaps {
+        match &overlap.nested {
+            Some(_) => eprintln!(
+                "Warning: {overlap}, its files are not compared as part of '{}'",
+                overlap.outer.display()
+            ),
+            None => eprintln!("Warning: {overlap}"),
+        }
+    }
+
+    let view = View::new(args);
+
+    // Network filesystems get larger reads, fewer readers and retries unless set explicitly
+    let filesystems = |roots: &[PathBuf]| -> Vec<Option<Filesystem>> {
+        roots.iter().map(|root| detect_filesystem(root)).collect()
+    };
+    let (filesystems1, filesystems2) = (filesystems(&roots1), filesystems(&roots2));
+    let on_network = |filesystems: &[Option<Filesystem>]| {
+        !args.no_network_tuning && filesystems.iter().flatten().any(|fs| fs.network)
+    };
+    let network = [on_network(&filesystems1), on_network(&filesystems2)];
+
+    // Hard disks are read one file at a time in large reads unless set explicitly; a side on
+    // several kinds of storage is read as suits the slowest
+    let storage = |roots: &[PathBuf]| {
+        args.storage.or_else(|| {
+            let kinds: Vec<_> = roots
+                .iter()
+                .filter_map(|root| detect_storage(root))
+                .collect();
+            kinds
+                .iter()
+                .copied()
+                .max_by_key(|kind| *kind == StorageKind::Hdd)
+        })
+    };
+    let storage = [storage(&roots1), storage(&roots2)];
+    let side_readers = |threads: Option<u16>, network: bool, storage: Option<StorageKind>| {
+        let explicit = args.io_threads.or(args.threads);
+        let hdd = storage == Some(StorageKind::Hdd);
+        threads
+            .or(explicit.filter(|_| hdd))
+            .map(usize::from)
+            .or_else(|| {
+                (network && explicit.is_none() && !args.adaptive_io).then_some(NETWORK_IO_THREADS)
+            })
+            .or(hdd.then_some(HDD_IO_THREADS))
+    };
+
+    let defaults = CompareOptions::default();
+    let options = CompareOptions {
+        // Grouped output and rollups need the roots of the files, so the paths are made
+        // relative later
+        relative: args.relative && !view.late_relative,
+        absolute: args.absolute,
+        relative_to1: args.relative_to1.clone().filter(|_| !view.late_relative),
+        relative_to2: args.relative_to2.clone().filter(|_| !view.late_relative),
+        skip_hidden: args.skip_hidden,
+        use_ignore_file: !args.no_ignore_file,
+        respect_gitignore: args.gitignore,
+        walk_filter: None,
+        modified_since: args.since,
+        min_size: args.min_size,
+        max_size: args.max_size,
+        follow_links: args.follow_links,
+        allow_overlapping_roots: args.allow_overlap,
+        sort: args.sort,
+        include_intersection: view.intersection
+            || args.exec_intersection.is_some()
+            || args.notify_webhook.is_some(),
+        include_unique_dir1: view.dir1
+            || args.exec_unique_dir1.is_some()
+            || args.notify_webhook.is_some(),
+        include_unique_dir2: view.dir2
+            || args.exec_unique_dir2.is_some()
+            || args.notify_webhook.is_some(),
+        include_groups: args.counts
+            || args.by_extension
+            || view.late_relative
+            || (view.intersection && view.intersection_side != IntersectionSide::Both),
+        include_excluded: view.skipped,
+        io_threads: args.io_threads.or(args.threads).map_or_else(
+            || {
+                if args.adaptive_io {
+                    defaults.io_threads * 4
+                } else {
+                    defaults.io_threads
+                }
+            },
+            usize::from,
+        ),
+        io_threads1: side_readers(args.io_threads1, network[0], storage[0]),
+        io_threads2: side_readers(args.io_threads2, network[1], storage[1]),
+        storage,
+        adaptive_io: args.adaptive_io,
+        hash_threads: args
+            .hash_threads
+            .or(args.threads)
+            .map_or(defaults.hash_threads, usize::from),
+        retries: args.retries.unwrap_or(if network.contains(&true) {
+            NETWORK_RETRIES
+        } else {
+            defaults.retries
+        }),
+        retry_delay: args.retry_delay,
+        locked: args.locked,
+        lock_timeout: args.lock_timeout,
+        retry_unstable: args.retry_unstable,
+        fail_on_walk_error: args.fail_on_walk_error,
+        read_errors: if args.ignore_errors {
+            ErrorPolicy::CollectAndContinue
+        } else {
+            ErrorPolicy::Strict
+        },
+        decompress: args.decompress.clone(),
+        strip_metadata: args.strip_metadata,
+        transform: None,
+        sums: args.sums,
+        journal: None,
+        verify_sums: args.verify_sums.unwrap_or_default(),
+        reference_set: args.reference.as_deref().map(|path| {
+            Arc::new(ReferenceSet::open(path).unwrap_or_else(|err| exit_with_error(err)))
+        }),
+        ignore_hashes: Arc::new(
+            args.ignore_hashes
+                .iter()
+                .flat_map(|path| load_hash_list(path).unwrap_or_else(|err| exit_with_error(err)))
+                .collect(),
+        ),
+        chunk_size: args.chunk_size.unwrap_or(if network.contains(&true) {
+            NETWORK_CHUNK_SIZE
+        } else if storage.contains(&Some(StorageKind::Hdd)) {
+            HDD_CHUNK_SIZE
+        } else {
+            defaults.chunk_size
+        }),
+        max_open_files: args
+            .max_open_files
+            .map(|n| n as usize)
+            .or_else(|| open_file_limit.map(max_open_files)),
+        max_in_flight: args.max_in_flight.map(|n| n as usize),
+        prefilter_sizes: args.prefilter_sizes,
+        collapse_case_aliases: args.collapse_case_aliases,
+        collapse_link_aliases: args.collapse_link_aliases,
+        read_only: args.assert_read_only,
+        range_hash: args.range_hash.map(|min_size| {
+            let range = args.range_size.unwrap_or(RangeHash::DEFAULT_RANGE);
+            RangeHash {
+                min_size,
+                head: range,
+                tail: range,
+                verify: args.verify_ranges,
+            }
+        }),
+        slow_files: args.slow_report.unwrap_or(0),
+        on_progress: None,
+    };
+
+    if args.summary {
+        if !args.profile.is_empty() {
+            eprintln!("Profiles: {}", args.profile.join(", "));
+        }
+        print_tuning(
+            [(&roots1, &filesystems1), (&roots2, &filesystems2)],
+            &options,
+            open_file_limit,
+        );
+    }
+    let started = Instant::now();
+    let result = if args.snapshots {
+        match compare_snapshots(&roots1[0], &roots2[0], &options) {
+            Ok(comparison) => {
+                if args.summary {
+                    eprintln!(
+                        "Matched {} files unchanged between the {} snapshots without reading them",
+                        comparison.unchanged,
+                        comparison.kind.name()
+                    );
+                }
+                Ok(comparison.result)
+            }
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+                eprintln!("Warning: {err}, comparing every file");
+                compare_multiple_roots(&roots1, &roots2, &options)
+            }
+            Err(err) => Err(err.into()),
+        }
+    } else if let Some(cache) = result_cache(args) {
+        compare_cached(&roots1, &roots2, &options, &cache).map(|cached| {
+            if cached.from_cache && args.summary {
+                eprintln!(
+                    "Took the result of an identical comparison of the unchanged directories \
+                     from the cache in '{}'",
+                    cache.directory.display()
+                );
+            }
+            cached.result
+        })
+    } else {
+        compare_multiple_roots(&roots1, &roots2, &options)
+    }
+    .unwrap_or_else(|err| exit_with_comparison_error(err));
+    if args.summary {
+        eprintln!("Compared in {:.1?}", started.elapsed());
+    }
+    // A file missing from an incomplete side could be the only other copy of a duplicate
+    let side_name = |side: Side| {
+        let roots = match side {
+            Side::Dir1 => &roots1,
+            Side::Dir2 => &roots2,
+        };
+        args.output.side_name(side, &display_roots(roots))
+    };
+    if let (Some(_), Some(side)) = (args.dedupe, result.incomplete.first()) {
+        exit_with_error(format!(
+            "{} became unavailable during the comparison, refusing to remove duplicates based on \
+             incomplete results",
+            side_name(*side)
+        ));
+    }
+    let mut not_reproducible = false;
+    if args.repro_check {
+        let differences = reproducibility_check(&roots1, &roots2, &result, &options)
+            .unwrap_or_else(|err| exit_with_comparison_error(err));
+        not_reproducible = !differences.is_empty();
+        if not_reproducible {
+            eprintln!(
+                "Reproducibility check failed: a second run reading every file again gave a \
+                 different result:"
+            );
+        } else {
+            eprintln!("Reproducibility check passed: a second run reading every file again gave the same result");
+        }
+        for difference in &differences {
+            let name = difference.category.name();
+            for path in &difference.only_first {
+                eprintln!("{name}: only in the first run: {}", path.display());
+            }
+            for path in &difference.only_second {
+                eprintln!("{name}: only in the second run: {}", path.display());
+            }
+        }
+    }
+    if args.verify_sums.is_some() {
+        let check = &result.sums_check;
+        eprintln!(
+            "Checked {} recorded hashes, {} stale ({:.2}%)",
+            check.checked,
+            check.stale.len(),
+            check.error_rate() * 100.0
+        );
+        for path in &check.stale {
+            eprintln!("{}", path.display());
+        }
+    }
+    if let Some(algorithm) = args.double_check {
+        let groups = result.groups.as_deref().unwrap_or_default();
+        let checked =
+            double_check(groups, algorithm, &options).unwrap_or_else(|err| exit_with_error(err));
+        if args.summary {
+            eprintln!("Double-checked {checked} files with {}", algorithm.name());
+        }
+    }
+    if let Some(fraction) = args.recheck {
+        let groups = result.groups.as_deref().unwrap_or_default();
+        let recheck =
+            recheck_matches(groups, fraction, &options).unwrap_or_else(|err| exit_with_error(err));
+        eprintln!(
+            "Rechecked {} matches, {} differ on a second read",
+            recheck.checked,
+            recheck.mismatched.len()
+        );
+        for [path1, path2] in &recheck.mismatched {
+            eprintln!("{}  {}", path1.display(), path2.display());
+        }
+    }
+
+    // The commands and the webhook get all sections, and those only computed for them are not
+    // shown
+    let exec_failed = run_exec_hooks(args, &result);
+    if let Some(url) = &args.notify_webhook {
+        let labels = summary_labels(&args.output, [&roots1, &roots2]);
+        let summary = summarize(&result, [&labels[0], &labels[1]], SUMMARY_PATHS);
+        if let Err(err) = post_webhook(url, &summary) {
+            eprintln!("Warning: cannot notify {url}: {err}");
+        }
+    }
+    let incomplete = result.incomplete.clone();
+    print_result(args, &view, result, [&roots1, &roots2], options.range_hash);
+    for side in &incomplete {
+        eprintln!(
+            "Error: {} became unavailable during the comparison, so the results are incomplete",
+            side_name(*side)
+        );
+    }
+    if exec_failed || not_reproducible || !incomplete.is_empty() {
+        std::process::exit(1);
+    }
+}
 
-// Compares files between two directories by hash
-#[derive(Parser)]
-#[command(
-    version = env!("CARGO_PKG_VERSION"),
-    author = env!("CARGO_PKG_AUTHORS"),
-    about = "Compares files between two directories by hash",
-    after_help = "If none of --intersection, --dir1, or --dir2 are set, then all are displayed",
-)]
-#[allow(clippy::struct_excessive_bools)]
-struct Cli {
-    /// The first directory to compare
-    #[arg(required = true)]
-    directory1: PathBuf,
+/// Prints the result of a comparison as set on the command line.
+///
+/// # Parameters
+/// - `view`: How the result is shown.
+/// - `result`: The result, with the sections only computed for the commands and the webhook.
+/// - `roots`: The roots of directory1 and directory2.
+/// - `range_hash`: How large files were matched, to warn about matches that are only probable.
+fn print_result(
+    args: &Cli,
+    view: &View,
+    mut result: ComparisonResult,
+    roots: [&[PathBuf]; 2],
+    range_hash: Option<RangeHash>,
+) {
+    for (paths, shown) in [
+        (&mut result.intersection, view.intersection),
+        (&mut result.unique_dir1, view.dir1),
+        (&mut result.unique_dir2, view.dir2),
+    ] {
+        if !shown {
+            *paths = None;
+        }
+    }
 
-    /// The second directory to compare
-    #[arg(required = true)]
-    directory2: PathBuf,
+    if args.counts {
+        let groups = result.groups.take().unwrap_or_default();
+        let shared = groups.into_iter().filter(HashGroup::is_shared).collect();
+        print_counts(&paginate(shared, args.offset, args.limit), &args.output);
+        return;
+    }
+    // Outputs reading paths as found make them relative themselves
+    let base = |relative_to: &Option<PathBuf>, roots: &[PathBuf]| match relative_to {
+        Some(base) => vec![base.clone()],
+        None if args.relative => roots.to_vec(),
+        None => Vec::new(),
+    };
+    let (bases1, bases2) = (
+        base(&args.relative_to1, roots[0]),
+        base(&args.relative_to2, roots[1]),
+    );
+    let bases: [&[PathBuf]; 2] = [&bases1, &bases2];
+    if view.late_relative {
+        make_relative(&mut result, bases);
+    }
+    print_warnings(args, &result, range_hash);
 
-    /// Sort output paths
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    sort: bool,
+    let labels = [
+        args.output.side_name(Side::Dir1, &display_roots(roots[0])),
+        args.output.side_name(Side::Dir2, &display_roots(roots[1])),
+    ];
+    if view.format == OutputFormat::Summary {
+        let labels = summary_labels(&args.output, roots);
+        let summary = summarize(&result, [&labels[0], &labels[1]], SUMMARY_PATHS);
+        println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+    } else if view.format == OutputFormat::JsonResult {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&result.to_json()).unwrap()
+        );
+    } else if view.format == OutputFormat::JsonGrouped {
+        let groups = result.groups.take().unwrap_or_default();
+        let groups = groups.into_iter().filter(|group| view.shows(group));
+        let groups = paginate(groups.collect(), args.offset, args.limit);
+        print_grouped(groups.into_iter(), bases, &args.output);
+    } else if let Some(rules) = &args.keep {
+        let groups = result.groups.take().unwrap_or_default();
+        let groups = groups.iter().filter(|group| view.shows(group));
+        let decisions: Vec<KeepDecision> = groups.filter_map(|group| rules.decide(group)).collect();
+        let decisions = paginate(decisions, args.offset, args.limit);
+        print_keepers(&decisions, bases, &args.output);
+    } else if let Some(action) = args.dedupe {
+        let groups = result.groups.take().unwrap_or_default();
+        remove_duplicates(args, action, &groups, roots[1], bases);
+    } else if args.rollup {
+        let groups = result.groups.take().unwrap_or_default();
+        let rollups = [
+            view.dir1
+                .then(|| rollup_by_directory(&groups, Side::Dir1, roots[0])),
+            view.dir2
+                .then(|| rollup_by_directory(&groups, Side::Dir2, roots[1])),
+        ];
+        print_rollup(rollups, bases, &labels, &args.output);
+    } else if args.by_extension {
+        let groups = result.groups.take().unwrap_or_default();
+        let rollups = [
+            view.dir1.then(|| rollup_by_extension(&groups, Side::Dir1)),
+            view.dir2.then(|| rollup_by_extension(&groups, Side::Dir2)),
+        ];
+        print_extension_rollup(rollups, &labels, &args.output);
+    } else {
+        print_sections(args, view, result, roots, bases);
+        return;
+    }
+    report_problems(&result.skipped, &result.walk_errors, &result.read_errors);
+}
 
-    /// Skip hidden files and directories
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    skip_hidden: bool,
+/// Makes the paths of a result relative to the bases of their side, for outputs that needed
+/// the roots of the files.
+fn make_relative(result: &mut ComparisonResult, bases: [&[PathBuf]; 2]) {
+    let all_bases = [bases[0], bases[1]].concat();
+    for file in result
+        .skipped
+        .iter_mut()
+        .chain(result.excluded.iter_mut().flatten())
+    {
+        file.path = relative_path(file.path.clone(), matching_root(&file.path, &all_bases));
+    }
+    for err in &mut result.walk_errors {
+        err.path = relative_path(err.path.clone(), matching_root(&err.path, &all_bases));
+    }
+    for path in result.errors.iter_mut().chain(&mut result.ranged_matches) {
+        *path = relative_path(path.clone(), matching_root(path, &all_bases));
+    }
+    for file in &mut result.slow_files {
+        let bases = match file.side {
+            Side::Dir1 => bases[0],
+            Side::Dir2 => bases[1],
+        };
+        file.path = relative_path(file.path.clone(), matching_root(&file.path, bases));
+    }
+    for (paths, bases) in [
+        (&mut result.intersection, &all_bases[..]),
+        (&mut result.unique_dir1, bases[0]),
+        (&mut result.unique_dir2, bases[1]),
+    ] {
+        for path in paths.iter_mut().flatten() {
+            *path = relative_path(path.clone(), matching_root(path, bases));
+        }
+    }
+}
 
-    /// Display output paths relative to argument directory
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    relative: bool,
+/// Prints the matches that are only probable and the slowest files to stderr, as requested.
+fn print_warnings(args: &Cli, result: &ComparisonResult, range_hash: Option<RangeHash>) {
+    if let Some(ranges) = range_hash.filter(|_| !result.ranged_matches.is_empty()) {
+        eprintln!(
+            "Warning: {} files were matched by their size and their first and last {} bytes \
+             only; these matches are probable, not certain, run with --verify-ranges to read \
+             them in full:",
+            result.ranged_matches.len(),
+            ranges.head
+        );
+        for path in &result.ranged_matches {
+            eprintln!("{}", path.display());
+        }
+    }
 
-    /// Display as json
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    json: bool,
+    if args.slow_report.is_some() {
+        eprintln!("Slowest files to hash:");
+        for file in &result.slow_files {
+            let side = match file.side {
+                Side::Dir1 => "directory1",
+                Side::Dir2 => "directory2",
+            };
+            let side = args.output.label(file.side).unwrap_or(side);
+            eprintln!("{:>10.3?}  {side}  {}", file.duration, file.path.display());
+        }
+    }
+}
 
-    /// Display files both in directory1 and directory2
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    intersection: bool,
+/// Removes the copies in directory2 of files also in directory1 as set by --dedupe, or writes
+/// a plan to do so with --write-plan.
+fn remove_duplicates(
+    args: &Cli,
+    action: DedupeAction,
+    groups: &[HashGroup],
+    roots2: &[PathBuf],
+    bases: [&[PathBuf]; 2],
+) {
+    let duplicates = cross_side_duplicates(groups);
+    if let Some(path) = &args.write_plan {
+        let plan = dedupe_plan(&duplicates, action, roots2, args.trash_dir.as_deref())
+            .and_then(|plan| plan.write(path).map(|()| plan))
+            .unwrap_or_else(|err| exit_with_error(err));
+        println!(
+            "Wrote a plan of {} steps to {}, apply it with `filematch apply --plan {}`",
+            plan.steps.len(),
+            path.display(),
+            path.display()
+        );
+        return;
+    }
+    let mut log = open_audit_log(args.audit_log.as_deref());
+    let outcome = dedupe(
+        &duplicates,
+        action,
+        roots2,
+        args.trash_dir.as_deref(),
+        args.dry_run,
+        log.as_mut(),
+    )
+    .unwrap_or_else(|err| exit_with_error(err));
+    print_dedupe(&outcome, action, args.dry_run, bases, &args.output);
+}
 
-    /// Display unique files in dir1
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    dir1: bool,
+/// Prints the intersection and the files unique to each side, the intersection listing the
+/// side or the pairs set by --intersection-side.
+fn print_sections(
+    args: &Cli,
+    view: &View,
+    mut result: ComparisonResult,
+    roots: [&[PathBuf]; 2],
+    bases: [&[PathBuf]; 2],
+) {
+    // Replace the mixed intersection by the requested paths of each shared content
+    let mut pairs = None;
+    if let Some(groups) = result.groups.take() {
+        let shared = groups.into_iter().filter(HashGroup::is_shared);
+        match view.intersection_side {
+            IntersectionSide::Both => {}
+            IntersectionSide::Dir1 => {
+                result.intersection = Some(shared.map(|group| group.dir1[0].clone()).collect());
+            }
+            IntersectionSide::Dir2 => {
+                result.intersection = Some(shared.map(|group| group.dir2[0].clone()).collect());
+            }
+            IntersectionSide::Pairs => {
+                pairs = Some(shared_pairs(shared, bases, args.mtime_delta));
+            }
+        }
+        if args.sort {
+            if let Some(paths) = &mut result.intersection {
+                paths.sort();
+            }
+            if let Some(pairs) = &mut pairs {
+                pairs.sort();
+            }
+        }
+    }
 
-    /// Display unique files in dir2
-    #[arg(long, action = clap::ArgAction::SetTrue)]
-    dir2: bool,
+    let output = OutputArgs {
+        json: view.format == OutputFormat::Json,
+        ..args.output.clone()
+    };
+    print_comparison(
+        result,
+        pairs,
+        &display_roots(roots[0]),
+        &display_roots(roots[1]),
+        &output,
+    );
 }
 
-fn main() {
-    let args = Cli::parse();
+/// Returns every pair of a file in directory1 and a file in directory2 with the same content.
+///
+/// # Parameters
+/// - `shared`: The groups of files found on both sides.
+/// - `bases`: The bases the paths of each side are made relative to.
+/// - `with_mtime_delta`: Whether to add the difference of the modification times of the files.
+fn shared_pairs(
+    shared: impl Iterator<Item = HashGroup>,
+    bases: [&[PathBuf]; 2],
+    with_mtime_delta: bool,
+) -> Vec<Pair> {
+    shared
+        .flat_map(|group| {
+            group
+                .dir1
+                .iter()
+                .flat_map(|path1| {
+                    group.dir2.iter().map(move |path2| Pair {
+                        mtime_delta: with_mtime_delta.then(|| mtime_delta(path1, path2)),
+                        path1: relative_path(path1.clone(), matching_root(path1, bases[0])),
+                        path2: relative_path(path2.clone(), matching_root(path2, bases[1])),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
 
-    // Validate directories
-    if !args.directory1.is_dir() {
-        eprintln!(
-            "Error: '{}' does not exist or is not a directory.",
-            args.directory1.display()
+/// Returns the names of directory1 and directory2 in summaries: their labels, or else their
+/// roots.
+fn summary_labels(output: &OutputArgs, roots: [&[PathBuf]; 2]) -> [String; 2] {
+    [(Side::Dir1, roots[0]), (Side::Dir2, roots[1])].map(|(side, roots)| {
+        output.label(side).map_or_else(
+            || {
+                roots
+                    .iter()
+                    .map(|root| root.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+            ToString::to_string,
+        )
+    })
+}
+
+/// Runs the --exec commands for the files of their sections, and reports the files they failed
+/// for.
+///
+/// # Returns
+/// True if a command failed.
+fn run_exec_hooks(args: &Cli, result: &ComparisonResult) -> bool {
+    let sections = [
+        &result.intersection,
+        &result.unique_dir1,
+        &result.unique_dir2,
+    ];
+    let mut failed = false;
+    for ((template, label), paths) in args.exec_hooks().zip(sections) {
+        let (Some(template), Some(paths)) = (template, paths) else {
+            continue;
+        };
+        let report = run_for_each(template, paths, usize::from(args.exec_jobs))
+            .unwrap_or_else(|err| exit_with_error(err));
+        if !report.failed.is_empty() {
+            eprintln!(
+                "Warning: the command failed for {} of the {} files {label}:",
+                report.failed.len(),
+                paths.len()
+            );
+            for failure in &report.failed {
+                eprintln!("{} ({})", failure.path.display(), failure.message);
+            }
+            failed = true;
+        }
+    }
+    failed
+}
+
+/// Formats a part of a whole as a percentage.
+fn percentage(part: u64, whole: u64) -> String {
+    if whole == 0 {
+        return "-".to_string();
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let percentage = part as f64 * 100.0 / whole as f64;
+    format!("{percentage:.1}%")
+}
+
+/// Prints the rollups of both sides as text or JSON.
+///
+/// # Parameters
+/// - `rollups`: The rollups of each side, if requested.
+/// - `bases`: The roots of each side to display the directories relative to, or empty slices
+///   to display them unchanged.
+/// - `labels`: The names of the sides in the headers.
+/// - `output`: The output options.
+fn print_rollup(
+    rollups: [Option<Vec<DirectoryRollup>>; 2],
+    bases: [&[PathBuf]; 2],
+    labels: &[String; 2],
+    output: &OutputArgs,
+) {
+    let display = |rollup: &DirectoryRollup, bases: &[PathBuf]| {
+        let path = relative_path(
+            rollup.directory.clone(),
+            matching_root(&rollup.directory, bases),
         );
-        std::process::exit(1);
+        if path.as_os_str().is_empty() {
+            ".".to_string()
+        } else {
+            output.display(&path)
+        }
+    };
+
+    if output.json {
+        let mut result = serde_json::Map::new();
+        for (key, rollups, bases) in [
+            ("directory1", &rollups[0], bases[0]),
+            ("directory2", &rollups[1], bases[1]),
+        ] {
+            if let Some(rollups) = rollups {
+                result.insert(
+                    key.to_string(),
+                    json!(rollups
+                        .iter()
+                        .map(|rollup| json!({
+                            "directory": display(rollup, bases),
+                            "files": rollup.files,
+                            "matched_files": rollup.matched_files,
+                            "bytes": rollup.bytes,
+                            "matched_bytes": rollup.matched_bytes,
+                        }))
+                        .collect::<Vec<_>>()),
+                );
+            }
+        }
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+        return;
+    }
+
+    let mut first = true;
+    for ((rollups, bases), label) in rollups.iter().zip(bases).zip(labels) {
+        let Some(rollups) = rollups else {
+            continue;
+        };
+        if !first {
+            println!();
+        }
+        first = false;
+
+        if !output.no_header {
+            println!("Matched share of the directories in {label}:");
+        }
+        for rollup in rollups {
+            println!(
+                "{}: {} of {} files ({}), {} of {} bytes ({})",
+                display(rollup, bases),
+                rollup.matched_files,
+                rollup.files,
+                percentage(rollup.matched_files as u64, rollup.files as u64),
+                rollup.matched_bytes,
+                rollup.bytes,
+                percentage(rollup.matched_bytes, rollup.bytes)
+            );
+        }
+    }
+}
+
+/// Prints the rollups by extension of both sides as text or JSON.
+///
+/// # Parameters
+/// - `rollups`: The rollups of each side, if requested.
+/// - `labels`: The names of the sides in the headers.
+/// - `output`: The output options.
+fn print_extension_rollup(
+    rollups: [Option<Vec<ExtensionRollup>>; 2],
+    labels: &[String; 2],
+    output: &OutputArgs,
+) {
+    if output.json {
+        let mut result = serde_json::Map::new();
+        for (key, rollups) in [("directory1", &rollups[0]), ("directory2", &rollups[1])] {
+            if let Some(rollups) = rollups {
+                result.insert(
+                    key.to_string(),
+                    json!(rollups
+                        .iter()
+                        .map(|rollup| json!({
+                            "extension": rollup.extension,
+                            "files": rollup.files,
+                            "matched_files": rollup.matched_files,
+                            "bytes": rollup.bytes,
+                            "matched_bytes": rollup.matched_bytes,
+                        }))
+                        .collect::<Vec<_>>()),
+                );
+            }
+        }
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+        return;
+    }
+
+    let mut first = true;
+    for (rollups, label) in rollups.iter().zip(labels) {
+        let Some(rollups) = rollups else {
+            continue;
+        };
+        if !first {
+            println!();
+        }
+        first = false;
+
+        if !output.no_header {
+            println!("Matched share of the file types in {label}:");
+        }
+        for rollup in rollups {
+            println!(
+                "{}: {} of {} files ({}), {} of {} bytes ({})",
+                rollup.extension.as_deref().unwrap_or("(none)"),
+                rollup.matched_files,
+                rollup.files,
+                percentage(rollup.matched_files as u64, rollup.files as u64),
+                rollup.matched_bytes,
+                rollup.bytes,
+                percentage(rollup.matched_bytes, rollup.bytes)
+            );
+        }
     }
-    if !args.directory2.is_dir() {
+}
+
+/// Prints each content as a JSON object with its hash, size, and files per directory.
+///
+/// # Parameters
+/// - `groups`: The contents to print, with absolute paths.
+/// - `bases`: The roots of each directory to display the paths relative to, or empty slices to
+///   display them unchanged.
+/// - `output`: The output options.
+fn print_grouped(
+    groups: impl Iterator<Item = HashGroup>,
+    bases: [&[PathBuf]; 2],
+    output: &OutputArgs,
+) {
+    let files = |paths: &[PathBuf], bases: &[PathBuf], total_size: &mut u64| {
+        paths
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok();
+                let size = metadata.as_ref().map(fs::Metadata::len);
+                let modified = metadata
+                    .and_then(|metadata| metadata.modified().ok())
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs());
+                *total_size += size.unwrap_or_default();
+
+                let display = relative_path(path.clone(), matching_root(path, bases));
+                json!({
+                    "path": output.display(&display),
+                    "size": size,
+                    "modified": modified,
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let groups: Vec<_> = groups
+        .map(|group| {
+            let mut total_size = 0;
+            let dir1 = files(&group.dir1, bases[0], &mut total_size);
+            let dir2 = files(&group.dir2, bases[1], &mut total_size);
+            json!({
+                "hash": group.hash.to_hex().to_string(),
+                "size": group.size,
+                "total_size": total_size,
+                "directory1": dir1,
+                "directory2": dir2,
+            })
+        })
+        .collect();
+
+    println!("{}", serde_json::to_string_pretty(&groups).unwrap());
+}
+
+/// Prints the file to keep of each content and its duplicates as text or JSON.
+///
+/// In text, each keeper is followed by its duplicates indented by a tab, and the contents are
+/// separated by blank lines. With `--prefix`, every line is tagged with `KEEP` or `DUPLICATE`.
+fn print_keepers(decisions: &[KeepDecision], bases: [&[PathBuf]; 2], output: &OutputArgs) {
+    let display = |file: &GroupFile| {
+        let bases = match file.side {
+            Side::Dir1 => bases[0],
+            Side::Dir2 => bases[1],
+        };
+        output.display(&relative_path(
+            file.path.clone(),
+            matching_root(&file.path, bases),
+        ))
+    };
+
+    if output.json {
+        let decisions: Vec<_> = decisions
+            .iter()
+            .map(|decision| {
+                json!({
+                    "keep": display(&decision.keep),
+                    "duplicates": decision.duplicates.iter().map(display).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&decisions).unwrap());
+        return;
+    }
+
+    for (index, decision) in decisions.iter().enumerate() {
+        if output.prefix {
+            println!("KEEP\t{}", display(&decision.keep));
+            for duplicate in &decision.duplicates {
+                println!("DUPLICATE\t{}", display(duplicate));
+            }
+            continue;
+        }
+        if index > 0 {
+            println!();
+        }
+        println!("{}", display(&decision.keep));
+        for duplicate in &decision.duplicates {
+            println!("\t{}", display(duplicate));
+        }
+    }
+}
+
+/// Prints the files of directory2 dealt with by --dedupe, each with its file in directory1.
+fn print_dedupe(
+    outcome: &Dedupe,
+    action: DedupeAction,
+    dry_run: bool,
+    bases: [&[PathBuf]; 2],
+    output: &OutputArgs,
+) {
+    let display = |path: &Path, bases: &[PathBuf]| {
+        output.display(&relative_path(
+            path.to_path_buf(),
+            matching_root(path, bases),
+        ))
+    };
+    let columns = |duplicate: &Duplicate| {
+        format!(
+            "{}\t{}",
+            display(&duplicate.path, bases[1]),
+            display(&duplicate.original, bases[0])
+        )
+    };
+
+    if output.json {
+        let files = |duplicates: &[Duplicate]| {
+            duplicates
+                .iter()
+                .map(|duplicate| {
+                    json!({
+                        "path": display(&duplicate.path, bases[1]),
+                        "original": display(&duplicate.original, bases[0]),
+                        "size": duplicate.size,
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let result = json!({
+            "action": action.name(),
+            "dry_run": dry_run,
+            "done": files(&outcome.done),
+            "changed": files(&outcome.changed),
+            "same_file": files(&outcome.same_file),
+            "bytes": outcome.bytes(),
+        });
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+        return;
+    }
+
+    let (verb, how) = match action {
+        DedupeAction::Remove => (["Removed", "Would remove"], ""),
+        DedupeAction::Trash => (["Moved", "Would move"], " to the trash"),
+        DedupeAction::Hardlink => (["Replaced", "Would replace"], " with hard links"),
+        DedupeAction::Symlink => (["Replaced", "Would replace"], " with symbolic links"),
+    };
+    let verb = verb[usize::from(dry_run)];
+    let sections = [
+        (
+            format!(
+                "{verb} {} files of directory2{how} ({} bytes):",
+                outcome.done.len(),
+                outcome.bytes()
+            ),
+            &outcome.done,
+        ),
+        (
+            "Left alone, changed since the comparison:".to_string(),
+            &outcome.changed,
+        ),
+        (
+            "Left alone, the same file as in directory1:".to_string(),
+            &outcome.same_file,
+        ),
+    ];
+    for (index, (title, duplicates)) in sections.iter().enumerate() {
+        if index > 0 && duplicates.is_empty() {
+            continue;
+        }
+        if index > 0 {
+            println!();
+        }
+        if !output.no_header {
+            println!("{title}");
+        }
+        for duplicate in *duplicates {
+            println!("{}", columns(duplicate));
+        }
+    }
+}
+
+/// A pair of files with the same content, one from each directory.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Pair {
+    path1: PathBuf,
+    path2: PathBuf,
+    /// Seconds the file in directory2 was modified after the one in directory1, if requested.
+    /// The inner value is None if a modification time cannot be read.
+    mtime_delta: Option<Option<i64>>,
+}
+
+impl Pair {
+    /// Returns the paths, and the modification time difference if requested, separated by tabs.
+    fn columns(&self, output: &OutputArgs) -> String {
+        let paths = format!(
+            "{}\t{}",
+            output.display(&self.path1),
+            output.display(&self.path2)
+        );
+        match self.mtime_delta {
+            Some(delta) => format!("{paths}\t{}", format_delta(delta)),
+            None => paths,
+        }
+    }
+}
+
+/// Returns the seconds the second file was modified after the first, negative if before.
+fn mtime_delta(path1: &Path, path2: &Path) -> Option<i64> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let (modified1, modified2) = (modified(path1).ok()?, modified(path2).ok()?);
+
+    match modified2.duration_since(modified1) {
+        Ok(later) => i64::try_from(later.as_secs()).ok(),
+        Err(earlier) => i64::try_from(earlier.duration().as_secs())
+            .ok()
+            .map(|secs| -secs),
+    }
+}
+
+/// Formats a signed number of seconds as days, hours, minutes and seconds, e.g. `+1d 2h 0m 5s`.
+fn format_delta(delta: Option<i64>) -> String {
+    let Some(delta) = delta else {
+        return "unknown".to_string();
+    };
+    let sign = if delta < 0 { '-' } else { '+' };
+    let secs = delta.unsigned_abs();
+
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{sign}{days}d {hours}h {minutes}m {}s", secs % 60)
+    } else if hours > 0 {
+        format!("{sign}{hours}h {minutes}m {}s", secs % 60)
+    } else if minutes > 0 {
+        format!("{sign}{minutes}m {}s", secs % 60)
+    } else {
+        format!("{sign}{secs}s")
+    }
+}
+
+/// Prints each content with its number of copies on each side.
+fn print_counts(groups: &[HashGroup], output: &OutputArgs) {
+    if output.json {
+        let counts: Vec<_> = groups
+            .iter()
+            .map(|group| {
+                json!({
+                    "hash": group.hash.to_hex().to_string(),
+                    "directory1": group.dir1.len(),
+                    "directory2": group.dir2.len(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&counts).unwrap());
+    } else {
+        for group in groups {
+            println!(
+                "{}: {} in dir1, {} in dir2",
+                group.hash,
+                group.dir1.len(),
+                group.dir2.len()
+            );
+        }
+    }
+}
+
+/// Returns the contents selected by --offset and --limit.
+///
+/// If contents are left after the selection, the offset of the next page is printed on stderr,
+/// so tools can page through a large result without parsing it.
+fn paginate<T>(items: Vec<T>, offset: usize, limit: Option<usize>) -> Vec<T> {
+    let total = items.len();
+    let page: Vec<T> = items
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    let next = offset.saturating_add(page.len());
+    if next < total {
         eprintln!(
-            "Error: '{}' does not exist or is not a directory.",
-            args.directory2.display()
+            "Displayed {} of {total} contents, continue with --offset {next}",
+            page.len()
         );
-        std::process::exit(1);
     }
+    page
+}
 
-    // If no selective directory is set all are true
-    let all = !args.intersection && !args.dir1 && !args.dir2;
-    let intersection = all || args.intersection;
-    let dir1 = all || args.dir1;
-    let dir2 = all || args.dir2;
-
-    // Call the function to compare directories
-    let (intersection_paths, unique_dir1_paths, unique_dir2_paths) = compare_two_directories(
-        &args.directory1,
-        &args.directory2,
-        args.relative,
-        args.skip_hidden,
-        args.sort,
-        intersection,
-        dir1,
-        dir2,
-    );
+/// Prints the result of a comparison as text or JSON.
+///
+/// Only the groups present in `result` and requested by `output` are printed, in the requested
+/// order. If `pairs` is given, it is printed as the
+/// intersection, with the two paths of a pair and their modification time difference separated
+/// by tabs.
+fn print_comparison(
+    result: ComparisonResult,
+    pairs: Option<Vec<Pair>>,
+    label1: &str,
+    label2: &str,
+    output: &OutputArgs,
+) {
+    let ComparisonResult {
+        intersection: intersection_paths,
+        unique_dir1: unique_dir1_paths,
+        unique_dir2: unique_dir2_paths,
+        skipped,
+        excluded,
+        walk_errors,
+        read_errors,
+        ..
+    } = result;
+    // Leave out the sections that are not requested
+    let shows = |section| output.sections().contains(&section);
+    let intersection_paths = intersection_paths.filter(|_| shows(Section::Intersection));
+    let pairs = pairs.filter(|_| shows(Section::Intersection));
+    let unique_dir1_paths = unique_dir1_paths.filter(|_| shows(Section::Dir1));
+    let unique_dir2_paths = unique_dir2_paths.filter(|_| shows(Section::Dir2));
+    let label1 = &output.side_name(Side::Dir1, label1);
+    let label2 = &output.side_name(Side::Dir2, label2);
 
-    if args.json {
+    if output.json {
         // Create a JSON value with string representations of the paths.
         let mut result = serde_json::Map::new();
 
-        if intersection {
+        if output.label1.is_some() || output.label2.is_some() {
+            result.insert(
+                "labels".to_string(),
+                json!({
+                    "directory1": output.label1,
+                    "directory2": output.label2,
+                }),
+            );
+        }
+
+        if let Some(pairs) = &pairs {
+            result.insert(
+                "intersection".to_string(),
+                json!(pairs
+                    .iter()
+                    .map(|pair| {
+                        let mut pair_json = vec![
+                            json!(output.display(&pair.path1)),
+                            json!(output.display(&pair.path2)),
+                        ];
+                        if let Some(delta) = pair.mtime_delta {
+                            pair_json.push(json!(delta));
+                        }
+                        pair_json
+                    })
+                    .collect::<Vec<_>>()),
+            );
+        } else if let Some(intersection_paths) = intersection_paths {
             result.insert(
                 "intersection".to_string(),
                 json!(intersection_paths
-                    .unwrap()
                     .iter()
-                    .map(|p| p.display().to_string())
+                    .map(|p| output.display(p))
                     .collect::<Vec<_>>()),
             );
         }
 
-        if dir1 {
+        if let Some(unique_dir1_paths) = unique_dir1_paths {
             result.insert(
                 "directory1".to_string(),
                 json!(unique_dir1_paths
-                    .unwrap()
                     .iter()
-                    .map(|p| p.display().to_string())
+                    .map(|p| output.display(p))
                     .collect::<Vec<_>>()),
             );
         }
 
-        if dir2 {
+        if let Some(unique_dir2_paths) = unique_dir2_paths {
             result.insert(
                 "directory2".to_string(),
                 json!(unique_dir2_paths
-                    .unwrap()
                     .iter()
-                    .map(|p| p.display().to_string())
+                    .map(|p| output.display(p))
                     .collect::<Vec<_>>()),
             );
         }
 
-        println!("{}", serde_json::to_string_pretty(&result).unwrap());
-    } else {
-        // Print the results
-        if intersection {
-            println!(
-                "Files both in '{}' and '{}':",
-                args.directory1.display(),
-                args.directory2.display()
+        if !skipped.is_empty() {
+            result.insert(
+                "skipped".to_string(),
+                json!(skipped
+                    .iter()
+                    .map(|file| json!({
+                        "path": output.display(&file.path),
+                        "reason": file.reason.to_string(),
+                    }))
+                    .collect::<Vec<_>>()),
             );
-            for path in intersection_paths.unwrap() {
-                println!("{}", path.display());
-            }
         }
 
-        if intersection && dir1 {
-            println!();
+        if let Some(excluded) = excluded.filter(|_| shows(Section::Skipped)) {
+            result.insert(
+                "excluded".to_string(),
+                json!(excluded
+                    .iter()
+                    .map(|file| json!({
+                        "path": output.display(&file.path),
+                        "reason": file.reason.to_string(),
+                    }))
+                    .collect::<Vec<_>>()),
+            );
         }
 
-        if dir1 {
-            println!("Files unique in '{}':", &args.directory1.display());
-            for path in unique_dir1_paths.unwrap() {
-                println!("{}", path.display());
-            }
+        if !walk_errors.is_empty() {
+            result.insert(
+                "walk_errors".to_string(),
+                json!(walk_errors
+                    .iter()
+                    .map(|err| json!({
+                        "path": output.display(&err.path),
+                        "message": err.message,
+                    }))
+                    .collect::<Vec<_>>()),
+            );
         }
 
-        if (intersection || dir1) && dir2 {
-            println!();
+        if !read_errors.is_empty() {
+            result.insert(
+                "read_errors".to_string(),
+                json!(read_errors
+                    .iter()
+                    .map(|err| json!({
+                        "path": output.display(&err.path),
+                        "message": err.message,
+                    }))
+                    .collect::<Vec<_>>()),
+            );
+        }
+
+        println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    } else {
+        // Collect the lines of each section to print them in the requested order
+        let paths = |paths: Option<Vec<PathBuf>>| {
+            paths.map(|paths| {
+                paths
+                    .iter()
+                    .map(|path| output.display(path))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let intersection_lines = match &pairs {
+            Some(pairs) => Some(pairs.iter().map(|pair| pair.columns(output)).collect()),
+            None => paths(intersection_paths),
+        };
+        // Everything left out, whether by a filter or because it could not be compared
+        let skipped_lines = excluded
+            .filter(|_| shows(Section::Skipped))
+            .map(|excluded| {
+                let mut lines: Vec<(&Path, String)> = excluded
+                    .iter()
+                    .chain(&skipped)
+                    .map(|file| (file.path.as_path(), file.reason.to_string()))
+                    .chain(
+                        walk_errors
+                            .iter()
+                            .map(|err| (err.path.as_path(), err.message.clone())),
+                    )
+                    .collect();
+                lines.sort();
+                lines
+                    .into_iter()
+                    .map(|(path, reason)| format!("{} ({reason})", output.display(path)))
+                    .collect()
+            });
+        let mut sections = [
+            (Section::Intersection, intersection_lines),
+            (Section::Dir1, paths(unique_dir1_paths)),
+            (Section::Dir2, paths(unique_dir2_paths)),
+            (Section::Skipped, skipped_lines),
+        ];
+
+        let mut first = true;
+        for section in output.sections() {
+            let Some(lines) = sections
+                .iter_mut()
+                .find(|(candidate, _)| candidate == section)
+                .and_then(|(_, lines)| lines.take())
+            else {
+                continue;
+            };
+
+            if output.prefix {
+                // Print one line per path, tagged with its section
+                let prefix = match section {
+                    Section::Intersection => "BOTH",
+                    Section::Dir1 => "ONLY1",
+                    Section::Dir2 => "ONLY2",
+                    Section::Skipped => "SKIPPED",
+                };
+                for line in lines {
+                    println!("{prefix}\t{line}");
+                }
+                continue;
+            }
+
+            if !first {
+                println!();
+            }
+            first = false;
+            if !output.no_header {
+                match section {
+                    Section::Intersection => println!("Files both in {label1} and {label2}:"),
+                    Section::Dir1 => println!("Files unique in {label1}:"),
+                    Section::Dir2 => println!("Files unique in {label2}:"),
+                    Section::Skipped => println!("Files skipped:"),
+                }
+            }
+            for line in lines {
+                println!("{line}");
+            }
         }
+    }
+
+    // Report skipped files on stderr to keep the regular output intact
+    if !output.json {
+        report_problems(&skipped, &walk_errors, &read_errors);
+    }
+}
+
+/// Returns the cache of comparison results to use, or None if results are not to be cached.
+///
+/// Results are only cached with --result-cache, and runs that must read the files or must not
+/// write anything, with --repro-check or --assert-read-only, bypass the cache.
+fn result_cache(args: &Cli) -> Option<ResultCache> {
+    if !args.result_cache || args.repro_check || args.assert_read_only {
+        return None;
+    }
+    default_cache_directory().map(|directory| ResultCache {
+        directory,
+        validity: args.result_cache_ttl,
+    })
+}
 
-        if dir2 {
-            println!("Files unique in '{}':", &args.directory2.display());
-            for path in unique_dir2_paths.unwrap() {
-                println!("{}", path.display());
+/// Prints the filesystem of each root, the resulting read settings and the limit of open files
+/// on stderr.
+fn print_tuning(
+    sides: [(&[PathBuf], &[Option<Filesystem>]); 2],
+    options: &CompareOptions,
+    open_file_limit: Option<u64>,
+) {
+    for (index, (roots, filesystems)) in sides.into_iter().enumerate() {
+        for (root, filesystem) in roots.iter().zip(filesystems) {
+            let mut description = match filesystem {
+                Some(fs) if fs.network => format!("{} (network)", fs.kind),
+                Some(fs) => fs.kind.clone(),
+                None => "unknown filesystem".to_string(),
+            };
+            if let Some(kind) = options.storage[index] {
+                description.push_str(&format!(" on {}", kind.name()));
             }
+            eprintln!("directory{} '{}': {description}", index + 1, root.display());
+        }
+    }
+
+    let readers = |threads: Option<usize>| threads.unwrap_or(options.io_threads);
+    let readers = if options.io_threads1.is_some() || options.io_threads2.is_some() {
+        format!(
+            "{} for directory1, {} for directory2",
+            readers(options.io_threads1),
+            readers(options.io_threads2)
+        )
+    } else {
+        options.io_threads.to_string()
+    };
+    eprintln!(
+        "Read size: {} bytes, reader threads: {readers}, retries: {}",
+        options.chunk_size, options.retries
+    );
+    let limit = open_file_limit.map_or("none".to_string(), |limit| limit.to_string());
+    let max_open = options
+        .max_open_files
+        .map_or("one per reader".to_string(), |max| max.to_string());
+    eprintln!("Open file limit: {limit}, files read at once: at most {max_open}");
+}
+
+/// Inserts the options of the profiles selected with --profile into the command line.
+///
+/// The profiles file is only read if a profile is selected.
+fn with_profiles(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let args: Vec<OsString> = args.collect();
+    let selects_profile = args.iter().any(|arg| {
+        arg.to_str()
+            .is_some_and(|arg| arg == "--profile" || arg.starts_with("--profile="))
+    });
+    if !selects_profile {
+        return args;
+    }
+
+    let profiles = match default_profiles_file() {
+        Some(file) => Profiles::load(&file).unwrap_or_else(|err| exit_with_error(err)),
+        None => Profiles::builtin(),
+    };
+    profiles
+        .expand(args)
+        .unwrap_or_else(|err| exit_with_error(err))
+}
+
+/// Reports skipped files and paths that could not be read on stderr.
+fn report_problems(skipped: &[SkippedFile], walk_errors: &[WalkError], read_errors: &[ReadError]) {
+    if !skipped.is_empty() {
+        eprintln!("Warning: skipped {} files:", skipped.len());
+        for file in skipped {
+            eprintln!("{} ({})", file.path.display(), file.reason);
+        }
+    }
+    if !walk_errors.is_empty() {
+        eprintln!(
+            "Warning: could not read {} paths, files below them were not compared:",
+            walk_errors.len()
+        );
+        for err in walk_errors {
+            eprintln!("{} ({})", err.path.display(), err.message);
+        }
+    }
+    if !read_errors.is_empty() {
+        eprintln!(
+            "Warning: could not read {} files, they were not compared:",
+            read_errors.len()
+        );
+        for err in read_errors {
+            eprintln!("{} ({})", err.path.display(), err.message);
         }
     }
 }
@@ -0,0 +1,470 @@
+use blake3::Hash;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::checksum::{for_each_digest, Algorithm};
+use crate::options::CompareOptions;
+use crate::sums::{escape_path, parse_checksum_line, parse_modified, unescape_path, FileStamp};
+use crate::util::invalid_data;
+
+/// First line of every manifest, telling it apart from checksum files of other tools.
+pub const MANIFEST_HEADER: &str = "# filematch manifest 1";
+
+/// A single file recorded in a manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The path of the file, relative to the directory of the manifest.
+    pub path: PathBuf,
+    /// The BLAKE3 hash of the file contents.
+    pub hash: Hash,
+    /// The size and modification time of the file when it was hashed.
+    pub stamp: FileStamp,
+}
+
+/// The files of a directory tree with their hashes, sizes and modification times.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The recorded files, sorted by path.
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// Hashes every file of a directory tree with the default options, see
+/// [`generate_manifest_with_options`].
+///
+/// # Errors
+/// This function returns the errors of [`generate_manifest_with_options`].
+pub fn generate_manifest(directory: &Path) -> io::Result<Manifest> {
+    generate_manifest_with_options(directory, &CompareOptions::default())
+}
+
+/// Hashes every file of a directory tree, and records each with its size and modification
+/// time.
+///
+/// The tree is walked like a comparison root, and the files are hashed by `io_threads`
+/// threads. Since the manifest is meant to cover the whole tree, a file or directory that
+/// cannot be read is an error. The modification time is read once the file has been hashed.
+///
+/// # Parameters
+/// - `directory`: The root directory of the tree.
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The manifest, with paths relative to `directory`.
+///
+/// # Errors
+/// This function returns the first `io::Error` encountered while reading a file or its
+/// metadata, or an `io::Error` naming the first path that could not be read while walking the
+/// tree.
+pub fn generate_manifest_with_options(
+    directory: &Path,
+    options: &CompareOptions,
+) -> io::Result<Manifest> {
+    let mut entries = Vec::new();
+    for_each_digest(directory, &[Algorithm::Blake3], options, |file| {
+        let stamp = FileStamp::read(&file.path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", file.path.display())))?;
+        let hash = Hash::from_hex(&file.digests[0]).map_err(io::Error::other)?;
+        let path = file
+            .path
+            .strip_prefix(directory)
+            .map_or_else(|_| file.path.clone(), Path::to_path_buf);
+        entries.push(ManifestEntry { path, hash, stamp });
+        Ok(())
+    })?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(Manifest { entries })
+}
+
+/// Writes a manifest.
+///
+/// After a header line, each line holds the hex hash, the size, the modification time as
+/// seconds and nanoseconds since the Unix epoch, and the path of a file, separated by spaces.
+/// The path is the rest of the line and may contain spaces. Like checksum lines, paths
+/// containing a backslash or line break are escaped, and their line starts with a backslash.
+///
+/// # Parameters
+/// - `writer`: Where to write the manifest.
+/// - `manifest`: The manifest to write.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails.
+pub fn write_manifest(mut writer: impl Write, manifest: &Manifest) -> io::Result<()> {
+    writeln!(writer, "{MANIFEST_HEADER}")?;
+    for entry in &manifest.entries {
+        let path = entry.path.display().to_string();
+        let (prefix, path) = match escape_path(&path) {
+            Some(escaped) => ("\\", escaped),
+            None => ("", path),
+        };
+        writeln!(
+            writer,
+            "{prefix}{} {} {}.{:09} {path}",
+            entry.hash,
+            entry.stamp.size,
+            entry.stamp.modified.as_secs(),
+            entry.stamp.modified.subsec_nanos()
+        )?;
+    }
+
+    writer.flush()
+}
+
+/// Parses a manifest written by [`write_manifest`].
+///
+/// # Parameters
+/// - `reader`: The manifest contents.
+///
+/// # Returns
+/// The manifest, with the files in the order of the file.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, the header is missing, or a line is
+/// malformed.
+pub fn read_manifest(reader: impl BufRead) -> io::Result<Manifest> {
+    let mut lines = reader.lines();
+
+    let header = lines.next().transpose()?;
+    if header.as_deref().map(str::trim_end) != Some(MANIFEST_HEADER) {
+        return Err(invalid_data("manifest", 1, "missing header"));
+    }
+
+    let mut entries = Vec::new();
+    for (index, line) in lines.enumerate() {
+        if let Some(entry) = parse_manifest_line(&line?, index + 2)? {
+            entries.push(entry);
+        }
+    }
+
+    Ok(Manifest { entries })
+}
+
+/// Parses a single line of a manifest, see [`write_manifest`].
+///
+/// # Parameters
+/// - `line`: The line.
+/// - `number`: The number of the line in the manifest, counting from 1, for error messages.
+///
+/// # Returns
+/// The file of the line, or None for empty lines and comments.
+///
+/// # Errors
+/// This function returns an `io::Error` of kind `InvalidData` if the line is malformed.
+pub(crate) fn parse_manifest_line(line: &str, number: usize) -> io::Result<Option<ManifestEntry>> {
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let fields: Vec<&str> = line.splitn(4, ' ').collect();
+    let [hash, size, modified, path] = fields[..] else {
+        return Err(invalid_data(
+            "manifest",
+            number,
+            "expected a hash, size, time and path",
+        ));
+    };
+    let hash =
+        Hash::from_hex(hash).map_err(|_| invalid_data("manifest", number, "invalid hash"))?;
+    let size = size
+        .parse()
+        .map_err(|_| invalid_data("manifest", number, "invalid size"))?;
+    let modified = parse_modified(modified)
+        .ok_or_else(|| invalid_data("manifest", number, "invalid modification time"))?;
+    let path = if escaped {
+        unescape_path(path)
+    } else {
+        path.to_string()
+    };
+
+    Ok(Some(ManifestEntry {
+        path: PathBuf::from(path),
+        hash,
+        stamp: FileStamp { size, modified },
+    }))
+}
+
+/// The outcome of checking a directory against a manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManifestCheck {
+    /// Files whose content matches the manifest.
+    pub unchanged: Vec<PathBuf>,
+    /// Files whose content differs from the manifest.
+    pub changed: Vec<PathBuf>,
+    /// Files listed in the manifest but absent from the directory.
+    pub missing: Vec<PathBuf>,
+    /// Files of the directory not listed in the manifest.
+    pub added: Vec<PathBuf>,
+}
+
+impl ManifestCheck {
+    /// Returns true if the directory holds exactly the files of the manifest, with the same
+    /// contents.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.changed.is_empty() && self.missing.is_empty() && self.added.is_empty()
+    }
+}
+
+/// Checks a directory against a manifest written earlier, such as for a backup whose original
+/// is no longer at hand.
+///
+/// Every file of the directory is read, so content that changed without changing the size or
+/// modification time of its file, such as by bit rot, is found too. Files are matched by their
+/// path relative to the directory.
+///
+/// # Parameters
+/// - `directory`: The directory to check.
+/// - `manifest`: The manifest of the directory, see [`read_manifest`].
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The outcome of the check, with all lists sorted by path.
+///
+/// # Errors
+/// This function returns the errors of [`generate_manifest_with_options`].
+pub fn verify_manifest(
+    directory: &Path,
+    manifest: &Manifest,
+    options: &CompareOptions,
+) -> io::Result<ManifestCheck> {
+    let recorded = manifest
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry.hash));
+    let found = generate_manifest_with_options(directory, options)?
+        .entries
+        .into_iter()
+        .map(|entry| (entry.path, entry.hash));
+
+    Ok(check_digests(recorded, found))
+}
+
+/// Matches the files found in a directory with the recorded ones by path, and compares their
+/// digests.
+///
+/// # Parameters
+/// - `recorded`: The recorded files and their digests.
+/// - `found`: The files found and their digests, sorted by path.
+fn check_digests<'a, D: PartialEq>(
+    recorded: impl IntoIterator<Item = (&'a Path, D)>,
+    found: impl IntoIterator<Item = (PathBuf, D)>,
+) -> ManifestCheck {
+    let mut recorded: HashMap<&Path, D> = recorded.into_iter().collect();
+
+    let mut check = ManifestCheck::default();
+    for (path, digest) in found {
+        match recorded.remove(path.as_path()) {
+            Some(recorded) if recorded == digest => check.unchanged.push(path),
+            Some(_) => check.changed.push(path),
+            None => check.added.push(path),
+        }
+    }
+    check.missing = recorded.into_keys().map(Path::to_path_buf).collect();
+    check.missing.sort();
+
+    check
+}
+
+/// The files of a directory tree with their digests, as listed by `sha256sum`, `md5sum` and
+/// the other checksum tools of coreutils.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumList {
+    /// The algorithm of the digests.
+    pub algorithm: Algorithm,
+    /// The paths of the files, relative to the directory of the list, and their lowercase hex
+    /// digests.
+    pub entries: Vec<(PathBuf, String)>,
+}
+
+/// Parses a tagged checksum line, as written by `sha256sum --tag`, such as
+/// `SHA256 (photos/a.jpg) = 2f1c...`.
+///
+/// # Returns
+/// The algorithm named by the tag, the path and the lowercase hex digest, or None if the line
+/// is not tagged.
+fn parse_tagged_line(line: &str) -> Option<(Algorithm, PathBuf, String)> {
+    let line = line.trim_end_matches('\r');
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (tag, rest) = line.split_once(" (")?;
+    let (path, digest) = rest.rsplit_once(") = ")?;
+    let algorithm = Algorithm::from_name(tag)?;
+    if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let path = if escaped {
+        unescape_path(path)
+    } else {
+        path.to_string()
+    };
+
+    Some((algorithm, PathBuf::from(path), digest.to_ascii_lowercase()))
+}
+
+/// Parses a checksum file written by `sha256sum`, `md5sum` or another checksum tool of
+/// coreutils, such as a `SHA256SUMS` file.
+///
+/// Lines hold the hex digest and the path, separated by two spaces, or by a space and an
+/// asterisk for files hashed in binary mode, or are tagged with the algorithm, as written with
+/// `--tag`. Escaped lines starting with a backslash are unescaped, and empty lines and
+/// comments are skipped.
+///
+/// # Parameters
+/// - `reader`: The checksum file contents.
+/// - `algorithm`: The algorithm of the digests, or None to take it from the tags of the lines
+///   or from the length of the digests. A 64 digit digest is taken for SHA-256, so BLAKE3
+///   digests must be given their algorithm.
+///
+/// # Returns
+/// The list, with the files in the order of the file.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, or one of kind `InvalidData` if a
+/// line is malformed, its digest does not have the length of the algorithm, or the algorithm
+/// cannot be told.
+pub fn read_checksum_list(
+    reader: impl BufRead,
+    algorithm: Option<Algorithm>,
+) -> io::Result<ChecksumList> {
+    let mut algorithm = algorithm;
+    let mut entries = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = index + 1;
+        let (tag, path, digest) = match parse_tagged_line(&line) {
+            Some((tag, path, digest)) => (Some(tag), path, digest),
+            None => match parse_checksum_line(&line, index)? {
+                Some((path, digest)) => (None, path, digest),
+                None => continue,
+            },
+        };
+
+        let expected = match algorithm {
+            Some(algorithm) => algorithm,
+            None => {
+                let by_length = match digest.len() {
+                    32 => Some(Algorithm::Md5),
+                    40 => Some(Algorithm::Sha1),
+                    64 => Some(Algorithm::Sha256),
+                    128 => Some(Algorithm::Sha512),
+                    _ => None,
+                };
+                *algorithm.insert(
+                    tag.or(by_length)
+                        .ok_or_else(|| invalid_data("checksum", number, "unknown algorithm"))?,
+                )
+            }
+        };
+        if tag.is_some_and(|tag| tag != expected) || digest.len() != 2 * expected.digest_len() {
+            return Err(invalid_data(
+                "checksum",
+                number,
+                &format!("expected a {} digest", expected.name()),
+            ));
+        }
+        entries.push((path, digest));
+    }
+
+    let algorithm = algorithm.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no checksums to tell the algorithm from",
+        )
+    })?;
+    Ok(ChecksumList { algorithm, entries })
+}
+
+/// Digests every file of a directory tree, like [`generate_manifest_with_options`], for a
+/// checksum file of another tool.
+///
+/// # Parameters
+/// - `directory`: The root directory of the tree.
+/// - `algorithm`: The algorithm of the digests.
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The list, with paths relative to `directory`, sorted by path.
+///
+/// # Errors
+/// This function returns the errors of [`generate_manifest_with_options`].
+pub fn generate_checksum_list(
+    directory: &Path,
+    algorithm: Algorithm,
+    options: &CompareOptions,
+) -> io::Result<ChecksumList> {
+    let mut entries = Vec::new();
+    for_each_digest(directory, &[algorithm], options, |mut file| {
+        let path = file
+            .path
+            .strip_prefix(directory)
+            .map_or_else(|_| file.path.clone(), Path::to_path_buf);
+        entries.push((path, file.digests.remove(0)));
+        Ok(())
+    })?;
+    entries.sort();
+
+    Ok(ChecksumList { algorithm, entries })
+}
+
+/// Writes a checksum file as `sha256sum` and the other checksum tools of coreutils do, so they
+/// can check it.
+///
+/// Each line holds the hex digest, two spaces and the path. Paths containing a backslash or
+/// line break are escaped, and their line starts with a backslash.
+///
+/// # Parameters
+/// - `writer`: Where to write the checksum file.
+/// - `list`: The list to write.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails.
+pub fn write_checksum_list(mut writer: impl Write, list: &ChecksumList) -> io::Result<()> {
+    for (path, digest) in &list.entries {
+        let path = path.display().to_string();
+        match escape_path(&path) {
+            Some(escaped) => writeln!(writer, "\\{digest}  {escaped}")?,
+            None => writeln!(writer, "{digest}  {path}")?,
+        }
+    }
+
+    writer.flush()
+}
+
+/// Checks a directory against a checksum file of another tool, such as a `SHA256SUMS` file,
+/// like [`verify_manifest`].
+///
+/// # Parameters
+/// - `directory`: The directory the paths of the list are relative to.
+/// - `list`: The checksum file, see [`read_checksum_list`].
+/// - `options`: The options controlling which files are included and the thread count.
+///
+/// # Returns
+/// The outcome of the check, with all lists sorted by path.
+///
+/// # Errors
+/// This function returns the errors of [`generate_manifest_with_options`].
+pub fn verify_checksum_list(
+    directory: &Path,
+    list: &ChecksumList,
+    options: &CompareOptions,
+) -> io::Result<ManifestCheck> {
+    let recorded = list
+        .entries
+        .iter()
+        .map(|(path, digest)| (path.as_path(), digest.as_str()));
+    let found = generate_checksum_list(directory, list.algorithm, options)?.entries;
+
+    Ok(check_digests(
+        recorded,
+        found
+            .iter()
+            .map(|(path, digest)| (path.clone(), digest.as_str())),
+    ))
+}
@@ -0,0 +1,314 @@
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+use crate::manifest::{parse_manifest_line, MANIFEST_HEADER};
+use crate::result::{modified_paths, Category, ComparisonResult};
+use crate::sums::parse_checksum_line;
+
+/// The number of files in each category of a manifest comparison.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ManifestDiff {
+    /// Files of either manifest whose digest is in both.
+    pub intersection: usize,
+    /// Files whose digest is only in the first manifest.
+    pub unique1: usize,
+    /// Files whose digest is only in the second manifest.
+    pub unique2: usize,
+}
+
+/// A file read from a manifest.
+struct Entry {
+    path: PathBuf,
+    /// The lowercase hex digest.
+    digest: String,
+    /// The size, if the manifest records it.
+    size: Option<u64>,
+}
+
+/// The files of a manifest with the same digest.
+struct Group {
+    digest: String,
+    /// The size of the files, if the manifest records it.
+    size: Option<u64>,
+    /// The paths, in the order of the manifest.
+    paths: Vec<PathBuf>,
+}
+
+/// Creates the error reported when two files with the same digest differ in size.
+fn size_mismatch(digest: &str, files: [(&Path, u64); 2]) -> io::Error {
+    let [(path1, size1), (path2, size2)] = files;
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "'{}' ({size1} bytes) and '{}' ({size2} bytes) have the same digest {digest}",
+            path1.display(),
+            path2.display()
+        ),
+    )
+}
+
+/// Reads a manifest sorted by digest, one group of files with the same digest at a time.
+///
+/// Checksum lines and the lines of manifests written by `filematch manifest` are both read, the
+/// latter once their header line is reached, which sorts before every entry.
+struct SortedManifest<R> {
+    /// The name of the manifest in error messages.
+    name: &'static str,
+    lines: std::iter::Enumerate<io::Lines<R>>,
+    /// True once the header of a manifest written by `filematch manifest` was read.
+    filematch: bool,
+    /// The first entry of the next group, read ahead.
+    next: Option<Entry>,
+}
+
+impl<R: BufRead> SortedManifest<R> {
+    fn new(name: &'static str, reader: R) -> io::Result<Self> {
+        let mut manifest = Self {
+            name,
+            lines: reader.lines().enumerate(),
+            filematch: false,
+            next: None,
+        };
+        manifest.next = manifest.read_entry()?;
+        Ok(manifest)
+    }
+
+    /// Reads the next entry, skipping empty lines and comments.
+    fn read_entry(&mut self) -> io::Result<Option<Entry>> {
+        while let Some((index, line)) = self.lines.next() {
+            let line = line?;
+            if line.trim_end() == MANIFEST_HEADER {
+                self.filematch = true;
+                continue;
+            }
+            let entry = if self.filematch {
+                parse_manifest_line(line.trim_end_matches('\r'), index + 1).map(|entry| {
+                    entry.map(|entry| Entry {
+                        path: entry.path,
+                        digest: entry.hash.to_hex().to_string(),
+                        size: Some(entry.stamp.size),
+                    })
+                })
+            } else {
+                parse_checksum_line(&line, index).map(|entry| {
+                    entry.map(|(path, digest)| Entry {
+                        path,
+                        digest,
+                        size: None,
+                    })
+                })
+            };
+            let entry =
+                entry.map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", self.name)))?;
+            if entry.is_some() {
+                return Ok(entry);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the digest of the next group, or None at the end of the manifest.
+    fn digest(&self) -> Option<&str> {
+        self.next.as_ref().map(|entry| entry.digest.as_str())
+    }
+
+    /// Reads the next group of files with the same digest.
+    ///
+    /// # Errors
+    /// This function returns an error of kind `InvalidData` if the manifest is not sorted by
+    /// digest, or if it records different sizes for files with the same digest.
+    fn read_group(&mut self) -> io::Result<Option<Group>> {
+        let Some(first) = self.next.take() else {
+            return Ok(None);
+        };
+        let mut group = Group {
+            digest: first.digest,
+            size: first.size,
+            paths: vec![first.path],
+        };
+
+        loop {
+            match self.read_entry()? {
+                Some(entry) if entry.digest == group.digest => {
+                    if let (Some(size), Some(other)) = (group.size, entry.size) {
+                        if size != other {
+                            return Err(size_mismatch(
+                                &group.digest,
+                                [(&group.paths[0], size), (&entry.path, other)],
+                            ));
+                        }
+                    }
+                    group.paths.push(entry.path);
+                }
+                Some(entry) if entry.digest < group.digest => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{}: '{}' is out of order, the manifest must be sorted by digest",
+                            self.name,
+                            entry.path.display()
+                        ),
+                    ));
+                }
+                next => {
+                    self.next = next;
+                    return Ok(Some(group));
+                }
+            }
+        }
+    }
+}
+
+/// Reads two manifests sorted by digest side by side, like a merge join, and hands each digest
+/// to a callback with the files of either manifest that have it.
+///
+/// # Parameters
+/// - `manifest1`: The first manifest.
+/// - `manifest2`: The second manifest.
+/// - `each`: Called in the order of the digests with the category of the digest and its groups
+///   in the first and the second manifest, of which only those of the category are present.
+///
+/// # Errors
+/// This function returns the errors of reading the manifests and those of `each`, or an error of
+/// kind `InvalidData` if the files of a digest differ in size between the manifests.
+fn merge_sorted_manifests(
+    manifest1: impl BufRead,
+    manifest2: impl BufRead,
+    mut each: impl FnMut(Category, Option<Group>, Option<Group>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut manifest1 = SortedManifest::new("manifest 1", manifest1)?;
+    let mut manifest2 = SortedManifest::new("manifest 2", manifest2)?;
+
+    loop {
+        let category = match (manifest1.digest(), manifest2.digest()) {
+            (None, None) => return Ok(()),
+            (Some(_), None) => Category::UniqueDir1,
+            (None, Some(_)) => Category::UniqueDir2,
+            (Some(digest1), Some(digest2)) => match digest1.cmp(digest2) {
+                std::cmp::Ordering::Less => Category::UniqueDir1,
+                std::cmp::Ordering::Greater => Category::UniqueDir2,
+                std::cmp::Ordering::Equal => Category::Intersection,
+            },
+        };
+
+        let group1 = match category {
+            Category::UniqueDir2 => None,
+            _ => manifest1.read_group()?,
+        };
+        let group2 = match category {
+            Category::UniqueDir1 => None,
+            _ => manifest2.read_group()?,
+        };
+        if let (Some(group1), Some(group2)) = (&group1, &group2) {
+            if let (Some(size1), Some(size2)) = (group1.size, group2.size) {
+                if size1 != size2 {
+                    return Err(size_mismatch(
+                        &group1.digest,
+                        [(&group1.paths[0], size1), (&group2.paths[0], size2)],
+                    ));
+                }
+            }
+        }
+        each(category, group1, group2)?;
+    }
+}
+
+/// Compares two checksum manifests sorted by digest, without loading them.
+///
+/// The manifests are read once, side by side, like a merge join, so they may be larger than
+/// memory: only the files sharing a digest are held at once. Checksum lines start with the
+/// digest, so `LC_ALL=C sort` sorts a manifest as required, except for escaped lines, which
+/// start with a backslash. A manifest that is not sorted is an error once the first entry out of
+/// order is reached, after the files before it were emitted.
+///
+/// # Parameters
+/// - `manifest1`: The first manifest, as written by `b3sum`, `filematch export` or
+///   `filematch manifest`.
+/// - `manifest2`: The second manifest.
+/// - `emit`: Called with the category, path and digest of every file, in the order of the
+///   digests. Files whose digest is in both manifests are emitted as `Category::Intersection`,
+///   the files of the first manifest first.
+///
+/// # Returns
+/// The number of files in each category.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails or `emit` fails, or an error of kind
+/// `InvalidData` if a line is malformed, a manifest is not sorted by digest, or files with the
+/// same digest are recorded with different sizes.
+pub fn diff_sorted_manifests(
+    manifest1: impl BufRead,
+    manifest2: impl BufRead,
+    mut emit: impl FnMut(Category, &Path, &str) -> io::Result<()>,
+) -> io::Result<ManifestDiff> {
+    let mut diff = ManifestDiff::default();
+
+    merge_sorted_manifests(manifest1, manifest2, |category, group1, group2| {
+        let mut emitted = 0;
+        for group in [group1, group2].into_iter().flatten() {
+            for path in &group.paths {
+                emit(category, path, &group.digest)?;
+            }
+            emitted += group.paths.len();
+        }
+        match category {
+            Category::UniqueDir1 => diff.unique1 += emitted,
+            Category::UniqueDir2 => diff.unique2 += emitted,
+            _ => diff.intersection += emitted,
+        }
+        Ok(())
+    })?;
+    Ok(diff)
+}
+
+/// Compares the directories of two manifests sorted by digest by the contents of their files,
+/// without reading any file, such as for directories on machines that are never online at the
+/// same time.
+///
+/// The manifests are read like by [`diff_sorted_manifests`], so only the result is held in
+/// memory. It is the one of comparing the directories with relative paths: the files whose
+/// content is in both manifests, those whose content is only in one of them, and the paths
+/// whose content differs between them as modified. The paths are grouped by digest, in order of
+/// the digest, and sorted by path within a digest, the files of the first manifest first, as by
+/// [`compare_multiple_roots`].
+///
+/// [`compare_multiple_roots`]: crate::compare_two_directories::compare_multiple_roots
+///
+/// # Parameters
+/// - `manifest1`: The manifest of the first directory.
+/// - `manifest2`: The manifest of the second directory.
+///
+/// # Returns
+/// The result of the comparison, with every category.
+///
+/// # Errors
+/// This function returns the errors of [`diff_sorted_manifests`].
+pub fn compare_sorted_manifests(
+    manifest1: impl BufRead,
+    manifest2: impl BufRead,
+) -> io::Result<ComparisonResult> {
+    let mut paths: [Vec<PathBuf>; 3] = Default::default();
+
+    merge_sorted_manifests(manifest1, manifest2, |category, group1, group2| {
+        let paths = match category {
+            Category::Intersection => &mut paths[0],
+            Category::UniqueDir1 => &mut paths[1],
+            _ => &mut paths[2],
+        };
+        for mut group in [group1, group2].into_iter().flatten() {
+            group.paths.sort();
+            paths.append(&mut group.paths);
+        }
+        Ok(())
+    })?;
+
+    let [intersection, unique_dir1, unique_dir2] = paths;
+    let modified = modified_paths(Some(&unique_dir1), Some(&unique_dir2));
+    Ok(ComparisonResult {
+        intersection: Some(intersection),
+        unique_dir1: Some(unique_dir1),
+        unique_dir2: Some(unique_dir2),
+        modified,
+        ..ComparisonResult::default()
+    })
+}
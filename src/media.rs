@@ -0,0 +1,299 @@
+// Byte ranges are collected in vectors, often starting with a single one.
+#![allow(clippy::single_range_in_vec_init)]
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+/// A media format whose embedded metadata can be stripped before hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaFormat {
+    Jpeg,
+    Png,
+    Mp3,
+    Flac,
+}
+
+impl MediaFormat {
+    /// Determines the media format of a file from its extension.
+    ///
+    /// # Parameters
+    /// - `path`: The path of the file.
+    ///
+    /// # Returns
+    /// The media format, or None if the extension is not a supported media format.
+    #[must_use]
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "mp3" => Some(Self::Mp3),
+            "flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+}
+
+/// PNG chunks holding metadata rather than image data.
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+/// Reads exactly `buffer.len()` bytes at the given offset.
+///
+/// # Returns
+/// False if the file ends before the buffer is filled, true otherwise.
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the offset after any ID3v2 tags at the start of a file.
+fn skip_id3v2(file: &mut File) -> io::Result<u64> {
+    let mut offset = 0;
+    let mut header = [0; 10];
+
+    while read_at(file, offset, &mut header)? && &header[..3] == b"ID3" {
+        // The size is stored as a syncsafe integer, 7 bits per byte
+        let size = header[6..10]
+            .iter()
+            .fold(0, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+        let footer = if header[5] & 0x10 == 0 { 0 } else { 10 };
+        offset += 10 + size + footer;
+    }
+
+    Ok(offset)
+}
+
+/// Finds the ranges of a JPEG file outside of APPn (EXIF, XMP, ICC, ...) and comment segments.
+fn jpeg_ranges(file: &mut File, length: u64) -> io::Result<Option<Vec<Range<u64>>>> {
+    let mut marker = [0; 2];
+    if !read_at(file, 0, &mut marker)? || marker != [0xff, 0xd8] {
+        return Ok(None);
+    }
+
+    let mut ranges = vec![0..2];
+    let mut offset = 2;
+    loop {
+        if !read_at(file, offset, &mut marker)? || marker[0] != 0xff {
+            return Ok(None);
+        }
+        match marker[1] {
+            // Fill byte before a marker
+            0xff => {
+                offset += 1;
+                continue;
+            }
+            // End of image
+            0xd9 => {
+                ranges.push(offset..offset + 2);
+                return Ok(Some(ranges));
+            }
+            // Markers without a segment
+            0x01 | 0xd0..=0xd7 => {
+                ranges.push(offset..offset + 2);
+                offset += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut size = [0; 2];
+        if !read_at(file, offset + 2, &mut size)? {
+            return Ok(None);
+        }
+        let end = offset + 2 + u64::from(u16::from_be_bytes(size));
+
+        match marker[1] {
+            // Start of scan: the compressed image data runs up to the end of the file
+            0xda => {
+                ranges.push(offset..length);
+                return Ok(Some(ranges));
+            }
+            // Application segments and comments
+            0xe0..=0xef | 0xfe => {}
+            _ => ranges.push(offset..end),
+        }
+        offset = end;
+    }
+}
+
+/// Finds the ranges of a PNG file outside of text, EXIF and time chunks.
+fn png_ranges(file: &mut File) -> io::Result<Option<Vec<Range<u64>>>> {
+    let mut signature = [0; 8];
+    if !read_at(file, 0, &mut signature)? || &signature != b"\x89PNG\r\n\x1a\n" {
+        return Ok(None);
+    }
+
+    let mut ranges = vec![0..8];
+    let mut offset = 8;
+    let mut header = [0; 8];
+    loop {
+        if !read_at(file, offset, &mut header)? {
+            return Ok(None);
+        }
+        let size = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let chunk_type = &header[4..8];
+        // Length, type, data and CRC
+        let end = offset + 12 + size;
+
+        if !PNG_METADATA_CHUNKS
+            .iter()
+            .any(|name| &name[..] == chunk_type)
+        {
+            ranges.push(offset..end);
+        }
+        if chunk_type == b"IEND" {
+            return Ok(Some(ranges));
+        }
+        offset = end;
+    }
+}
+
+/// Finds the range of an MP3 file between its ID3v2 and ID3v1 tags.
+fn mp3_ranges(file: &mut File, length: u64) -> io::Result<Option<Vec<Range<u64>>>> {
+    let start = skip_id3v2(file)?;
+
+    let mut end = length;
+    let mut tag = [0; 3];
+    if length >= start + 128 && read_at(file, length - 128, &mut tag)? && &tag == b"TAG" {
+        end -= 128;
+    }
+
+    Ok((start <= end).then(|| vec![start..end]))
+}
+
+/// Finds the ranges of a FLAC file holding the stream info and the audio frames.
+///
+/// All other metadata blocks, such as Vorbis comments, pictures and padding, are skipped. The
+/// header of the stream info block is skipped as well, as it records whether more blocks follow.
+fn flac_ranges(file: &mut File, length: u64) -> io::Result<Option<Vec<Range<u64>>>> {
+    let start = skip_id3v2(file)?;
+    let mut marker = [0; 4];
+    if !read_at(file, start, &mut marker)? || &marker != b"fLaC" {
+        return Ok(None);
+    }
+
+    let mut ranges = vec![start..start + 4];
+    let mut offset = start + 4;
+    let mut header = [0; 4];
+    loop {
+        if !read_at(file, offset, &mut header)? {
+            return Ok(None);
+        }
+        let last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let size = u64::from(u32::from_be_bytes([0, header[1], header[2], header[3]]));
+        let end = offset + 4 + size;
+
+        // Stream info
+        if block_type == 0 {
+            ranges.push(offset + 4..end);
+        }
+        offset = end;
+
+        if last {
+            ranges.push(offset..length);
+            return Ok(Some(ranges));
+        }
+    }
+}
+
+/// Finds the byte ranges of a media file holding its actual content.
+///
+/// # Parameters
+/// - `file`: The media file.
+/// - `format`: The format of the file.
+///
+/// # Returns
+/// The ranges in ascending order, or None if the file does not have the structure of its
+/// format.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be read.
+pub fn content_ranges(file: &mut File, format: MediaFormat) -> io::Result<Option<Vec<Range<u64>>>> {
+    let length = file.metadata()?.len();
+
+    let ranges = match format {
+        MediaFormat::Jpeg => jpeg_ranges(file, length)?,
+        MediaFormat::Png => png_ranges(file)?,
+        MediaFormat::Mp3 => mp3_ranges(file, length)?,
+        MediaFormat::Flac => flac_ranges(file, length)?,
+    };
+
+    // Segments reaching past the end of the file mean the file is truncated or not what its
+    // extension claims.
+    Ok(ranges.filter(|ranges| ranges.iter().all(|range| range.end <= length)))
+}
+
+/// A reader yielding only the given byte ranges of a file.
+pub struct RangesReader {
+    file: File,
+    ranges: std::vec::IntoIter<Range<u64>>,
+    remaining: u64,
+}
+
+impl RangesReader {
+    /// Creates a reader over the given ranges of a file.
+    #[must_use]
+    pub fn new(file: File, ranges: Vec<Range<u64>>) -> Self {
+        Self {
+            file,
+            ranges: ranges.into_iter(),
+            remaining: 0,
+        }
+    }
+}
+
+impl Read for RangesReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let Some(range) = self.ranges.next() else {
+                return Ok(0);
+            };
+            self.file.seek(SeekFrom::Start(range.start))?;
+            self.remaining = range.end - range.start;
+        }
+
+        let limit = usize::try_from(self.remaining).unwrap_or(usize::MAX);
+        let bytes_read = buffer.len().min(limit);
+        let bytes_read = self.file.read(&mut buffer[..bytes_read])?;
+        if bytes_read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.remaining -= bytes_read as u64;
+
+        Ok(bytes_read)
+    }
+}
+
+/// Opens a media file so that only its content, without embedded metadata, is read.
+///
+/// JPEG files lose their APPn segments (EXIF, XMP, ICC profiles, ...) and comments, PNG files
+/// their text, EXIF and time chunks, MP3 files their ID3 tags, and FLAC files all metadata
+/// blocks except the stream info. Files that do not have the structure their extension claims
+/// are read unchanged.
+///
+/// # Parameters
+/// - `file`: The media file.
+/// - `format`: The format of the file.
+///
+/// # Returns
+/// A reader yielding the content of the file.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be read.
+pub fn strip_metadata(mut file: File, format: MediaFormat) -> io::Result<RangesReader> {
+    let ranges = match content_ranges(&mut file, format)? {
+        Some(ranges) => ranges,
+        None => vec![0..file.metadata()?.len()],
+    };
+
+    Ok(RangesReader::new(file, ranges))
+}
@@ -0,0 +1,115 @@
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::result::ComparisonResult;
+
+/// Number of files unique to each side listed in a summary by default.
+pub const SUMMARY_PATHS: usize = 10;
+
+/// Summarizes a comparison as JSON for notifications, such as a chat message or a mail.
+///
+/// The summary has the labels of both sides, the counts of [`ComparisonResult::counts`],
+/// whether the directories hold the same contents, the first `max_paths` files unique to each
+/// side by path, and a one-line `text` describing the result, which chat services such as
+/// Slack display. The directories count as holding the same contents if no side that was
+/// compared has unique files.
+///
+/// # Parameters
+/// - `result`: The result of the comparison.
+/// - `labels`: The names of the first and second directory, such as their paths.
+/// - `max_paths`: The number of files unique to each side to list.
+///
+/// # Returns
+/// The summary as a JSON object.
+#[must_use]
+pub fn summarize(result: &ComparisonResult, labels: [&str; 2], max_paths: usize) -> Value {
+    let counts = result.counts();
+    let unique = [
+        counts.unique_dir1.unwrap_or_default(),
+        counts.unique_dir2.unwrap_or_default(),
+    ];
+    let first = |paths: Option<&Vec<PathBuf>>| {
+        let mut paths: Vec<_> = paths.into_iter().flatten().collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .take(max_paths)
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let mut text = if unique == [0, 0] {
+        format!("'{}' and '{}' hold the same contents", labels[0], labels[1])
+    } else {
+        format!(
+            "'{}' and '{}' differ: {} files only in '{}', {} only in '{}'",
+            labels[0], labels[1], unique[0], labels[0], unique[1], labels[1]
+        )
+    };
+    let problems = counts.skipped + counts.walk_errors + counts.read_errors;
+    if problems > 0 {
+        text.push_str(&format!(", {problems} files skipped or unreadable"));
+    }
+
+    json!({
+        "directory1": labels[0],
+        "directory2": labels[1],
+        "in_sync": unique == [0, 0],
+        "counts": counts.to_json(),
+        "unique_dir1": first(result.unique_dir1.as_ref()),
+        "unique_dir2": first(result.unique_dir2.as_ref()),
+        "text": text,
+    })
+}
+
+/// Posts JSON to a webhook, such as a Slack incoming webhook or a ticketing system.
+///
+/// The request is sent by `curl`, which must be installed and found on the `PATH`, and must
+/// succeed within 30 seconds.
+///
+/// # Parameters
+/// - `url`: The `http` or `https` URL of the webhook.
+/// - `body`: The JSON to post.
+///
+/// # Errors
+/// This function returns an `io::Error` of kind `InvalidInput` if the URL is not an `http` or
+/// `https` URL, one of kind `NotFound` if `curl` is not installed, and an `io::Error` if `curl`
+/// cannot be run or the request fails.
+pub fn post_webhook(url: &str, body: &Value) -> io::Result<()> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{url}' is not an http or https URL"),
+        ));
+    }
+
+    let mut curl = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "30"])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", "--url", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => io::Error::new(
+                io::ErrorKind::NotFound,
+                "curl not found, install curl to post to webhooks",
+            ),
+            _ => io::Error::new(err.kind(), format!("curl: {err}")),
+        })?;
+    if let Some(mut stdin) = curl.stdin.take() {
+        stdin.write_all(body.to_string().as_bytes())?;
+    }
+    let output = curl.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
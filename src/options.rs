@@ -0,0 +1,513 @@
+use blake3::Hash;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+pub use walkdir::DirEntry;
+
+use crate::filesystem::StorageKind;
+use crate::pipeline::{Side, CHUNK_SIZE};
+use crate::reference::ReferenceSet;
+
+/// What to do with files that are locked by another process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LockedPolicy {
+    /// Leave the file out of the comparison and report it as skipped.
+    Skip,
+    /// Wait until the file is no longer locked, up to the lock timeout, and then handle it as a
+    /// file that cannot be read.
+    Wait,
+    /// Fail the comparison.
+    #[default]
+    Error,
+}
+
+/// What to do with files that cannot be read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Fail the comparison.
+    #[default]
+    Strict,
+    /// Leave the file out of the comparison and report it in the result.
+    CollectAndContinue,
+}
+
+/// Whether hashes are taken from and written to `.filematch.sums` files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SumsPolicy {
+    /// Read every file.
+    #[default]
+    Ignore,
+    /// Use the recorded hash of files whose size and modification time are unchanged.
+    Trust,
+    /// Like trust, and record the hash of every file read in the sums file of its directory.
+    Update,
+}
+
+/// A compression format whose files can be compared by their decompressed contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    /// gzip, for files ending in `.gz`.
+    Gz,
+    /// xz, for files ending in `.xz`.
+    Xz,
+    /// Zstandard, for files ending in `.zst`.
+    Zst,
+}
+
+impl Compression {
+    /// Returns the file extension of the format, without the leading dot.
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gz => "gz",
+            Self::Xz => "xz",
+            Self::Zst => "zst",
+        }
+    }
+}
+
+/// Hashing only the start, the end and the size of large files, as a quick triage of huge
+/// files.
+///
+/// Files differing only between the ranges get the same hash, so matches between files hashed
+/// by ranges are probable rather than certain until the files are read in full. Files that
+/// differ in their ranges or their size are certainly different.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeHash {
+    /// Files larger than this many bytes are hashed by ranges, smaller ones in full.
+    pub min_size: u64,
+    /// Number of bytes hashed at the start of each file.
+    pub head: u64,
+    /// Number of bytes hashed at the end of each file.
+    pub tail: u64,
+    /// Read the files matched by ranges in full after the comparison, so only certain matches
+    /// remain.
+    pub verify: bool,
+}
+
+impl RangeHash {
+    /// Default number of bytes hashed at the start and at the end of each file.
+    pub const DEFAULT_RANGE: u64 = 16 * 1024 * 1024;
+
+    /// Creates the ranges hashing the first and last 16 MiB of files larger than `min_size`,
+    /// without reading matches in full.
+    #[must_use]
+    pub fn new(min_size: u64) -> Self {
+        Self {
+            min_size,
+            head: Self::DEFAULT_RANGE,
+            tail: Self::DEFAULT_RANGE,
+            verify: false,
+        }
+    }
+
+    /// Returns true if a file of the given size is hashed by ranges, which takes it to be larger
+    /// than `min_size` and than both ranges together.
+    #[must_use]
+    pub fn applies(&self, size: u64) -> bool {
+        size > self.min_size && size > self.head.saturating_add(self.tail)
+    }
+}
+
+/// A predicate deciding which entries of a directory tree are compared.
+///
+/// Entries are [`walkdir::DirEntry`] values, re-exported as [`DirEntry`].
+///
+/// It is called for every file and directory below the roots. Files it rejects are left out,
+/// and directories it rejects are not walked.
+#[derive(Clone)]
+pub struct WalkFilter(Arc<dyn Fn(&DirEntry) -> bool + Send + Sync>);
+
+impl WalkFilter {
+    /// Creates a filter from a predicate returning true for the entries to keep.
+    pub fn new(predicate: impl Fn(&DirEntry) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(predicate))
+    }
+
+    /// Determines if an entry is kept.
+    #[must_use]
+    pub fn keeps(&self, entry: &DirEntry) -> bool {
+        (self.0)(entry)
+    }
+}
+
+impl fmt::Debug for WalkFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WalkFilter")
+    }
+}
+
+/// A function applied to the contents of every file before hashing.
+///
+/// It receives the path of the file and a reader yielding its contents, after any
+/// decompression or metadata stripping, and returns the reader whose data is hashed instead.
+/// This allows comparing files by a domain-specific definition of equality, e.g. skipping a
+/// header, decrypting, or normalizing line endings.
+#[derive(Clone)]
+pub struct ContentTransform(Arc<TransformFn>);
+
+/// A reader yielding the contents of a file.
+pub type ContentReader = Box<dyn Read + Send>;
+
+type TransformFn = dyn Fn(&Path, ContentReader) -> ContentReader + Send + Sync;
+
+impl ContentTransform {
+    /// Creates a transform from a function wrapping the reader of a file.
+    pub fn new(
+        transform: impl Fn(&Path, Box<dyn Read + Send>) -> Box<dyn Read + Send> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(transform))
+    }
+
+    /// Applies the transform to the reader of a file.
+    #[must_use]
+    pub fn apply(&self, path: &Path, reader: ContentReader) -> ContentReader {
+        (self.0)(path, reader)
+    }
+}
+
+impl fmt::Debug for ContentTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ContentTransform")
+    }
+}
+
+/// The progress of a comparison, reported each time a file has been hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent<'a> {
+    /// The number of files hashed so far, by all hasher threads.
+    pub files_hashed: usize,
+    /// The number of bytes hashed so far, by all hasher threads. Files whose hash was taken
+    /// from a sums file or a journal count as hashed, but their bytes do not.
+    pub bytes_hashed: u64,
+    /// The side of the file just hashed.
+    pub side: Side,
+    /// The file just hashed, as found.
+    pub path: &'a Path,
+    /// The time it took to hash the file just hashed, see
+    /// [`FileTiming::duration`](crate::result::FileTiming::duration).
+    pub duration: Duration,
+}
+
+/// A function called with the progress of a comparison, such as to update a progress bar.
+///
+/// It is called from the hasher threads, so it may be called by several threads at once, and
+/// the hashing of each thread waits for it to return.
+#[derive(Clone)]
+pub struct ProgressHook(Arc<dyn Fn(&ProgressEvent) + Send + Sync>);
+
+impl ProgressHook {
+    /// Creates a hook from a function receiving each progress event.
+    pub fn new(on_progress: impl Fn(&ProgressEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(on_progress))
+    }
+
+    /// Reports a progress event.
+    pub fn report(&self, event: &ProgressEvent) {
+        (self.0)(event);
+    }
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+/// Options controlling how two directories are compared.
+///
+/// The defaults mirror the command line defaults, see each field.
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct CompareOptions {
+    /// Return file paths relative to the directory they were found in.
+    /// Off by default, which returns the paths as found.
+    pub relative: bool,
+    /// Return canonical absolute file paths, resolving relative roots, `..` and roots reached
+    /// through symbolic links.
+    ///
+    /// The roots are canonicalized before the comparison, so `relative_to1` and `relative_to2`
+    /// must be canonical as well. Off by default.
+    pub absolute: bool,
+    /// Return the file paths of the first side relative to this directory instead, which must
+    /// contain all roots of that side. None by default.
+    pub relative_to1: Option<PathBuf>,
+    /// Return the file paths of the second side relative to this directory instead, which must
+    /// contain all roots of that side. None by default.
+    pub relative_to2: Option<PathBuf>,
+    /// Skip hidden files and directories. Off by default.
+    pub skip_hidden: bool,
+    /// Skip files matched by a `.filematchignore` file at the root of each directory.
+    /// On by default.
+    pub use_ignore_file: bool,
+    /// Skip files matched by the `.gitignore` and `.ignore` files of their directory or the
+    /// directories above it up to the root, or by the global git excludes.
+    ///
+    /// A pattern in a deeper directory takes precedence, and `.ignore` over `.gitignore` in the
+    /// same directory, as with ripgrep. As with git, `.gitignore` files and the global excludes
+    /// only apply inside a git repository. The ignore files are compared themselves.
+    ///
+    /// Off by default.
+    pub respect_gitignore: bool,
+    /// Only compare the entries accepted by this predicate, in addition to the other filters.
+    /// None by default.
+    pub walk_filter: Option<WalkFilter>,
+    /// Only compare files modified after this point in time, leaving out all others.
+    ///
+    /// Files whose modification time cannot be read are compared.
+    ///
+    /// None by default, so files are compared regardless of their modification time.
+    pub modified_since: Option<SystemTime>,
+    /// Only compare files of at least this many bytes as stored, leaving out the others before
+    /// they are read. None by default.
+    pub min_size: Option<u64>,
+    /// Only compare files of at most this many bytes as stored, leaving out the others before
+    /// they are read. None by default.
+    pub max_size: Option<u64>,
+    /// Descend into symbolic links to directories, and junctions and mount points on Windows.
+    ///
+    /// They are skipped by default, as they may form cycles or pull in unrelated volumes. When
+    /// followed, a link back to one of its own parent directories is reported as a walk error
+    /// instead of being walked again.
+    pub follow_links: bool,
+    /// Compare even if a root directory is inside, or the same as, another root directory.
+    ///
+    /// A nested root is left out of the walk of the root containing it, so each file is only
+    /// found once. Overlapping roots are an error otherwise, as the results would be misleading.
+    ///
+    /// Off by default.
+    pub allow_overlapping_roots: bool,
+    /// Sort the resulting file paths. Off by default.
+    pub sort: bool,
+    /// Compute the file paths present in both directories. On by default.
+    pub include_intersection: bool,
+    /// Compute the file paths unique to the first directory. On by default.
+    pub include_unique_dir1: bool,
+    /// Compute the file paths unique to the second directory. On by default.
+    pub include_unique_dir2: bool,
+    /// Compute the files of each side grouped by content. Off by default.
+    pub include_groups: bool,
+    /// Report the files and directories left out by the filters, and why, see
+    /// `ComparisonResult::excluded`. Off by default.
+    pub include_excluded: bool,
+    /// Number of threads reading file contents from disk. Defaults to the number of physical cores.
+    pub io_threads: usize,
+    /// Number of threads reading only the files of the first side, or None to use `io_threads`
+    /// if `io_threads2` is set.
+    ///
+    /// When either this or `io_threads2` is set, each side is read by threads of its own, for
+    /// example many for an SSD and one for a USB hard disk. Otherwise `io_threads` threads read
+    /// the files of both sides. None by default.
+    pub io_threads1: Option<usize>,
+    /// Number of threads reading only the files of the second side, see `io_threads1`.
+    /// None by default.
+    pub io_threads2: Option<usize>,
+    /// The kind of storage the first and the second side are on, or None where it is unknown.
+    ///
+    /// A side on a hard disk is read by [`HDD_IO_THREADS`] threads of its own unless
+    /// `io_threads1` or `io_threads2` sets its readers, and files are then read in pieces of
+    /// [`HDD_CHUNK_SIZE`] unless `chunk_size` was changed. Solid-state storage is read as
+    /// configured. Set it from [`detect_storage`] to choose how each side is read
+    /// automatically, or to a kind of your own to override the detection. Unknown for both sides
+    /// by default.
+    ///
+    /// [`HDD_IO_THREADS`]: crate::filesystem::HDD_IO_THREADS
+    /// [`HDD_CHUNK_SIZE`]: crate::filesystem::HDD_CHUNK_SIZE
+    /// [`detect_storage`]: crate::filesystem::detect_storage
+    pub storage: [Option<StorageKind>; 2],
+    /// Adjust the number of files read at once to the observed throughput, between one and
+    /// `io_threads`, starting at the number of physical cores.
+    ///
+    /// Fast storage benefits from many concurrent reads, while spinning disks and network
+    /// shares with high latency do better with few.
+    ///
+    /// Off by default, which reads a fixed number of files at once.
+    pub adaptive_io: bool,
+    /// Number of threads hashing the contents produced by the readers.
+    /// Defaults to the number of physical cores.
+    pub hash_threads: usize,
+    /// Number of times reading a file is retried after a transient I/O error.
+    /// Defaults to 0, so failed reads are not retried.
+    pub retries: u32,
+    /// Delay before the first retry. It doubles with every further attempt.
+    /// Defaults to 500 milliseconds.
+    pub retry_delay: Duration,
+    /// What to do with files that are locked by another process. Defaults to `LockedPolicy::Error`.
+    pub locked: LockedPolicy,
+    /// How long a locked file is waited for with `LockedPolicy::Wait`, trying again every
+    /// `retry_delay`, before it is handled like a file that cannot be read, see `read_errors`.
+    /// Defaults to 60 seconds.
+    pub lock_timeout: Duration,
+    /// Read a file once more if it was modified while it was read.
+    ///
+    /// The size and modification time of every file are checked before and after reading it.
+    /// A file that changed may have been hashed partly old and partly new, so it is skipped as
+    /// unstable, unless it is unchanged on the second attempt.
+    ///
+    /// Off by default, so files modified while read are skipped.
+    pub retry_unstable: bool,
+    /// Fail the comparison if a directory or file cannot be read while walking the directories,
+    /// instead of reporting it in the result. Off by default.
+    pub fail_on_walk_error: bool,
+    /// What to do with files that cannot be read, such as files without read permission or
+    /// files removed after the directories were walked. Locked files are only affected if
+    /// `locked` is `LockedPolicy::Error`. Defaults to `ErrorPolicy::Strict`.
+    pub read_errors: ErrorPolicy,
+    /// Compression formats whose files are decompressed before hashing.
+    /// Empty by default, so compressed files are compared as they are.
+    pub decompress: Vec<Compression>,
+    /// Hash JPEG, PNG, MP3 and FLAC files without their embedded metadata. Off by default.
+    pub strip_metadata: bool,
+    /// Transform applied to the contents of every file before hashing. None by default.
+    pub transform: Option<ContentTransform>,
+    /// Whether hashes are taken from and written to the `.filematch.sums` file of each
+    /// directory.
+    ///
+    /// Sums files record the hashes of the unmodified file contents, so they cannot be combined
+    /// with `decompress`, `strip_metadata` or `transform`. Defaults to `SumsPolicy::Ignore`.
+    pub sums: SumsPolicy,
+    /// Journal file the hash of every file read is appended to, and taken from for files whose
+    /// size and modification time are unchanged, or None for no journal.
+    ///
+    /// Running a comparison again with the same journal only reads the files that were not
+    /// hashed yet, so long comparisons can be resumed after an interruption. None by default.
+    pub journal: Option<PathBuf>,
+    /// Fraction of the files with a hash recorded in a sums file or the journal that are read
+    /// anyway to check the recorded hash, from 0 to 1.
+    ///
+    /// The files to check are picked at random. Recorded hashes found to be wrong are reported
+    /// in the result, and replaced with `SumsPolicy::Update`. Defaults to 0, which checks none.
+    pub verify_sums: f64,
+    /// Hashes of contents left out of the result, such as empty files or license texts.
+    ///
+    /// The files are still read, but left out of every result group and of the groups by
+    /// content. Empty by default.
+    pub ignore_hashes: Arc<HashSet<Hash>>,
+    /// Reference set of known files, such as the NSRL, whose files are left out of both sides,
+    /// or None for no reference set.
+    ///
+    /// The digest of the reference set is computed for every file read, so hashes recorded in
+    /// sums files or the journal are not used. Known files are matched by their contents as
+    /// stored, so a reference set cannot be combined with `decompress`, `strip_metadata` or
+    /// `transform`. None by default.
+    pub reference_set: Option<Arc<ReferenceSet>>,
+    /// Number of bytes read from a file at once.
+    ///
+    /// Large reads of 1 to 4 MiB are faster on fast storage such as NVMe arrays, while small
+    /// reads reduce the memory used on constrained devices. Defaults to 64 KiB.
+    pub chunk_size: usize,
+    /// Maximum number of files kept open by the readers at once, or None for one per reader.
+    ///
+    /// Readers beyond the limit wait for another file to be closed, so many reader threads do
+    /// not run out of file descriptors. See `limits::max_open_files` for a value derived from
+    /// the limit of the process. None by default.
+    pub max_open_files: Option<usize>,
+    /// Maximum number of file paths and files queued for hashing at any stage of the pipeline,
+    /// or None for no limit.
+    ///
+    /// Each queued file may hold a few read buffers, so this bounds the memory used for file
+    /// contents. Without a limit, the paths of all files found may be held at once.
+    ///
+    /// None by default.
+    pub max_in_flight: Option<usize>,
+    /// Only read the files of a size found on the other side too.
+    ///
+    /// Files of a size found on one side only cannot match the other side, so they are reported
+    /// as unique without being read, and their hashes are not recorded in sums files. The
+    /// directories are walked completely before reading starts. Ignored when the hash of every
+    /// file is needed, see [`crate::compare_two_directories::compare_multiple_roots`].
+    ///
+    /// Off by default.
+    pub prefilter_sizes: bool,
+    /// Leave out paths leading to the same file as another path of their side differing only in
+    /// case, as found on case-insensitive filesystems, and report them as excluded.
+    ///
+    /// The directories are walked completely before reading starts. Off by default.
+    pub collapse_case_aliases: bool,
+    /// Leave out paths leading, through symbolic links or junctions, to a file already found on
+    /// their side, so each file is read once, and report them as excluded.
+    ///
+    /// The directories are walked completely before reading starts. Off by default.
+    pub collapse_link_aliases: bool,
+    /// Refuse to write anything, for evidence or production archives.
+    ///
+    /// Writing sums files or a journal is rejected, and every file is checked to be opened for
+    /// reading only before it is read. Off by default.
+    pub read_only: bool,
+    /// Hash only ranges of large files, see [`RangeHash`], and list the files matched this way
+    /// in `ComparisonResult::ranged_matches`.
+    ///
+    /// The hashes of such files are not hashes of their contents, so they cannot be combined
+    /// with sums files, a journal, ignored contents, a reference set, `decompress`,
+    /// `strip_metadata` or `transform`. None by default, so every file is read in full.
+    pub range_hash: Option<RangeHash>,
+    /// Time the hashing of every file and list this many of the slowest in
+    /// `ComparisonResult::slow_files`, such as to find failing disks or cold network storage.
+    /// Defaults to 0, which times no files.
+    pub slow_files: usize,
+    /// Report the progress of the comparison to this hook each time a file has been hashed.
+    /// None by default.
+    pub on_progress: Option<ProgressHook>,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        let physical_cores = num_cpus::get_physical();
+
+        Self {
+            relative: false,
+            absolute: false,
+            relative_to1: None,
+            relative_to2: None,
+            skip_hidden: false,
+            use_ignore_file: true,
+            respect_gitignore: false,
+            walk_filter: None,
+            modified_since: None,
+            min_size: None,
+            max_size: None,
+            follow_links: false,
+            allow_overlapping_roots: false,
+            sort: false,
+            include_intersection: true,
+            include_unique_dir1: true,
+            include_unique_dir2: true,
+            include_groups: false,
+            include_excluded: false,
+            io_threads: physical_cores,
+            io_threads1: None,
+            io_threads2: None,
+            storage: [None, None],
+            adaptive_io: false,
+            hash_threads: physical_cores,
+            retries: 0,
+            retry_delay: Duration::from_millis(500),
+            locked: LockedPolicy::Error,
+            lock_timeout: Duration::from_secs(60),
+            retry_unstable: false,
+            fail_on_walk_error: false,
+            read_errors: ErrorPolicy::Strict,
+            decompress: Vec::new(),
+            strip_metadata: false,
+            transform: None,
+            sums: SumsPolicy::Ignore,
+            journal: None,
+            verify_sums: 0.0,
+            ignore_hashes: Arc::default(),
+            reference_set: None,
+            chunk_size: CHUNK_SIZE,
+            max_open_files: None,
+            max_in_flight: None,
+            prefilter_sizes: false,
+            collapse_case_aliases: false,
+            collapse_link_aliases: false,
+            read_only: false,
+            range_hash: None,
+            slow_files: 0,
+            on_progress: None,
+        }
+    }
+}
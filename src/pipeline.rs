@@ -0,0 +1,875 @@
+use blake3::{Hash, Hasher};
+use crossbeam_channel::{bounded, select, unbounded, Receiver, RecvTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::checksum::{from_hex, MultiHasher};
+use crate::error::FileMatchError;
+use crate::options::{
+    CompareOptions, ErrorPolicy, LockedPolicy, ProgressEvent, ProgressHook, RangeHash,
+};
+use crate::reference::ReferenceSet;
+use crate::result::{keep_slowest, FileTiming, ReadError, SkipReason, SkippedFile, SumsCheck};
+use crate::sums::{FileStamp, SumsStore};
+use crate::util::{
+    file_identity, insert_path, is_locked_error, is_transient_error, matching_root, open_file,
+    open_raw_file, relative_path,
+};
+
+/// Default size of a single chunk read from disk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Files up to this size are hashed by the reader at once instead of being streamed.
+pub(crate) const SMALL_FILE_SIZE: usize = 4 * 1024;
+
+/// Number of chunks a reader may buffer ahead of the hasher for a single file.
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Hashed after the ranges of a file hashed by ranges, with its size, so its hash cannot be the
+/// hash of the contents of any file.
+const RANGE_HASH_DOMAIN: &[u8] = b"filematch range hash";
+
+/// Interval at which the adaptive limit of files read at once is adjusted.
+const ADJUST_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Limits the number of files read at once, adjusting the limit to the observed throughput.
+///
+/// Readers enter the gate before reading a file and count the bytes they read. A controller
+/// periodically moves the limit by one, keeping the direction while the throughput improves
+/// and turning around once it does not. Fast storage such as NVMe arrays thus ends up with many
+/// concurrent reads, while spinning disks and high latency network shares settle at few.
+pub struct ReadGate {
+    /// The number of files being read and the current limit.
+    state: Mutex<(usize, usize)>,
+    released: Condvar,
+    bytes: AtomicU64,
+    max: usize,
+}
+
+/// Permission to read a file, given back when dropped.
+pub struct ReadPermit<'a>(&'a ReadGate);
+
+impl Drop for ReadPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().expect("Read gate lock poisoned");
+        state.0 -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+impl ReadGate {
+    /// Creates a gate.
+    ///
+    /// # Parameters
+    /// - `initial`: The number of files read at once to start with.
+    /// - `max`: The highest number of files read at once, usually the number of readers.
+    #[must_use]
+    pub fn new(initial: usize, max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            state: Mutex::new((0, initial.clamp(1, max))),
+            released: Condvar::new(),
+            bytes: AtomicU64::new(0),
+            max,
+        }
+    }
+
+    /// Returns the current number of files read at once.
+    ///
+    /// # Panics
+    /// This function panics if another thread panicked while holding the lock.
+    #[must_use]
+    pub fn limit(&self) -> usize {
+        self.state.lock().expect("Read gate lock poisoned").1
+    }
+
+    /// Waits until another file may be read.
+    ///
+    /// # Panics
+    /// This function panics if another thread panicked while holding the lock.
+    pub fn enter(&self) -> ReadPermit<'_> {
+        let mut state = self.state.lock().expect("Read gate lock poisoned");
+        while state.0 >= state.1 {
+            state = self.released.wait(state).expect("Read gate lock poisoned");
+        }
+        state.0 += 1;
+
+        ReadPermit(self)
+    }
+
+    /// Counts bytes that were read.
+    fn add_bytes(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Adjusts the limit to the throughput until `stop` is closed.
+    ///
+    /// # Parameters
+    /// - `stop`: A channel closed once reading is done.
+    ///
+    /// # Panics
+    /// This function panics if another thread panicked while holding the lock.
+    pub fn control(&self, stop: &Receiver<()>) {
+        let mut previous = 0.0;
+        let mut step = 1;
+        let mut counted = 0;
+        let mut last = Instant::now();
+
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(ADJUST_INTERVAL) {
+            let bytes = self.bytes.load(Ordering::Relaxed);
+            #[allow(clippy::cast_precision_loss)]
+            let throughput = (bytes - counted) as f64 / last.elapsed().as_secs_f64();
+            counted = bytes;
+            last = Instant::now();
+
+            // Keep going while the throughput clearly improves, and turn around otherwise
+            if throughput <= previous * 1.05 {
+                step = -step;
+            }
+            previous = throughput;
+
+            let mut state = self.state.lock().expect("Read gate lock poisoned");
+            state.1 = state.1.saturating_add_signed(step).clamp(1, self.max);
+            self.released.notify_all();
+        }
+    }
+}
+
+/// Creates a channel holding at most `capacity` messages, or any number of them if None.
+#[must_use]
+pub fn channel<T>(capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    match capacity {
+        Some(capacity) => bounded(capacity),
+        None => unbounded(),
+    }
+}
+
+/// Which of the two compared directories a file belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    #[serde(rename = "directory1")]
+    Dir1,
+    #[serde(rename = "directory2")]
+    Dir2,
+}
+
+/// A message sent from a reader to the hasher of a file.
+pub enum Chunk {
+    /// The next piece of the file contents.
+    Data(Vec<u8>),
+    /// Reading failed transiently and starts over from the beginning of the file.
+    Restart,
+    /// Reading failed for good. This is the last message of the file.
+    Failed(io::Error),
+    /// The file is left out of the comparison. This is the last message of the file.
+    Skipped(SkipReason),
+    /// The hash and size of the file are known from a sums file, so it is not read. This is the
+    /// only message of the file.
+    Known(Hash, u64),
+    /// The hash of the file is known from a sums file, but the file is read anyway to check it.
+    /// Data follows as for any other file.
+    Verify(Hash),
+    /// The file was small enough to be hashed by the reader, with the given hash and size. No
+    /// data follows, but the file may still be restarted or skipped.
+    Hashed(Hash, u64),
+    /// Only ranges of the file, of the given size, are read. Their data follows as for any
+    /// other file.
+    Ranged(u64),
+}
+
+/// The file paths grouped by hash by a single hasher thread.
+#[derive(Default)]
+pub struct HashedFiles {
+    /// File paths of the first directory grouped by hash.
+    pub map1: HashMap<Hash, Vec<PathBuf>>,
+    /// File paths of the second directory grouped by hash.
+    pub map2: HashMap<Hash, Vec<PathBuf>>,
+    /// The number of bytes hashed for each hash.
+    pub sizes: HashMap<Hash, u64>,
+    /// Files that were not hashed.
+    pub skipped: Vec<SkippedFile>,
+    /// Files that were hashed but left out, as they are in the reference set.
+    pub excluded: Vec<SkippedFile>,
+    /// Files that could not be read, if errors are collected.
+    pub read_errors: Vec<ReadError>,
+    /// The recorded hashes checked by reading the files anyway.
+    pub sums_check: SumsCheck,
+    /// The files hashed by ranges only, with their side and their paths as found, by hash.
+    pub ranged: HashMap<Hash, Vec<(Side, PathBuf)>>,
+    /// The files that took longest to hash, slowest first, if files are timed.
+    pub slow_files: Vec<FileTiming>,
+}
+
+/// Counts the files and bytes hashed by all hasher threads, and reports them to a hook.
+pub struct ProgressCounter {
+    hook: ProgressHook,
+    files: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+impl ProgressCounter {
+    /// Creates a counter reporting to a hook, starting at zero.
+    #[must_use]
+    pub fn new(hook: ProgressHook) -> Self {
+        Self {
+            hook,
+            files: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a hashed file and reports the totals with it.
+    pub fn file_hashed(&self, side: Side, path: &Path, bytes: u64, duration: Duration) {
+        let files_hashed = self.files.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes_hashed = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.hook.report(&ProgressEvent {
+            files_hashed,
+            bytes_hashed,
+            side,
+            path,
+            duration,
+        });
+    }
+}
+
+/// Returns what identifies a root directory while it stays available, see
+/// [`file_identity`], so a mount point whose filesystem is unmounted counts as another
+/// directory.
+///
+/// # Returns
+/// The identity, or None if the path cannot be read or is not a directory. On platforms
+/// without file identities, every directory has the same identity.
+fn root_identity(path: &Path) -> Option<(u64, u64)> {
+    fs::metadata(path).ok().filter(fs::Metadata::is_dir)?;
+    match file_identity(path) {
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => Some((0, 0)),
+        identity => identity.ok(),
+    }
+}
+
+/// A root directory of a comparison, with its identity when the comparison started.
+#[derive(Debug)]
+struct WatchedRoot {
+    side: Side,
+    path: PathBuf,
+    identity: Option<(u64, u64)>,
+    lost: AtomicBool,
+}
+
+impl WatchedRoot {
+    /// Checks the root once more, and records it as lost if it became unavailable.
+    fn check(&self) -> bool {
+        if !self.lost.load(Ordering::Relaxed) && root_identity(&self.path) != self.identity {
+            self.lost.store(true, Ordering::Relaxed);
+        }
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+/// Watches the root directories of a comparison for becoming unavailable while their files are
+/// read, such as a network share that is unmounted or a disk that is ejected.
+///
+/// A root is lost once it can no longer be read, is no longer a directory, or is another
+/// directory than at the start, as an empty mount point is. A lost root stays lost even if it
+/// becomes available again.
+#[derive(Debug)]
+pub struct RootWatch {
+    roots: Vec<WatchedRoot>,
+}
+
+impl RootWatch {
+    /// Starts watching the roots of both sides, as they are now.
+    #[must_use]
+    pub fn new(dirs1: &[PathBuf], dirs2: &[PathBuf]) -> Self {
+        let watched = |side, dirs: &[PathBuf]| {
+            dirs.iter()
+                .map(|path| WatchedRoot {
+                    side,
+                    path: path.clone(),
+                    identity: root_identity(path),
+                    lost: AtomicBool::new(false),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut roots = watched(Side::Dir1, dirs1);
+        roots.extend(watched(Side::Dir2, dirs2));
+        Self { roots }
+    }
+
+    /// Checks whether a root containing a file of a side was lost, such as after the file
+    /// could not be read.
+    #[must_use]
+    pub fn is_lost(&self, side: Side, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .filter(|root| root.side == side && path.starts_with(&root.path))
+            .any(WatchedRoot::check)
+    }
+
+    /// Checks every root once more, and returns the sides with a root that was lost.
+    #[must_use]
+    pub fn lost_sides(&self) -> Vec<Side> {
+        let mut sides = Vec::new();
+        for root in &self.roots {
+            if root.check() && !sides.contains(&root.side) {
+                sides.push(root.side);
+            }
+        }
+        sides
+    }
+}
+
+/// A file whose contents are streamed from a reader thread to a hasher thread.
+///
+/// The reader sends the chunks of the file through `chunks` and closes the channel once the
+/// end of the file is reached. If the hash is to be recorded in a sums file, `stamp` holds the
+/// size and modification time of the file before it was read.
+pub struct HashJob {
+    pub side: Side,
+    pub path: PathBuf,
+    pub chunks: Receiver<Chunk>,
+    pub stamp: Option<FileStamp>,
+}
+
+/// Streams the contents of a file to a hasher in chunks.
+///
+/// Files of at most `SMALL_FILE_SIZE` bytes, such as most files of source checkouts, are read
+/// and hashed in one go instead, sparing the hasher the round trips of the chunks.
+///
+/// # Parameters
+/// - `path`: The file to read.
+/// - `sender`: The channel to the hasher of the file.
+/// - `buffers`: Buffers handed back by the hashers for reuse.
+/// - `options`: The options selecting how the contents are read.
+/// - `gate`: Where to count the bytes read, if anywhere.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be opened, read or decompressed. A
+/// hasher that went away is not an error, as its result is discarded anyway.
+fn stream_file(
+    path: &Path,
+    sender: &Sender<Chunk>,
+    buffers: &Receiver<Vec<u8>>,
+    options: &CompareOptions,
+    gate: Option<&ReadGate>,
+) -> io::Result<()> {
+    if let Some(ranges) = options.range_hash {
+        let size = std::fs::metadata(path)?.len();
+        if ranges.applies(size) {
+            return stream_ranges(path, size, ranges, sender, buffers, options, gate);
+        }
+    }
+
+    let mut file = open_file(path, options)?;
+
+    // Reading one byte more than a small file tells whether the end was reached
+    let mut start = Vec::with_capacity(SMALL_FILE_SIZE + 1);
+    (&mut file)
+        .take(SMALL_FILE_SIZE as u64 + 1)
+        .read_to_end(&mut start)?;
+    if let Some(gate) = gate {
+        gate.add_bytes(start.len());
+    }
+    // The hasher computes the digest of the reference set from the data
+    if start.len() <= SMALL_FILE_SIZE && options.reference_set.is_none() {
+        let _ = sender.send(Chunk::Hashed(blake3::hash(&start), start.len() as u64));
+        return Ok(());
+    }
+    if sender.send(Chunk::Data(start)).is_err() {
+        return Ok(());
+    }
+
+    send_chunks(file, sender, buffers, options, gate).map(|_| ())
+}
+
+/// Streams the first and last bytes of a large file to a hasher, for hashing by ranges.
+///
+/// # Parameters
+/// - `path`: The file to read.
+/// - `size`: The size of the file, larger than both ranges together.
+/// - `ranges`: The number of bytes to read at the start and at the end.
+/// - `sender`: The channel to the hasher of the file.
+/// - `buffers`: Buffers handed back by the hashers for reuse.
+/// - `options`: The options selecting how the contents are read.
+/// - `gate`: Where to count the bytes read, if anywhere.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be opened or read.
+fn stream_ranges(
+    path: &Path,
+    size: u64,
+    ranges: RangeHash,
+    sender: &Sender<Chunk>,
+    buffers: &Receiver<Vec<u8>>,
+    options: &CompareOptions,
+    gate: Option<&ReadGate>,
+) -> io::Result<()> {
+    let mut file = open_raw_file(path, options)?;
+    if sender.send(Chunk::Ranged(size)).is_err()
+        || !send_chunks(
+            (&mut file).take(ranges.head),
+            sender,
+            buffers,
+            options,
+            gate,
+        )?
+    {
+        return Ok(());
+    }
+    file.seek(SeekFrom::Start(size - ranges.tail))?;
+    send_chunks(file.take(ranges.tail), sender, buffers, options, gate).map(|_| ())
+}
+
+/// Streams everything read from `reader` to a hasher in chunks of `options.chunk_size` bytes.
+///
+/// # Returns
+/// False if the hasher went away, true otherwise.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails.
+fn send_chunks(
+    mut reader: impl Read,
+    sender: &Sender<Chunk>,
+    buffers: &Receiver<Vec<u8>>,
+    options: &CompareOptions,
+    gate: Option<&ReadGate>,
+) -> io::Result<bool> {
+    let chunk_size = options.chunk_size.max(1);
+
+    loop {
+        let mut buffer = buffers.try_recv().unwrap_or_default();
+        buffer.resize(chunk_size, 0);
+
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(true),
+            Ok(bytes_read) => {
+                if let Some(gate) = gate {
+                    gate.add_bytes(bytes_read);
+                }
+                buffer.truncate(bytes_read);
+                if sender.send(Chunk::Data(buffer)).is_err() {
+                    return Ok(false);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Decides at random whether a file is read anyway to check its hash.
+///
+/// # Parameters
+/// - `path`: The file.
+/// - `fraction`: The fraction of files to check, from 0 to 1.
+pub(crate) fn sampled(path: &Path, fraction: f64) -> bool {
+    // Each new state is seeded differently, which makes its hashes random enough for sampling
+    #[allow(clippy::cast_precision_loss)]
+    let random = RandomState::new().hash_one(path) as f64 / u64::MAX as f64;
+    random < fraction
+}
+
+/// Reads the contents of a single file and streams them to a hasher.
+///
+/// The job is queued before the file is opened so hashers pick up files in the order the
+/// readers started them. Files that are read wait for `gate`, if given, before their job is
+/// queued and hold it until they are read, so every queued job is fed by a running reader.
+/// Transient errors are retried with exponential backoff as configured in `options` before the
+/// error is forwarded to the hasher. Files locked by another process are skipped, waited for,
+/// or reported as an error depending on `options.locked`. Files whose size or modification time
+/// changed while they were read are skipped as unstable, after one more attempt if
+/// `options.retry_unstable` is set. Files with an unchanged hash in `sums` are not read at all,
+/// unless `options.reference_set` is set, except for a random sample of `options.verify_sums`
+/// of them, which are read to check the recorded hash.
+///
+/// # Parameters
+/// - `side`: The directory the file belongs to.
+/// - `path`: The file to read.
+/// - `jobs`: The channel feeding the hasher threads.
+/// - `buffers`: Buffers handed back by the hashers for reuse.
+/// - `options`: The options controlling the comparison.
+/// - `sums`: The recorded hashes to use, if any.
+/// - `gate`: The limit of files read at once, if any.
+///
+/// # Returns
+/// False if no hasher is left to receive the job, true otherwise.
+fn read_file(
+    side: Side,
+    path: PathBuf,
+    jobs: &Sender<HashJob>,
+    buffers: &Receiver<Vec<u8>>,
+    options: &CompareOptions,
+    sums: Option<&SumsStore>,
+    gate: Option<&ReadGate>,
+) -> bool {
+    let (sender, receiver) = bounded(CHUNKS_IN_FLIGHT);
+
+    // Files without a stamp are read, and their hash is not recorded. They are not checked for
+    // modifications either.
+    let mut stamp = FileStamp::read(&path).ok();
+    let known = sums
+        .filter(|_| options.reference_set.is_none())
+        .zip(stamp.as_ref())
+        .and_then(|(sums, stamp)| sums.lookup(&path, stamp));
+
+    let known = known
+        .zip(stamp)
+        .map(|(hash, stamp)| (hash, stamp, sampled(&path, options.verify_sums)));
+
+    // Entering the gate only after queueing the job would let jobs of waiting readers fill the
+    // hashers, which then wait for chunks that are never read
+    let _permit = match known {
+        Some((_, _, false)) => None,
+        _ => gate.map(ReadGate::enter),
+    };
+
+    let job = HashJob {
+        side,
+        path: path.clone(),
+        chunks: receiver,
+        stamp: sums.and(stamp),
+    };
+    if jobs.send(job).is_err() {
+        return false;
+    }
+
+    if let Some((hash, stamp, sampled)) = known {
+        if !sampled {
+            let _ = sender.send(Chunk::Known(hash, stamp.size));
+            return true;
+        }
+        if sender.send(Chunk::Verify(hash)).is_err() {
+            return true;
+        }
+    }
+
+    let mut attempt = 0;
+    let mut retried_unstable = false;
+    let mut locked_since = None;
+    loop {
+        match stream_file(&path, &sender, buffers, options, gate) {
+            Ok(()) => {
+                let current = FileStamp::read(&path).ok();
+                if stamp.is_none() || current == stamp {
+                    return true;
+                }
+                if options.retry_unstable && !retried_unstable {
+                    retried_unstable = true;
+                    stamp = current;
+                    if sender.send(Chunk::Restart).is_err() {
+                        return true;
+                    }
+                } else {
+                    let _ = sender.send(Chunk::Skipped(SkipReason::Unstable));
+                    return true;
+                }
+            }
+            Err(err)
+                if is_locked_error(&err)
+                    && options.locked == LockedPolicy::Wait
+                    && locked_since.get_or_insert_with(Instant::now).elapsed()
+                        < options.lock_timeout =>
+            {
+                thread::sleep(options.retry_delay);
+                if sender.send(Chunk::Restart).is_err() {
+                    return true;
+                }
+            }
+            Err(err) if is_locked_error(&err) && options.locked == LockedPolicy::Skip => {
+                let _ = sender.send(Chunk::Skipped(SkipReason::Locked));
+                return true;
+            }
+            Err(err) if attempt < options.retries && is_transient_error(&err) => {
+                thread::sleep(options.retry_delay.saturating_mul(1 << attempt.min(16)));
+                attempt += 1;
+                if sender.send(Chunk::Restart).is_err() {
+                    return true;
+                }
+            }
+            Err(err) => {
+                let _ = sender.send(Chunk::Failed(err));
+                return true;
+            }
+        }
+    }
+}
+
+/// Receives file paths from two channels and streams the contents of each file to the hashers.
+///
+/// When one channel is closed, it drains the other channel. Reading stops early if all hashers
+/// have exited.
+///
+/// # Parameters
+/// - `r1`: Receiver for file paths of the first directory.
+/// - `r2`: Receiver for file paths of the second directory.
+/// - `jobs`: The channel feeding the hasher threads.
+/// - `buffers`: Buffers handed back by the hashers for reuse.
+/// - `options`: The options controlling the comparison.
+/// - `sums`: The recorded hashes to use, if any.
+/// - `gate`: The limit of files read at once, if any.
+pub fn read_files(
+    r1: &Receiver<PathBuf>,
+    r2: &Receiver<PathBuf>,
+    jobs: &Sender<HashJob>,
+    buffers: &Receiver<Vec<u8>>,
+    options: &CompareOptions,
+    sums: Option<&SumsStore>,
+    gate: Option<&ReadGate>,
+) {
+    loop {
+        select! {
+            recv(r1) -> msg => {
+                if let Ok(path) = msg {
+                    if !read_file(Side::Dir1, path, jobs, buffers, options, sums, gate) {
+                        return;
+                    }
+                } else {
+                    for path in r2 {
+                        if !read_file(Side::Dir2, path, jobs, buffers, options, sums, gate) {
+                            return;
+                        }
+                    }
+                    return;
+                }
+            },
+            recv(r2) -> msg => {
+                if let Ok(path) = msg {
+                    if !read_file(Side::Dir2, path, jobs, buffers, options, sums, gate) {
+                        return;
+                    }
+                } else {
+                    for path in r1 {
+                        if !read_file(Side::Dir1, path, jobs, buffers, options, sums, gate) {
+                            return;
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Creates the error reported when two files with the same hash differ in size.
+///
+/// Files with the same hash must have the same content, so this indicates a hash collision or a
+/// bug in filematch, and the comparison cannot be trusted.
+pub(crate) fn size_mismatch(hash: &Hash, files: [(&Path, u64); 2]) -> io::Error {
+    let [(path1, size1), (path2, size2)] = files;
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "'{}' ({size1} bytes) and '{}' ({size2} bytes) have the same hash {hash}; \
+             this is a hash collision or a bug in filematch",
+            path1.display(),
+            path2.display()
+        ),
+    )
+}
+
+/// Hashes the files streamed by the readers and groups their paths by hash.
+///
+/// Files with the same hash are checked to have the same size, as a safety net against hash
+/// collisions and bugs.
+///
+/// # Parameters
+/// - `jobs`: The channel the readers queue files on.
+/// - `buffers`: The channel used to hand consumed buffers back to the readers.
+/// - `bases1`: Base directories for file paths from the first side. Paths are made relative to
+///   the longest base they are in.
+/// - `bases2`: Base directories for file paths from the second side.
+/// - `sums`: Where to record the hashes of the files read, if anywhere.
+/// - `reference`: The reference set whose files are left out, if any.
+/// - `errors`: Whether files that cannot be read fail the comparison or are collected.
+/// - `roots`: The roots of the comparison, if watched. Files that cannot be read as their root
+///   was lost are collected whatever `errors` says.
+/// - `progress`: Where to count and report the files hashed, if anywhere.
+/// - `slow_files`: The number of files that took longest to hash to keep, 0 to time none.
+///
+/// # Returns
+/// A Result containing the file paths of both sides grouped by their computed hash, the files
+/// that were skipped, and those that could not be read if errors are collected.
+///
+/// # Errors
+/// This function returns `FileMatchError::Hash` with the first file a reader failed to read
+/// unless errors are collected, or an error of kind `InvalidData` if two files with the same
+/// hash differ in size.
+#[allow(clippy::too_many_arguments)]
+pub fn hash_files(
+    jobs: &Receiver<HashJob>,
+    buffers: &Sender<Vec<u8>>,
+    bases1: &[PathBuf],
+    bases2: &[PathBuf],
+    sums: Option<&SumsStore>,
+    reference: Option<&ReferenceSet>,
+    errors: ErrorPolicy,
+    roots: Option<&RootWatch>,
+    progress: Option<&ProgressCounter>,
+    slow_files: usize,
+) -> Result<HashedFiles, FileMatchError> {
+    let mut hashed = HashedFiles::default();
+
+    'jobs: for job in jobs {
+        let started = Instant::now();
+        let base = match job.side {
+            Side::Dir1 => matching_root(&job.path, bases1),
+            Side::Dir2 => matching_root(&job.path, bases2),
+        };
+
+        let mut hasher = Hasher::new();
+        let reference_hasher = || reference.map(|set| MultiHasher::new(&[set.algorithm()]));
+        let mut digest = reference_hasher();
+        let mut size = 0;
+        let mut hashed_bytes = 0;
+        let mut known = None;
+        let mut ranged = None;
+        // Hashes taken from a sums file are already recorded there
+        let mut from_sums = false;
+        let mut expected = None;
+        for chunk in &job.chunks {
+            match chunk {
+                Chunk::Data(data) => {
+                    hasher.update(&data);
+                    if let Some(digest) = &mut digest {
+                        digest.update(&data);
+                    }
+                    size += data.len() as u64;
+                    hashed_bytes += data.len() as u64;
+                    let _ = buffers.send(data);
+                }
+                Chunk::Restart => {
+                    hasher.reset();
+                    digest = reference_hasher();
+                    size = 0;
+                    hashed_bytes = 0;
+                    known = None;
+                    ranged = None;
+                }
+                Chunk::Failed(source)
+                    if errors == ErrorPolicy::CollectAndContinue
+                        || roots.is_some_and(|roots| roots.is_lost(job.side, &job.path)) =>
+                {
+                    hashed.read_errors.push(ReadError {
+                        side: job.side,
+                        path: relative_path(job.path, base),
+                        message: source.to_string(),
+                    });
+                    continue 'jobs;
+                }
+                Chunk::Failed(source) => {
+                    return Err(FileMatchError::Hash {
+                        path: job.path,
+                        source,
+                    })
+                }
+                Chunk::Skipped(reason) => {
+                    hashed.skipped.push(SkippedFile {
+                        side: job.side,
+                        path: relative_path(job.path, base),
+                        reason,
+                    });
+                    continue 'jobs;
+                }
+                Chunk::Known(hash, known_size) => {
+                    known = Some(hash);
+                    size = known_size;
+                    from_sums = true;
+                }
+                Chunk::Hashed(hash, hashed_size) => {
+                    known = Some(hash);
+                    size = hashed_size;
+                    hashed_bytes = hashed_size;
+                }
+                Chunk::Verify(hash) => expected = Some(hash),
+                Chunk::Ranged(file_size) => ranged = Some(file_size),
+            }
+        }
+        if let Some(file_size) = ranged {
+            hasher.update(RANGE_HASH_DOMAIN);
+            hasher.update(&file_size.to_le_bytes());
+            size = file_size;
+        }
+        let hash = known.unwrap_or_else(|| hasher.finalize());
+        let duration = started.elapsed();
+        if let Some(progress) = progress {
+            progress.file_hashed(job.side, &job.path, hashed_bytes, duration);
+        }
+        if slow_files > 0 {
+            hashed.slow_files.push(FileTiming {
+                side: job.side,
+                path: relative_path(job.path.clone(), base),
+                duration,
+            });
+            // Prune once in a while rather than for every file
+            if hashed.slow_files.len() >= 2 * slow_files {
+                keep_slowest(&mut hashed.slow_files, slow_files);
+            }
+        }
+        if let Some(expected) = expected {
+            hashed.sums_check.checked += 1;
+            if expected != hash {
+                hashed
+                    .sums_check
+                    .stale
+                    .push(relative_path(job.path.clone(), base));
+            }
+        }
+        // A file that changed while it was read must not be recorded with its old stamp
+        if let (Some(sums), Some(stamp), false) = (sums, job.stamp, from_sums) {
+            if FileStamp::read(&job.path).is_ok_and(|current| current == stamp) {
+                sums.record(&job.path, stamp, hash)?;
+            }
+        }
+
+        // Known files are left out of both sides
+        let in_reference = reference.zip(digest).is_some_and(|(set, digest)| {
+            digest
+                .finalize()
+                .first()
+                .and_then(|hex| from_hex(hex))
+                .is_some_and(|digest| set.contains(&digest))
+        });
+        if in_reference {
+            hashed.excluded.push(SkippedFile {
+                side: job.side,
+                path: relative_path(job.path, base),
+                reason: SkipReason::Reference,
+            });
+            continue;
+        }
+
+        let known_size = *hashed.sizes.entry(hash).or_insert(size);
+        if known_size != size {
+            let known_path = hashed
+                .map1
+                .get(&hash)
+                .or_else(|| hashed.map2.get(&hash))
+                .and_then(|paths| paths.first());
+            if let Some(known_path) = known_path {
+                return Err(
+                    size_mismatch(&hash, [(known_path, known_size), (&job.path, size)]).into(),
+                );
+            }
+        }
+
+        if ranged.is_some() {
+            let files = hashed.ranged.entry(hash).or_default();
+            files.push((job.side, job.path.clone()));
+        }
+        match job.side {
+            Side::Dir1 => insert_path(&mut hashed.map1, hash, job.path, base),
+            Side::Dir2 => insert_path(&mut hashed.map2, hash, job.path, base),
+        }
+    }
+
+    keep_slowest(&mut hashed.slow_files, slow_files);
+    Ok(hashed)
+}
@@ -0,0 +1,387 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::audit_log::{hash_file, AuditLog, Operation};
+use crate::compare_two_directories::files_identical;
+use crate::dedupe::DedupeAction;
+
+/// Version of the plan format written by [`Plan::to_json`].
+const PLAN_VERSION: u64 = 1;
+
+/// Suffix of the temporary link created next to a file before it replaces the file.
+const LINK_SUFFIX: &str = ".filematch-link";
+
+/// A change to a file that duplicates another, reviewed before it is applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanStep {
+    /// What is done with the duplicate.
+    pub action: DedupeAction,
+    /// The duplicate, which is changed.
+    pub path: PathBuf,
+    /// The file with the same content, which is kept.
+    pub original: PathBuf,
+    /// The size of the content in bytes.
+    pub size: u64,
+    /// Where the duplicate is moved to, for `DedupeAction::Trash`.
+    pub target: Option<PathBuf>,
+}
+
+/// Changes to the filesystem, written out to be reviewed and applied later with [`apply_plan`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// The changes, in the order they are applied.
+    pub steps: Vec<PlanStep>,
+}
+
+/// How a step of a plan ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StepOutcome {
+    /// The change was made.
+    Done,
+    /// The duplicate no longer has the contents of the original, so it was left alone.
+    Changed,
+    /// The duplicate is the original itself, or a symbolic link to it, so it was left alone.
+    SameFile,
+    /// The duplicate no longer exists.
+    Gone,
+}
+
+impl StepOutcome {
+    /// Returns the name of the outcome, as recorded in the journal.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Done => "done",
+            Self::Changed => "changed",
+            Self::SameFile => "same-file",
+            Self::Gone => "gone",
+        }
+    }
+
+    /// Looks up an outcome by its name.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "done" => Some(Self::Done),
+            "changed" => Some(Self::Changed),
+            "same-file" => Some(Self::SameFile),
+            "gone" => Some(Self::Gone),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of applying a plan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Applied {
+    /// The steps applied by this run, each with its outcome, in the order of the plan.
+    pub steps: Vec<(PlanStep, StepOutcome)>,
+    /// The number of steps skipped because the journal records them from an earlier run.
+    pub resumed: usize,
+}
+
+impl Applied {
+    /// Returns the steps of this run with an outcome.
+    pub fn with_outcome(&self, outcome: StepOutcome) -> impl Iterator<Item = &PlanStep> {
+        self.steps
+            .iter()
+            .filter(move |(_, step_outcome)| *step_outcome == outcome)
+            .map(|(step, _)| step)
+    }
+}
+
+impl Plan {
+    /// Converts the plan to JSON, an object with the format version and the steps.
+    ///
+    /// Paths that are not valid UTF-8 are written lossily.
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        let steps: Vec<Value> = self
+            .steps
+            .iter()
+            .map(|step| {
+                json!({
+                    "action": step.action.name(),
+                    "path": step.path.to_string_lossy(),
+                    "original": step.original.to_string_lossy(),
+                    "size": step.size,
+                    "target": step.target.as_ref().map(|target| target.to_string_lossy()),
+                })
+            })
+            .collect();
+        json!({ "version": PLAN_VERSION, "steps": steps })
+    }
+
+    /// Reads a plan from JSON written by [`Plan::to_json`].
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` of kind `InvalidData` if the JSON is not a plan of a
+    /// known version, naming the step that is not understood.
+    pub fn from_json(value: &Value) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if value.get("version").and_then(Value::as_u64) != Some(PLAN_VERSION) {
+            return Err(invalid(format!(
+                "expected a plan of version {PLAN_VERSION}"
+            )));
+        }
+        let steps = value
+            .get("steps")
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid("expected the steps of a plan".to_string()))?;
+
+        let steps = steps
+            .iter()
+            .enumerate()
+            .map(|(index, step)| {
+                let text = |key: &str| step.get(key).and_then(Value::as_str);
+                let invalid = || invalid(format!("plan step {}: expected a step", index + 1));
+                let action = match text("action") {
+                    Some("remove") => DedupeAction::Remove,
+                    Some("trash") => DedupeAction::Trash,
+                    Some("hardlink") => DedupeAction::Hardlink,
+                    Some("symlink") => DedupeAction::Symlink,
+                    _ => return Err(invalid()),
+                };
+                let target = text("target").map(PathBuf::from);
+                if action == DedupeAction::Trash && target.is_none() {
+                    return Err(invalid());
+                }
+                Ok(PlanStep {
+                    action,
+                    path: text("path").map(PathBuf::from).ok_or_else(invalid)?,
+                    original: text("original").map(PathBuf::from).ok_or_else(invalid)?,
+                    size: step
+                        .get("size")
+                        .and_then(Value::as_u64)
+                        .ok_or_else(invalid)?,
+                    target,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self { steps })
+    }
+
+    /// Writes the plan to a file as pretty-printed JSON.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` naming the file if it cannot be written.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let text = serde_json::to_string_pretty(&self.to_json()).map_err(io::Error::other)?;
+        fs::write(path, text + "\n")
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))
+    }
+
+    /// Reads a plan written by [`Plan::write`].
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` naming the file if it cannot be read or is not a
+    /// plan.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let named =
+            |err: io::Error| io::Error::new(err.kind(), format!("{}: {err}", path.display()));
+        let text = fs::read_to_string(path).map_err(named)?;
+        let value: Value = serde_json::from_str(&text)
+            .map_err(|err| named(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+        Self::from_json(&value).map_err(named)
+    }
+
+    /// Returns the BLAKE3 hash of the plan, which ties a journal to the plan it records.
+    fn fingerprint(&self) -> String {
+        blake3::hash(self.to_json().to_string().as_bytes())
+            .to_hex()
+            .to_string()
+    }
+}
+
+/// Applies the steps of a plan, recording each one in a journal so an interrupted run can be
+/// resumed.
+///
+/// Before a duplicate is touched, it is compared byte by byte with its original, see
+/// [`files_identical`], and left alone if the two differ or are the same file. Duplicates that
+/// no longer exist are skipped. Links replace the duplicate atomically, and trashed files are
+/// moved to their target, creating its directories, and copied if the target is on another
+/// filesystem. Every step is appended to `journal` as soon as it ended, after a first line tying
+/// the journal to the plan. Steps already recorded in the journal are not applied again, and a
+/// step interrupted before it was recorded is checked again, so it is safe to apply a plan
+/// until it finishes. Every change made or failed is recorded in `log` as well, so it can be
+/// undone with [`crate::audit_log::undo`].
+///
+/// # Parameters
+/// - `plan`: The plan to apply.
+/// - `journal`: The journal of the plan, created if it does not exist, or None to keep none.
+/// - `log`: The audit log every change is recorded in, if any.
+///
+/// # Returns
+/// The steps applied by this run with their outcomes, and the number of steps applied before.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the file if a file cannot be read, removed,
+/// moved or linked, or the journal cannot be read or written, and one of kind `InvalidData` if
+/// the journal belongs to another plan. The steps applied before the error are recorded in the
+/// journal. Failing to write the audit log is an error as well.
+pub fn apply_plan(
+    plan: &Plan,
+    journal: Option<&Path>,
+    mut log: Option<&mut AuditLog>,
+) -> io::Result<Applied> {
+    let fingerprint = plan.fingerprint();
+    let mut recorded = HashMap::new();
+    let mut journal = journal
+        .map(|path| -> io::Result<_> {
+            let named =
+                |err: io::Error| io::Error::new(err.kind(), format!("{}: {err}", path.display()));
+            recorded = read_journal(path, &fingerprint).map_err(named)?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(named)?;
+            if recorded.is_empty() && file.metadata().map_err(named)?.len() == 0 {
+                writeln!(file, "plan {fingerprint}").map_err(named)?;
+            }
+            Ok(file)
+        })
+        .transpose()?;
+
+    let mut applied = Applied::default();
+    for (index, step) in plan.steps.iter().enumerate() {
+        if recorded.contains_key(&index) {
+            applied.resumed += 1;
+            continue;
+        }
+        let outcome = apply_step(step, log.as_deref_mut())
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", step.path.display())))?;
+        if let Some(journal) = &mut journal {
+            journal.write_all(format!("{index} {}\n", outcome.name()).as_bytes())?;
+        }
+        applied.steps.push((step.clone(), outcome));
+    }
+    Ok(applied)
+}
+
+/// Reads the steps recorded in the journal of a plan, keyed by their index.
+fn read_journal(path: &Path, fingerprint: &str) -> io::Result<HashMap<usize, StepOutcome>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err),
+    };
+    let mut lines = text.lines();
+    match lines.next() {
+        None => return Ok(HashMap::new()),
+        Some(line) if line.strip_prefix("plan ") == Some(fingerprint) => {}
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the journal belongs to another plan",
+            ))
+        }
+    }
+
+    // A line cut short by an interruption is ignored, so its step is checked again
+    Ok(lines
+        .filter_map(|line| {
+            let (index, outcome) = line.split_once(' ')?;
+            Some((index.parse().ok()?, StepOutcome::from_name(outcome)?))
+        })
+        .collect())
+}
+
+/// Applies a single step after checking that the duplicate still matches its original, recording
+/// the change in `log`, if given.
+fn apply_step(step: &PlanStep, log: Option<&mut AuditLog>) -> io::Result<StepOutcome> {
+    let path = &step.path;
+    if fs::symlink_metadata(path).is_err_and(|err| err.kind() == io::ErrorKind::NotFound) {
+        return Ok(StepOutcome::Gone);
+    }
+    // Overlapping roots or an earlier run with symbolic links
+    let canonical = |path: &Path| fs::canonicalize(path).ok();
+    if canonical(path).is_some_and(|path| Some(path) == canonical(&step.original)) {
+        return Ok(StepOutcome::SameFile);
+    }
+    if !files_identical(&step.original, path)? {
+        return Ok(StepOutcome::Changed);
+    }
+
+    let Some(log) = log else {
+        return change(step).map(|()| StepOutcome::Done);
+    };
+    // The original keeps the contents, which a trashed file takes along as well
+    let hash = hash_file(&step.original)?;
+    let changed = change(step);
+    let (operation, source, target) = match step.action {
+        DedupeAction::Remove => (Operation::Remove, &step.original, path),
+        DedupeAction::Trash => (Operation::Trash, path, step.target.as_ref().unwrap_or(path)),
+        DedupeAction::Hardlink => (Operation::Hardlink, &step.original, path),
+        DedupeAction::Symlink => (Operation::Symlink, &step.original, path),
+    };
+    let error = changed.as_ref().err().map(ToString::to_string);
+    log.record(
+        operation,
+        source,
+        target,
+        &hash,
+        step.size,
+        error.as_deref(),
+    )?;
+    changed.map(|()| StepOutcome::Done)
+}
+
+/// Makes the change of a step.
+fn change(step: &PlanStep) -> io::Result<()> {
+    let path = &step.path;
+    match step.action {
+        DedupeAction::Remove => fs::remove_file(path)?,
+        DedupeAction::Trash => {
+            let target = step
+                .target
+                .as_deref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no trash directory"))?;
+            move_file(path, target)?;
+        }
+        DedupeAction::Hardlink | DedupeAction::Symlink => {
+            replace_with_link(&step.original, path, step.action)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves a file, creating the directories of the target, and copying it to another filesystem.
+pub(crate) fn move_file(path: &Path, target: &Path) -> io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if target.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists", target.display()),
+        ));
+    }
+    if fs::rename(path, target).is_err() {
+        fs::copy(path, target)?;
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Replaces a file with a link to the original, atomically.
+fn replace_with_link(original: &Path, path: &Path, action: DedupeAction) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(LINK_SUFFIX);
+    let temporary = PathBuf::from(temporary);
+
+    if action == DedupeAction::Hardlink {
+        fs::hard_link(original, &temporary)?;
+    } else {
+        let original = std::path::absolute(original)?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(original, &temporary)?;
+        #[cfg(windows)]
+        std::os::windows::fs::symlink_file(original, &temporary)?;
+    }
+    fs::rename(&temporary, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
@@ -0,0 +1,149 @@
+use blake3::Hash;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::compare_two_directories::{compare_multiple_roots, partition_map_values};
+use crate::options::CompareOptions;
+use crate::pipeline::Side;
+use crate::result::{modified_paths, ComparisonResult};
+
+/// Creates the error reported for grouped JSON output that cannot be understood.
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the files of one side of a previous run from its grouped JSON output.
+///
+/// The output is the one written by `--format json-grouped`. Plain `--json` output does not
+/// record the hashes of the files, so it cannot be read.
+///
+/// # Parameters
+/// - `reader`: The grouped JSON output.
+/// - `side`: The directory of the previous run to read the files of.
+///
+/// # Returns
+/// The paths of the files of `side`, as displayed by the previous run, grouped by hash.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, or an error of kind `InvalidData` if
+/// the input is not grouped JSON output.
+pub fn read_grouped_json(reader: impl Read, side: Side) -> io::Result<HashMap<Hash, Vec<PathBuf>>> {
+    let value: Value = serde_json::from_reader(reader)?;
+    let Value::Array(groups) = value else {
+        return Err(invalid(
+            "expected grouped JSON output; write it with --format json-grouped, as --json \
+             output has no hashes",
+        ));
+    };
+    let key = match side {
+        Side::Dir1 => "directory1",
+        Side::Dir2 => "directory2",
+    };
+
+    let mut files: HashMap<Hash, Vec<PathBuf>> = HashMap::new();
+    for group in &groups {
+        let hash = group
+            .get("hash")
+            .and_then(Value::as_str)
+            .and_then(|hash| Hash::from_hex(hash).ok())
+            .ok_or_else(|| invalid("a group has no valid hash"))?;
+        let paths = group
+            .get(key)
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid(&format!("a group has no {key} files")))?;
+
+        for file in paths {
+            let path = file
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or_else(|| invalid("a file has no path"))?;
+            files.entry(hash).or_default().push(PathBuf::from(path));
+        }
+    }
+
+    Ok(files)
+}
+
+/// Compares a directory against the files recorded by a previous run.
+///
+/// The directory takes the place of the first side, and the previous files the place of the
+/// second side. Content present in both is unchanged since the previous run, content unique to
+/// the directory is new, and content unique to the previous files is gone. The paths of the
+/// previous files are returned as recorded.
+///
+/// # Parameters
+/// - `directory`: The directory to compare.
+/// - `previous`: The files of the previous run grouped by hash, see [`read_grouped_json`].
+/// - `options`: The options controlling the comparison. Groups are not computed.
+///
+/// # Returns
+/// A Result containing the file paths present in both, unique to `directory`, and unique to
+/// `previous` (each if requested), the files that were skipped, and the paths that could not be
+/// read.
+///
+/// # Errors
+/// This function returns an `io::Error` if the directory cannot be compared, see
+/// [`compare_multiple_roots`].
+pub fn compare_against(
+    directory: &Path,
+    previous: &HashMap<Hash, Vec<PathBuf>>,
+    options: &CompareOptions,
+) -> io::Result<ComparisonResult> {
+    let scanned = compare_multiple_roots(
+        std::slice::from_ref(&directory.to_path_buf()),
+        &[],
+        &CompareOptions {
+            include_intersection: false,
+            include_unique_dir1: false,
+            include_unique_dir2: false,
+            include_groups: true,
+            ..options.clone()
+        },
+    )?;
+
+    let current: HashMap<[u8; 32], Vec<PathBuf>> = scanned
+        .groups
+        .unwrap_or_default()
+        .into_iter()
+        .map(|group| (*group.hash.as_bytes(), group.dir1))
+        .collect();
+    let previous: HashMap<[u8; 32], Vec<PathBuf>> = previous
+        .iter()
+        .filter(|(hash, _)| !options.ignore_hashes.contains(*hash))
+        .map(|(hash, paths)| (*hash.as_bytes(), paths.clone()))
+        .collect();
+
+    let (mut intersection, mut unique_dir1, mut unique_dir2) = partition_map_values(
+        &current,
+        &previous,
+        options.include_intersection,
+        options.include_unique_dir1,
+        options.include_unique_dir2,
+    );
+
+    if options.sort {
+        for paths in [&mut intersection, &mut unique_dir1, &mut unique_dir2]
+            .into_iter()
+            .flatten()
+        {
+            paths.sort();
+        }
+    }
+
+    let modified = if options.relative {
+        modified_paths(unique_dir1.as_deref(), unique_dir2.as_deref())
+    } else {
+        None
+    };
+
+    Ok(ComparisonResult {
+        intersection,
+        unique_dir1,
+        unique_dir2,
+        modified,
+        groups: None,
+        ..scanned
+    })
+}
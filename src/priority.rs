@@ -0,0 +1,124 @@
+use std::io;
+
+/// How much disk time the process asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IoPriority {
+    /// Read only while no other process uses the disk.
+    Idle,
+}
+
+/// Creates the error reported on platforms without a priority of the requested kind.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{what} is not supported on this platform"),
+    )
+}
+
+/// Lowers the CPU priority of the process, like `nice`.
+///
+/// On Linux the priority is set per thread and inherited by the threads spawned later, so call
+/// this before spawning any. On Windows, levels from 1 to 14 select the below normal priority
+/// class and higher levels the idle priority class.
+///
+/// # Parameters
+/// - `level`: The niceness from 0 (unchanged) to 19 (lowest priority).
+///
+/// # Errors
+/// This function returns an `io::Error` if the priority cannot be changed, or an error of kind
+/// `Unsupported` on platforms other than Unix and Windows.
+pub fn set_nice(level: u8) -> io::Result<()> {
+    let level = level.min(19);
+
+    #[cfg(unix)]
+    {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, libc::c_int::from(level)) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::System::Threading::{
+            GetCurrentProcess, SetPriorityClass, BELOW_NORMAL_PRIORITY_CLASS, IDLE_PRIORITY_CLASS,
+        };
+
+        if level == 0 {
+            return Ok(());
+        }
+        let class = if level < 15 {
+            BELOW_NORMAL_PRIORITY_CLASS
+        } else {
+            IDLE_PRIORITY_CLASS
+        };
+        if unsafe { SetPriorityClass(GetCurrentProcess(), class) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = level;
+        Err(unsupported("changing the CPU priority"))
+    }
+}
+
+/// Lowers the disk priority of the process, like `ionice`.
+///
+/// On Linux this selects the I/O scheduling class, which is set per thread and inherited by the
+/// threads spawned later, so call this before spawning any. macOS and Windows have no separate
+/// disk priority: the process is moved to the background there instead, which throttles its disk
+/// and CPU use alike.
+///
+/// # Parameters
+/// - `priority`: The disk priority to use.
+///
+/// # Errors
+/// This function returns an `io::Error` if the priority cannot be changed, or an error of kind
+/// `Unsupported` on other platforms.
+pub fn set_io_priority(priority: IoPriority) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+        let IoPriority::Idle = priority;
+        let value = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let IoPriority::Idle = priority;
+        if unsafe { libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::System::Threading::{
+            GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+        };
+
+        let IoPriority::Idle = priority;
+        if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    {
+        let IoPriority::Idle = priority;
+        Err(unsupported("changing the disk priority"))
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file defining profiles in the configuration directory of filematch.
+pub const PROFILES_FILE_NAME: &str = "profiles";
+
+/// The option selecting a profile on the command line.
+const PROFILE_OPTION: &str = "--profile";
+
+/// The profiles available without a configuration file, with their options.
+pub const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    ("photos", "--strip-metadata --skip-hidden --relative --sort"),
+    ("code", "--skip-hidden --relative --sort --path-style unix"),
+    (
+        "backup-verify",
+        "--dir1 --relative --sort --fail-on-walk-error --recheck 1%",
+    ),
+];
+
+/// Named sets of command line options, selected with `--profile NAME`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profiles {
+    profiles: BTreeMap<String, Vec<String>>,
+}
+
+impl Profiles {
+    /// Returns the built-in profiles.
+    #[must_use]
+    pub fn builtin() -> Self {
+        let profiles = BUILTIN_PROFILES
+            .iter()
+            .map(|(name, options)| {
+                let options = options.split_whitespace().map(String::from).collect();
+                ((*name).to_string(), options)
+            })
+            .collect();
+        Profiles { profiles }
+    }
+
+    /// Returns the built-in profiles together with those defined in a profiles file.
+    ///
+    /// Each line of the file defines a profile as its name, an equals sign, and its options
+    /// separated by whitespace, e.g. `music = --strip-metadata --intersection`. Empty lines and
+    /// lines starting with `#` are ignored. A profile defined in the file replaces a built-in
+    /// profile of the same name. A missing file defines no profiles.
+    ///
+    /// # Parameters
+    /// - `path`: The profiles file.
+    ///
+    /// # Returns
+    /// The profiles.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` if the file cannot be read, or of kind
+    /// `InvalidData` naming the line if a line is not a profile definition.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut profiles = Self::builtin();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(profiles),
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("{}: {err}", path.display()),
+                ))
+            }
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} line {}: {message}", path.display(), number + 1),
+                )
+            };
+            let (name, options) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected NAME = OPTIONS"))?;
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(invalid("a profile name cannot be empty or contain spaces"));
+            }
+            let options: Vec<String> = options.split_whitespace().map(String::from).collect();
+            if options
+                .iter()
+                .any(|option| option == PROFILE_OPTION || option.starts_with("--profile="))
+            {
+                return Err(invalid("a profile cannot select another profile"));
+            }
+            profiles.profiles.insert(name.to_string(), options);
+        }
+        Ok(profiles)
+    }
+
+    /// Returns the options of a profile, or None if there is no profile of that name.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&[String]> {
+        self.profiles.get(name).map(Vec::as_slice)
+    }
+
+    /// Returns the names of all profiles, sorted.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    /// Inserts the options of the selected profiles into a command line.
+    ///
+    /// The options of a profile are inserted right before the `--profile NAME` selecting it,
+    /// which is kept, so options given after it on the command line take precedence. Arguments
+    /// after `--` are left as they are.
+    ///
+    /// # Parameters
+    /// - `args`: The command line, starting with the program name.
+    ///
+    /// # Returns
+    /// The command line with the options of the profiles.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` of kind `InvalidInput` if a selected profile does
+    /// not exist.
+    pub fn expand(&self, args: impl IntoIterator<Item = OsString>) -> io::Result<Vec<OsString>> {
+        let mut expanded = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                expanded.push(arg);
+                expanded.extend(args.by_ref());
+                break;
+            }
+
+            let name = if arg == PROFILE_OPTION {
+                // A missing name is left for the parser to report
+                let Some(name) = args.next() else {
+                    expanded.push(arg);
+                    break;
+                };
+                name
+            } else if let Some(name) = arg.to_str().and_then(|arg| arg.strip_prefix("--profile=")) {
+                OsString::from(name)
+            } else {
+                expanded.push(arg);
+                continue;
+            };
+
+            let options = name
+                .to_str()
+                .and_then(|name| self.get(name))
+                .ok_or_else(|| {
+                    let names: Vec<&str> = self.names().collect();
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "unknown profile '{}', available: {}",
+                            name.to_string_lossy(),
+                            names.join(", ")
+                        ),
+                    )
+                })?;
+            expanded.extend(options.iter().map(OsString::from));
+            expanded.push(OsString::from(PROFILE_OPTION));
+            expanded.push(name);
+        }
+        Ok(expanded)
+    }
+}
+
+/// Returns the default location of the profiles file: `filematch/profiles` in
+/// `$XDG_CONFIG_HOME`, `%APPDATA%` on Windows, or `~/.config`.
+///
+/// # Returns
+/// The file, or None if the home directory is unknown.
+#[must_use]
+pub fn default_profiles_file() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(config.join("filematch").join(PROFILES_FILE_NAME))
+}
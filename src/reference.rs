@@ -0,0 +1,249 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+use crate::checksum::{from_hex, Algorithm};
+
+/// Bytes every reference index starts with.
+const INDEX_MAGIC: &[u8; 8] = b"FMREFIX1";
+
+/// Length of the header of a reference index: the magic bytes and the name of the algorithm,
+/// padded with zeros.
+const HEADER_LEN: usize = 16;
+
+/// Creates the error reported for reference lists and indexes that cannot be understood.
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses the digests of a reference list.
+///
+/// NSRL files (`NSRLFile.txt`) and other CSV files are recognized by a header line of quoted
+/// column names, and the digests are taken from the column named after the algorithm, such as
+/// `"SHA-1"`. Any other line starts with a digest, optionally followed by a comma or whitespace
+/// and anything else, so plain lists and `sha1sum` output can be used as they are. Empty lines
+/// and lines starting with `#` are ignored.
+///
+/// # Parameters
+/// - `reader`: The list contents.
+/// - `algorithm`: The algorithm of the digests.
+///
+/// # Returns
+/// The digests, concatenated, in the order of the list.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, or an error of kind `InvalidData` if
+/// a line has no digest of the algorithm or a header has no column for it.
+pub fn read_reference_list(reader: impl BufRead, algorithm: Algorithm) -> io::Result<Vec<u8>> {
+    let mut digests = Vec::new();
+    let mut column = 0;
+    let mut first = true;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = || {
+            line.split([',', ' ', '\t'])
+                .map(|field| field.trim_matches('"'))
+        };
+
+        // Only the first line can be a header
+        if std::mem::take(&mut first) && line.starts_with('"') {
+            let names: Vec<&str> = fields().collect();
+            if from_hex(names[0]).is_none() {
+                column = names
+                    .iter()
+                    .position(|name| Algorithm::from_name(name) == Some(algorithm))
+                    .ok_or_else(|| {
+                        invalid(format!("the header has no {} column", algorithm.name()))
+                    })?;
+                continue;
+            }
+        }
+
+        let digest = fields()
+            .nth(column)
+            .and_then(from_hex)
+            .filter(|digest| digest.len() == algorithm.digest_len())
+            .ok_or_else(|| {
+                invalid(format!(
+                    "reference list line {}: expected a {} digest",
+                    index + 1,
+                    algorithm.name()
+                ))
+            })?;
+        digests.extend(digest);
+    }
+
+    Ok(digests)
+}
+
+/// Writes a reference index, the sorted digests of a reference list in a form that can be
+/// searched without loading it, see [`ReferenceSet`].
+///
+/// # Parameters
+/// - `writer`: Where to write the index.
+/// - `algorithm`: The algorithm of the digests.
+/// - `digests`: The digests, concatenated, as read by [`read_reference_list`].
+///
+/// # Returns
+/// The number of distinct digests written.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails.
+///
+/// # Panics
+/// This function panics if the length of `digests` is not a multiple of the digest length.
+pub fn write_reference_index(
+    mut writer: impl Write,
+    algorithm: Algorithm,
+    digests: &[u8],
+) -> io::Result<usize> {
+    let width = algorithm.digest_len();
+    assert!(
+        digests.len().is_multiple_of(width),
+        "Digests must be a multiple of the digest length"
+    );
+    let mut sorted: Vec<&[u8]> = digests.chunks_exact(width).collect();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut header = [0; HEADER_LEN];
+    header[..INDEX_MAGIC.len()].copy_from_slice(INDEX_MAGIC);
+    let name = algorithm.name().as_bytes();
+    header[INDEX_MAGIC.len()..INDEX_MAGIC.len() + name.len()].copy_from_slice(name);
+    writer.write_all(&header)?;
+    for digest in &sorted {
+        writer.write_all(digest)?;
+    }
+    writer.flush()?;
+
+    Ok(sorted.len())
+}
+
+/// Builds a reference index from a reference list, such as an NSRL file.
+///
+/// # Parameters
+/// - `list`: The reference list, see [`read_reference_list`].
+/// - `algorithm`: The algorithm of the digests to index.
+/// - `output`: The index file to write.
+///
+/// # Returns
+/// The number of distinct digests indexed.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the file that cannot be read, parsed or written.
+pub fn build_reference_index(
+    list: &Path,
+    algorithm: Algorithm,
+    output: &Path,
+) -> io::Result<usize> {
+    let with_path = |path: &Path, err: io::Error| {
+        io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+    };
+    let digests = File::open(list)
+        .and_then(|file| read_reference_list(io::BufReader::new(file), algorithm))
+        .map_err(|err| with_path(list, err))?;
+
+    File::create(output)
+        .and_then(|file| write_reference_index(BufWriter::new(file), algorithm, &digests))
+        .map_err(|err| with_path(output, err))
+}
+
+/// A set of reference digests, such as the known software of the NSRL, searched in a memory
+/// mapped index without loading it.
+#[derive(Debug)]
+pub struct ReferenceSet {
+    algorithm: Algorithm,
+    index: Mmap,
+}
+
+impl ReferenceSet {
+    /// Opens a reference index written by [`write_reference_index`].
+    ///
+    /// # Parameters
+    /// - `path`: The index file.
+    ///
+    /// # Returns
+    /// The reference set.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` naming the file if it cannot be mapped, or an error
+    /// of kind `InvalidData` if it is not a reference index.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let error =
+            |err: io::Error| io::Error::new(err.kind(), format!("{}: {err}", path.display()));
+        let file = File::open(path).map_err(error)?;
+        // SAFETY: The index is only read. Modifying it while it is mapped is not supported, as
+        // for any memory mapped file.
+        let index = unsafe { Mmap::map(&file) }.map_err(error)?;
+
+        let algorithm = index
+            .get(..HEADER_LEN)
+            .filter(|header| header.starts_with(INDEX_MAGIC))
+            .and_then(|header| {
+                let name = &header[INDEX_MAGIC.len()..];
+                let end = name
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(name.len());
+                std::str::from_utf8(&name[..end]).ok()
+            })
+            .and_then(Algorithm::from_name)
+            .ok_or_else(|| error(invalid("not a reference index".to_string())))?;
+        if !(index.len() - HEADER_LEN).is_multiple_of(algorithm.digest_len()) {
+            return Err(error(invalid(
+                "the reference index is truncated".to_string(),
+            )));
+        }
+
+        Ok(Self { algorithm, index })
+    }
+
+    /// Returns the algorithm of the digests.
+    #[must_use]
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Returns the number of digests in the set.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        (self.index.len() - HEADER_LEN) / self.algorithm.digest_len()
+    }
+
+    /// Returns true if the set has no digests.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Determines if a digest is in the set.
+    ///
+    /// # Parameters
+    /// - `digest`: The digest, in bytes.
+    ///
+    /// # Returns
+    /// True if the digest is in the set, false otherwise.
+    #[must_use]
+    pub fn contains(&self, digest: &[u8]) -> bool {
+        let width = self.algorithm.digest_len();
+        let digests = &self.index[HEADER_LEN..];
+        let (mut low, mut high) = (0, self.len());
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match digests[middle * width..(middle + 1) * width].cmp(digest) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+
+        false
+    }
+}
@@ -0,0 +1,1239 @@
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::pipeline::Side;
+use crate::util::matching_root;
+
+/// Why a file was left out of the comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// The file was locked by another process.
+    Locked,
+    /// The file was modified while it was read.
+    Unstable,
+    /// The file or directory is hidden and hidden files are skipped.
+    Hidden,
+    /// The file or directory is excluded by the ignore file at the root.
+    IgnoreFile,
+    /// The file or directory was rejected by the walk filter.
+    Filtered,
+    /// The directory is another root of the comparison, and is compared as such.
+    NestedRoot,
+    /// The file was not modified after the time given as `modified_since`.
+    NotModified,
+    /// The file is smaller than `min_size`.
+    TooSmall,
+    /// The file is larger than `max_size`.
+    TooLarge,
+    /// The path is a link to a directory, and links are not followed.
+    Link,
+    /// The path is a link whose target does not exist.
+    BrokenLink,
+    /// The path is neither a regular file nor a directory, such as a socket or a device.
+    Special,
+    /// The content of the file has one of the ignored hashes.
+    IgnoredHash,
+    /// The file was found in the reference set.
+    Reference,
+    /// The path leads to the same file as another path differing only in case.
+    CaseAlias,
+    /// The path leads through links to a file found under another path.
+    LinkAlias,
+}
+
+impl SkipReason {
+    /// All reasons.
+    const ALL: [Self; 16] = [
+        Self::Locked,
+        Self::Unstable,
+        Self::Hidden,
+        Self::IgnoreFile,
+        Self::Filtered,
+        Self::NestedRoot,
+        Self::NotModified,
+        Self::TooSmall,
+        Self::TooLarge,
+        Self::Link,
+        Self::BrokenLink,
+        Self::Special,
+        Self::IgnoredHash,
+        Self::Reference,
+        Self::CaseAlias,
+        Self::LinkAlias,
+    ];
+
+    /// Returns the short name of the reason, as written by [`ComparisonResult::to_json`].
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Locked => "locked",
+            Self::Unstable => "unstable",
+            Self::Hidden => "hidden",
+            Self::IgnoreFile => "ignore-file",
+            Self::Filtered => "filtered",
+            Self::NestedRoot => "nested-root",
+            Self::NotModified => "not-modified",
+            Self::TooSmall => "too-small",
+            Self::TooLarge => "too-large",
+            Self::Link => "link",
+            Self::BrokenLink => "broken-link",
+            Self::Special => "special",
+            Self::IgnoredHash => "ignored-hash",
+            Self::Reference => "reference",
+            Self::CaseAlias => "case-alias",
+            Self::LinkAlias => "link-alias",
+        }
+    }
+
+    /// Looks up a reason by its short name.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|reason| reason.name() == name)
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Locked => write!(f, "locked by another process"),
+            Self::Unstable => write!(f, "modified while it was read"),
+            Self::Hidden => write!(f, "hidden"),
+            Self::IgnoreFile => write!(f, "excluded by the ignore file"),
+            Self::Filtered => write!(f, "rejected by the walk filter"),
+            Self::NestedRoot => write!(f, "compared as a separate root"),
+            Self::NotModified => write!(f, "not modified since the given time"),
+            Self::TooSmall => write!(f, "smaller than the minimum size"),
+            Self::TooLarge => write!(f, "larger than the maximum size"),
+            Self::Link => write!(f, "link to a directory, not followed"),
+            Self::BrokenLink => write!(f, "link to a missing target"),
+            Self::Special => write!(f, "not a regular file"),
+            Self::IgnoredHash => write!(f, "content has an ignored hash"),
+            Self::Reference => write!(f, "found in the reference set"),
+            Self::CaseAlias => write!(f, "same file as a path differing in case"),
+            Self::LinkAlias => write!(f, "link to a file found under another path"),
+        }
+    }
+}
+
+/// A file, or a directory with everything below it, that was found but left out of the
+/// comparison.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedFile {
+    /// The directory the file was found in.
+    pub side: Side,
+    /// The (possibly relative) path of the file or directory.
+    #[serde(with = "lossless::path")]
+    pub path: PathBuf,
+    /// Why the file was skipped.
+    pub reason: SkipReason,
+}
+
+/// A path that could not be read while walking a directory tree.
+///
+/// If the path is a directory, none of the files below it were compared.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalkError {
+    /// The directory the path was found in.
+    pub side: Side,
+    /// The (possibly relative) path that could not be read.
+    #[serde(with = "lossless::path")]
+    pub path: PathBuf,
+    /// The reason the path could not be read.
+    pub message: String,
+}
+
+/// A file that could not be read, left out of the comparison as errors are collected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadError {
+    /// The directory the file was found in.
+    pub side: Side,
+    /// The (possibly relative) path of the file.
+    #[serde(with = "lossless::path")]
+    pub path: PathBuf,
+    /// The reason the file could not be read.
+    pub message: String,
+}
+
+/// The time it took to hash a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileTiming {
+    /// The directory the file was found in.
+    pub side: Side,
+    /// The (possibly relative) path of the file.
+    #[serde(with = "lossless::path")]
+    pub path: PathBuf,
+    /// The time from the hasher taking up the file until its hash was known, waiting for its
+    /// data included.
+    pub duration: Duration,
+}
+
+/// Orders files by the time it took to hash them, slowest first, and keeps the first `count`.
+pub(crate) fn keep_slowest(files: &mut Vec<FileTiming>, count: usize) {
+    files.sort_by(|a, b| {
+        b.duration
+            .cmp(&a.duration)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    files.truncate(count);
+}
+
+/// The files of both directories sharing the same content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashGroup {
+    /// The BLAKE3 hash of the content.
+    #[serde(with = "lossless::hash")]
+    pub hash: Hash,
+    /// The size of the content in bytes, after any decompression or metadata stripping.
+    pub size: u64,
+    /// The (possibly relative) paths of the files in the first directory, sorted.
+    #[serde(with = "lossless::paths")]
+    pub dir1: Vec<PathBuf>,
+    /// The (possibly relative) paths of the files in the second directory, sorted.
+    #[serde(with = "lossless::paths")]
+    pub dir2: Vec<PathBuf>,
+}
+
+impl HashGroup {
+    /// Returns true if the content is present in both directories.
+    #[must_use]
+    pub fn is_shared(&self) -> bool {
+        !self.dir1.is_empty() && !self.dir2.is_empty()
+    }
+}
+
+/// How much of a directory is matched by content on the other side of a comparison.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectoryRollup {
+    /// The directory. Files directly in a root are counted under the root itself.
+    pub directory: PathBuf,
+    /// The number of files in the directory and below.
+    pub files: usize,
+    /// The number of those files whose content is found on the other side.
+    pub matched_files: usize,
+    /// The size of the contents of all files.
+    pub bytes: u64,
+    /// The size of the contents of the matched files.
+    pub matched_bytes: u64,
+}
+
+/// Returns the immediate subdirectory of its root a file is in, or the root for files directly
+/// in it.
+fn top_directory(path: &Path, roots: &[PathBuf]) -> PathBuf {
+    let root = matching_root(path, roots).unwrap_or(Path::new(""));
+    let rest = path.strip_prefix(root).unwrap_or(path);
+    let mut components = rest.components();
+    match (components.next(), components.next()) {
+        (Some(first), Some(_)) => root.join(first),
+        _ => root.to_path_buf(),
+    }
+}
+
+/// Sums up the files of one side of a comparison per immediate subdirectory of its roots.
+///
+/// # Parameters
+/// - `groups`: The files grouped by content, with paths as found, not relative to their root.
+/// - `side`: The side to sum up.
+/// - `roots`: The root directories of that side.
+///
+/// # Returns
+/// The rollup of each subdirectory, and of each root holding files directly, sorted by path.
+#[must_use]
+pub fn rollup_by_directory(
+    groups: &[HashGroup],
+    side: Side,
+    roots: &[PathBuf],
+) -> Vec<DirectoryRollup> {
+    let mut rollups: BTreeMap<PathBuf, DirectoryRollup> = BTreeMap::new();
+
+    for group in groups {
+        let paths = match side {
+            Side::Dir1 => &group.dir1,
+            Side::Dir2 => &group.dir2,
+        };
+        for path in paths {
+            let directory = top_directory(path, roots);
+            let rollup = rollups
+                .entry(directory.clone())
+                .or_insert_with(|| DirectoryRollup {
+                    directory,
+                    ..DirectoryRollup::default()
+                });
+            rollup.files += 1;
+            rollup.bytes += group.size;
+            if group.is_shared() {
+                rollup.matched_files += 1;
+                rollup.matched_bytes += group.size;
+            }
+        }
+    }
+
+    rollups.into_values().collect()
+}
+
+/// How many of the files with an extension are matched by content on the other side of a
+/// comparison.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionRollup {
+    /// The extension in lowercase, without the leading dot, or None for files without one.
+    pub extension: Option<String>,
+    /// The number of files with the extension.
+    pub files: usize,
+    /// The number of those files whose content is found on the other side.
+    pub matched_files: usize,
+    /// The size of the contents of all files.
+    pub bytes: u64,
+    /// The size of the contents of the matched files.
+    pub matched_bytes: u64,
+}
+
+/// Sums up the files of one side of a comparison per file extension.
+///
+/// Extensions are compared case-insensitively, so `IMG.JPG` and `img.jpg` are counted together.
+///
+/// # Parameters
+/// - `groups`: The files grouped by content.
+/// - `side`: The side to sum up.
+///
+/// # Returns
+/// The rollup of each extension, sorted by extension, with files without an extension first.
+#[must_use]
+pub fn rollup_by_extension(groups: &[HashGroup], side: Side) -> Vec<ExtensionRollup> {
+    let mut rollups: BTreeMap<Option<String>, ExtensionRollup> = BTreeMap::new();
+
+    for group in groups {
+        let paths = match side {
+            Side::Dir1 => &group.dir1,
+            Side::Dir2 => &group.dir2,
+        };
+        for path in paths {
+            let extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase());
+            let rollup = rollups
+                .entry(extension.clone())
+                .or_insert_with(|| ExtensionRollup {
+                    extension,
+                    ..ExtensionRollup::default()
+                });
+            rollup.files += 1;
+            rollup.bytes += group.size;
+            if group.is_shared() {
+                rollup.matched_files += 1;
+                rollup.matched_bytes += group.size;
+            }
+        }
+    }
+
+    rollups.into_values().collect()
+}
+
+/// How much of the data in a directory is distinct.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectoryUsage {
+    /// The directory. Files directly in a root are counted under the root itself.
+    pub directory: PathBuf,
+    /// The number of files in the directory and below.
+    pub files: usize,
+    /// The size of all files, counting duplicate contents each time.
+    pub bytes: u64,
+    /// The size of the distinct contents, counting duplicates within the directory once.
+    pub distinct_bytes: u64,
+    /// The size of the distinct contents found nowhere else in the roots.
+    pub exclusive_bytes: u64,
+}
+
+/// Sums up the disk usage of the first side of a scan per immediate subdirectory of its roots.
+///
+/// # Parameters
+/// - `groups`: The files grouped by content, with paths as found, not relative to their root.
+/// - `roots`: The root directories of the first side.
+///
+/// # Returns
+/// The usage of each subdirectory, and of each root holding files directly, sorted by path.
+#[must_use]
+pub fn usage_by_directory(groups: &[HashGroup], roots: &[PathBuf]) -> Vec<DirectoryUsage> {
+    let mut usages: BTreeMap<PathBuf, DirectoryUsage> = BTreeMap::new();
+
+    for group in groups {
+        // The number of copies of the content in each directory
+        let mut copies: BTreeMap<PathBuf, usize> = BTreeMap::new();
+        for path in &group.dir1 {
+            *copies.entry(top_directory(path, roots)).or_default() += 1;
+        }
+
+        let exclusive = copies.len() == 1;
+        for (directory, count) in copies {
+            let usage = usages
+                .entry(directory.clone())
+                .or_insert_with(|| DirectoryUsage {
+                    directory,
+                    ..DirectoryUsage::default()
+                });
+            usage.files += count;
+            usage.bytes += group.size * count as u64;
+            usage.distinct_bytes += group.size;
+            if exclusive {
+                usage.exclusive_bytes += group.size;
+            }
+        }
+    }
+
+    usages.into_values().collect()
+}
+
+/// The outcome of reading files anyway to check the hashes recorded for them in sums files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SumsCheck {
+    /// The number of recorded hashes checked.
+    pub checked: usize,
+    /// The (possibly relative) paths of the files whose recorded hash was wrong, sorted.
+    #[serde(with = "lossless::paths")]
+    pub stale: Vec<PathBuf>,
+}
+
+impl SumsCheck {
+    /// Returns the fraction of the checked hashes that were wrong, or zero if none was checked.
+    #[must_use]
+    pub fn error_rate(&self) -> f64 {
+        if self.checked == 0 {
+            return 0.0;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let rate = self.stale.len() as f64 / self.checked as f64;
+        rate
+    }
+}
+
+/// The outcome of reading a sample of matched files once more at the end of a comparison.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recheck {
+    /// The number of matches checked.
+    pub checked: usize,
+    /// The files of each side of the matches whose contents differed on the second read.
+    pub mismatched: Vec<[PathBuf; 2]>,
+}
+
+/// A category of file paths in the result of a comparison.
+///
+/// Results can be indexed by category, e.g. `result[Category::UniqueDir2]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Files whose content is present in both directories.
+    Intersection,
+    /// Files whose content is only in the first directory.
+    UniqueDir1,
+    /// Files whose content is only in the second directory.
+    UniqueDir2,
+    /// Relative paths present in both directories with different content.
+    Modified,
+    /// Paths that were skipped or could not be read.
+    Error,
+}
+
+impl Category {
+    /// All categories, in output order.
+    pub const ALL: [Self; 5] = [
+        Self::Intersection,
+        Self::UniqueDir1,
+        Self::UniqueDir2,
+        Self::Modified,
+        Self::Error,
+    ];
+
+    /// The categories that are only computed if requested.
+    const OPTIONAL: [Self; 4] = [
+        Self::Intersection,
+        Self::UniqueDir1,
+        Self::UniqueDir2,
+        Self::Modified,
+    ];
+
+    /// Returns the name of the field of the category in JSON, e.g. `unique_dir1`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Intersection => "intersection",
+            Self::UniqueDir1 => "unique_dir1",
+            Self::UniqueDir2 => "unique_dir2",
+            Self::Modified => "modified",
+            Self::Error => "errors",
+        }
+    }
+}
+
+/// The paths of a category listed by only one of two results, such as two runs of the same
+/// comparison.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResultDifference {
+    /// The category.
+    pub category: Category,
+    /// The paths only the first result lists, sorted. A path listed more often by the first
+    /// result is included as often as it is listed more often.
+    pub only_first: Vec<PathBuf>,
+    /// The paths only the second result lists, sorted.
+    pub only_second: Vec<PathBuf>,
+}
+
+/// Finds the paths unique to both directories, i.e. files whose content changed.
+///
+/// # Parameters
+/// - `unique_dir1`: The paths unique to the first directory.
+/// - `unique_dir2`: The paths unique to the second directory.
+///
+/// # Returns
+/// The sorted paths present in both lists, or None if either list is missing.
+pub(crate) fn modified_paths(
+    unique_dir1: Option<&[PathBuf]>,
+    unique_dir2: Option<&[PathBuf]>,
+) -> Option<Vec<PathBuf>> {
+    let unique_dir2: HashSet<&PathBuf> = unique_dir2?.iter().collect();
+    let mut modified: Vec<PathBuf> = unique_dir1?
+        .iter()
+        .filter(|path| unique_dir2.contains(path))
+        .cloned()
+        .collect();
+    modified.sort();
+    Some(modified)
+}
+
+/// Collects the sorted paths of skipped files, walk errors and read errors.
+pub(crate) fn error_paths(
+    skipped: &[SkippedFile],
+    walk_errors: &[WalkError],
+    read_errors: &[ReadError],
+) -> Vec<PathBuf> {
+    let mut errors: Vec<PathBuf> = skipped
+        .iter()
+        .map(|file| file.path.clone())
+        .chain(walk_errors.iter().map(|err| err.path.clone()))
+        .chain(read_errors.iter().map(|err| err.path.clone()))
+        .collect();
+    errors.sort();
+    errors.dedup();
+    errors
+}
+
+/// The outcome of comparing two directories.
+///
+/// With serde, paths that are not valid UTF-8 are written as their raw bytes on Unix and as
+/// their UTF-16 code units on Windows, so a result reads back unchanged on the same platform,
+/// unlike with [`ComparisonResult::to_json`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComparisonResult {
+    /// File paths present in both directories (if requested).
+    #[serde(with = "lossless::optional_paths")]
+    pub intersection: Option<Vec<PathBuf>>,
+    /// File paths unique to the first directory (if requested).
+    #[serde(with = "lossless::optional_paths")]
+    pub unique_dir1: Option<Vec<PathBuf>>,
+    /// File paths unique to the second directory (if requested).
+    #[serde(with = "lossless::optional_paths")]
+    pub unique_dir2: Option<Vec<PathBuf>>,
+    /// Paths unique to both directories, i.e. changed files, sorted (if both unique groups are
+    /// requested). The paths are relative to their root, or to the directory the paths of each
+    /// side are made relative to, even if the other groups hold the paths as found.
+    #[serde(with = "lossless::optional_paths")]
+    pub modified: Option<Vec<PathBuf>>,
+    /// Paths of the skipped files, walk errors and read errors, sorted.
+    #[serde(with = "lossless::paths")]
+    pub errors: Vec<PathBuf>,
+    /// Files that were found but not compared.
+    pub skipped: Vec<SkippedFile>,
+    /// Files and directories left out by the filters of the comparison, such as hidden files,
+    /// with the reason, sorted by path (if requested).
+    pub excluded: Option<Vec<SkippedFile>>,
+    /// Paths that could not be read while walking the directories.
+    pub walk_errors: Vec<WalkError>,
+    /// Files that could not be read, sorted by path, if errors are collected instead of failing
+    /// the comparison.
+    pub read_errors: Vec<ReadError>,
+    /// The recorded hashes that were checked by reading the files anyway.
+    pub sums_check: SumsCheck,
+    /// Files present in both directories matched by ranges of their contents only, sorted, see
+    /// `CompareOptions::range_hash`. These matches are probable, not certain, until the files
+    /// are read in full.
+    #[serde(with = "lossless::paths")]
+    pub ranged_matches: Vec<PathBuf>,
+    /// The files that took longest to hash, slowest first (as many as requested, see
+    /// `CompareOptions::slow_files`).
+    pub slow_files: Vec<FileTiming>,
+    /// The sides with a root directory that became unavailable during the comparison, such as
+    /// an unmounted network share. Files of these sides may be missing from every category.
+    pub incomplete: Vec<Side>,
+    /// Every content found, with its files on each side, ordered by hash (if requested).
+    pub groups: Option<Vec<HashGroup>>,
+}
+
+/// The number of paths of each category of a comparison.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// The number of files present in both directories (if requested).
+    pub intersection: Option<usize>,
+    /// The number of files unique to the first directory (if requested).
+    pub unique_dir1: Option<usize>,
+    /// The number of files unique to the second directory (if requested).
+    pub unique_dir2: Option<usize>,
+    /// The number of changed files (if requested).
+    pub modified: Option<usize>,
+    /// The number of files that were found but not compared.
+    pub skipped: usize,
+    /// The number of paths that could not be read while walking the directories.
+    pub walk_errors: usize,
+    /// The number of files that could not be read.
+    pub read_errors: usize,
+}
+
+impl Counts {
+    /// Converts the counts to a JSON object with a field for each count, null for categories
+    /// that were not requested.
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        json!({
+            "intersection": self.intersection,
+            "unique_dir1": self.unique_dir1,
+            "unique_dir2": self.unique_dir2,
+            "modified": self.modified,
+            "skipped": self.skipped,
+            "walk_errors": self.walk_errors,
+            "read_errors": self.read_errors,
+        })
+    }
+}
+
+impl ComparisonResult {
+    /// Returns the number of paths of each category.
+    #[must_use]
+    pub fn counts(&self) -> Counts {
+        Counts {
+            intersection: self.intersection.as_ref().map(Vec::len),
+            unique_dir1: self.unique_dir1.as_ref().map(Vec::len),
+            unique_dir2: self.unique_dir2.as_ref().map(Vec::len),
+            modified: self.modified.as_ref().map(Vec::len),
+            skipped: self.skipped.len(),
+            walk_errors: self.walk_errors.len(),
+            read_errors: self.read_errors.len(),
+        }
+    }
+
+    /// Converts the result to JSON, an object with a field for each field of the result and
+    /// `counts` with the number of paths of each category.
+    ///
+    /// Categories that were not requested are null. Sides are written as `directory1` and
+    /// `directory2`, skip reasons by their short names, see [`SkipReason::name`], and hashes as
+    /// hex. Paths that are not valid UTF-8 are written lossily.
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        let paths = |paths: &[PathBuf]| -> Vec<String> {
+            paths
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect()
+        };
+        let skipped = |files: &[SkippedFile]| {
+            files
+                .iter()
+                .map(|file| {
+                    json!({
+                        "side": side_name(file.side),
+                        "path": file.path.to_string_lossy(),
+                        "reason": file.reason.name(),
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "intersection": self.intersection.as_deref().map(paths),
+            "unique_dir1": self.unique_dir1.as_deref().map(paths),
+            "unique_dir2": self.unique_dir2.as_deref().map(paths),
+            "modified": self.modified.as_deref().map(paths),
+            "errors": paths(&self.errors),
+            "skipped": skipped(&self.skipped),
+            "excluded": self.excluded.as_deref().map(skipped),
+            "walk_errors": self.walk_errors.iter().map(|err| json!({
+                "side": side_name(err.side),
+                "path": err.path.to_string_lossy(),
+                "message": err.message,
+            })).collect::<Vec<_>>(),
+            "read_errors": self.read_errors.iter().map(|err| json!({
+                "side": side_name(err.side),
+                "path": err.path.to_string_lossy(),
+                "message": err.message,
+            })).collect::<Vec<_>>(),
+            "sums_check": {
+                "checked": self.sums_check.checked,
+                "stale": paths(&self.sums_check.stale),
+            },
+            "ranged_matches": paths(&self.ranged_matches),
+            "slow_files": self.slow_files.iter().map(|file| json!({
+                "side": side_name(file.side),
+                "path": file.path.to_string_lossy(),
+                "seconds": file.duration.as_secs_f64(),
+            })).collect::<Vec<_>>(),
+            "incomplete": self.incomplete.iter().map(|side| side_name(*side)).collect::<Vec<_>>(),
+            "groups": self.groups.as_ref().map(|groups| groups.iter().map(|group| json!({
+                "hash": group.hash.to_hex().as_str(),
+                "size": group.size,
+                "dir1": paths(&group.dir1),
+                "dir2": paths(&group.dir2),
+            })).collect::<Vec<_>>()),
+            "counts": self.counts().to_json(),
+        })
+    }
+
+    /// Reads a result from JSON written by [`ComparisonResult::to_json`].
+    ///
+    /// The counts are not read, as they follow from the paths.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` of kind `InvalidData` naming the field that is
+    /// missing or not understood.
+    pub fn from_json(value: &Value) -> io::Result<Self> {
+        let invalid = |field: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a comparison result with valid {field}"),
+            )
+        };
+        let paths = |value: &Value, field: &str| -> io::Result<Vec<PathBuf>> {
+            value
+                .as_array()
+                .and_then(|paths| {
+                    paths
+                        .iter()
+                        .map(|path| path.as_str().map(PathBuf::from))
+                        .collect()
+                })
+                .ok_or_else(|| invalid(field))
+        };
+        let optional_paths = |field: &str| match &value[field] {
+            Value::Null => Ok(None),
+            paths_value => paths(paths_value, field).map(Some),
+        };
+        let side = |value: &Value, field: &str| match value["side"].as_str() {
+            Some("directory1") => Ok(Side::Dir1),
+            Some("directory2") => Ok(Side::Dir2),
+            _ => Err(invalid(field)),
+        };
+        let skipped = |value: &Value, field: &str| -> io::Result<Vec<SkippedFile>> {
+            let files = value.as_array().ok_or_else(|| invalid(field))?;
+            files
+                .iter()
+                .map(|file| {
+                    Ok(SkippedFile {
+                        side: side(file, field)?,
+                        path: file["path"]
+                            .as_str()
+                            .map(PathBuf::from)
+                            .ok_or_else(|| invalid(field))?,
+                        reason: file["reason"]
+                            .as_str()
+                            .and_then(SkipReason::from_name)
+                            .ok_or_else(|| invalid(field))?,
+                    })
+                })
+                .collect()
+        };
+
+        // Walk errors and read errors share their fields
+        let errors = |field: &str| -> io::Result<Vec<(Side, PathBuf, String)>> {
+            let errors = value[field].as_array().ok_or_else(|| invalid(field))?;
+            errors
+                .iter()
+                .map(|err| {
+                    Ok((
+                        side(err, field)?,
+                        err["path"]
+                            .as_str()
+                            .map(PathBuf::from)
+                            .ok_or_else(|| invalid(field))?,
+                        err["message"]
+                            .as_str()
+                            .ok_or_else(|| invalid(field))?
+                            .to_string(),
+                    ))
+                })
+                .collect()
+        };
+
+        let walk_errors = errors("walk_errors")?
+            .into_iter()
+            .map(|(side, path, message)| WalkError {
+                side,
+                path,
+                message,
+            })
+            .collect();
+        let read_errors = errors("read_errors")?
+            .into_iter()
+            .map(|(side, path, message)| ReadError {
+                side,
+                path,
+                message,
+            })
+            .collect();
+        // Results written before files were timed have none
+        let slow_files = match &value["slow_files"] {
+            Value::Null => Vec::new(),
+            files => files
+                .as_array()
+                .ok_or_else(|| invalid("slow_files"))?
+                .iter()
+                .map(|file| {
+                    Ok(FileTiming {
+                        side: side(file, "slow_files")?,
+                        path: file["path"]
+                            .as_str()
+                            .map(PathBuf::from)
+                            .ok_or_else(|| invalid("slow_files"))?,
+                        duration: file["seconds"]
+                            .as_f64()
+                            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                            .ok_or_else(|| invalid("slow_files"))?,
+                    })
+                })
+                .collect::<io::Result<_>>()?,
+        };
+        // Results written before roots were watched have no incomplete sides
+        let incomplete = match &value["incomplete"] {
+            Value::Null => Vec::new(),
+            sides => sides
+                .as_array()
+                .ok_or_else(|| invalid("incomplete"))?
+                .iter()
+                .map(|side| match side.as_str() {
+                    Some("directory1") => Ok(Side::Dir1),
+                    Some("directory2") => Ok(Side::Dir2),
+                    _ => Err(invalid("incomplete")),
+                })
+                .collect::<io::Result<_>>()?,
+        };
+        let groups = match &value["groups"] {
+            Value::Null => None,
+            groups => Some(
+                groups
+                    .as_array()
+                    .ok_or_else(|| invalid("groups"))?
+                    .iter()
+                    .map(|group| {
+                        Ok(HashGroup {
+                            hash: group["hash"]
+                                .as_str()
+                                .and_then(|hash| Hash::from_hex(hash).ok())
+                                .ok_or_else(|| invalid("groups"))?,
+                            size: group["size"].as_u64().ok_or_else(|| invalid("groups"))?,
+                            dir1: paths(&group["dir1"], "groups")?,
+                            dir2: paths(&group["dir2"], "groups")?,
+                        })
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+        };
+
+        Ok(Self {
+            intersection: optional_paths("intersection")?,
+            unique_dir1: optional_paths("unique_dir1")?,
+            unique_dir2: optional_paths("unique_dir2")?,
+            modified: optional_paths("modified")?,
+            errors: paths(&value["errors"], "errors")?,
+            skipped: skipped(&value["skipped"], "skipped")?,
+            excluded: match &value["excluded"] {
+                Value::Null => None,
+                excluded => Some(skipped(excluded, "excluded")?),
+            },
+            walk_errors,
+            read_errors,
+            sums_check: SumsCheck {
+                checked: value["sums_check"]["checked"]
+                    .as_u64()
+                    .and_then(|checked| usize::try_from(checked).ok())
+                    .ok_or_else(|| invalid("sums_check"))?,
+                stale: paths(&value["sums_check"]["stale"], "sums_check")?,
+            },
+            // Results written before hashing by ranges have none
+            ranged_matches: match &value["ranged_matches"] {
+                Value::Null => Vec::new(),
+                ranged => paths(ranged, "ranged_matches")?,
+            },
+            slow_files,
+            incomplete,
+            groups,
+        })
+    }
+
+    /// Returns the paths of a category.
+    ///
+    /// # Returns
+    /// The paths, or None if the category was not requested.
+    #[must_use]
+    pub fn category(&self, category: Category) -> Option<&[PathBuf]> {
+        match category {
+            Category::Intersection => self.intersection.as_deref(),
+            Category::UniqueDir1 => self.unique_dir1.as_deref(),
+            Category::UniqueDir2 => self.unique_dir2.as_deref(),
+            Category::Modified => self.modified.as_deref(),
+            Category::Error => Some(&self.errors),
+        }
+    }
+
+    /// Returns the paths of a category that is only computed if requested, for modification.
+    ///
+    /// # Panics
+    /// This function panics for categories that are always computed.
+    fn optional_category_mut(&mut self, category: Category) -> &mut Option<Vec<PathBuf>> {
+        match category {
+            Category::Intersection => &mut self.intersection,
+            Category::UniqueDir1 => &mut self.unique_dir1,
+            Category::UniqueDir2 => &mut self.unique_dir2,
+            Category::Modified => &mut self.modified,
+            Category::Error => unreachable!("the error category is always computed"),
+        }
+    }
+
+    /// Lists the paths of each category that this result and another do not share, such as two
+    /// runs of the same comparison.
+    ///
+    /// The paths of a category are compared regardless of their order. A category that was not
+    /// requested counts as empty.
+    ///
+    /// # Parameters
+    /// - `other`: The result to compare with.
+    ///
+    /// # Returns
+    /// The differences, in output order of the categories, leaving out categories without any.
+    #[must_use]
+    pub fn differences(&self, other: &Self) -> Vec<ResultDifference> {
+        fn sorted(result: &ComparisonResult, category: Category) -> Vec<&PathBuf> {
+            let mut paths: Vec<&PathBuf> = result.iter_category(category).collect();
+            paths.sort();
+            paths
+        }
+
+        let mut differences = Vec::new();
+        for category in Category::ALL {
+            let (first, second) = (sorted(self, category), sorted(other, category));
+            let (mut only_first, mut only_second) = (Vec::new(), Vec::new());
+            let (mut i, mut j) = (0, 0);
+            while i < first.len() || j < second.len() {
+                let order = match (first.get(i), second.get(j)) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (Some(_), None) => Ordering::Less,
+                    (None, _) => Ordering::Greater,
+                };
+                match order {
+                    Ordering::Less => {
+                        only_first.push(first[i].clone());
+                        i += 1;
+                    }
+                    Ordering::Greater => {
+                        only_second.push(second[j].clone());
+                        j += 1;
+                    }
+                    Ordering::Equal => {
+                        i += 1;
+                        j += 1;
+                    }
+                }
+            }
+            if !only_first.is_empty() || !only_second.is_empty() {
+                differences.push(ResultDifference {
+                    category,
+                    only_first,
+                    only_second,
+                });
+            }
+        }
+        differences
+    }
+
+    /// Iterates over the paths of a category, yielding nothing if it was not requested.
+    pub fn iter_category(&self, category: Category) -> impl Iterator<Item = &PathBuf> {
+        self.category(category).unwrap_or_default().iter()
+    }
+
+    /// Iterates over the paths of all categories, in output order, with their category.
+    pub fn iter_all(&self) -> impl Iterator<Item = (Category, &PathBuf)> {
+        Category::ALL.into_iter().flat_map(move |category| {
+            self.iter_category(category)
+                .map(move |path| (category, path))
+        })
+    }
+
+    /// Keeps only the paths matching a predicate.
+    ///
+    /// The paths of all categories, skipped files, walk errors and groups are filtered. Groups
+    /// left without files are removed.
+    ///
+    /// # Parameters
+    /// - `predicate`: Returns true for the paths to keep.
+    ///
+    /// # Returns
+    /// The filtered result.
+    #[must_use]
+    pub fn filter_paths(mut self, mut predicate: impl FnMut(&Path) -> bool) -> Self {
+        for category in Category::OPTIONAL {
+            if let Some(paths) = self.optional_category_mut(category) {
+                paths.retain(|path| predicate(path));
+            }
+        }
+        self.errors.retain(|path| predicate(path));
+        self.skipped.retain(|file| predicate(&file.path));
+        if let Some(excluded) = &mut self.excluded {
+            excluded.retain(|file| predicate(&file.path));
+        }
+        self.walk_errors.retain(|err| predicate(&err.path));
+        self.read_errors.retain(|err| predicate(&err.path));
+        self.ranged_matches.retain(|path| predicate(path));
+        self.slow_files.retain(|file| predicate(&file.path));
+        if let Some(groups) = &mut self.groups {
+            for group in groups.iter_mut() {
+                group.dir1.retain(|path| predicate(path));
+                group.dir2.retain(|path| predicate(path));
+            }
+            groups.retain(|group| !group.dir1.is_empty() || !group.dir2.is_empty());
+        }
+
+        self
+    }
+
+    /// Appends the paths of another result, such as the comparison of another pair of
+    /// directories.
+    ///
+    /// The paths of each category are appended to those of `self`. A category requested in only
+    /// one of the results is kept. Groups with the same hash are joined, and the groups stay
+    /// ordered by hash. The categories are not recomputed, so content found on the first side of
+    /// one result and the second side of the other is not moved to the intersection.
+    ///
+    /// # Parameters
+    /// - `other`: The result to append.
+    pub fn merge(&mut self, other: Self) {
+        let Self {
+            intersection,
+            unique_dir1,
+            unique_dir2,
+            modified,
+            errors,
+            skipped,
+            excluded,
+            walk_errors,
+            read_errors,
+            sums_check,
+            ranged_matches,
+            slow_files,
+            incomplete,
+            groups,
+        } = other;
+
+        for (category, other_paths) in [
+            (Category::Intersection, intersection),
+            (Category::UniqueDir1, unique_dir1),
+            (Category::UniqueDir2, unique_dir2),
+            (Category::Modified, modified),
+        ] {
+            let paths = self.optional_category_mut(category);
+            match (paths.as_mut(), other_paths) {
+                (Some(paths), Some(other_paths)) => paths.extend(other_paths),
+                (None, other_paths) => *paths = other_paths,
+                (Some(_), None) => {}
+            }
+        }
+        self.errors.extend(errors);
+        self.skipped.extend(skipped);
+        match (&mut self.excluded, excluded) {
+            (Some(files), Some(other_files)) => files.extend(other_files),
+            (None, other_files) => self.excluded = other_files,
+            (Some(_), None) => {}
+        }
+        self.walk_errors.extend(walk_errors);
+        self.read_errors.extend(read_errors);
+        self.sums_check.checked += sums_check.checked;
+        self.sums_check.stale.extend(sums_check.stale);
+        self.sums_check.stale.sort();
+        self.ranged_matches.extend(ranged_matches);
+        self.ranged_matches.sort();
+        self.slow_files.extend(slow_files);
+        keep_slowest(&mut self.slow_files, usize::MAX);
+        for side in incomplete {
+            if !self.incomplete.contains(&side) {
+                self.incomplete.push(side);
+            }
+        }
+
+        if let Some(other_groups) = groups {
+            let groups = self.groups.get_or_insert_with(Vec::new);
+            for other_group in other_groups {
+                match groups.binary_search_by(|group| {
+                    group.hash.as_bytes().cmp(other_group.hash.as_bytes())
+                }) {
+                    Ok(index) => {
+                        let group = &mut groups[index];
+                        group.dir1.extend(other_group.dir1);
+                        group.dir2.extend(other_group.dir2);
+                        group.dir1.sort();
+                        group.dir2.sort();
+                    }
+                    Err(index) => groups.insert(index, other_group),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the name of a side in JSON.
+fn side_name(side: Side) -> &'static str {
+    match side {
+        Side::Dir1 => "directory1",
+        Side::Dir2 => "directory2",
+    }
+}
+
+impl Index<Category> for ComparisonResult {
+    type Output = [PathBuf];
+
+    /// Returns the paths of a category, or no paths if the category was not requested.
+    fn index(&self, category: Category) -> &[PathBuf] {
+        self.category(category).unwrap_or_default()
+    }
+}
+
+/// Serde encodings of paths and hashes that keep them unchanged, for `#[serde(with)]`.
+mod lossless {
+    use blake3::Hash;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    /// A path as written: its text if it is valid UTF-8, or else its raw bytes on Unix and its
+    /// UTF-16 code units on Windows.
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum EncodedPath {
+        Text(String),
+        Bytes { bytes: Vec<u8> },
+        Wide { wide: Vec<u16> },
+    }
+
+    fn encode(path: &Path) -> EncodedPath {
+        if let Some(text) = path.to_str() {
+            return EncodedPath::Text(text.to_string());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            EncodedPath::Bytes {
+                bytes: path.as_os_str().as_bytes().to_vec(),
+            }
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            EncodedPath::Wide {
+                wide: path.as_os_str().encode_wide().collect(),
+            }
+        }
+        #[cfg(not(any(unix, windows)))]
+        EncodedPath::Text(path.to_string_lossy().into_owned())
+    }
+
+    fn decode<E: Error>(path: EncodedPath) -> Result<PathBuf, E> {
+        match path {
+            EncodedPath::Text(text) => Ok(PathBuf::from(text)),
+            #[cfg(unix)]
+            EncodedPath::Bytes { bytes } => {
+                use std::os::unix::ffi::OsStringExt;
+                Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+            }
+            #[cfg(windows)]
+            EncodedPath::Wide { wide } => {
+                use std::os::windows::ffi::OsStringExt;
+                Ok(PathBuf::from(std::ffi::OsString::from_wide(&wide)))
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(E::custom("a path written on another platform")),
+        }
+    }
+
+    /// A single path.
+    pub mod path {
+        use super::{
+            decode, encode, Deserialize, Deserializer, EncodedPath, Path, PathBuf, Serialize,
+            Serializer,
+        };
+
+        pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+            encode(path).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<PathBuf, D::Error> {
+            decode(EncodedPath::deserialize(deserializer)?)
+        }
+    }
+
+    /// A list of paths.
+    pub mod paths {
+        use super::{decode, encode, Deserialize, Deserializer, EncodedPath, PathBuf, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            paths: &[PathBuf],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(paths.iter().map(|path| encode(path)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<PathBuf>, D::Error> {
+            Vec::<EncodedPath>::deserialize(deserializer)?
+                .into_iter()
+                .map(decode)
+                .collect()
+        }
+    }
+
+    /// A list of paths that may be missing.
+    pub mod optional_paths {
+        use super::{decode, Deserialize, Deserializer, EncodedPath, PathBuf, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            paths: &Option<Vec<PathBuf>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match paths {
+                Some(paths) => super::paths::serialize(paths, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<PathBuf>>, D::Error> {
+            Option::<Vec<EncodedPath>>::deserialize(deserializer)?
+                .map(|paths| paths.into_iter().map(decode).collect())
+                .transpose()
+        }
+    }
+
+    /// A hash, written as hex like by [`super::ComparisonResult::to_json`].
+    pub mod hash {
+        use super::{Deserialize, Deserializer, Error, Hash, Serializer};
+
+        pub fn serialize<S: Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(hash.to_hex().as_str())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hash, D::Error> {
+            let hex = String::deserialize(deserializer)?;
+            Hash::from_hex(hex).map_err(D::Error::custom)
+        }
+    }
+}
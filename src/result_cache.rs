@@ -0,0 +1,349 @@
+use blake3::{Hash, Hasher};
+use crossbeam_channel::unbounded;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::error::FileMatchError;
+use crate::options::{CompareOptions, SumsPolicy};
+use crate::result::ComparisonResult;
+use crate::sums::FileStamp;
+use crate::util::send_file_paths;
+
+/// How long a cached result is used by default.
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(60);
+
+/// A directory of comparison results, each used for identical comparisons of unchanged trees
+/// for a while after it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResultCache {
+    /// The directory holding the results, created when the first one is written.
+    pub directory: PathBuf,
+    /// How long after a result was written it is used.
+    pub validity: Duration,
+}
+
+impl ResultCache {
+    /// Creates a cache in a directory, using its results for [`DEFAULT_VALIDITY`].
+    #[must_use]
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            validity: DEFAULT_VALIDITY,
+        }
+    }
+
+    /// Returns the file holding the result of a key.
+    fn entry(&self, key: &Hash) -> PathBuf {
+        self.directory.join(format!("{key}.json"))
+    }
+
+    /// Reads the result of a key, if it was written within the validity window.
+    fn load(&self, key: &Hash) -> Option<ComparisonResult> {
+        let path = self.entry(key);
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age >= self.validity {
+            return None;
+        }
+        serde_json::from_reader(BufReader::new(File::open(&path).ok()?)).ok()
+    }
+
+    /// Writes the result of a key, replacing it atomically, and removes the results that are
+    /// no longer valid.
+    fn store(&self, key: &Hash, result: &ComparisonResult) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        let path = self.entry(key);
+        let partial = path.with_extension("json.partial");
+        let mut file = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut file, result)?;
+        file.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(&partial, &path)?;
+
+        for entry in fs::read_dir(&self.directory)?.flatten() {
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age >= self.validity));
+            if expired && entry.path().extension().is_some_and(|ext| ext == "json") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the directory comparison results are cached in by default.
+///
+/// This is `filematch/results` in `$XDG_CACHE_HOME`, `~/.cache`, or `%LOCALAPPDATA%` on
+/// Windows.
+///
+/// # Returns
+/// The directory, or None if the home directory is unknown.
+#[must_use]
+pub fn default_cache_directory() -> Option<PathBuf> {
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+
+    Some(cache.join("filematch").join("results"))
+}
+
+/// Computes a fingerprint of a directory tree from the paths, sizes and modification times of
+/// its files, without reading them.
+///
+/// The tree is walked as a comparison root would be with the same options, so the fingerprint
+/// changes when a file that would be compared is added, removed, resized or touched, or when a
+/// path can no longer be read. A file rewritten with the same size and modification time
+/// leaves it unchanged.
+///
+/// # Parameters
+/// - `directory`: The root directory of the tree.
+/// - `options`: The options controlling which files are included.
+///
+/// # Returns
+/// The fingerprint, as a BLAKE3 hash.
+#[must_use]
+pub fn tree_fingerprint(directory: &Path, options: &CompareOptions) -> Hash {
+    let (sender, receiver) = unbounded();
+    let walk_errors = send_file_paths(directory, &sender, options, &[], None);
+    drop(sender);
+
+    let mut files: Vec<(PathBuf, Option<FileStamp>)> = receiver
+        .into_iter()
+        .map(|path| {
+            let stamp = FileStamp::read(&path).ok();
+            (path, stamp)
+        })
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hasher = Hasher::new();
+    for (path, stamp) in &files {
+        hasher.update(path.as_os_str().as_encoded_bytes());
+        hasher.update(&[0]);
+        if let Some(stamp) = stamp {
+            hasher.update(&stamp.size.to_le_bytes());
+            hasher.update(&stamp.modified.as_nanos().to_le_bytes());
+        }
+    }
+    for err in &walk_errors {
+        hasher.update(err.to_string().as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.finalize()
+}
+
+/// Feeds a field of a cache key to a hasher, prefixed by its length so that consecutive fields
+/// cannot be confused.
+fn key_field(hasher: &mut Hasher, bytes: &[u8]) {
+    hasher.update(&(bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// Feeds an optional path of a cache key to a hasher.
+fn key_path(hasher: &mut Hasher, path: Option<&Path>) {
+    key_field(hasher, &[u8::from(path.is_some())]);
+    key_field(
+        hasher,
+        path.map_or(&[][..], |path| path.as_os_str().as_encoded_bytes()),
+    );
+}
+
+/// Computes the key of a comparison in a [`ResultCache`], from its roots, its options and the
+/// fingerprints of its trees, see [`tree_fingerprint`].
+///
+/// Every option changing the result is part of the key. Options only tuning the speed of the
+/// comparison, such as the number of threads, are not.
+///
+/// # Returns
+/// The key, or None if the comparison cannot be cached as it has a walk filter, a content
+/// transform or a reference set, which cannot be told apart from others.
+#[must_use]
+pub fn cache_key(dirs1: &[PathBuf], dirs2: &[PathBuf], options: &CompareOptions) -> Option<Hash> {
+    // Listing every field makes adding an option a compile error here until it is keyed
+    let CompareOptions {
+        relative,
+        absolute,
+        relative_to1,
+        relative_to2,
+        skip_hidden,
+        use_ignore_file,
+        respect_gitignore,
+        walk_filter,
+        modified_since,
+        min_size,
+        max_size,
+        follow_links,
+        allow_overlapping_roots,
+        sort,
+        include_intersection,
+        include_unique_dir1,
+        include_unique_dir2,
+        include_groups,
+        include_excluded,
+        io_threads: _,
+        io_threads1: _,
+        io_threads2: _,
+        storage: _,
+        adaptive_io: _,
+        hash_threads: _,
+        retries: _,
+        retry_delay: _,
+        locked,
+        lock_timeout: _,
+        retry_unstable,
+        fail_on_walk_error,
+        read_errors,
+        decompress,
+        strip_metadata,
+        transform,
+        sums,
+        journal: _,
+        verify_sums,
+        ignore_hashes,
+        reference_set,
+        chunk_size: _,
+        max_open_files: _,
+        max_in_flight: _,
+        prefilter_sizes,
+        collapse_case_aliases,
+        collapse_link_aliases,
+        read_only: _,
+        range_hash,
+        slow_files,
+        on_progress: _,
+    } = options;
+    if walk_filter.is_some() || transform.is_some() || reference_set.is_some() {
+        return None;
+    }
+
+    let mut hasher = Hasher::new();
+    let flags = [
+        *relative,
+        *absolute,
+        *skip_hidden,
+        *use_ignore_file,
+        *respect_gitignore,
+        *follow_links,
+        *allow_overlapping_roots,
+        *sort,
+        *include_intersection,
+        *include_unique_dir1,
+        *include_unique_dir2,
+        *include_groups,
+        *include_excluded,
+        *retry_unstable,
+        *fail_on_walk_error,
+        *strip_metadata,
+        *prefilter_sizes,
+        *collapse_case_aliases,
+        *collapse_link_aliases,
+    ];
+    key_field(&mut hasher, &flags.map(u8::from));
+    key_path(&mut hasher, relative_to1.as_deref());
+    key_path(&mut hasher, relative_to2.as_deref());
+    let since = modified_since.map(|time| {
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos())
+    });
+    for number in [since, min_size.map(u128::from), max_size.map(u128::from)] {
+        key_field(&mut hasher, &[u8::from(number.is_some())]);
+        key_field(&mut hasher, &number.unwrap_or_default().to_le_bytes());
+    }
+    key_field(
+        &mut hasher,
+        &[*locked as u8, *read_errors as u8, *sums as u8],
+    );
+    for compression in decompress {
+        key_field(&mut hasher, compression.extension().as_bytes());
+    }
+    key_field(&mut hasher, &verify_sums.to_le_bytes());
+    let mut ignored: Vec<_> = ignore_hashes.iter().map(|hash| *hash.as_bytes()).collect();
+    ignored.sort_unstable();
+    key_field(&mut hasher, ignored.as_flattened());
+    match range_hash {
+        Some(range) => {
+            key_field(&mut hasher, &range.min_size.to_le_bytes());
+            key_field(&mut hasher, &range.head.to_le_bytes());
+            key_field(&mut hasher, &range.tail.to_le_bytes());
+            key_field(&mut hasher, &[u8::from(range.verify)]);
+        }
+        None => key_field(&mut hasher, &[]),
+    }
+    key_field(&mut hasher, &(*slow_files as u64).to_le_bytes());
+
+    for (side, dirs) in [dirs1, dirs2].into_iter().enumerate() {
+        key_field(&mut hasher, &(side as u64).to_le_bytes());
+        for dir in dirs {
+            let root = fs::canonicalize(dir).unwrap_or_else(|_| dir.clone());
+            key_field(&mut hasher, root.as_os_str().as_encoded_bytes());
+            hasher.update(tree_fingerprint(dir, options).as_bytes());
+        }
+    }
+    Some(hasher.finalize())
+}
+
+/// The result of a comparison that may have been taken from a cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedComparison {
+    /// The result of the comparison.
+    pub result: ComparisonResult,
+    /// True if the result was taken from the cache instead of comparing the files.
+    pub from_cache: bool,
+}
+
+/// Compares directories like [`compare_multiple_roots`], unless an identical comparison of the
+/// unchanged trees was cached within the validity window of the cache.
+///
+/// The trees are walked and the metadata of every file is read to compute the key of the
+/// comparison, see [`cache_key`], but no file is read on a cache hit. Results of comparisons
+/// that run are cached, unless a root became unavailable while they ran. A cache that cannot
+/// be read or written is bypassed.
+///
+/// Comparisons with side effects beyond their result always run, so that their checksum files
+/// are updated with `SumsPolicy::Update` and their journal is written. So do comparisons that
+/// read files regardless, with `verify_sums`, and those that cannot be cached, see
+/// [`cache_key`].
+///
+/// # Parameters
+/// - `dirs1`: The root directories of the first side.
+/// - `dirs2`: The root directories of the second side.
+/// - `options`: The options of the comparison.
+/// - `cache`: The cache to use.
+///
+/// # Returns
+/// The result, and whether it was taken from the cache.
+///
+/// # Errors
+/// This function returns the errors of [`compare_multiple_roots`].
+pub fn compare_cached(
+    dirs1: &[PathBuf],
+    dirs2: &[PathBuf],
+    options: &CompareOptions,
+    cache: &ResultCache,
+) -> Result<CachedComparison, FileMatchError> {
+    let cacheable = options.sums != SumsPolicy::Update
+        && options.journal.is_none()
+        && options.verify_sums <= 0.0;
+    let key = cache_key(dirs1, dirs2, options).filter(|_| cacheable);
+    if let Some(result) = key.and_then(|key| cache.load(&key)) {
+        return Ok(CachedComparison {
+            result,
+            from_cache: true,
+        });
+    }
+
+    let result = compare_multiple_roots(dirs1, dirs2, options)?;
+    if let Some(key) = key.filter(|_| result.incomplete.is_empty()) {
+        let _ = cache.store(&key, &result);
+    }
+    Ok(CachedComparison {
+        result,
+        from_cache: false,
+    })
+}
@@ -0,0 +1,245 @@
+use blake3::Hasher;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Name of the file written to measure the read speed of a directory.
+pub const TEST_FILE_NAME: &str = ".filematch-selftest.tmp";
+
+/// Size of the buffer hashed over and over to measure the hash throughput.
+const HASH_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Time spent hashing for each measurement of the hash throughput.
+const HASH_TIME: Duration = Duration::from_secs(1);
+
+/// Longest time spent on random reads, as spinning disks manage only about a hundred a second.
+const RANDOM_READ_TIME: Duration = Duration::from_secs(3);
+
+/// Random reads are this much slower than sequential ones on storage that seeks slowly.
+const SEEK_PENALTY: f64 = 4.0;
+
+/// The speeds measured by a self-test, in bytes per second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTest {
+    /// The number of bytes hashed per second by a single thread.
+    pub hash_single: f64,
+    /// The number of bytes hashed per second by `hash_threads` threads together.
+    pub hash_multi: f64,
+    /// The number of threads hashing together.
+    pub hash_threads: usize,
+    /// The number of bytes read per second from the test file, front to back.
+    pub sequential_read: f64,
+    /// The number of bytes read per second from the test file, one chunk at a random offset at a
+    /// time.
+    pub random_read: f64,
+}
+
+/// The settings suggested by a self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recommendation {
+    /// True if random reads are much slower than sequential ones, as on spinning disks.
+    pub seeks_slowly: bool,
+    /// The suggested number of reader threads.
+    pub io_threads: usize,
+    /// Whether to adjust the number of files read at once to the throughput.
+    pub adaptive_io: bool,
+    /// The suggested number of hasher threads.
+    pub hash_threads: usize,
+}
+
+impl SelfTest {
+    /// Derives the settings suited to the measured speeds.
+    ///
+    /// Storage that seeks slowly is best read one file at a time, with as many hashers as it
+    /// takes to keep up with a sequential read. Other storage is read by four readers per hasher
+    /// with adaptive reads, and hashed by all `hash_threads` threads.
+    #[must_use]
+    pub fn recommendation(&self) -> Recommendation {
+        let seeks_slowly = self.random_read * SEEK_PENALTY < self.sequential_read;
+        if seeks_slowly {
+            #[allow(
+                clippy::cast_possible_truncation,
+                clippy::cast_sign_loss,
+                clippy::cast_precision_loss
+            )]
+            let hashers = (self.sequential_read / self.hash_single).ceil() as usize;
+            Recommendation {
+                seeks_slowly,
+                io_threads: 1,
+                adaptive_io: false,
+                hash_threads: hashers.clamp(1, self.hash_threads.max(1)),
+            }
+        } else {
+            Recommendation {
+                seeks_slowly,
+                io_threads: self.hash_threads.max(1) * 4,
+                adaptive_io: true,
+                hash_threads: self.hash_threads.max(1),
+            }
+        }
+    }
+}
+
+/// Measures how fast BLAKE3 hashes on this machine and how fast a directory can be read.
+///
+/// The hash throughput is measured with one thread and with `hash_threads` threads, hashing
+/// data in memory. The read speed is measured on a test file of `size` bytes written to
+/// `directory` and removed afterwards: once reading it front to back, and once reading chunks
+/// of `chunk_size` bytes at random offsets. The file is flushed and dropped from the page cache
+/// before each read on Linux and read past the cache on macOS, so the storage is measured
+/// rather than the memory. On other platforms the reads may be served from the cache.
+///
+/// # Parameters
+/// - `directory`: A directory on the storage to measure.
+/// - `size`: The size of the test file in bytes.
+/// - `chunk_size`: The number of bytes read at once.
+/// - `hash_threads`: The number of threads hashing together.
+///
+/// # Returns
+/// The measured speeds.
+///
+/// # Errors
+/// This function returns an `io::Error` if the test file cannot be written, read or removed.
+pub fn run_self_test(
+    directory: &Path,
+    size: u64,
+    chunk_size: usize,
+    hash_threads: usize,
+) -> io::Result<SelfTest> {
+    let hash_threads = hash_threads.max(1);
+    let chunk_size = chunk_size.max(1);
+    let mut data = vec![0; HASH_BUFFER_SIZE];
+    Hasher::new()
+        .update(b"filematch selftest")
+        .finalize_xof()
+        .fill(&mut data);
+
+    let hash_single = hash_throughput(&data, 1);
+    let hash_multi = hash_throughput(&data, hash_threads);
+
+    let path = directory.join(TEST_FILE_NAME);
+    let result = measure_reads(&path, &data, size.max(1), chunk_size);
+    let removed = fs::remove_file(&path);
+    let (sequential_read, random_read) = result?;
+    removed?;
+
+    Ok(SelfTest {
+        hash_single,
+        hash_multi,
+        hash_threads,
+        sequential_read,
+        random_read,
+    })
+}
+
+/// Hashes the data over and over with several threads at once for `HASH_TIME`.
+///
+/// # Returns
+/// The number of bytes hashed per second by all threads together.
+fn hash_throughput(data: &[u8], threads: usize) -> f64 {
+    let start = Instant::now();
+    let hashed: u64 = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut hashed = 0;
+                    while start.elapsed() < HASH_TIME {
+                        let mut hasher = Hasher::new();
+                        hasher.update(data);
+                        let _ = hasher.finalize();
+                        hashed += data.len() as u64;
+                    }
+                    hashed
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Thread panicked"))
+            .sum()
+    });
+
+    #[allow(clippy::cast_precision_loss)]
+    let throughput = hashed as f64 / start.elapsed().as_secs_f64();
+    throughput
+}
+
+/// Writes the test file and measures reading it sequentially and at random offsets.
+///
+/// # Returns
+/// The number of bytes read per second sequentially and at random offsets.
+fn measure_reads(path: &Path, data: &[u8], size: u64, chunk_size: usize) -> io::Result<(f64, f64)> {
+    let mut file = File::create(path)?;
+    let mut written = 0;
+    while written < size {
+        let length =
+            usize::try_from(size - written).map_or(data.len(), |rest| rest.min(data.len()));
+        file.write_all(&data[..length])?;
+        written += length as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+
+    let mut buffer = vec![0; chunk_size];
+
+    let mut file = open_uncached(path)?;
+    let start = Instant::now();
+    let mut read = 0;
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => read += bytes_read as u64,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let sequential = read as f64 / start.elapsed().as_secs_f64();
+
+    // Chunks at random offsets, seeded differently in every run
+    let chunks = size.div_ceil(chunk_size as u64);
+    let mut state = RandomState::new().hash_one(path) | 1;
+    let mut file = open_uncached(path)?;
+    let start = Instant::now();
+    let mut read = 0;
+    for _ in 0..chunks {
+        if start.elapsed() >= RANDOM_READ_TIME {
+            break;
+        }
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        file.seek(SeekFrom::Start(state % chunks * chunk_size as u64))?;
+        read += file.read(&mut buffer)? as u64;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let random = read as f64 / start.elapsed().as_secs_f64();
+
+    Ok((sequential, random))
+}
+
+/// Opens a file for reading past the page cache, as far as the platform allows.
+pub(crate) fn open_uncached(path: &Path) -> io::Result<File> {
+    let file = File::open(path)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // The file was synced, so its pages are clean and can be dropped
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::fd::AsRawFd;
+
+        unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+    }
+
+    Ok(file)
+}
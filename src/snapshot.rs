@@ -0,0 +1,442 @@
+use crossbeam_channel::unbounded;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::filesystem::detect_filesystem;
+use crate::options::{CompareOptions, WalkFilter};
+use crate::result::{ComparisonResult, SkipReason};
+use crate::util::send_file_paths;
+
+/// A filesystem that records which files changed between two snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotKind {
+    Btrfs,
+    Zfs,
+}
+
+impl SnapshotKind {
+    /// Returns the name of the filesystem, e.g. `btrfs`.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Btrfs => "btrfs",
+            Self::Zfs => "zfs",
+        }
+    }
+}
+
+/// The files changed between two snapshots of the same filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotChanges {
+    /// The filesystem that recorded the changes.
+    pub kind: SnapshotKind,
+    /// The paths of the changed files, relative to the snapshots.
+    pub changed: HashSet<PathBuf>,
+}
+
+/// Creates the error reported when the changes between two directories cannot be looked up.
+fn unsupported(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+/// Runs a command and returns what it printed.
+///
+/// # Errors
+/// This function returns an `io::Error` if the command cannot be run or fails.
+fn run(program: &str, args: &[&OsStr]) -> io::Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| io::Error::new(err.kind(), format!("{program}: {err}")))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Unescapes the characters `zfs diff` escapes in paths as a backslash and four octal digits,
+/// such as `\0040` for a space.
+fn unescape_zfs_path(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 5)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        if let Some(code) = code {
+            unescaped.push(code);
+            i += 5;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    PathBuf::from(String::from_utf8_lossy(&unescaped).into_owned())
+}
+
+/// Parses the output of `zfs diff -H`.
+///
+/// # Parameters
+/// - `output`: The lines of the change type and the path, or both paths of renamed files,
+///   separated by tabs.
+/// - `root`: The directory of the live dataset the snapshots were taken of, as `zfs diff` names
+///   the files inside it.
+///
+/// # Returns
+/// The paths of all files added, removed, modified or renamed, relative to `root`. Renamed
+/// files are listed under both names.
+#[must_use]
+pub fn parse_zfs_diff(output: &str, root: &Path) -> HashSet<PathBuf> {
+    output
+        .lines()
+        .flat_map(|line| line.split('\t').skip(1))
+        .filter_map(|path| {
+            unescape_zfs_path(path)
+                .strip_prefix(root)
+                .ok()
+                .map(Path::to_path_buf)
+        })
+        .collect()
+}
+
+/// Parses the output of `btrfs subvolume find-new`.
+///
+/// # Parameters
+/// - `output`: One line per changed extent, ending with the flags and the path of its file,
+///   followed by the transaction id marker.
+///
+/// # Returns
+/// The paths of the files with changed data, relative to the subvolume.
+#[must_use]
+pub fn parse_btrfs_find_new(output: &str) -> HashSet<PathBuf> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("inode "))
+        .filter_map(|line| {
+            let (_, rest) = line.split_once(" flags ")?;
+            let (_, path) = rest.split_once(' ')?;
+            Some(PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// Splits the path of a ZFS snapshot, such as `/tank/data/.zfs/snapshot/monday/photos`, into the
+/// directory of the live dataset, the name of the snapshot, and the directory inside it.
+fn split_zfs_snapshot(path: &Path) -> Option<(PathBuf, String, PathBuf)> {
+    let components: Vec<Component> = path.components().collect();
+    let position = components
+        .windows(2)
+        .position(|pair| pair[0].as_os_str() == ".zfs" && pair[1].as_os_str() == "snapshot")?;
+    let name = components.get(position + 2)?.as_os_str().to_str()?;
+
+    Some((
+        components[..position].iter().collect(),
+        name.to_string(),
+        components[position + 3..].iter().collect(),
+    ))
+}
+
+/// Looks up the changes between two ZFS snapshots with `zfs diff`.
+fn zfs_changes(root1: &Path, root2: &Path) -> io::Result<HashSet<PathBuf>> {
+    let not_snapshots = || {
+        unsupported(format!(
+            "'{}' and '{}' are not the same directory of two snapshots of a ZFS dataset",
+            root1.display(),
+            root2.display()
+        ))
+    };
+    let (dataset1, snapshot1, inner1) = split_zfs_snapshot(root1).ok_or_else(not_snapshots)?;
+    let (dataset2, snapshot2, inner2) = split_zfs_snapshot(root2).ok_or_else(not_snapshots)?;
+    if dataset1 != dataset2 || inner1 != inner2 {
+        return Err(not_snapshots());
+    }
+
+    let name = run(
+        "zfs",
+        &["list", "-H", "-o", "name"]
+            .map(OsStr::new)
+            .into_iter()
+            .chain([dataset1.as_os_str()])
+            .collect::<Vec<_>>(),
+    )?;
+    let name = name.trim();
+    let diff = run(
+        "zfs",
+        &[
+            "diff".to_string(),
+            "-H".to_string(),
+            format!("{name}@{snapshot1}"),
+            format!("{name}@{snapshot2}"),
+        ]
+        .iter()
+        .map(OsStr::new)
+        .collect::<Vec<_>>(),
+    )?;
+
+    Ok(parse_zfs_diff(&diff, &dataset1.join(inner1)))
+}
+
+/// Reads the fields of `btrfs subvolume show`, such as `Generation`.
+fn btrfs_subvolume(path: &Path) -> io::Result<HashMap<String, String>> {
+    let output = run(
+        "btrfs",
+        &[
+            OsStr::new("subvolume"),
+            OsStr::new("show"),
+            path.as_os_str(),
+        ],
+    )?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect())
+}
+
+/// Looks up the changes between two Btrfs snapshots with `btrfs subvolume find-new`.
+///
+/// The files whose data changed in the newer snapshot after the generation of the older one are
+/// listed. Deleted files are not, so files present in only one snapshot must be treated as
+/// changed as well.
+fn btrfs_changes(root1: &Path, root2: &Path) -> io::Result<HashSet<PathBuf>> {
+    let subvolume1 = btrfs_subvolume(root1)?;
+    let subvolume2 = btrfs_subvolume(root2)?;
+    let field = |subvolume: &HashMap<String, String>, key: &str| {
+        subvolume.get(key).cloned().filter(|value| value != "-")
+    };
+
+    // Snapshots of the same subvolume share its UUID as their parent
+    let related = |a: &HashMap<String, String>, b: &HashMap<String, String>| {
+        field(a, "Parent UUID").is_some_and(|parent| {
+            Some(&parent) == field(b, "Parent UUID").as_ref()
+                || Some(&parent) == field(b, "UUID").as_ref()
+        })
+    };
+    if !related(&subvolume1, &subvolume2) && !related(&subvolume2, &subvolume1) {
+        return Err(unsupported(format!(
+            "'{}' and '{}' are not snapshots of the same Btrfs subvolume",
+            root1.display(),
+            root2.display()
+        )));
+    }
+
+    let generation = |subvolume: &HashMap<String, String>| {
+        field(subvolume, "Generation")
+            .and_then(|generation| generation.parse::<u64>().ok())
+            .ok_or_else(|| io::Error::other("btrfs reported no generation"))
+    };
+    let (generation1, generation2) = (generation(&subvolume1)?, generation(&subvolume2)?);
+    let (newer, older_generation) = if generation1 <= generation2 {
+        (root2, generation1)
+    } else {
+        (root1, generation2)
+    };
+    let output = run(
+        "btrfs",
+        &[
+            OsStr::new("subvolume"),
+            OsStr::new("find-new"),
+            newer.as_os_str(),
+            OsStr::new(&older_generation.to_string()),
+        ],
+    )?;
+
+    Ok(parse_btrfs_find_new(&output))
+}
+
+/// Looks up which files changed between two snapshots of the same Btrfs subvolume or ZFS
+/// dataset, as recorded by the filesystem, without reading them.
+///
+/// ZFS snapshots are the directories of the snapshots in `.zfs/snapshot`, or the same directory
+/// inside them. Btrfs snapshots must be the subvolumes themselves. The `zfs` and `btrfs` tools
+/// are run to look up the changes, and `btrfs` needs root privileges to do so.
+///
+/// # Parameters
+/// - `root1`: The first snapshot.
+/// - `root2`: The second snapshot, older or newer.
+///
+/// # Returns
+/// The changed paths, relative to the snapshots. The paths of files present in only one
+/// snapshot may be missing.
+///
+/// # Errors
+/// This function returns an error of kind `Unsupported` if the directories are not snapshots of
+/// the same filesystem, and an `io::Error` if the tool of the filesystem cannot be run or fails.
+pub fn snapshot_changes(root1: &Path, root2: &Path) -> io::Result<SnapshotChanges> {
+    let root1 = fs::canonicalize(root1)?;
+    let root2 = fs::canonicalize(root2)?;
+    let kind = |root: &Path| detect_filesystem(root).map(|filesystem| filesystem.kind);
+
+    match (kind(&root1).as_deref(), kind(&root2).as_deref()) {
+        (Some("zfs"), Some("zfs")) => Ok(SnapshotChanges {
+            kind: SnapshotKind::Zfs,
+            changed: zfs_changes(&root1, &root2)?,
+        }),
+        (Some("btrfs"), Some("btrfs")) => Ok(SnapshotChanges {
+            kind: SnapshotKind::Btrfs,
+            changed: btrfs_changes(&root1, &root2)?,
+        }),
+        _ => Err(unsupported(format!(
+            "'{}' and '{}' are not both on Btrfs or ZFS",
+            root1.display(),
+            root2.display()
+        ))),
+    }
+}
+
+/// The outcome of comparing two snapshots.
+#[derive(Debug, Clone)]
+pub struct SnapshotComparison {
+    /// The result of the comparison.
+    pub result: ComparisonResult,
+    /// The filesystem that recorded the changes.
+    pub kind: SnapshotKind,
+    /// The number of files matched by path without reading them, on each side.
+    pub unchanged: usize,
+}
+
+/// Lists the files of a root with their sizes, keyed by their path relative to the root.
+fn list_files(root: &Path, options: &CompareOptions) -> HashMap<PathBuf, u64> {
+    let (sender, receiver) = unbounded();
+    send_file_paths(root, &sender, options, &[], None);
+    drop(sender);
+
+    receiver
+        .into_iter()
+        .filter_map(|path| {
+            let size = fs::metadata(&path).ok()?.len();
+            Some((path.strip_prefix(root).ok()?.to_path_buf(), size))
+        })
+        .collect()
+}
+
+/// Compares two snapshots of the same Btrfs subvolume or ZFS dataset, only reading the files
+/// that changed between them.
+///
+/// Files the filesystem records as unchanged, present in both snapshots with the same size, are
+/// added to the intersection by path without reading them. All other files are compared by
+/// content like [`compare_multiple_roots`] does. This trusts the records of the filesystem,
+/// which makes verifying one snapshot against another nearly free, but does not detect damage
+/// to unchanged files. The file of an unchanged path is not compared to the changed files either,
+/// so a changed file with the same content as an unchanged one is reported as unique.
+///
+/// # Parameters
+/// - `root1`: The first snapshot, see [`snapshot_changes`].
+/// - `root2`: The second snapshot.
+/// - `options`: The options controlling the comparison. Groups by content cannot be computed,
+///   and paths cannot be made relative to other directories.
+///
+/// # Returns
+/// The result, and the number of files matched without reading them.
+///
+/// # Errors
+/// This function returns an error of kind `Unsupported` if the changes between the snapshots
+/// cannot be looked up, see [`snapshot_changes`], one of kind `InvalidInput` if groups or
+/// paths relative to other directories are requested, and any error of
+/// [`compare_multiple_roots`].
+pub fn compare_snapshots(
+    root1: &Path,
+    root2: &Path,
+    options: &CompareOptions,
+) -> io::Result<SnapshotComparison> {
+    if options.include_groups || options.relative_to1.is_some() || options.relative_to2.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "snapshots cannot be compared by their changes when grouping files or making paths \
+             relative to other directories",
+        ));
+    }
+    let changes = snapshot_changes(root1, root2)?;
+
+    let files1 = list_files(root1, options);
+    let files2 = list_files(root2, options);
+    let unchanged: Arc<HashSet<PathBuf>> = Arc::new(
+        files1
+            .iter()
+            .filter(|(path, size)| {
+                !changes.changed.contains(*path) && files2.get(*path) == Some(size)
+            })
+            .map(|(path, _)| path.clone())
+            .collect(),
+    );
+
+    // Only the other files are walked and read
+    let roots: Vec<PathBuf> = [root1, root2]
+        .into_iter()
+        .flat_map(|root| {
+            [
+                root.to_path_buf(),
+                fs::canonicalize(root).unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let skipped = Arc::clone(&unchanged);
+    let outer = options.walk_filter.clone();
+    let filter = WalkFilter::new(move |entry| {
+        let is_unchanged = !entry.file_type().is_dir()
+            && roots.iter().any(|root| {
+                entry
+                    .path()
+                    .strip_prefix(root)
+                    .is_ok_and(|path| skipped.contains(path))
+            });
+        !is_unchanged && outer.as_ref().is_none_or(|outer| outer.keeps(entry))
+    });
+    let mut result = compare_multiple_roots(
+        &[root1.to_path_buf()],
+        &[root2.to_path_buf()],
+        &CompareOptions {
+            walk_filter: Some(filter),
+            ..options.clone()
+        },
+    )?;
+
+    let display = |root: &Path, path: &Path| {
+        if options.relative {
+            path.to_path_buf()
+        } else if options.absolute {
+            fs::canonicalize(root).unwrap_or_default().join(path)
+        } else {
+            root.join(path)
+        }
+    };
+    let matched: Vec<PathBuf> = unchanged
+        .iter()
+        .flat_map(|path| [display(root1, path), display(root2, path)])
+        .collect();
+    // The unchanged files were only left out of the walk to be matched by path
+    if let Some(excluded) = &mut result.excluded {
+        let matched: HashSet<&PathBuf> = matched.iter().collect();
+        excluded
+            .retain(|file| file.reason != SkipReason::Filtered || !matched.contains(&file.path));
+    }
+    if let Some(intersection) = &mut result.intersection {
+        intersection.extend(matched);
+        if options.sort {
+            intersection.sort();
+        }
+    }
+
+    Ok(SnapshotComparison {
+        result,
+        kind: changes.kind,
+        unchanged: unchanged.len(),
+    })
+}
@@ -0,0 +1,711 @@
+use blake3::Hash;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::options::{CompareOptions, SumsPolicy};
+use crate::result::{SkippedFile, WalkError};
+use crate::util::{invalid_data, send_file_paths, walk_error_to_io};
+
+/// Name of the file holding the hashes of the files in its directory.
+pub const SUMS_FILE_NAME: &str = ".filematch.sums";
+
+/// First line of every sums file.
+const HEADER: &str = "# filematch sums 1";
+
+/// The size and modification time of a file, used to tell if a recorded hash is still valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The modification time, as the time since the Unix epoch.
+    pub modified: Duration,
+}
+
+impl FileStamp {
+    /// Reads the stamp of the file at the given path.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` if the metadata cannot be read, or an error of kind
+    /// `Unsupported` if the file has no modification time after the Unix epoch.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| io::Error::from(io::ErrorKind::Unsupported))?;
+
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// The hash recorded for a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SumsEntry {
+    /// The BLAKE3 hash of the file contents.
+    pub hash: Hash,
+    /// The stamp of the file when it was hashed.
+    pub stamp: FileStamp,
+}
+
+/// Parses a sums file.
+///
+/// Each line holds the hash, size, modification time and name of a file, separated by spaces.
+/// The name is the rest of the line and may contain spaces.
+///
+/// # Parameters
+/// - `reader`: The sums file contents.
+///
+/// # Returns
+/// The recorded entries, keyed by file name.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails, the header is missing, or a line is
+/// malformed.
+pub fn read_sums(reader: impl BufRead) -> io::Result<HashMap<String, SumsEntry>> {
+    let mut lines = reader.lines();
+
+    let header = lines.next().transpose()?;
+    if header.as_deref().map(str::trim_end) != Some(HEADER) {
+        return Err(invalid_data("sums", 1, "missing header"));
+    }
+
+    let mut entries = HashMap::new();
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        let number = index + 2;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, entry) = parse_line(&line, number)?;
+        entries.insert(name.to_string(), entry);
+    }
+
+    Ok(entries)
+}
+
+/// Parses a single line of a sums file into the name and entry of a file.
+fn parse_line(line: &str, number: usize) -> io::Result<(&str, SumsEntry)> {
+    let fields: Vec<&str> = line.splitn(4, ' ').collect();
+    let [hash, size, modified, name] = fields[..] else {
+        return Err(invalid_data(
+            "sums",
+            number,
+            "expected a hash, size, time and name",
+        ));
+    };
+    let hash = Hash::from_hex(hash).map_err(|_| invalid_data("sums", number, "invalid hash"))?;
+    let size = size
+        .parse()
+        .map_err(|_| invalid_data("sums", number, "invalid size"))?;
+    let modified = parse_modified(modified)
+        .ok_or_else(|| invalid_data("sums", number, "invalid modification time"))?;
+
+    Ok((
+        name,
+        SumsEntry {
+            hash,
+            stamp: FileStamp { size, modified },
+        },
+    ))
+}
+
+/// Parses a modification time written as seconds and nanoseconds since the Unix epoch, such as
+/// `1700000000.000000123`.
+pub(crate) fn parse_modified(modified: &str) -> Option<Duration> {
+    modified
+        .split_once('.')
+        .and_then(|(secs, nanos)| Some((secs.parse().ok()?, nanos.parse().ok()?)))
+        .filter(|&(_, nanos)| nanos < 1_000_000_000)
+        .map(|(secs, nanos)| Duration::new(secs, nanos))
+}
+
+/// Formats an entry as a line of a sums file, without the line break.
+fn format_line(name: &str, entry: &SumsEntry) -> String {
+    format!(
+        "{} {} {}.{:09} {name}",
+        entry.hash,
+        entry.stamp.size,
+        entry.stamp.modified.as_secs(),
+        entry.stamp.modified.subsec_nanos()
+    )
+}
+
+/// Writes entries in sums format.
+///
+/// # Parameters
+/// - `writer`: Where to write the sums file.
+/// - `entries`: The entries to record, keyed by file name.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails.
+pub fn write_sums(mut writer: impl Write, entries: &BTreeMap<String, SumsEntry>) -> io::Result<()> {
+    writeln!(writer, "{HEADER}")?;
+
+    for (name, entry) in entries {
+        writeln!(writer, "{}", format_line(name, entry))?;
+    }
+
+    writer.flush()
+}
+
+/// Returns the directory and the name under which a file is recorded.
+///
+/// Files whose name is not valid UTF-8 or contains a line break cannot be recorded.
+fn split_path(path: &Path) -> Option<(&Path, &str)> {
+    let name = path.file_name()?.to_str()?;
+    if name.contains(['\n', '\r']) {
+        return None;
+    }
+    Some((path.parent()?, name))
+}
+
+/// The sums of a single directory, as loaded and updated during a comparison.
+struct DirectorySums {
+    entries: HashMap<String, SumsEntry>,
+    changed: bool,
+}
+
+impl DirectorySums {
+    /// Loads the sums file of a directory.
+    ///
+    /// A sums file that cannot be read or parsed is treated as empty, and replaced on save.
+    fn load(directory: &Path) -> Self {
+        Self {
+            entries: File::open(directory.join(SUMS_FILE_NAME))
+                .and_then(|file| read_sums(BufReader::new(file)))
+                .unwrap_or_default(),
+            changed: false,
+        }
+    }
+}
+
+/// A file recording the hash of every file read, keyed by its full path, as it is read.
+///
+/// The journal uses the format of sums files. Each hash is appended as soon as it is known, so
+/// an interrupted comparison can be resumed without reading the recorded files again. A line
+/// cut short by the interruption is ignored when the journal is opened again.
+pub struct HashJournal {
+    entries: HashMap<PathBuf, SumsEntry>,
+    writer: BufWriter<File>,
+}
+
+impl HashJournal {
+    /// Opens a journal, creating it if it does not exist.
+    ///
+    /// # Parameters
+    /// - `path`: The journal file.
+    ///
+    /// # Returns
+    /// The journal, holding the entries recorded so far.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` if the file cannot be read or created, or an error
+    /// of kind `InvalidData` if it is not a journal.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut entries = HashMap::new();
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut lines = BufReader::new(&file).lines();
+        let created = match lines.next().transpose()? {
+            None => true,
+            Some(header) if header.trim_end() == HEADER => false,
+            Some(_) => return Err(invalid_data("sums", 1, "missing header")),
+        };
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if let Ok((name, entry)) = parse_line(&line, index + 2) {
+                entries.insert(PathBuf::from(name), entry);
+            }
+        }
+
+        if created {
+            writeln!(file, "{HEADER}")?;
+        } else {
+            // Start on a new line, in case the last one was cut short
+            writeln!(file)?;
+        }
+        Ok(Self {
+            entries,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Returns the number of files recorded.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if no file is recorded.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks up the recorded hash of a file, like [`SumsStore::lookup`].
+    fn lookup(&self, path: &Path, stamp: &FileStamp) -> Option<Hash> {
+        self.entries
+            .get(path)
+            .filter(|entry| entry.stamp == *stamp)
+            .map(|entry| entry.hash)
+    }
+
+    /// Appends the hash of a file. Paths that are not valid UTF-8 or contain a line break are
+    /// not recorded.
+    fn record(&mut self, path: &Path, entry: SumsEntry) -> io::Result<()> {
+        let Some(name) = path.to_str().filter(|name| !name.contains(['\n', '\r'])) else {
+            return Ok(());
+        };
+        writeln!(self.writer, "{}", format_line(name, &entry))?;
+        self.entries.insert(path.to_path_buf(), entry);
+        Ok(())
+    }
+}
+
+/// The sums files of all directories touched by a comparison.
+///
+/// Sums files are loaded the first time a file of their directory is looked up. Files whose
+/// size and modification time still match their entry are not read again. New hashes are kept
+/// in memory until [`SumsStore::save`] writes them. A store may also keep a [`HashJournal`],
+/// which is consulted and updated along with, or instead of, the sums files.
+pub struct SumsStore {
+    update: bool,
+    use_files: bool,
+    directories: Mutex<HashMap<PathBuf, DirectorySums>>,
+    journal: Option<Mutex<HashJournal>>,
+}
+
+impl SumsStore {
+    /// Creates a store.
+    ///
+    /// # Parameters
+    /// - `update`: Record the hashes of files that were read, to be written by `save`.
+    #[must_use]
+    pub fn new(update: bool) -> Self {
+        Self {
+            update,
+            use_files: true,
+            directories: Mutex::new(HashMap::new()),
+            journal: None,
+        }
+    }
+
+    /// Creates a store that only uses a journal, leaving the sums files alone.
+    #[must_use]
+    pub fn journal_only(journal: HashJournal) -> Self {
+        Self {
+            update: false,
+            use_files: false,
+            directories: Mutex::new(HashMap::new()),
+            journal: Some(Mutex::new(journal)),
+        }
+    }
+
+    /// Adds a journal to the store, consulted after the sums files and updated with the hash of
+    /// every file read.
+    #[must_use]
+    pub fn with_journal(mut self, journal: HashJournal) -> Self {
+        self.journal = Some(Mutex::new(journal));
+        self
+    }
+
+    /// Looks up the recorded hash of a file.
+    ///
+    /// # Parameters
+    /// - `path`: The file to look up.
+    /// - `stamp`: The current stamp of the file.
+    ///
+    /// # Returns
+    /// The recorded hash, or None if the file is not recorded or changed since.
+    ///
+    /// # Panics
+    /// This function panics if another thread panicked while holding the lock.
+    #[must_use]
+    pub fn lookup(&self, path: &Path, stamp: &FileStamp) -> Option<Hash> {
+        self.lookup_file(path, stamp).or_else(|| {
+            let journal = self.journal.as_ref()?;
+            journal
+                .lock()
+                .expect("Journal lock poisoned")
+                .lookup(path, stamp)
+        })
+    }
+
+    /// Looks up the hash of a file recorded in the sums file of its directory.
+    fn lookup_file(&self, path: &Path, stamp: &FileStamp) -> Option<Hash> {
+        let (directory, name) = split_path(path).filter(|_| self.use_files)?;
+        let mut directories = self.directories.lock().expect("Sums lock poisoned");
+
+        let sums = directories
+            .entry(directory.to_path_buf())
+            .or_insert_with(|| DirectorySums::load(directory));
+
+        sums.entries
+            .get(name)
+            .filter(|entry| entry.stamp == *stamp)
+            .map(|entry| entry.hash)
+    }
+
+    /// Records the hash of a file that was read, if the store updates sums files or has a
+    /// journal.
+    ///
+    /// # Parameters
+    /// - `path`: The file that was read.
+    /// - `stamp`: The stamp of the file before it was read.
+    /// - `hash`: The hash of its contents.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` if the journal cannot be written.
+    ///
+    /// # Panics
+    /// This function panics if another thread panicked while holding the lock.
+    pub fn record(&self, path: &Path, stamp: FileStamp, hash: Hash) -> io::Result<()> {
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .expect("Journal lock poisoned")
+                .record(path, SumsEntry { hash, stamp })?;
+        }
+
+        let Some((directory, name)) = split_path(path).filter(|_| self.update) else {
+            return Ok(());
+        };
+        let mut directories = self.directories.lock().expect("Sums lock poisoned");
+
+        let sums = directories
+            .entry(directory.to_path_buf())
+            .or_insert_with(|| DirectorySums::load(directory));
+        sums.entries
+            .insert(name.to_string(), SumsEntry { hash, stamp });
+        sums.changed = true;
+        Ok(())
+    }
+
+    /// Writes the sums files of all directories with new hashes, and flushes the journal.
+    ///
+    /// Entries of files that no longer exist are dropped. Each file is written to a temporary
+    /// file first and then renamed, so an interrupted save never leaves a truncated sums file.
+    ///
+    /// # Errors
+    /// This function returns an `io::Error` if a sums file or the journal cannot be written.
+    ///
+    /// # Panics
+    /// This function panics if another thread panicked while holding the lock.
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .expect("Journal lock poisoned")
+                .writer
+                .flush()?;
+        }
+        let directories = self.directories.lock().expect("Sums lock poisoned");
+
+        for (directory, sums) in directories.iter().filter(|(_, sums)| sums.changed) {
+            let entries: BTreeMap<String, SumsEntry> = sums
+                .entries
+                .iter()
+                .filter(|(name, _)| directory.join(name).is_file())
+                .map(|(name, entry)| (name.clone(), *entry))
+                .collect();
+
+            let path = directory.join(SUMS_FILE_NAME);
+            let temporary = directory.join(format!("{SUMS_FILE_NAME}.tmp"));
+            write_sums(BufWriter::new(File::create(&temporary)?), &entries)
+                .and_then(|()| fs::rename(&temporary, &path))
+                .map_err(|err| {
+                    let _ = fs::remove_file(&temporary);
+                    io::Error::new(err.kind(), format!("{}: {err}", path.display()))
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The outcome of bringing the sums files of directory trees up to date.
+#[derive(Debug, Clone, Default)]
+pub struct PrecomputeReport {
+    /// The number of files whose hash is recorded.
+    pub files: usize,
+    /// Files that were found but not hashed.
+    pub skipped: Vec<SkippedFile>,
+    /// Paths that could not be read while walking the trees.
+    pub walk_errors: Vec<WalkError>,
+}
+
+/// Records the hash of every file in directory trees in the sums file of its directory.
+///
+/// Files already recorded with an unchanged size and modification time are not read again, so
+/// running this regularly only reads new and modified files. A later comparison trusting the
+/// sums files then only needs to read the other side. Overlapping directories are allowed.
+///
+/// # Parameters
+/// - `directories`: The root directories of the trees.
+/// - `options`: The options controlling which files are included and the thread counts.
+///   `options.sums` is ignored, as the sums files are always updated.
+///
+/// # Returns
+/// The number of files recorded, the files that were skipped, and the paths that could not be
+/// read.
+///
+/// # Panics
+/// This function may panic if a thread panics.
+///
+/// # Errors
+/// This function returns an `io::Error` if a file cannot be read or a sums file cannot be
+/// written, like [`compare_multiple_roots`].
+pub fn precompute(
+    directories: &[PathBuf],
+    options: &CompareOptions,
+) -> io::Result<PrecomputeReport> {
+    let options = CompareOptions {
+        relative: false,
+        // Each file is only hashed once anyway
+        allow_overlapping_roots: true,
+        include_intersection: false,
+        include_unique_dir1: true,
+        include_unique_dir2: false,
+        include_groups: false,
+        sums: SumsPolicy::Update,
+        ..options.clone()
+    };
+    let result = compare_multiple_roots(directories, &[], &options)?;
+
+    Ok(PrecomputeReport {
+        files: result.unique_dir1.map_or(0, |paths| paths.len()),
+        skipped: result.skipped,
+        walk_errors: result.walk_errors,
+    })
+}
+
+/// The recorded hashes of a directory tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportedSums {
+    /// The files with an up to date hash, with paths relative to the tree.
+    pub hashes: Vec<(PathBuf, Hash)>,
+    /// The files without a recorded hash, or changed since it was recorded.
+    pub missing: Vec<PathBuf>,
+}
+
+/// Collects the hashes recorded in the sums files of a directory tree.
+///
+/// No file is read: files without an up to date entry in the sums file of their directory are
+/// reported as missing instead.
+///
+/// # Parameters
+/// - `directory`: The root directory of the tree.
+/// - `options`: The options controlling which files are included.
+///
+/// # Returns
+/// The recorded hashes and the files without one, both sorted by path.
+///
+/// # Errors
+/// This function returns an `io::Error` naming the first path that could not be read while
+/// walking the tree.
+pub fn export_sums(directory: &Path, options: &CompareOptions) -> io::Result<ExportedSums> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let walk_errors = send_file_paths(directory, &sender, options, &[], None);
+    drop(sender);
+    if let Some(err) = walk_errors.first() {
+        return Err(walk_error_to_io(err));
+    }
+
+    let sums = SumsStore::new(false);
+    let mut exported = ExportedSums::default();
+    for path in receiver {
+        let hash = FileStamp::read(&path)
+            .ok()
+            .and_then(|stamp| sums.lookup(&path, &stamp));
+        let path = path
+            .strip_prefix(directory)
+            .map_or_else(|_| path.clone(), Path::to_path_buf);
+
+        match hash {
+            Some(hash) => exported.hashes.push((path, hash)),
+            None => exported.missing.push(path),
+        }
+    }
+    exported.hashes.sort_by(|a, b| a.0.cmp(&b.0));
+    exported.missing.sort();
+
+    Ok(exported)
+}
+
+/// Writes hashes as checksum lines, as read by `b3sum --check`.
+///
+/// Each line holds the hex hash, two spaces and the path. Like coreutils, paths containing a
+/// backslash or line break are escaped, and their line starts with a backslash.
+///
+/// # Parameters
+/// - `writer`: Where to write the lines.
+/// - `hashes`: The paths and their hashes.
+///
+/// # Errors
+/// This function returns an `io::Error` if writing fails.
+pub fn write_checksum_lines(mut writer: impl Write, hashes: &[(PathBuf, Hash)]) -> io::Result<()> {
+    for (path, hash) in hashes {
+        let path = path.display().to_string();
+        match escape_path(&path) {
+            Some(escaped) => writeln!(writer, "\\{hash}  {escaped}")?,
+            None => writeln!(writer, "{hash}  {path}")?,
+        }
+    }
+
+    writer.flush()
+}
+
+/// Parses checksum lines, as written by `b3sum` or `sha256sum`.
+///
+/// Each line holds the hex digest and the path, separated by two spaces, or by a space and an
+/// asterisk for files hashed in binary mode. Escaped lines starting with a backslash are
+/// unescaped.
+///
+/// # Parameters
+/// - `reader`: The checksum file contents.
+///
+/// # Returns
+/// The paths and their lowercase hex digests, in the order of the file.
+///
+/// # Errors
+/// This function returns an `io::Error` if reading fails or a line is malformed.
+pub fn read_checksum_lines(reader: impl BufRead) -> io::Result<Vec<(PathBuf, String)>> {
+    let mut checksums = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        if let Some(checksum) = parse_checksum_line(&line?, index)? {
+            checksums.push(checksum);
+        }
+    }
+
+    Ok(checksums)
+}
+
+/// Parses a single checksum line, see [`read_checksum_lines`].
+///
+/// # Parameters
+/// - `line`: The line.
+/// - `index`: The index of the line in the file, counting from 0, for error messages.
+///
+/// # Returns
+/// The path and its lowercase hex digest, or None for empty lines and comments.
+///
+/// # Errors
+/// This function returns an `io::Error` of kind `InvalidData` if the line is malformed.
+pub(crate) fn parse_checksum_line(
+    line: &str,
+    index: usize,
+) -> io::Result<Option<(PathBuf, String)>> {
+    let line = line.trim_end_matches('\r');
+    if line.trim().is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let Some((digest, path)) = line
+        .split_once(' ')
+        .and_then(|(digest, rest)| Some((digest, rest.strip_prefix([' ', '*'])?)))
+        .filter(|(digest, _)| digest.chars().all(|c| c.is_ascii_hexdigit()))
+    else {
+        return Err(invalid_data(
+            "checksum",
+            index + 1,
+            "expected a digest and a path",
+        ));
+    };
+
+    let path = if escaped {
+        unescape_path(path)
+    } else {
+        path.to_string()
+    };
+    Ok(Some((PathBuf::from(path), digest.to_ascii_lowercase())))
+}
+
+/// Escapes the backslashes and line breaks of a path as coreutils does, so it fits on a line.
+///
+/// # Returns
+/// The escaped path, or None if the path has nothing to escape. Lines with an escaped path
+/// start with a backslash.
+pub(crate) fn escape_path(path: &str) -> Option<String> {
+    path.contains(['\\', '\n', '\r']).then(|| {
+        path.replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+    })
+}
+
+/// Reverses [`escape_path`].
+pub(crate) fn unescape_path(path: &str) -> String {
+    path.replace("\\\\", "\0")
+        .replace("\\n", "\n")
+        .replace("\\r", "\r")
+        .replace('\0', "\\")
+}
+
+/// The outcome of importing checksums into sums files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The number of files whose hash was recorded.
+    pub imported: usize,
+    /// The listed files that do not exist.
+    pub missing: Vec<PathBuf>,
+}
+
+/// Records known BLAKE3 hashes in the sums files of a directory tree, without reading the files.
+///
+/// The hashes are trusted as they are: each is recorded with the current size and modification
+/// time of its file, so the file is not read by later runs until it changes. Only import
+/// checksums that are known to match the current files.
+///
+/// # Parameters
+/// - `directory`: The directory the paths are relative to.
+/// - `checksums`: The paths and their hex BLAKE3 hashes.
+///
+/// # Returns
+/// The number of hashes recorded, and the listed files that do not exist.
+///
+/// # Errors
+/// This function returns an error of kind `InvalidData` if a hash is not a valid BLAKE3 hash,
+/// or an `io::Error` if a sums file cannot be written.
+pub fn import_sums(directory: &Path, checksums: &[(PathBuf, String)]) -> io::Result<ImportReport> {
+    let sums = SumsStore::new(true);
+    let mut report = ImportReport::default();
+
+    for (path, digest) in checksums {
+        let hash = Hash::from_hex(digest).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: '{digest}' is not a BLAKE3 hash", path.display()),
+            )
+        })?;
+
+        let full_path = directory.join(path);
+        match FileStamp::read(&full_path) {
+            Ok(stamp) if full_path.is_file() => {
+                sums.record(&full_path, stamp, hash)?;
+                report.imported += 1;
+            }
+            _ => report.missing.push(path.clone()),
+        }
+    }
+    sums.save()?;
+
+    Ok(report)
+}
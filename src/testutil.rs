@@ -0,0 +1,283 @@
+//! Deterministic directory fixtures for benchmarks and tests.
+//!
+//! File names and contents are derived from a seed, so the same calls always produce the same
+//! tree. This module is only available with the `testutil` feature.
+
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::options::ContentTransform;
+
+/// File sizes generated by default: 1 GiB, 100 MiB, 10 MiB and 1 MiB.
+pub const DEFAULT_FILE_SIZES: [u64; 4] = [
+    1024 * 1024 * 1024,
+    100 * 1024 * 1024,
+    10 * 1024 * 1024,
+    1024 * 1024,
+];
+
+/// Writes a file of the given size filled with pseudo-random bytes derived from `init`.
+///
+/// # Parameters
+/// - `path`: The file to create.
+/// - `size`: The size of the file in bytes.
+/// - `init`: The seed of the file contents.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be written.
+pub fn generate_deterministic_file(path: &Path, size: u64, init: u64) -> io::Result<()> {
+    // Create a buffered writer to reduce the overhead of multiple small writes
+    let file = File::create(path)?;
+    let mut writer = BufWriter::new(file);
+
+    // Initialize the Xoshiro256Plus RNG with the given seed
+    let mut rng = Xoshiro256Plus::seed_from_u64(init);
+
+    // Use a larger buffer to write fewer times
+    let mut buffer = vec![0_u8; 64 * 1024]; // 64 KiB
+    let mut remaining = size;
+
+    while remaining > 0 {
+        // Determine the chunk size to avoid overrun
+        let chunk_size = usize::try_from(remaining).map_or(buffer.len(), |r| r.min(buffer.len()));
+
+        // Fill the buffer slice
+        rng.fill_bytes(&mut buffer[..chunk_size]);
+
+        // Write the chunk
+        writer.write_all(&buffer[..chunk_size])?;
+
+        remaining -= chunk_size as u64;
+    }
+
+    // Ensure all data is flushed
+    writer.flush()?;
+    Ok(())
+}
+
+/// Creates a deterministic file named after its seed in the given directory.
+///
+/// An existing file of that name is kept, so repeated setups reuse the files.
+///
+/// # Parameters
+/// - `path`: The directory to create the file in.
+/// - `size`: The size of the file in bytes.
+/// - `init`: The seed of the file name and contents.
+///
+/// # Returns
+/// The path of the file.
+///
+/// # Errors
+/// This function returns an `io::Error` if `path` is not a directory or the file cannot be
+/// written.
+pub fn generate_deterministic_file_in_dir(
+    path: &Path,
+    size: u64,
+    init: u64,
+) -> io::Result<PathBuf> {
+    if !path.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Path must be a directory",
+        ));
+    }
+
+    // Generate the file name using hexadecimal formatting with a .bin extension.
+    let file_name = format!("random_{init:016x}.bin");
+    let file_path = path.join(file_name);
+
+    if !file_path.exists() {
+        generate_deterministic_file(&file_path, size, init)?;
+    }
+
+    Ok(file_path)
+}
+
+/// Generates `len` pseudo-random numbers from a seed.
+#[must_use]
+pub fn generate_deterministic_numbers(seed: u64, len: usize) -> Vec<u64> {
+    // Initialize the generator with the given seed.
+    let mut rng = Xoshiro256Plus::seed_from_u64(seed);
+
+    // Generate len random u64 values.
+    (0..len).map(|_| rng.next_u64()).collect()
+}
+
+/// Creates `count` deterministic files of the same size in a directory.
+///
+/// # Parameters
+/// - `dir`: The directory to create the files in.
+/// - `size`: The size of each file in bytes.
+/// - `seed`: The seed the file seeds are derived from.
+/// - `count`: The number of files.
+///
+/// # Returns
+/// The paths of the files.
+///
+/// # Errors
+/// This function returns an `io::Error` if a file cannot be written.
+pub fn generate_deterministic_files(
+    dir: &Path,
+    size: u64,
+    seed: u64,
+    count: usize,
+) -> io::Result<Vec<PathBuf>> {
+    // Generate file seeds deterministically, and a file for each seed
+    generate_deterministic_numbers(seed, count)
+        .into_iter()
+        .map(|seed| generate_deterministic_file_in_dir(dir, size, seed))
+        .collect()
+}
+
+/// The files generated for a benchmark, as the comparison is expected to report them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BenchmarkFiles {
+    /// Files present in both directories.
+    pub both: Vec<PathBuf>,
+    /// Files only in directory A.
+    pub only_a: Vec<PathBuf>,
+    /// Files only in directory B.
+    pub only_b: Vec<PathBuf>,
+}
+
+/// Sets up a benchmark file structure in two directories (A and B) based on a given total size.
+///
+/// The total size is divided equally among one group per file size. Sizes that do not fit
+/// their share at least once are left out, and if no size fits, the smallest one is used. For
+/// each group, files are split into three categories:
+/// - Files that reside only in directory A.
+/// - Files that reside only in directory B.
+/// - Files that exist in both directories (generated in A and then copied to B).
+///
+/// The provided `base_seed` guarantees deterministic file names and content.
+///
+/// # Parameters
+/// - `dir_a`: Directory A.
+/// - `dir_b`: Directory B.
+/// - `total`: The total size of the files in directory A, in bytes.
+/// - `file_sizes`: The file sizes in bytes, one group each.
+/// - `base_seed`: The seed all file names and contents are derived from.
+///
+/// # Returns
+/// The generated files by category.
+///
+/// # Errors
+/// This function returns an `io::Error` if `file_sizes` is empty or a file cannot be written.
+pub fn setup_benchmark_files(
+    dir_a: &Path,
+    dir_b: &Path,
+    total: u64,
+    file_sizes: &[u64],
+    base_seed: u64,
+) -> io::Result<BenchmarkFiles> {
+    let Some(&smallest) = file_sizes.iter().filter(|&&size| size > 0).min() else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "at least one non-zero file size is required",
+        ));
+    };
+
+    // Ensure the directories exist.
+    fs::create_dir_all(dir_a)?;
+    fs::create_dir_all(dir_b)?;
+
+    // Divide the total equally among the groups.
+    let group_total = total / file_sizes.len() as u64;
+
+    // Only include groups where group_total can accommodate at least one file.
+    let mut groups: Vec<(u64, u64)> = file_sizes
+        .iter()
+        .filter(|&&size| size > 0 && group_total >= size)
+        .map(|&size| (size, group_total / size))
+        .collect();
+
+    // If no group qualifies, default to the smallest file size.
+    if groups.is_empty() {
+        groups.push((smallest, (group_total / smallest).max(1)));
+    }
+
+    let mut files = BenchmarkFiles::default();
+
+    // For each group, split files into three categories: only in A, only in B, and in both.
+    for (group_idx, &(file_size, count)) in groups.iter().enumerate() {
+        let count = usize::try_from(count).unwrap_or(usize::MAX);
+        let only_a = count / 3;
+        let only_b = count / 3;
+        let both = count - only_a - only_b;
+
+        // Derive unique seeds for each category.
+        let group_seed = base_seed.wrapping_add((group_idx as u64) << 48);
+
+        // Generate files only in subdirectory A.
+        files.only_a.extend(generate_deterministic_files(
+            dir_a, file_size, group_seed, only_a,
+        )?);
+
+        // Generate files only in subdirectory B.
+        files.only_b.extend(generate_deterministic_files(
+            dir_b,
+            file_size,
+            group_seed.wrapping_add(1),
+            only_b,
+        )?);
+
+        // Generate files that will exist in both: create in A then copy to B.
+        for file in
+            generate_deterministic_files(dir_a, file_size, group_seed.wrapping_add(2), both)?
+        {
+            let file_b = dir_b.join(file.file_name().expect("generated files have a name"));
+            if !file_b.exists() {
+                fs::copy(&file, &file_b)?;
+            }
+            files.both.push(file);
+            files.both.push(file_b);
+        }
+    }
+
+    files.both.sort();
+    files.only_a.sort();
+    files.only_b.sort();
+
+    Ok(files)
+}
+
+/// Creates a content transform that makes files with different contents collide, so tests can
+/// check how grouped results are handled without crafting real hash collisions.
+///
+/// Every listed file is hashed as if it had the contents of the first one, so all of them end up
+/// in a single group with the hash and size of the first file. Other files are hashed as they
+/// are. Set the transform as `CompareOptions::transform`.
+///
+/// # Parameters
+/// - `paths`: The files to treat as equal. Paths are matched after canonicalizing them, so they
+///   may be given in any form.
+///
+/// # Returns
+/// The transform.
+///
+/// # Errors
+/// This function returns an `io::Error` if no file is given, or a file cannot be read or
+/// canonicalized.
+pub fn forced_collision(paths: &[PathBuf]) -> io::Result<ContentTransform> {
+    let first = paths
+        .first()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no file to collide"))?;
+    let contents: Arc<[u8]> = fs::read(first)?.into();
+    let colliding = paths
+        .iter()
+        .map(fs::canonicalize)
+        .collect::<io::Result<HashSet<_>>>()?;
+
+    Ok(ContentTransform::new(move |path, reader| {
+        if fs::canonicalize(path).is_ok_and(|path| colliding.contains(&path)) {
+            Box::new(Cursor::new(Arc::clone(&contents)))
+        } else {
+            reader
+        }
+    }))
+}
@@ -0,0 +1,196 @@
+use blake3::Hash;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::options::CompareOptions;
+
+/// How a path changed in one copy relative to the common ancestor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// The file is not in the ancestor.
+    Added,
+    /// The file of the ancestor is gone.
+    Removed,
+    /// The file has other contents than in the ancestor.
+    Modified,
+}
+
+impl Change {
+    /// Determines how a file changed from its contents in the ancestor and a copy.
+    fn between(base: Option<Hash>, copy: Option<Hash>) -> Option<Self> {
+        match (base, copy) {
+            (None, Some(_)) => Some(Self::Added),
+            (Some(_), None) => Some(Self::Removed),
+            (Some(base), Some(copy)) if base != copy => Some(Self::Modified),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added => write!(f, "added"),
+            Self::Removed => write!(f, "removed"),
+            Self::Modified => write!(f, "modified"),
+        }
+    }
+}
+
+/// Where a path changed relative to the common ancestor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThreeWayStatus {
+    /// Only the first copy changed, so its version can be taken.
+    ChangedInA,
+    /// Only the second copy changed, so its version can be taken.
+    ChangedInB,
+    /// Both copies changed the same way, so they agree.
+    ChangedInBoth,
+    /// Both copies changed differently, so they must be reconciled by hand.
+    Conflict,
+}
+
+/// A path whose contents differ between the common ancestor and at least one copy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreeWayChange {
+    /// The path relative to the directories.
+    pub path: PathBuf,
+    /// The hash of the file in the ancestor, or None if it has no such file.
+    pub base: Option<Hash>,
+    /// The hash of the file in the first copy, or None if it has no such file.
+    pub a: Option<Hash>,
+    /// The hash of the file in the second copy, or None if it has no such file.
+    pub b: Option<Hash>,
+}
+
+impl ThreeWayChange {
+    /// Returns how the file changed in the first copy, or None if it did not.
+    #[must_use]
+    pub fn change_a(&self) -> Option<Change> {
+        Change::between(self.base, self.a)
+    }
+
+    /// Returns how the file changed in the second copy, or None if it did not.
+    #[must_use]
+    pub fn change_b(&self) -> Option<Change> {
+        Change::between(self.base, self.b)
+    }
+
+    /// Returns where the file changed.
+    #[must_use]
+    pub fn status(&self) -> ThreeWayStatus {
+        match (self.change_a(), self.change_b()) {
+            (Some(_), None) => ThreeWayStatus::ChangedInA,
+            (None, Some(_)) => ThreeWayStatus::ChangedInB,
+            _ if self.a == self.b => ThreeWayStatus::ChangedInBoth,
+            _ => ThreeWayStatus::Conflict,
+        }
+    }
+}
+
+/// The outcome of comparing two copies with their common ancestor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreeWayDiff {
+    /// The paths that changed in either copy, sorted.
+    pub changes: Vec<ThreeWayChange>,
+    /// The number of paths with the same contents in all three directories.
+    pub unchanged: usize,
+    /// The relative paths that were skipped or could not be read in any of the directories,
+    /// sorted. They are left out of the changes, as they cannot be told apart from removed
+    /// files.
+    pub errors: Vec<PathBuf>,
+}
+
+impl ThreeWayDiff {
+    /// Returns the changes with the given status, sorted by path.
+    pub fn with_status(&self, status: ThreeWayStatus) -> impl Iterator<Item = &ThreeWayChange> {
+        self.changes
+            .iter()
+            .filter(move |change| change.status() == status)
+    }
+}
+
+/// Hashes the files of a directory, keyed by their path relative to it.
+fn hash_by_path(
+    directory: &Path,
+    options: &CompareOptions,
+    errors: &mut BTreeSet<PathBuf>,
+) -> io::Result<BTreeMap<PathBuf, Hash>> {
+    let result =
+        compare_multiple_roots(std::slice::from_ref(&directory.to_path_buf()), &[], options)?;
+    errors.extend(result.errors);
+
+    Ok(result
+        .groups
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|group| group.dir1.into_iter().map(move |path| (path, group.hash)))
+        .collect())
+}
+
+/// Compares two divergent copies of a directory with their common ancestor.
+///
+/// Files are matched by their path relative to the directories and compared by content, so
+/// every path is classified by which copies changed it since the ancestor: only one of them,
+/// both the same way, or both differently, which is a conflict. Renamed files show up as
+/// removed under the old path and added under the new one.
+///
+/// # Parameters
+/// - `base`: The common ancestor of the copies.
+/// - `a`: The first copy.
+/// - `b`: The second copy.
+/// - `options`: The options controlling which files are included and how they are read. Paths
+///   are always relative, and the categories and sort order of the options do not apply.
+///
+/// # Returns
+/// The changed paths with their hashes in each directory, and the number of unchanged paths.
+///
+/// # Errors
+/// This function returns an `io::Error` if comparing any of the directories fails, see
+/// [`compare_multiple_roots`].
+pub fn three_way_diff(
+    base: &Path,
+    a: &Path,
+    b: &Path,
+    options: &CompareOptions,
+) -> io::Result<ThreeWayDiff> {
+    let options = CompareOptions {
+        relative: true,
+        relative_to1: None,
+        relative_to2: None,
+        include_intersection: false,
+        include_unique_dir1: false,
+        include_unique_dir2: false,
+        include_groups: true,
+        ..options.clone()
+    };
+    let mut errors = BTreeSet::new();
+    let base = hash_by_path(base, &options, &mut errors)?;
+    let a = hash_by_path(a, &options, &mut errors)?;
+    let b = hash_by_path(b, &options, &mut errors)?;
+
+    let paths: BTreeSet<&PathBuf> = base.keys().chain(a.keys()).chain(b.keys()).collect();
+    let mut diff = ThreeWayDiff::default();
+    for path in paths {
+        if errors.contains(path) {
+            continue;
+        }
+        let change = ThreeWayChange {
+            path: path.clone(),
+            base: base.get(path).copied(),
+            a: a.get(path).copied(),
+            b: b.get(path).copied(),
+        };
+        if change.change_a().is_none() && change.change_b().is_none() {
+            diff.unchanged += 1;
+        } else {
+            diff.changes.push(change);
+        }
+    }
+    diff.errors = errors.into_iter().collect();
+
+    Ok(diff)
+}
@@ -1,12 +1,22 @@
 use blake3::Hash;
 use blake3::Hasher as BlakeHasher;
 use crossbeam_channel::Sender;
-use std::collections::HashMap;
-use std::fs::File;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{self, Read};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::media::{strip_metadata, MediaFormat};
+use crate::options::{CompareOptions, Compression};
+use crate::result::SkipReason;
+use crate::sums::SUMS_FILE_NAME;
+
+/// Name of the file holding per-directory exclusions in gitignore syntax.
+pub const IGNORE_FILE_NAME: &str = ".filematchignore";
+
 /// Computes the BLAKE3 hash of the file at the given path.
 ///
 /// Opens the file, reads it in chunks, and feeds the data to the hasher.
@@ -52,62 +62,900 @@ fn is_hidden(entry: &DirEntry) -> bool {
         .is_some_and(|s| s.starts_with('.'))
 }
 
-/// Recursively sends all file paths from a directory through a channel.
+/// Names of the ignore files honored in every directory with `respect_gitignore`, the later
+/// taking precedence over the earlier.
+pub const GITIGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// The `.gitignore` and `.ignore` files of a directory tree, loaded as the tree is walked, and
+/// the global git excludes.
 ///
-/// Walks the directory tree and sends file paths if they are not hidden (when `skip_hidden` is true).
+/// As with git and ripgrep, `.gitignore` files and the global excludes only apply inside a git
+/// repository, a directory with a `.git` entry or below one, while `.ignore` files apply
+/// everywhere.
+struct GitignoreFiles {
+    global: Gitignore,
+    directories: HashMap<PathBuf, Option<Gitignore>>,
+    /// Whether each directory seen is inside a git repository.
+    repositories: HashMap<PathBuf, bool>,
+}
+
+impl GitignoreFiles {
+    fn new() -> Self {
+        Self {
+            global: Gitignore::global().0,
+            directories: HashMap::new(),
+            repositories: HashMap::new(),
+        }
+    }
+
+    /// Determines if a directory is inside a git repository.
+    fn in_repository(&mut self, directory: &Path) -> bool {
+        if let Some(&inside) = self.repositories.get(directory) {
+            return inside;
+        }
+        let inside = directory.join(".git").exists()
+            || directory
+                .parent()
+                .is_some_and(|parent| self.in_repository(parent));
+        self.repositories.insert(directory.to_path_buf(), inside);
+        inside
+    }
+
+    /// Returns the matcher built from the ignore files of a directory, if it has any.
+    fn matcher(&mut self, directory: &Path) -> Option<&Gitignore> {
+        if !self.directories.contains_key(directory) {
+            let in_repository = self.in_repository(directory);
+            let mut builder = GitignoreBuilder::new(directory);
+            let mut found = false;
+            for name in GITIGNORE_FILE_NAMES {
+                if name == ".gitignore" && !in_repository {
+                    continue;
+                }
+                let path = directory.join(name);
+                if path.is_file() {
+                    builder.add(path);
+                    found = true;
+                }
+            }
+            let matcher = found.then(|| builder.build().ok()).flatten();
+            self.directories.insert(directory.to_path_buf(), matcher);
+        }
+        self.directories[directory].as_ref()
+    }
+
+    /// Determines if the given file or directory is excluded.
+    ///
+    /// The ignore files of the nearest directory above the entry with a matching pattern
+    /// decide, and the global excludes if none has one and the entry is inside a repository.
+    fn is_ignored(&mut self, entry: &DirEntry) -> bool {
+        let is_dir = entry.file_type().is_dir();
+        for directory in entry.path().ancestors().skip(1).take(entry.depth()) {
+            if let Some(matcher) = self.matcher(directory) {
+                let matched = matcher.matched(entry.path(), is_dir);
+                if !matched.is_none() {
+                    return matched.is_ignore();
+                }
+            }
+        }
+        entry.depth() > 0
+            && entry
+                .path()
+                .parent()
+                .is_some_and(|parent| self.in_repository(parent))
+            && self.global.matched(entry.path(), is_dir).is_ignore()
+    }
+}
+
+/// Loads the ignore file at the root of a directory tree.
+///
+/// The file uses gitignore syntax, with patterns anchored at `directory`. Lines with invalid
+/// patterns are ignored.
 ///
 /// # Parameters
-/// - `directory`: The root directory to scan.
-/// - `sender`: The channel sender to pass file paths.
-/// - `skip_hidden`: If true, skips hidden files.
+/// - `directory`: The root directory of the tree.
+///
+/// # Returns
+/// The matcher built from the ignore file, or None if the directory has no ignore file.
+#[must_use]
+pub fn load_ignore_file(directory: &Path) -> Option<Gitignore> {
+    let path = directory.join(IGNORE_FILE_NAME);
+    if !path.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(directory);
+    builder.add(path);
+    builder.build().ok()
+}
+
+/// Determines if the given file or directory is excluded by an ignore file.
+///
+/// The ignore file at the root of the tree is always excluded itself.
+///
+/// # Parameters
+/// - `entry`: The directory entry to check.
+/// - `ignore`: The matcher built from the ignore file at the root of the tree.
+///
+/// # Returns
+/// True if the entry is excluded, false otherwise.
+fn is_ignored(entry: &DirEntry, ignore: &Gitignore) -> bool {
+    match entry.depth() {
+        0 => false,
+        1 if entry.file_name() == IGNORE_FILE_NAME => true,
+        _ => ignore
+            .matched(entry.path(), entry.file_type().is_dir())
+            .is_ignore(),
+    }
+}
+
+/// A root directory that is inside, or the same as, another root directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootOverlap {
+    /// The outer root directory, as given.
+    pub outer: PathBuf,
+    /// The root directory inside `outer`, as given.
+    pub inner: PathBuf,
+    /// The path of `inner` as found when walking `outer`, or None if both are the same directory.
+    pub nested: Option<PathBuf>,
+}
+
+impl std::fmt::Display for RootOverlap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.nested.is_some() {
+            write!(
+                f,
+                "'{}' is inside '{}'",
+                self.inner.display(),
+                self.outer.display()
+            )
+        } else {
+            write!(
+                f,
+                "'{}' and '{}' are the same directory",
+                self.outer.display(),
+                self.inner.display()
+            )
+        }
+    }
+}
+
+/// Finds root directories that are inside, or the same as, another root directory.
 ///
-/// # Panics
-/// This function may panic if the `sender.send()` call fails.
+/// The roots are compared after canonicalization, so different spellings of the same directory
+/// and paths through symbolic links are detected.
+///
+/// # Parameters
+/// - `roots`: The root directories of all sides of a comparison.
+///
+/// # Returns
+/// The overlapping pairs of roots, in the order of `roots`.
 ///
 /// # Errors
-/// This function does not return any errors directly, but it may panic if the `unwrap()` call fails.
-pub fn send_file_paths(directory: &Path, sender: &Sender<PathBuf>, skip_hidden: bool) {
-    for entry in WalkDir::new(directory)
-        .into_iter()
-        .filter_entry(|e| !skip_hidden || !is_hidden(e))
-        .filter_map(Result::ok)
+/// This function returns an `io::Error` if a root cannot be canonicalized.
+pub fn find_overlapping_roots(roots: &[PathBuf]) -> io::Result<Vec<RootOverlap>> {
+    let canonical = roots
+        .iter()
+        .map(std::fs::canonicalize)
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut overlaps = Vec::new();
+
+    for (i, outer) in canonical.iter().enumerate() {
+        for (j, inner) in canonical.iter().enumerate() {
+            let Ok(rest) = inner.strip_prefix(outer) else {
+                continue;
+            };
+            let same = rest.as_os_str().is_empty();
+            // Report identical roots once, and a root never overlaps itself
+            if i == j || (same && j < i) {
+                continue;
+            }
+
+            overlaps.push(RootOverlap {
+                outer: roots[i].clone(),
+                inner: roots[j].clone(),
+                nested: (!same).then(|| roots[i].join(rest)),
+            });
+        }
+    }
+
+    Ok(overlaps)
+}
+
+/// Returns what identifies the file or directory a path leads to: on Unix its device and inode
+/// numbers, and on Windows its volume serial number and file index, which hard links to a file
+/// share.
+///
+/// # Errors
+/// This function returns an `io::Error` if the path cannot be read, or one of kind
+/// `Unsupported` on other platforms.
+pub fn file_identity(path: &Path) -> io::Result<(u64, u64)> {
+    #[cfg(unix)]
     {
-        if entry.path().is_file() {
-            sender.send(entry.path().to_path_buf()).unwrap();
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path)?;
+        Ok((metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::Storage::FileSystem::{
+            GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS,
+        };
+
+        // No access is needed to read the information, and directories can only be opened with
+        // backup semantics
+        let file = fs::OpenOptions::new()
+            .access_mode(0)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+            .open(path)?;
+        // SAFETY: The structure is plain data, for which all zeroes is a valid value.
+        let mut information: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+        // SAFETY: The handle stays open while `file` lives, and `information` is a valid,
+        // writable structure of the type the function fills.
+        if unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut information) } == 0 {
+            return Err(io::Error::last_os_error());
         }
+        Ok((
+            u64::from(information.dwVolumeSerialNumber),
+            (u64::from(information.nFileIndexHigh) << 32) | u64::from(information.nFileIndexLow),
+        ))
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file identities are not supported on this platform",
+        ))
+    }
+}
+
+/// Determines if two paths lead to the same file on disk.
+///
+/// The identities of the files are compared, see [`file_identity`], so hard links to a file
+/// count as the same file. On platforms without file identities the canonical paths are
+/// compared.
+///
+/// # Returns
+/// True if both paths lead to the same file, false otherwise or if either cannot be read.
+#[must_use]
+pub fn same_file(path1: &Path, path2: &Path) -> bool {
+    match (file_identity(path1), file_identity(path2)) {
+        (Ok(identity1), Ok(identity2)) => identity1 == identity2,
+        (Err(err), _) | (_, Err(err)) if err.kind() == io::ErrorKind::Unsupported => {
+            match (fs::canonicalize(path1), fs::canonicalize(path2)) {
+                (Ok(canonical1), Ok(canonical2)) => canonical1 == canonical2,
+                _ => false,
+            }
+        }
+        _ => false,
     }
 }
 
-/// Computes a file's hash and records its (possibly relative) path in the given map.
+/// Splits off the paths leading to the same file as another path differing only in case, as
+/// the same file is found under several spellings on case-insensitive filesystems.
 ///
-/// This function computes the file's hash and converts the file's path to a relative path if a
-/// base directory is provided. It then inserts the final path into the
-/// hash map under the computed hash.
+/// Only paths that are equal ignoring case are checked to be the same file, see [`same_file`].
+/// Of each file, the path sorting first is kept.
 ///
 /// # Parameters
-/// - `map`: A mutable reference to a hash map that groups file paths by their computed hash.
-/// - `path`: The file path to process.
-/// - `base`: An optional base directory. If provided, the file path is converted to a relative path
-///           based on this directory.
+/// - `paths`: The paths of the files found.
 ///
 /// # Returns
-/// A Result indicating success or an `io::Error`.
+/// The paths to keep, sorted, and the aliases left out.
+#[must_use]
+pub fn split_case_aliases(mut paths: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    paths.sort();
+    let mut kept: Vec<PathBuf> = Vec::with_capacity(paths.len());
+    let mut aliases = Vec::new();
+    let mut folded: HashMap<String, Vec<usize>> = HashMap::new();
+
+    for path in paths {
+        let candidates = folded
+            .entry(path.to_string_lossy().to_lowercase())
+            .or_default();
+        if candidates
+            .iter()
+            .any(|&index| same_file(&kept[index], &path))
+        {
+            aliases.push(path);
+        } else {
+            candidates.push(kept.len());
+            kept.push(path);
+        }
+    }
+
+    (kept, aliases)
+}
+
+/// Splits off the paths leading, through symbolic links or junctions, to a file already found
+/// under another path, as in trees built as link farms.
 ///
-/// # Errors
-/// This function returns an `io::Error` if there is an issue reading the file to compute its hash.
+/// Paths are resolved with [`fs::canonicalize`]. Of each file, a path not leading through any
+/// link is kept if there is one, and the path sorting first otherwise. Paths that cannot be
+/// resolved, such as broken links, are kept.
+///
+/// # Parameters
+/// - `paths`: The paths of the files found.
+///
+/// # Returns
+/// The paths to keep, sorted, and the aliases left out.
+#[must_use]
+pub fn split_link_aliases(paths: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    // A path leads through a link if resolving links changes it
+    let mut paths: Vec<(bool, PathBuf, Option<PathBuf>)> = paths
+        .into_iter()
+        .map(|path| {
+            let canonical = fs::canonicalize(&path).ok();
+            let linked = canonical.is_some() && canonical != std::path::absolute(&path).ok();
+            (linked, path, canonical)
+        })
+        .collect();
+    paths.sort();
+    let mut kept = Vec::with_capacity(paths.len());
+    let mut aliases = Vec::new();
+    let mut seen = HashSet::new();
+
+    for (_, path, canonical) in paths {
+        if canonical.is_some_and(|canonical| !seen.insert(canonical)) {
+            aliases.push(path);
+        } else {
+            kept.push(path);
+        }
+    }
+
+    kept.sort();
+    (kept, aliases)
+}
+
+/// Recursively sends all file paths from a directory through a channel.
+///
+/// Walks the directory tree and sends file paths if they are not hidden (when `skip_hidden` is
+/// true), not excluded by an ignore file at the root of the tree (when `use_ignore_file` is
+/// true) or by `.gitignore` and `.ignore` files (when `respect_gitignore` is true), accepted by
+/// `walk_filter`, and within `min_size` and `max_size`. The ignore file itself is not sent
+/// either, nor are `.filematch.sums` files. Linked directories are only walked when
+/// `follow_links` is true.
+/// Entries that cannot be read, such as directories without permission or links forming a
+/// cycle, are collected and returned so the caller can report them. The walk stops early once
+/// nothing receives the paths anymore, such as after the comparison failed.
+///
+/// # Parameters
+/// - `directory`: The root directory to scan.
+/// - `sender`: The channel sender to pass file paths.
+/// - `options`: The options controlling which files are included.
+/// - `exclude`: Directories inside `directory` to leave out, such as nested comparison roots.
+/// - `excluded`: If given, receives the paths left out, and why. A directory left out stands
+///   for everything below it. Sums files and the ignore file are not listed.
+///
+/// # Returns
+/// The errors encountered while walking the tree.
+pub fn send_file_paths(
+    directory: &Path,
+    sender: &Sender<PathBuf>,
+    options: &CompareOptions,
+    exclude: &[PathBuf],
+    excluded: Option<&mut Vec<(PathBuf, SkipReason)>>,
+) -> Vec<walkdir::Error> {
+    let ignore = if options.use_ignore_file {
+        load_ignore_file(directory)
+    } else {
+        None
+    };
+    let mut gitignore = options.respect_gitignore.then(GitignoreFiles::new);
+    let mut errors = Vec::new();
+    let report = excluded.is_some();
+    let mut pruned = Vec::new();
+    let mut left_out = Vec::new();
+
+    // Junctions and mount points count as symbolic links, so they are only followed on request.
+    // Following links enables the cycle detection of walkdir.
+    let walker = WalkDir::new(directory).follow_links(options.follow_links);
+
+    for entry in walker.into_iter().filter_entry(|e| {
+        // The ignore file itself is not a file of the tree
+        if ignore.is_some() && e.depth() == 1 && e.file_name() == IGNORE_FILE_NAME {
+            return false;
+        }
+        let reason = if options.skip_hidden && is_hidden(e) {
+            SkipReason::Hidden
+        } else if ignore.as_ref().is_some_and(|ignore| is_ignored(e, ignore))
+            || gitignore
+                .as_mut()
+                .is_some_and(|gitignore| gitignore.is_ignored(e))
+        {
+            SkipReason::IgnoreFile
+        } else if e.depth() > 0 && options.walk_filter.as_ref().is_some_and(|f| !f.keeps(e)) {
+            SkipReason::Filtered
+        } else if exclude.iter().any(|excluded| e.path() == excluded) {
+            SkipReason::NestedRoot
+        } else {
+            return true;
+        };
+        if report {
+            pruned.push((e.path().to_path_buf(), reason));
+        }
+        false
+    }) {
+        match entry {
+            Ok(entry) if entry.file_name() == SUMS_FILE_NAME => {}
+            Ok(entry) if entry.path().is_file() => {
+                let reason = if modified_since(&entry, options.modified_since) {
+                    size_limit_exceeded(&entry, options)
+                } else {
+                    Some(SkipReason::NotModified)
+                };
+                match reason {
+                    None => {
+                        if sender.send(entry.path().to_path_buf()).is_err() {
+                            break;
+                        }
+                    }
+                    Some(reason) if report => left_out.push((entry.into_path(), reason)),
+                    Some(_) => {}
+                }
+            }
+            Ok(entry) if entry.file_type().is_dir() => {}
+            Ok(entry) if report => {
+                let reason = if entry.path_is_symlink() {
+                    match fs::metadata(entry.path()) {
+                        Ok(_) => SkipReason::Link,
+                        Err(_) => SkipReason::BrokenLink,
+                    }
+                } else {
+                    SkipReason::Special
+                };
+                left_out.push((entry.into_path(), reason));
+            }
+            Ok(_) => {}
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if let Some(excluded) = excluded {
+        excluded.extend(pruned);
+        excluded.extend(left_out);
+    }
+    errors
+}
+
+/// Determines if a file was modified after a point in time.
+///
+/// Files whose modification time cannot be read are assumed to be modified.
+fn modified_since(entry: &DirEntry, since: Option<SystemTime>) -> bool {
+    since.is_none_or(|since| {
+        fs::metadata(entry.path())
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .is_none_or(|modified| modified > since)
+    })
+}
+
+/// Determines if a file is smaller than `options.min_size` or larger than `options.max_size`.
+///
+/// Files whose size cannot be read are assumed to be within the limits.
+///
+/// # Returns
+/// Why the file is left out, or None if it is within the limits.
+fn size_limit_exceeded(entry: &DirEntry, options: &CompareOptions) -> Option<SkipReason> {
+    if options.min_size.is_none() && options.max_size.is_none() {
+        return None;
+    }
+    let size = fs::metadata(entry.path()).ok()?.len();
+    if options.min_size.is_some_and(|min| size < min) {
+        Some(SkipReason::TooSmall)
+    } else if options.max_size.is_some_and(|max| size > max) {
+        Some(SkipReason::TooLarge)
+    } else {
+        None
+    }
+}
+
+/// Creates an `io::Error` of kind `InvalidData` for a malformed line of a file.
+///
+/// # Parameters
+/// - `kind`: The kind of file, starting the message, such as `manifest`.
+/// - `line`: The number of the line, counting from 1.
+/// - `message`: What is wrong with the line.
+#[must_use]
+pub(crate) fn invalid_data(kind: &str, line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{kind} line {line}: {message}"),
+    )
+}
+
+/// Converts an error from walking a directory tree into an `io::Error` naming the path.
+///
+/// # Parameters
+/// - `err`: The error to convert.
+///
+/// # Returns
+/// The `io::Error`, with the path that could not be read in its message.
+#[must_use]
+pub fn walk_error_to_io(err: &walkdir::Error) -> io::Error {
+    let kind = err.io_error().map_or(io::ErrorKind::Other, io::Error::kind);
+    io::Error::new(kind, err.to_string())
+}
+
+/// Records a file's (possibly relative) path in the given map under its hash.
+///
+/// This function converts the file's path to a relative path if a base directory is provided.
+/// It then inserts the final path into the hash map under the given hash.
+///
+/// # Parameters
+/// - `map`: A mutable reference to a hash map that groups file paths by their computed hash.
+/// - `hash`: The hash computed for the file.
+/// - `path`: The file path to record.
+/// - `base`: An optional base directory. If provided, the file path is converted to a relative path
+///   based on this directory.
 #[allow(clippy::implicit_hasher)]
-pub fn compute_file_hash_and_insert_path(
+pub fn insert_path(
     map: &mut HashMap<Hash, Vec<PathBuf>>,
+    hash: Hash,
     path: PathBuf,
-    base: Option<&PathBuf>,
-) -> Result<(), io::Error> {
-    let hash = calculate_file_hash(&path)?;
-    let final_path = match base {
+    base: Option<&Path>,
+) {
+    map.entry(hash).or_default().push(relative_path(path, base));
+}
+
+/// Converts a path to a path relative to the given base directory.
+///
+/// # Parameters
+/// - `path`: The path to convert.
+/// - `base`: An optional base directory. If not provided, or if `path` is not inside it, the
+///   path is returned unchanged.
+///
+/// # Returns
+/// The (possibly relative) path.
+#[must_use]
+pub fn relative_path(path: PathBuf, base: Option<&Path>) -> PathBuf {
+    match base {
         Some(base_dir) => path
             .strip_prefix(base_dir)
             .map_or_else(|_| path.clone(), Path::to_path_buf),
         None => path,
+    }
+}
+
+/// The separator between the components of relative paths in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathStyle {
+    /// Forward slashes, as on Linux and macOS.
+    Unix,
+    /// Backslashes, as on Windows.
+    Windows,
+    /// The separator of the platform filematch runs on.
+    Native,
+}
+
+impl PathStyle {
+    /// Returns the separator of the style.
+    #[must_use]
+    pub fn separator(self) -> char {
+        match self {
+            Self::Unix => '/',
+            Self::Windows => '\\',
+            Self::Native => std::path::MAIN_SEPARATOR,
+        }
+    }
+
+    /// Formats a path for display, joining the components of relative paths with the separator
+    /// of the style.
+    ///
+    /// Absolute paths are displayed unchanged, as their prefixes and roots only make sense on the
+    /// platform they were found on. Only the separators of the platform filematch runs on split
+    /// the components, so a backslash in a file name on Unix is kept.
+    ///
+    /// # Parameters
+    /// - `path`: The path to format.
+    ///
+    /// # Returns
+    /// The path as displayed.
+    #[must_use]
+    pub fn display(self, path: &Path) -> String {
+        if matches!(
+            path.components().next(),
+            Some(Component::Prefix(_) | Component::RootDir)
+        ) {
+            return path.display().to_string();
+        }
+
+        let separator = self.separator().to_string();
+        path.components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(&separator)
+    }
+}
+
+/// Finds the root directory a path is located in.
+///
+/// # Parameters
+/// - `path`: The path to look up.
+/// - `roots`: The candidate root directories.
+///
+/// # Returns
+/// The longest root that `path` starts with, or None if it is in none of them.
+#[must_use]
+pub fn matching_root<'a>(path: &Path, roots: &'a [PathBuf]) -> Option<&'a Path> {
+    roots
+        .iter()
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+        .map(PathBuf::as_path)
+}
+
+/// Opens a file as it is stored, for reading parts of it.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be opened, or one of kind
+/// `PermissionDenied` if `options.read_only` is set and the file is not opened for reading
+/// only.
+pub(crate) fn open_raw_file(path: &Path, options: &CompareOptions) -> io::Result<File> {
+    let file = File::open(path)?;
+    if options.read_only && !is_opened_read_only(&file)? {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{}: not opened for reading only", path.display()),
+        ));
+    }
+    Ok(file)
+}
+
+/// Opens a file for reading the contents that are compared.
+///
+/// Files with an extension listed in `options.decompress` are decompressed, and media files are
+/// stripped of their metadata when `options.strip_metadata` is set. The result is passed through
+/// `options.transform`, if any. With `options.read_only` set, the file is checked to be opened
+/// for reading only.
+///
+/// # Parameters
+/// - `path`: The file to open.
+/// - `options`: The options controlling the comparison.
+///
+/// # Returns
+/// A reader yielding the contents of the file to compare.
+///
+/// # Errors
+/// This function returns an `io::Error` if the file cannot be opened, and one of kind
+/// `PermissionDenied` if it is not opened for reading only in read-only mode.
+pub fn open_file(path: &Path, options: &CompareOptions) -> io::Result<Box<dyn Read + Send>> {
+    let file = open_raw_file(path, options)?;
+
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let compression = options
+        .decompress
+        .iter()
+        .find(|compression| extension == Some(compression.extension()));
+
+    let reader: Box<dyn Read + Send> = match compression {
+        Some(Compression::Gz) => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        Some(Compression::Xz) => Box::new(xz2::read::XzDecoder::new_multi_decoder(file)),
+        Some(Compression::Zst) => Box::new(zstd::stream::read::Decoder::new(file)?),
+        None => match MediaFormat::from_path(path) {
+            Some(format) if options.strip_metadata => Box::new(strip_metadata(file, format)?),
+            _ => Box::new(file),
+        },
     };
-    map.entry(hash).or_default().push(final_path);
-    Ok(())
+
+    Ok(match &options.transform {
+        Some(transform) => transform.apply(path, reader),
+        None => reader,
+    })
+}
+
+/// Determines if a file is opened for reading only, going by its access mode.
+///
+/// # Errors
+/// This function returns an `io::Error` if the access mode cannot be queried.
+#[cfg(unix)]
+fn is_opened_read_only(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & libc::O_ACCMODE == libc::O_RDONLY)
+}
+
+/// Determines if a file is opened for reading only, which `File::open` always does.
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn is_opened_read_only(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Determines if an I/O error is likely to go away when the operation is retried.
+///
+/// Network filesystems occasionally fail reads with timeouts, dropped connections or generic
+/// I/O errors that succeed on a second attempt. Errors like a missing file or denied access
+/// are permanent and not considered transient.
+///
+/// # Parameters
+/// - `err`: The error to check.
+///
+/// # Returns
+/// True if retrying the operation may succeed, false otherwise.
+#[must_use]
+pub fn is_transient_error(err: &io::Error) -> bool {
+    use io::ErrorKind;
+
+    match err.kind() {
+        ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof
+        | ErrorKind::ResourceBusy
+        | ErrorKind::StaleNetworkFileHandle => true,
+        // EIO on Unix, network errors on Windows.
+        _ if cfg!(unix) => err.raw_os_error() == Some(5),
+        _ if cfg!(windows) => matches!(err.raw_os_error(), Some(59 | 64 | 121)),
+        _ => false,
+    }
+}
+
+/// Determines if an I/O error was caused by a file being locked by another process.
+///
+/// Only Windows enforces file locks on other processes. It reports them as sharing or lock
+/// violations, so this function always returns false on other platforms.
+///
+/// # Parameters
+/// - `err`: The error to check.
+///
+/// # Returns
+/// True if the file is locked, false otherwise.
+#[must_use]
+pub fn is_locked_error(err: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(err.raw_os_error(), Some(32 | 33))
+}
+
+/// Parses a duration such as `500ms`, `2s`, `1.5m` or `1h`.
+///
+/// # Parameters
+/// - `value`: The duration consisting of a number and a unit (`ms`, `s`, `m` or `h`).
+///
+/// # Returns
+/// The parsed duration.
+///
+/// # Errors
+/// This function returns an error message if the number or the unit is invalid.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{value}'"))?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "" if number == 0.0 => 0.0,
+        _ => {
+            return Err(format!(
+                "invalid duration '{value}', expected a unit like ms, s, m or h"
+            ))
+        }
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|err| format!("invalid duration '{value}': {err}"))
+}
+
+/// Parses a size in bytes such as `4096`, `512K`, `1.5M` or `2GiB`.
+///
+/// Units are binary, so `1K` is 1024 bytes. A trailing `B` or `iB` is accepted.
+///
+/// # Parameters
+/// - `value`: The size consisting of a number and an optional unit (`K`, `M`, `G` or `T`).
+///
+/// # Returns
+/// The parsed size in bytes.
+///
+/// # Errors
+/// This function returns an error message if the number or the unit is invalid.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{value}'"))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let multiplier: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "invalid size '{value}', expected a unit like K, M, G or T"
+            ))
+        }
+    };
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let bytes = (number * multiplier as f64).round() as u64;
+    Ok(bytes)
+}
+
+/// Parses a point in time in UTC such as `2024-05-01`, `2024-05-01T12:30:00Z` or
+/// `@1714566600` (seconds since the Unix epoch).
+///
+/// # Parameters
+/// - `value`: The date, optionally followed by a time of day separated by `T` or a space, and
+///   an optional trailing `Z`.
+///
+/// # Returns
+/// The parsed point in time.
+///
+/// # Errors
+/// This function returns an error message if the value is not a valid date and time after the
+/// Unix epoch.
+pub fn parse_timestamp(value: &str) -> Result<SystemTime, String> {
+    let invalid = || {
+        format!("invalid timestamp '{value}', expected e.g. 2024-05-01, 2024-05-01T12:30:00Z or @1714566600")
+    };
+    let value = value.trim();
+
+    if let Some(seconds) = value.strip_prefix('@') {
+        let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(seconds));
+    }
+
+    let value = value.strip_suffix('Z').unwrap_or(value);
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00:00"));
+    let numbers = |text: &str, separator: char| {
+        text.split(separator)
+            .map(|number| number.parse::<u64>().ok())
+            .collect::<Option<Vec<_>>>()
+    };
+
+    let (year, month, day) = match numbers(date, '-').as_deref() {
+        Some(&[year, month, day]) => (year, month, day),
+        _ => return Err(invalid()),
+    };
+    let (hour, minute, second) = match numbers(time, ':').as_deref() {
+        Some(&[hour, minute]) => (hour, minute, 0),
+        Some(&[hour, minute, second]) => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid()),
+    };
+    if year < 1970 || !(1..=days_in_month).contains(&day) || hour > 23 || minute > 59 || second > 59
+    {
+        return Err(invalid());
+    }
+
+    // Days since the epoch, counting years from March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year - 719_468;
+
+    Ok(UNIX_EPOCH + Duration::from_secs(days * 86_400 + hour * 3600 + minute * 60 + second))
 }
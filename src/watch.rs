@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::compare_two_directories::compare_multiple_roots;
+use crate::options::CompareOptions;
+
+/// How often two directories are compared while watching them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchOptions {
+    /// Time between two comparisons.
+    pub interval: Duration,
+    /// Time to wait before comparing again to confirm a change, so that copies still in progress
+    /// are not reported.
+    pub debounce: Duration,
+    /// Time between two summaries, or None for no summaries.
+    pub summary_interval: Option<Duration>,
+    /// Whether to also report the files whose contents drifted apart or matched again since the
+    /// previous report, as [`WatchEvent::Changed`].
+    pub changes: bool,
+}
+
+impl Default for WatchOptions {
+    /// Compares every minute, confirms changes after ten seconds, and emits no summaries or
+    /// changed files.
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            debounce: Duration::from_secs(10),
+            summary_interval: None,
+            changes: false,
+        }
+    }
+}
+
+/// A change in the state of two watched directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The directories no longer hold the same contents.
+    Diverged {
+        /// The number of files with contents only in the first directory.
+        unique_dir1: usize,
+        /// The number of files with contents only in the second directory.
+        unique_dir2: usize,
+    },
+    /// The directories hold the same contents again.
+    Reconverged,
+    /// The files that changed since the previous report of changed files, or since the start.
+    Changed {
+        /// The files of the first directory whose contents are no longer in the second.
+        diverged_dir1: Vec<PathBuf>,
+        /// The files of the second directory whose contents are no longer in the first.
+        diverged_dir2: Vec<PathBuf>,
+        /// The files of the first directory whose contents are in the second again, or that are
+        /// gone.
+        converged_dir1: Vec<PathBuf>,
+        /// The files of the second directory whose contents are in the first again, or that are
+        /// gone.
+        converged_dir2: Vec<PathBuf>,
+    },
+    /// The current state, emitted periodically.
+    Summary {
+        /// True if the directories do not hold the same contents.
+        diverged: bool,
+        /// The number of comparisons made so far.
+        checks: u64,
+        /// The number of files with contents only in the first directory.
+        unique_dir1: usize,
+        /// The number of files with contents only in the second directory.
+        unique_dir2: usize,
+    },
+}
+
+impl WatchEvent {
+    /// Returns the name of the event, e.g. `diverged`.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Diverged { .. } => "diverged",
+            Self::Reconverged => "reconverged",
+            Self::Changed { .. } => "changed",
+            Self::Summary { .. } => "summary",
+        }
+    }
+}
+
+/// The files unique to each side of a comparison.
+type Unique = [Vec<PathBuf>; 2];
+
+/// Compares the directories once.
+///
+/// # Returns
+/// The files unique to each side.
+fn check(dirs1: &[PathBuf], dirs2: &[PathBuf], options: &CompareOptions) -> io::Result<Unique> {
+    let result = compare_multiple_roots(dirs1, dirs2, options)?;
+
+    Ok([
+        result.unique_dir1.unwrap_or_default(),
+        result.unique_dir2.unwrap_or_default(),
+    ])
+}
+
+/// Returns true if a side has unique files.
+fn is_diverged(unique: &Unique) -> bool {
+    unique.iter().any(|paths| !paths.is_empty())
+}
+
+/// Returns the paths of `paths` not in `other`, in order.
+fn difference(paths: &[PathBuf], other: &[PathBuf]) -> Vec<PathBuf> {
+    let other: HashSet<_> = other.iter().collect();
+    paths
+        .iter()
+        .filter(|path| !other.contains(path))
+        .cloned()
+        .collect()
+}
+
+/// Watches two directories that should hold the same contents, reporting when they drift apart.
+///
+/// The directories are compared every `watch.interval`. The directories are assumed to hold the
+/// same contents at first. When a comparison disagrees with the last reported state, they are
+/// compared again after `watch.debounce`, and the change is only reported if it persists. Files
+/// that were skipped or could not be read do not count as a divergence.
+///
+/// With `watch.changes` set, the files unique to each side are compared with those of the
+/// previous report as well, and the files that changed are reported as [`WatchEvent::Changed`]
+/// once confirmed, after any divergence or reconvergence they cause. Only the changes are
+/// reported each time, so the events stay small during long watches.
+///
+/// # Parameters
+/// - `dirs1`: The root directories of the first side.
+/// - `dirs2`: The root directories of the second side.
+/// - `options`: The options controlling the comparisons. Only the unique files are computed.
+/// - `watch`: How often to compare the directories.
+/// - `on_event`: Called with every event. Watching stops once it returns false.
+///
+/// # Errors
+/// This function returns an `io::Error` if a comparison fails, see [`compare_multiple_roots`].
+pub fn watch(
+    dirs1: &[PathBuf],
+    dirs2: &[PathBuf],
+    options: &CompareOptions,
+    watch: &WatchOptions,
+    mut on_event: impl FnMut(&WatchEvent) -> bool,
+) -> io::Result<()> {
+    let options = CompareOptions {
+        include_intersection: false,
+        include_unique_dir1: true,
+        include_unique_dir2: true,
+        include_groups: false,
+        sort: true,
+        ..options.clone()
+    };
+    let mut diverged = false;
+    let mut reported = Unique::default();
+    let mut checks = 0;
+    let mut last_summary = Instant::now();
+
+    loop {
+        let mut unique = check(dirs1, dirs2, &options)?;
+        checks += 1;
+        if is_diverged(&unique) != diverged || (watch.changes && unique != reported) {
+            thread::sleep(watch.debounce);
+            unique = check(dirs1, dirs2, &options)?;
+            checks += 1;
+
+            if is_diverged(&unique) != diverged {
+                diverged = !diverged;
+                let event = if diverged {
+                    WatchEvent::Diverged {
+                        unique_dir1: unique[0].len(),
+                        unique_dir2: unique[1].len(),
+                    }
+                } else {
+                    WatchEvent::Reconverged
+                };
+                if !on_event(&event) {
+                    return Ok(());
+                }
+            }
+            if watch.changes && unique != reported {
+                let event = WatchEvent::Changed {
+                    diverged_dir1: difference(&unique[0], &reported[0]),
+                    diverged_dir2: difference(&unique[1], &reported[1]),
+                    converged_dir1: difference(&reported[0], &unique[0]),
+                    converged_dir2: difference(&reported[1], &unique[1]),
+                };
+                reported = unique.clone();
+                if !on_event(&event) {
+                    return Ok(());
+                }
+            }
+        }
+
+        if watch
+            .summary_interval
+            .is_some_and(|interval| last_summary.elapsed() >= interval)
+        {
+            last_summary = Instant::now();
+            let summary = WatchEvent::Summary {
+                diverged,
+                checks,
+                unique_dir1: unique[0].len(),
+                unique_dir2: unique[1].len(),
+            };
+            if !on_event(&summary) {
+                return Ok(());
+            }
+        }
+
+        thread::sleep(watch.interval);
+    }
+}
@@ -1,8 +1,64 @@
 use std::collections::HashSet;
+use std::ffi::OsString;
 use std::fs;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
 
-use filematch::compare_two_directories::compare_two_directories;
+use filematch::audit_log::{read_audit_log, undo, AuditLog};
+use filematch::bagit::{compare_bags, verify_bag};
+use filematch::cas::{blob_path, export_cas, restore_cas};
+use filematch::checksum::{digest_reader, digest_tree, for_each_digest, Algorithm};
+use filematch::compare_two_directories::{
+    compare_multiple_roots, compare_two_directories, compare_two_directories_with_options,
+    double_check, files_identical, recheck_matches, reproducibility_check, Comparison,
+};
+use filematch::dedupe::{
+    cross_side_duplicates, dedupe, dedupe_plan, find_duplicates, DedupeAction, DuplicateGroup,
+};
+use filematch::error::FileMatchError;
+use filematch::exec::{run_for_each, ExecTemplate};
+use filematch::filesystem::{mount_kind, mount_source, probe_storage, StorageKind};
+use filematch::hash_list::{check_known, read_hash_list};
+use filematch::hashdeep::{
+    audit, read_hashdeep, write_hashdeep, write_hashdeep_entry, write_hashdeep_header,
+};
+use filematch::job::{create_job, list_jobs, load_job, run_job, JobSpec, JobState};
+use filematch::keeper::{KeepRule, KeepRules};
+use filematch::limits::{
+    long_paths, max_open_files, raise_open_file_limit, PathPlatform, MAX_NAME_LENGTH,
+};
+use filematch::manifest::{
+    generate_checksum_list, generate_manifest, read_checksum_list, read_manifest,
+    verify_checksum_list, verify_manifest, write_checksum_list, write_manifest, MANIFEST_HEADER,
+};
+use filematch::manifest_diff::{compare_sorted_manifests, diff_sorted_manifests, ManifestDiff};
+use filematch::notify::summarize;
+use filematch::options::{
+    CompareOptions, Compression, ContentTransform, ErrorPolicy, RangeHash, SumsPolicy, WalkFilter,
+};
+use filematch::pipeline::{RootWatch, Side};
+use filematch::plan::{apply_plan, Plan, PlanStep, StepOutcome};
+use filematch::previous::{compare_against, read_grouped_json};
+use filematch::profile::Profiles;
+use filematch::reference::{build_reference_index, read_reference_list, ReferenceSet};
+use filematch::result::{
+    rollup_by_directory, rollup_by_extension, usage_by_directory, Category, ComparisonResult,
+    DirectoryRollup, DirectoryUsage, ExtensionRollup, HashGroup, SkipReason, SumsCheck,
+};
+use filematch::result_cache::{cache_key, compare_cached, tree_fingerprint, ResultCache};
+use filematch::selftest::{run_self_test, SelfTest, TEST_FILE_NAME};
+use filematch::snapshot::{parse_btrfs_find_new, parse_zfs_diff};
+use filematch::sums::{
+    export_sums, import_sums, precompute, read_checksum_lines, write_checksum_lines, FileStamp,
+    SUMS_FILE_NAME,
+};
+use filematch::three_way::{three_way_diff, Change, ThreeWayStatus};
+use filematch::util::{
+    file_identity, parse_duration, parse_size, parse_timestamp, same_file, PathStyle,
+};
+use filematch::watch::{watch, WatchEvent, WatchOptions};
 
 #[test]
 fn test_general() -> Result<(), Box<dyn std::error::Error>> {
@@ -55,8 +111,12 @@ fn test_general() -> Result<(), Box<dyn std::error::Error>> {
     let expected_unique_dir2: HashSet<PathBuf> = vec![unique2, unique_sub2].into_iter().collect();
 
     // Call the `compare_two_directories` function
-    let (Some(intersection_paths), Some(unique_dir1_paths), Some(unique_dir2_paths)) =
-        compare_two_directories(&dir1, &dir2, false, false, false, true, true, true)
+    let ComparisonResult {
+        intersection: Some(intersection_paths),
+        unique_dir1: Some(unique_dir1_paths),
+        unique_dir2: Some(unique_dir2_paths),
+        ..
+    } = compare_two_directories(&dir1, &dir2, false, false, false, true, true, true)?
     else {
         panic!("compare_two_directories did not return the expected result.");
     };
@@ -127,8 +187,12 @@ fn test_hidden() -> Result<(), Box<dyn std::error::Error>> {
 
     // Call the `compare_two_directories` function
     println!("{:?}", expected_intersection);
-    let (Some(intersection_paths), Some(unique_dir1_paths), Some(unique_dir2_paths)) =
-        compare_two_directories(&dir1, &dir2, false, true, false, true, true, true)
+    let ComparisonResult {
+        intersection: Some(intersection_paths),
+        unique_dir1: Some(unique_dir1_paths),
+        unique_dir2: Some(unique_dir2_paths),
+        ..
+    } = compare_two_directories(&dir1, &dir2, false, true, false, true, true, true)?
     else {
         panic!("compare_two_directories did not return the expected result.");
     };
@@ -155,6 +219,4008 @@ fn test_hidden() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn test_thread_pools() -> Result<(), Box<dyn std::error::Error>> {
+    // Create a temporary base directory
+    let base_dir = std::env::temp_dir().join("test_dirs_thread_pools");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    // Files larger than a single read chunk are streamed in several pieces
+    let large = "0123456789abcdef".repeat(16 * 1024);
+    let common1 = create_file(&dir1.join("large.bin"), &large)?;
+    let common2 = create_file(&dir2.join("large_copy.bin"), &large)?;
+    let unique1 = create_file(&dir1.join("unique1.txt"), "Unique file in dir1")?;
+    let unique2 = create_file(&dir2.join("unique2.txt"), &large[1..])?;
+    // Files differing only in the byte just past the size of small files
+    let edge1 = create_file(&dir1.join("edge.txt"), &large[..=4 * 1024])?;
+    let edge2 = create_file(&dir2.join("edge.txt"), &format!("{}x", &large[..4 * 1024]))?;
+
+    // More hashers than readers and the other way round must give the same result, with or
+    // without a limit on the files in flight, with any chunk size, and with adaptive reads
+    for (io_threads, hash_threads, max_in_flight, chunk_size, adaptive_io) in [
+        (1, 4, None, 64 * 1024, false),
+        (4, 1, None, 64 * 1024, false),
+        (1, 1, None, 1000, false),
+        (4, 2, Some(1), 4 * 1024 * 1024, false),
+        (1, 1, Some(1), 1, false),
+        (8, 2, None, 64 * 1024, true),
+        (1, 1, Some(1), 1000, true),
+    ] {
+        let options = CompareOptions {
+            sort: true,
+            io_threads,
+            adaptive_io,
+            hash_threads,
+            max_in_flight,
+            chunk_size,
+            ..CompareOptions::default()
+        };
+
+        let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+        assert_eq!(
+            result.intersection,
+            Some(vec![common1.clone(), common2.clone()])
+        );
+        assert_eq!(
+            result.unique_dir1,
+            Some(vec![edge1.clone(), unique1.clone()])
+        );
+        assert_eq!(
+            result.unique_dir2,
+            Some(vec![edge2.clone(), unique2.clone()])
+        );
+        assert!(result.skipped.is_empty());
+    }
+
+    // Fewer open files than readers gives the same result, with or without adaptive reads
+    for (max_open_files, adaptive_io) in [(Some(1), false), (Some(2), true), (Some(100), false)] {
+        let options = CompareOptions {
+            sort: true,
+            io_threads: 8,
+            adaptive_io,
+            max_open_files,
+            ..CompareOptions::default()
+        };
+        let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+        assert_eq!(
+            result.intersection,
+            Some(vec![common1.clone(), common2.clone()])
+        );
+        assert_eq!(
+            result.unique_dir1,
+            Some(vec![edge1.clone(), unique1.clone()])
+        );
+    }
+
+    // Each side read by readers of its own gives the same result
+    for (io_threads1, io_threads2) in [(Some(3), Some(1)), (Some(1), None), (None, Some(2))] {
+        let options = CompareOptions {
+            sort: true,
+            io_threads1,
+            io_threads2,
+            ..CompareOptions::default()
+        };
+        let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+        assert_eq!(
+            result.intersection,
+            Some(vec![common1.clone(), common2.clone()])
+        );
+        assert_eq!(
+            result.unique_dir2,
+            Some(vec![edge2.clone(), unique2.clone()])
+        );
+    }
+
+    // Either side on a hard disk gives the same result, and a directory without large files
+    // cannot be probed
+    for storage in [
+        [Some(StorageKind::Hdd), None],
+        [None, Some(StorageKind::Hdd)],
+    ] {
+        let result = Comparison::new(&dir1, &dir2)
+            .options(CompareOptions {
+                storage,
+                ..CompareOptions::default()
+            })
+            .sort(true)
+            .run()?;
+        assert_eq!(
+            result.intersection,
+            Some(vec![common1.clone(), common2.clone()])
+        );
+        assert_eq!(
+            result.unique_dir2,
+            Some(vec![edge2.clone(), unique2.clone()])
+        );
+    }
+    assert_eq!(probe_storage(&dir1), None);
+
+    // A single thread of each kind with a tiny read buffer gives the same result
+    let result = Comparison::new(&dir1, &dir2)
+        .sort(true)
+        .threads(1)
+        .chunk_size(100)
+        .run()?;
+    assert_eq!(result.intersection, Some(vec![common1, common2]));
+    assert_eq!(result.unique_dir1, Some(vec![edge1, unique1]));
+
+    Ok(())
+}
+
+#[test]
+fn test_read_gate_many_large_files() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_read_gate_many_large_files");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    // Many files of many chunks each, so waiting readers could fill the hashers with jobs
+    for i in 0..64 {
+        let contents = format!("{i:08}").repeat(8 * 1024);
+        create_file(&dir1.join(format!("{i}.bin")), &contents)?;
+        create_file(&dir2.join(format!("{i}.bin")), &contents)?;
+    }
+
+    // More readers than hashers with a limit on the files read at once, adaptive or fixed
+    for (io_threads, hash_threads, adaptive_io, max_open_files) in [
+        (16, 4, true, None),
+        (8, 1, true, None),
+        (8, 1, false, Some(1)),
+    ] {
+        let options = CompareOptions {
+            io_threads,
+            hash_threads,
+            adaptive_io,
+            max_open_files,
+            chunk_size: 4 * 1024,
+            ..CompareOptions::default()
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let (dir1, dir2) = (dir1.clone(), dir2.clone());
+        std::thread::spawn(move || {
+            let result = compare_two_directories_with_options(&dir1, &dir2, &options);
+            let _ = sender.send(result.map_err(|err| err.to_string()));
+        });
+        let result = receiver.recv_timeout(Duration::from_secs(60))??;
+
+        assert_eq!(result.intersection.map(|paths| paths.len()), Some(128));
+        assert_eq!(result.unique_dir1, Some(vec![]));
+        assert_eq!(result.unique_dir2, Some(vec![]));
+    }
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_failed_read_with_bounded_queues() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_failed_read_with_bounded_queues");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    // More files than fit in the queues, and one that cannot be read
+    for i in 0..50 {
+        create_file(&dir1.join(format!("{i}.txt")), &format!("File {i}"))?;
+        create_file(&dir2.join(format!("{i}.txt")), &format!("File {i}"))?;
+    }
+    std::os::unix::fs::symlink("/proc/self/mem", dir1.join("mem"))?;
+
+    // The walk stops instead of waiting on the full queue once the hasher failed
+    let options = CompareOptions {
+        io_threads: 1,
+        hash_threads: 1,
+        max_in_flight: Some(1),
+        ..CompareOptions::default()
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let (walked1, walked2) = (dir1.clone(), dir2.clone());
+    std::thread::spawn(move || {
+        let result = compare_two_directories_with_options(&walked1, &walked2, &options);
+        let _ = sender.send(result.map_err(|err| err.to_string()));
+    });
+    let result = receiver.recv_timeout(Duration::from_secs(60))?;
+    assert!(result.is_err_and(|err| err.contains("mem")));
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_output_order() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_output_order");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    for i in 0..20 {
+        create_file(&dir1.join(format!("z{i}.txt")), &format!("Common {i}"))?;
+        create_file(&dir2.join(format!("a{i}.txt")), &format!("Common {i}"))?;
+        create_file(&dir1.join(format!("unique{i}.txt")), &format!("Unique {i}"))?;
+    }
+
+    let options = CompareOptions {
+        relative: true,
+        ..CompareOptions::default()
+    };
+    let first = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+    // Files with the same content are next to each other, the first directory first
+    let intersection = first.intersection.clone().unwrap();
+    for pair in intersection.chunks(2) {
+        let name1 = pair[0].to_str().unwrap();
+        let name2 = pair[1].to_str().unwrap();
+        assert_eq!(name1.replace('z', "a"), name2);
+    }
+
+    // The order is the same in every run
+    for _ in 0..5 {
+        let again = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+        assert_eq!(again.intersection, first.intersection);
+        assert_eq!(again.unique_dir1, first.unique_dir1);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_walk_errors() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base_dir = std::env::temp_dir().join("test_dirs_walk_errors");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let locked = dir1.join("locked");
+    if locked.exists() {
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755))?;
+    }
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&locked)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&locked.join("hidden_by_permissions.txt"), "Locked")?;
+    create_file(&dir1.join("common.txt"), "Common")?;
+    create_file(&dir2.join("common.txt"), "Common")?;
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000))?;
+
+    // Permissions are not enforced for root
+    if fs::read_dir(&locked).is_ok() {
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755))?;
+        return Ok(());
+    }
+
+    let mut options = CompareOptions {
+        relative: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options);
+
+    options.fail_on_walk_error = true;
+    let strict = compare_two_directories_with_options(&dir1, &dir2, &options);
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755))?;
+
+    let result = result?;
+    assert_eq!(result.walk_errors.len(), 1);
+    assert_eq!(result.walk_errors[0].path, PathBuf::from("locked"));
+    assert_eq!(result[Category::Error], [PathBuf::from("locked")]);
+    assert_eq!(result.unique_dir1, Some(vec![]));
+    let err = strict.unwrap_err();
+    assert!(matches!(err, FileMatchError::Walk { .. }));
+    assert_eq!(err.path(), Some(locked.as_path()));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_follow_links() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_follow_links");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir1.join("real"))?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("real/common.txt"), "Common")?;
+    create_file(&dir2.join("common.txt"), "Common")?;
+
+    // A link to a directory outside the tree, and a link back to the root
+    std::os::unix::fs::symlink(dir1.join("real"), dir2.join("linked"))?;
+    std::os::unix::fs::symlink(&dir2, dir2.join("cycle"))?;
+
+    let mut options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![
+            PathBuf::from("common.txt"),
+            PathBuf::from("real/common.txt")
+        ])
+    );
+    assert!(result.walk_errors.is_empty());
+
+    options.follow_links = true;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![
+            PathBuf::from("common.txt"),
+            PathBuf::from("linked/common.txt"),
+            PathBuf::from("real/common.txt")
+        ])
+    );
+    assert_eq!(result.walk_errors.len(), 1);
+    assert_eq!(result.walk_errors[0].path, PathBuf::from("cycle"));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_collapse_link_aliases() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_collapse_link_aliases");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir1.join("store"))?;
+    fs::create_dir_all(dir1.join("farm"))?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("store/common.txt"), "Common")?;
+    create_file(&dir2.join("common.txt"), "Common")?;
+
+    // A farm of links to files and a directory of the store
+    std::os::unix::fs::symlink(
+        dir1.join("store/common.txt"),
+        dir1.join("farm/a-common.txt"),
+    )?;
+    std::os::unix::fs::symlink(dir1.join("store"), dir1.join("farm/linked"))?;
+
+    let mut options = CompareOptions {
+        relative: true,
+        sort: true,
+        follow_links: true,
+        include_excluded: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(4));
+
+    // The real file is kept, though a link sorts first
+    options.collapse_link_aliases = true;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![
+            PathBuf::from("common.txt"),
+            PathBuf::from("store/common.txt")
+        ])
+    );
+    let excluded: Vec<_> = result
+        .excluded
+        .unwrap_or_default()
+        .into_iter()
+        .map(|file| (file.path, file.reason))
+        .collect();
+    assert_eq!(
+        excluded,
+        [
+            (PathBuf::from("farm/a-common.txt"), SkipReason::LinkAlias),
+            (
+                PathBuf::from("farm/linked/common.txt"),
+                SkipReason::LinkAlias
+            )
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_overlapping_roots() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_overlapping_roots");
+    let outer = base_dir.join("outer");
+    let inner = outer.join("inner");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&inner)?;
+    let common1 = create_file(&outer.join("common.txt"), "Common")?;
+    let common2 = create_file(&inner.join("common.txt"), "Common")?;
+    let unique = create_file(&inner.join("unique.txt"), "Unique")?;
+
+    let mut options = CompareOptions {
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let err = compare_two_directories_with_options(&outer, &inner, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(compare_two_directories_with_options(&outer, &outer.join("."), &options).is_err());
+
+    // The nested root is only walked on its own
+    options.allow_overlapping_roots = true;
+    let result = compare_two_directories_with_options(&outer, &inner, &options)?;
+    assert_eq!(result.intersection, Some(vec![common1, common2]));
+    assert_eq!(result.unique_dir1, Some(vec![]));
+    assert_eq!(result.unique_dir2, Some(vec![unique]));
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_groups() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_hash_groups");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("b.txt"), "Shared")?;
+    create_file(&dir1.join("a.txt"), "Shared")?;
+    create_file(&dir2.join("c.txt"), "Shared")?;
+    create_file(&dir2.join("d.txt"), "Unique")?;
+
+    let options = CompareOptions {
+        relative: true,
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    let groups = result.groups.unwrap();
+    assert_eq!(groups.len(), 2);
+
+    let shared: Vec<_> = groups.iter().filter(|group| group.is_shared()).collect();
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].hash, blake3::hash(b"Shared"));
+    assert_eq!(shared[0].size, 6);
+    assert_eq!(
+        shared[0].dir1,
+        vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+    );
+    assert_eq!(shared[0].dir2, vec![PathBuf::from("c.txt")]);
+
+    Ok(())
+}
+
+#[test]
+fn test_rollup() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_rollup");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir1.join("photos"))?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("photos").join("a.jpg"), "Backed up")?;
+    create_file(&dir1.join("photos").join("b.jpg"), "New")?;
+    create_file(&dir1.join("notes.txt"), "Notes")?;
+    create_file(&dir2.join("a.jpg"), "Backed up")?;
+
+    let options = CompareOptions {
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    let rollups = rollup_by_directory(
+        &result.groups.unwrap(),
+        Side::Dir1,
+        std::slice::from_ref(&dir1),
+    );
+
+    assert_eq!(
+        rollups,
+        vec![
+            DirectoryRollup {
+                directory: dir1.clone(),
+                files: 1,
+                matched_files: 0,
+                bytes: 5,
+                matched_bytes: 0,
+            },
+            DirectoryRollup {
+                directory: dir1.join("photos"),
+                files: 2,
+                matched_files: 1,
+                bytes: 12,
+                matched_bytes: 9,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_rollup_by_extension() {
+    let group = |content: &[u8], dir1: &[&str], dir2: &[&str]| HashGroup {
+        hash: blake3::hash(content),
+        size: content.len() as u64,
+        dir1: dir1.iter().map(PathBuf::from).collect(),
+        dir2: dir2.iter().map(PathBuf::from).collect(),
+    };
+    let groups = [
+        group(b"Raw", &["a/img.CR2"], &["b/img.cr2"]),
+        group(b"Photo", &["a/img.jpg", "a/copy.jpg"], &[]),
+        group(b"Notes", &["a/README"], &["b/README"]),
+    ];
+
+    assert_eq!(
+        rollup_by_extension(&groups, Side::Dir1),
+        vec![
+            ExtensionRollup {
+                extension: None,
+                files: 1,
+                matched_files: 1,
+                bytes: 5,
+                matched_bytes: 5,
+            },
+            ExtensionRollup {
+                extension: Some("cr2".to_string()),
+                files: 1,
+                matched_files: 1,
+                bytes: 3,
+                matched_bytes: 3,
+            },
+            ExtensionRollup {
+                extension: Some("jpg".to_string()),
+                files: 2,
+                matched_files: 0,
+                bytes: 10,
+                matched_bytes: 0,
+            },
+        ]
+    );
+    assert_eq!(rollup_by_extension(&groups, Side::Dir2).len(), 2);
+}
+
+#[test]
+fn test_usage() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_usage");
+    let dir = base_dir.join("dir");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir.join("photos"))?;
+    fs::create_dir_all(dir.join("backup"))?;
+    create_file(&dir.join("photos").join("a.jpg"), "Photo")?;
+    create_file(&dir.join("photos").join("copy.jpg"), "Photo")?;
+    create_file(&dir.join("photos").join("b.jpg"), "Only here")?;
+    create_file(&dir.join("backup").join("a.jpg"), "Photo")?;
+
+    let options = CompareOptions {
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_multiple_roots(std::slice::from_ref(&dir), &[], &options)?;
+    let usages = usage_by_directory(&result.groups.unwrap(), std::slice::from_ref(&dir));
+
+    assert_eq!(
+        usages,
+        vec![
+            DirectoryUsage {
+                directory: dir.join("backup"),
+                files: 1,
+                bytes: 5,
+                distinct_bytes: 5,
+                exclusive_bytes: 0,
+            },
+            DirectoryUsage {
+                directory: dir.join("photos"),
+                files: 3,
+                bytes: 19,
+                distinct_bytes: 14,
+                exclusive_bytes: 9,
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_find_duplicates() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_find_duplicates");
+    let dir = base_dir.join("dir");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir.join("photos"))?;
+    create_file(&dir.join("photos").join("a.jpg"), "Photo")?;
+    create_file(&dir.join("photos").join("copy.jpg"), "Photo")?;
+    create_file(&dir.join("a.jpg"), "Photo")?;
+    create_file(&dir.join("notes.txt"), "Some longer notes")?;
+    create_file(&dir.join("notes copy.txt"), "Some longer notes")?;
+    create_file(&dir.join("only.txt"), "Only here")?;
+
+    let duplicates = find_duplicates(&dir, &CompareOptions::default())?;
+
+    assert_eq!(
+        duplicates.groups,
+        vec![
+            DuplicateGroup {
+                hash: blake3::hash(b"Some longer notes"),
+                size: 17,
+                paths: vec![dir.join("notes copy.txt"), dir.join("notes.txt")],
+            },
+            DuplicateGroup {
+                hash: blake3::hash(b"Photo"),
+                size: 5,
+                paths: vec![
+                    dir.join("a.jpg"),
+                    dir.join("photos").join("a.jpg"),
+                    dir.join("photos").join("copy.jpg"),
+                ],
+            },
+        ]
+    );
+    assert_eq!(duplicates.savings(), 27);
+
+    Ok(())
+}
+
+#[test]
+fn test_ignore_hashes() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_ignore_hashes");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("LICENSE"), "License")?;
+    create_file(&dir1.join("a.txt"), "Shared")?;
+    create_file(&dir1.join("empty"), "")?;
+    create_file(&dir2.join("COPYING"), "License")?;
+    create_file(&dir2.join("a.txt"), "Shared")?;
+
+    let list = format!(
+        "# boilerplate\n{}  LICENSE\n\n{}\n",
+        blake3::hash(b"License"),
+        blake3::hash(b"")
+    );
+    let ignored = read_hash_list(list.as_bytes())?;
+    assert_eq!(ignored.len(), 2);
+    assert!(read_hash_list("not a hash\n".as_bytes()).is_err());
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        include_groups: true,
+        ignore_hashes: Arc::new(ignored),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection.unwrap(),
+        vec![PathBuf::from("a.txt"); 2]
+    );
+    assert!(result.unique_dir1.unwrap().is_empty());
+    assert!(result.unique_dir2.unwrap().is_empty());
+    assert_eq!(result.groups.unwrap().len(), 1);
+
+    let check = check_known(&dir1, &options.ignore_hashes, &CompareOptions::default())?;
+    assert_eq!(check.known, vec![dir1.join("LICENSE"), dir1.join("empty")]);
+    assert_eq!(check.unknown, vec![dir1.join("a.txt")]);
+    assert!(!check.passed());
+
+    Ok(())
+}
+
+#[test]
+fn test_excluded_files() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_excluded_files");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir1.join(".git"))?;
+    fs::create_dir_all(dir2.join("build"))?;
+    create_file(&dir1.join("a.txt"), "Shared")?;
+    create_file(&dir1.join(".git/HEAD"), "ref")?;
+    create_file(&dir1.join("LICENSE"), "License")?;
+    create_file(&dir2.join("a.txt"), "Shared")?;
+    create_file(&dir2.join("build/out.o"), "Object")?;
+    create_file(&dir2.join("notes.log"), "Log")?;
+    create_file(&dir2.join(".filematchignore"), "*.log\n")?;
+
+    let options = CompareOptions {
+        relative: true,
+        skip_hidden: true,
+        walk_filter: Some(WalkFilter::new(|entry| entry.file_name() != "build")),
+        ignore_hashes: Arc::new(HashSet::from([blake3::hash(b"License")])),
+        include_excluded: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection.unwrap().len(), 2);
+    let excluded: Vec<(Side, PathBuf, SkipReason)> = result
+        .excluded
+        .unwrap()
+        .into_iter()
+        .map(|file| (file.side, file.path, file.reason))
+        .collect();
+    assert_eq!(
+        excluded,
+        vec![
+            (Side::Dir1, PathBuf::from(".git"), SkipReason::Hidden),
+            (
+                Side::Dir1,
+                PathBuf::from("LICENSE"),
+                SkipReason::IgnoredHash
+            ),
+            (Side::Dir2, PathBuf::from("build"), SkipReason::Filtered),
+            (
+                Side::Dir2,
+                PathBuf::from("notes.log"),
+                SkipReason::IgnoreFile
+            ),
+        ]
+    );
+    // Left out files are not errors
+    assert!(result.errors.is_empty());
+
+    let result = compare_two_directories_with_options(
+        &dir1,
+        &dir2,
+        &CompareOptions {
+            include_excluded: false,
+            ..options
+        },
+    )?;
+    assert!(result.excluded.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_reference_set() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_reference_set");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("kernel32.dll"), "System file")?;
+    create_file(&dir1.join("report.doc"), "Evidence")?;
+    create_file(&dir2.join("kernel32.dll"), "System file")?;
+    create_file(&dir2.join("notes.txt"), "Other evidence")?;
+
+    let sha1 = |data: &str| {
+        digest_reader(data.as_bytes(), &[Algorithm::Sha1])
+            .unwrap()
+            .1[0]
+            .clone()
+    };
+    let list = base_dir.join("NSRLFile.txt");
+    fs::write(
+        &list,
+        format!(
+            "\"SHA-1\",\"MD5\",\"CRC32\",\"FileName\",\"FileSize\"\n\
+             \"{}\",\"392126E756571EBF112CB1C1CDEDF926\",\"EBD105A0\",\"kernel32.dll\",11\n\
+             \"{}\",\"392126E756571EBF112CB1C1CDEDF926\",\"EBD105A0\",\"other.dll\",11\n",
+            sha1("System file").to_uppercase(),
+            sha1("Unrelated")
+        ),
+    )?;
+    assert!(read_reference_list("not a digest\n".as_bytes(), Algorithm::Sha1).is_err());
+    assert!(read_reference_list(fs::read(&list)?.as_slice(), Algorithm::Sha256).is_err());
+
+    let index = base_dir.join("nsrl.idx");
+    assert_eq!(build_reference_index(&list, Algorithm::Sha1, &index)?, 2);
+    let set = ReferenceSet::open(&index)?;
+    assert_eq!(set.algorithm(), Algorithm::Sha1);
+    assert_eq!(set.len(), 2);
+    assert!(ReferenceSet::open(&list).is_err());
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        reference_set: Some(Arc::new(set)),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert!(result.intersection.unwrap().is_empty());
+    assert_eq!(
+        result.unique_dir1.unwrap(),
+        vec![PathBuf::from("report.doc")]
+    );
+    assert_eq!(
+        result.unique_dir2.unwrap(),
+        vec![PathBuf::from("notes.txt")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_result_methods() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_result_methods");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("both.txt"), "Both")?;
+    create_file(&dir1.join("only1.txt"), "Only 1")?;
+    create_file(&dir1.join("only1.log"), "Only 1 log")?;
+    create_file(&dir2.join("both.txt"), "Both")?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+    let all: Vec<_> = result.iter_all().collect();
+    assert_eq!(all.len(), 4);
+    assert!(result[Category::UniqueDir2].is_empty());
+    assert!(result[Category::Modified].is_empty());
+    let changed = base_dir.join("changed");
+    fs::create_dir_all(&changed)?;
+    create_file(&changed.join("only1.txt"), "Changed")?;
+    let modified = compare_two_directories_with_options(&dir1, &changed, &options)?;
+    assert_eq!(modified[Category::Modified], [PathBuf::from("only1.txt")]);
+    assert!(result[Category::Error].is_empty());
+    assert_eq!(all[0], (Category::Intersection, &PathBuf::from("both.txt")));
+    assert_eq!(
+        result.iter_category(Category::UniqueDir1).count(),
+        2,
+        "{all:?}"
+    );
+
+    // Filtering applies to every category and the groups
+    let mut filtered = result
+        .clone()
+        .filter_paths(|path| path.extension().is_some_and(|e| e == "txt"));
+    assert_eq!(
+        filtered.category(Category::UniqueDir1),
+        Some(&[PathBuf::from("only1.txt")][..])
+    );
+    assert_eq!(filtered.groups.as_ref().map(Vec::len), Some(2));
+
+    // Merging appends the paths and joins the groups
+    filtered.merge(result);
+    assert_eq!(filtered.iter_category(Category::Intersection).count(), 4);
+    assert_eq!(filtered.iter_category(Category::UniqueDir1).count(), 3);
+    let groups = filtered.groups.unwrap();
+    assert_eq!(groups.len(), 3);
+    assert!(groups
+        .iter()
+        .any(|group| group.dir1.len() == 2 && group.dir2.len() == 2));
+
+    Ok(())
+}
+
+#[test]
+fn test_ignore_file() -> Result<(), Box<dyn std::error::Error>> {
+    // Create a temporary base directory
+    let base_dir = std::env::temp_dir().join("test_dirs_ignore_file");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(dir1.join("build"))?;
+    fs::create_dir_all(dir2.join("nested"))?;
+
+    // Only the root ignore file of each directory is honored
+    create_file(&dir1.join(".filematchignore"), "*.log\nbuild/\n")?;
+    create_file(&dir2.join("nested/.filematchignore"), "*.txt\n")?;
+
+    let common1 = create_file(&dir1.join("common1.txt"), "Common file content")?;
+    let common2 = create_file(&dir2.join("nested/common2.txt"), "Common file content")?;
+    let _ = create_file(&dir1.join("debug.log"), "Ignored log")?;
+    let _ = create_file(&dir1.join("build/output.bin"), "Ignored build output")?;
+    let nested_ignore = dir2.join("nested/.filematchignore");
+
+    let options = CompareOptions {
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+    assert_eq!(result.intersection, Some(vec![common1, common2]));
+    assert_eq!(result.unique_dir1, Some(vec![]));
+    assert_eq!(result.unique_dir2, Some(vec![nested_ignore]));
+
+    // Without the ignore file everything is compared
+    let options = CompareOptions {
+        use_ignore_file: false,
+        ..options
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.unique_dir1.map(|paths| paths.len()), Some(3));
+
+    Ok(())
+}
+
+#[test]
+fn test_gitignore() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_gitignore");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir1.join("sub/secret"))?;
+    fs::create_dir_all(dir1.join(".git"))?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join(".gitignore"), "*.log\n")?;
+    create_file(&dir1.join("sub/.gitignore"), "keep.log\n")?;
+    create_file(&dir1.join("sub/.ignore"), "!keep.log\nsecret/\n")?;
+    create_file(&dir1.join("a.txt"), "Kept")?;
+    create_file(&dir1.join("debug.log"), "Ignored log")?;
+    create_file(&dir1.join("sub/keep.log"), "Kept log")?;
+    create_file(&dir1.join("sub/secret/key.txt"), "Ignored secret")?;
+
+    // Deeper ignore files take precedence, and .ignore over .gitignore
+    let result = Comparison::new(&dir1, &dir2)
+        .relative(true)
+        .sort(true)
+        .respect_gitignore(true)
+        .run()?;
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![
+            PathBuf::from(".gitignore"),
+            PathBuf::from("a.txt"),
+            PathBuf::from("sub/.gitignore"),
+            PathBuf::from("sub/.ignore"),
+            PathBuf::from("sub/keep.log"),
+        ])
+    );
+
+    let result = Comparison::new(&dir1, &dir2).run()?;
+    assert_eq!(result.unique_dir1.map(|paths| paths.len()), Some(7));
+
+    // Outside a git repository, only .ignore files apply
+    fs::remove_dir(dir1.join(".git"))?;
+    let result = Comparison::new(&dir1, &dir2)
+        .relative(true)
+        .sort(true)
+        .respect_gitignore(true)
+        .run()?;
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![
+            PathBuf::from(".gitignore"),
+            PathBuf::from("a.txt"),
+            PathBuf::from("debug.log"),
+            PathBuf::from("sub/.gitignore"),
+            PathBuf::from("sub/.ignore"),
+            PathBuf::from("sub/keep.log"),
+        ])
+    );
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_size_limits() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_size_limits");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("tiny.txt"), "a")?;
+    create_file(&dir1.join("medium.txt"), "Medium size")?;
+    create_file(&dir1.join("large.txt"), &"Large ".repeat(100))?;
+    create_file(&dir2.join("medium.txt"), "Medium size")?;
+
+    let result = Comparison::new(&dir1, &dir2)
+        .options(CompareOptions {
+            include_excluded: true,
+            ..CompareOptions::default()
+        })
+        .relative(true)
+        .min_size(2)
+        .max_size(100)
+        .run()?;
+    assert_eq!(result.unique_dir1, Some(vec![]));
+    let mut excluded: Vec<_> = result
+        .excluded
+        .unwrap()
+        .into_iter()
+        .map(|file| (file.path, file.reason))
+        .collect();
+    excluded.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        excluded,
+        vec![
+            (PathBuf::from("large.txt"), SkipReason::TooLarge),
+            (PathBuf::from("tiny.txt"), SkipReason::TooSmall),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_range_hash() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_range_hash");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    let large = |middle: &str, end: &str| format!("{}{}{end}", "a".repeat(10), middle.repeat(200));
+    // Same ranges and size, different in between
+    create_file(&dir1.join("middle.bin"), &large("x", "bbbbbbbbbb"))?;
+    create_file(&dir2.join("middle.bin"), &large("y", "bbbbbbbbbb"))?;
+    create_file(&dir1.join("same.bin"), &large("z", "cccccccccc"))?;
+    create_file(&dir2.join("same.bin"), &large("z", "cccccccccc"))?;
+    create_file(&dir1.join("tail.bin"), &large("z", "dddddddddd"))?;
+    create_file(&dir2.join("tail.bin"), &large("z", "eeeeeeeeee"))?;
+    create_file(&dir1.join("small.txt"), "Small file")?;
+    create_file(&dir2.join("small.txt"), "Small file")?;
+
+    let ranges = RangeHash {
+        min_size: 100,
+        head: 10,
+        tail: 10,
+        verify: false,
+    };
+    let compare = |range_hash| {
+        Comparison::new(&dir1, &dir2)
+            .options(CompareOptions {
+                range_hash: Some(range_hash),
+                ..CompareOptions::default()
+            })
+            .relative(true)
+            .sort(true)
+            .run()
+    };
+
+    // Files differing between the ranges match, and are listed as probable matches
+    let result = compare(ranges)?;
+    let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let both = paths(&["middle.bin", "middle.bin", "same.bin", "same.bin"]);
+    assert_eq!(result.ranged_matches, both);
+    let mut intersection = both;
+    intersection.extend(paths(&["small.txt", "small.txt"]));
+    intersection.sort();
+    assert_eq!(result.intersection, Some(intersection));
+    assert_eq!(result.unique_dir1, Some(paths(&["tail.bin"])));
+    assert_eq!(
+        result.to_json()["ranged_matches"].as_array().unwrap().len(),
+        4
+    );
+
+    // Reading the matches in full leaves only certain matches
+    let result = compare(RangeHash {
+        verify: true,
+        ..ranges
+    })?;
+    assert!(result.ranged_matches.is_empty());
+    assert_eq!(
+        result.intersection,
+        Some(paths(&["same.bin", "same.bin", "small.txt", "small.txt"]))
+    );
+    assert_eq!(result.unique_dir1, Some(paths(&["middle.bin", "tail.bin"])));
+    assert_eq!(result.unique_dir2, Some(paths(&["middle.bin", "tail.bin"])));
+
+    // Hashes of ranges are not hashes of the contents, so they are not recorded
+    let err = Comparison::new(&dir1, &dir2)
+        .options(CompareOptions {
+            range_hash: Some(ranges),
+            sums: SumsPolicy::Trust,
+            ..CompareOptions::default()
+        })
+        .run()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    Ok(())
+}
+
+#[test]
+fn test_reproducibility_check() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_repro_check");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same contents")?;
+    create_file(&dir2.join("same.txt"), "Same contents")?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+
+    let roots1 = vec![dir1.clone()];
+    let roots2 = vec![dir2.clone()];
+    let options = CompareOptions {
+        relative: true,
+        ..CompareOptions::default()
+    };
+    let first = compare_multiple_roots(&roots1, &roots2, &options)?;
+
+    // Unchanged directories give the same result
+    assert!(reproducibility_check(&roots1, &roots2, &first, &options)?.is_empty());
+    assert!(first.differences(&first).is_empty());
+
+    // A file changed between the runs shows up in each category it moved between
+    create_file(&dir2.join("same.txt"), "Changed contents")?;
+    let differences = reproducibility_check(&roots1, &roots2, &first, &options)?;
+    let categories: Vec<_> = differences
+        .iter()
+        .map(|difference| difference.category)
+        .collect();
+    assert_eq!(
+        categories,
+        [
+            Category::Intersection,
+            Category::UniqueDir1,
+            Category::UniqueDir2,
+            Category::Modified,
+        ]
+    );
+    assert_eq!(differences[0].category.name(), "intersection");
+    assert_eq!(
+        differences[0].only_first,
+        [PathBuf::from("same.txt"), PathBuf::from("same.txt")]
+    );
+    assert!(differences[0].only_second.is_empty());
+    assert_eq!(differences[1].only_second, [PathBuf::from("same.txt")]);
+    assert_eq!(differences[2].only_second, [PathBuf::from("same.txt")]);
+    assert_eq!(differences[3].only_second, [PathBuf::from("same.txt")]);
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_progress() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_progress");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Same contents")?;
+    create_file(&dir2.join("a.txt"), "Same contents")?;
+    create_file(&dir1.join("large.txt"), &"x".repeat(100_000))?;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&events);
+    Comparison::new(&dir1, &dir2)
+        .hash_threads(2)
+        .chunk_size(1000)
+        .on_progress(move |event| {
+            let mut events = recorded.lock().unwrap();
+            events.push((
+                event.files_hashed,
+                event.bytes_hashed,
+                event.side,
+                event.path.to_path_buf(),
+            ));
+        })
+        .run()?;
+
+    // Every file is reported once, with the totals counted across hasher threads
+    let mut events = events.lock().unwrap().clone();
+    events.sort_by_key(|event| (event.0, event.1));
+    let counts: Vec<_> = events.iter().map(|event| event.0).collect();
+    assert_eq!(counts, [1, 2, 3]);
+    assert_eq!(events.iter().map(|event| event.1).max(), Some(100_026));
+    let mut paths: Vec<_> = events.iter().map(|event| (event.2, &event.3)).collect();
+    paths.sort_by_key(|(_, path)| path.as_path());
+    assert_eq!(
+        paths,
+        [
+            (Side::Dir1, &dir1.join("a.txt")),
+            (Side::Dir1, &dir1.join("large.txt")),
+            (Side::Dir2, &dir2.join("a.txt")),
+        ]
+    );
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_slow_files() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_slow_files");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        create_file(&dir1.join(name), name)?;
+    }
+    create_file(&dir2.join("large.txt"), &"x".repeat(1_000_000))?;
+
+    let compare = |slow_files| {
+        Comparison::new(&dir1, &dir2)
+            .options(CompareOptions {
+                slow_files,
+                ..CompareOptions::default()
+            })
+            .relative(true)
+            .hash_threads(2)
+            .run()
+    };
+
+    // Files are not timed unless requested
+    assert!(compare(0)?.slow_files.is_empty());
+
+    // Only the slowest files are kept, slowest first, with their paths as reported
+    let result = compare(2)?;
+    assert_eq!(result.slow_files.len(), 2);
+    assert!(result.slow_files[0].duration >= result.slow_files[1].duration);
+    assert!(result
+        .slow_files
+        .iter()
+        .all(|file| file.path.is_relative() && file.path.extension().unwrap() == "txt"));
+    let read = ComparisonResult::from_json(&serde_json::from_str(&result.to_json().to_string())?)?;
+    assert_eq!(read.slow_files.len(), 2);
+    assert_eq!(read.slow_files[0].path, result.slow_files[0].path);
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_result_serde() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_result_serde");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same contents")?;
+    create_file(&dir2.join("same.txt"), "Same contents")?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+    create_file(&dir2.join(".hidden"), "Hidden")?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        skip_hidden: true,
+        include_groups: true,
+        include_excluded: true,
+        slow_files: 1,
+        ..CompareOptions::default()
+    };
+    let mut result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    result.incomplete = vec![Side::Dir2];
+
+    // Paths that are not valid UTF-8 survive the round trip unchanged
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = PathBuf::from(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        result.unique_dir1.as_mut().unwrap().push(path.clone());
+        result.groups.as_mut().unwrap()[0].dir1.push(path);
+    }
+
+    let json = serde_json::to_value(&result)?;
+    assert_eq!(json["intersection"][0], "same.txt");
+    assert_eq!(json["incomplete"][0], "directory2");
+    assert_eq!(json["excluded"][0]["reason"], "hidden");
+    #[cfg(unix)]
+    assert!(json["unique_dir1"][1]["bytes"].is_array());
+    let read: ComparisonResult = serde_json::from_value(json)?;
+    assert_eq!(read, result);
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_result_cache() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_result_cache");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same contents")?;
+    create_file(&dir2.join("same.txt"), "Same contents")?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+
+    let roots1 = vec![dir1.clone()];
+    let roots2 = vec![dir2.clone()];
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let cache = ResultCache::new(base_dir.join("cache"));
+
+    // An identical comparison of the unchanged directories is taken from the cache
+    let first = compare_cached(&roots1, &roots2, &options, &cache)?;
+    assert!(!first.from_cache);
+    let second = compare_cached(&roots1, &roots2, &options, &cache)?;
+    assert!(second.from_cache);
+    assert_eq!(second.result, first.result);
+
+    // Other options and changed files miss the cache
+    let sorted_only = CompareOptions {
+        relative: false,
+        ..options.clone()
+    };
+    assert!(!compare_cached(&roots1, &roots2, &sorted_only, &cache)?.from_cache);
+    let fingerprint = tree_fingerprint(&dir2, &options);
+    create_file(&dir2.join("new.txt"), "New")?;
+    assert_ne!(tree_fingerprint(&dir2, &options), fingerprint);
+    let changed = compare_cached(&roots1, &roots2, &options, &cache)?;
+    assert!(!changed.from_cache);
+    assert_eq!(
+        changed.result.unique_dir2,
+        Some(vec![PathBuf::from("new.txt")])
+    );
+
+    // Results are not used past their validity, and filters that cannot be keyed are not cached
+    let expired = ResultCache {
+        validity: Duration::ZERO,
+        ..cache.clone()
+    };
+    assert!(!compare_cached(&roots1, &roots2, &options, &expired)?.from_cache);
+    let filtered = CompareOptions {
+        walk_filter: Some(WalkFilter::new(|_| true)),
+        ..options.clone()
+    };
+    assert_eq!(cache_key(&roots1, &roots2, &filtered), None);
+    assert!(cache_key(&roots1, &roots2, &options).is_some());
+
+    // Comparisons updating the sums files always run
+    let updating = CompareOptions {
+        sums: SumsPolicy::Update,
+        ..options.clone()
+    };
+    for _ in 0..2 {
+        assert!(!compare_cached(&roots1, &roots2, &updating, &cache)?.from_cache);
+    }
+
+    // Paths that are not valid UTF-8 survive a cache hit
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        create_file(&dir1.join(name), "Not UTF-8")?;
+        let first = compare_cached(&roots1, &roots2, &options, &cache)?;
+        let second = compare_cached(&roots1, &roots2, &options, &cache)?;
+        assert!(second.from_cache);
+        assert_eq!(second.result, first.result);
+        assert!(second
+            .result
+            .unique_dir1
+            .is_some_and(|paths| paths.contains(&PathBuf::from(name))));
+    }
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_walk_filter() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_walk_filter");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir1.join("build"))?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("keep.txt"), "Keep")?;
+    create_file(&dir1.join("drop.tmp"), "Drop")?;
+    create_file(&dir1.join("build/output.txt"), "Output")?;
+    create_file(&dir2.join("keep.txt"), "Keep")?;
+
+    let options = CompareOptions {
+        relative: true,
+        walk_filter: Some(WalkFilter::new(|entry| {
+            entry.file_name() != "build"
+                && entry
+                    .path()
+                    .extension()
+                    .is_none_or(|extension| extension != "tmp")
+        })),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(2));
+    assert_eq!(result.unique_dir1, Some(vec![]));
+
+    // Only files modified after the watermark are compared
+    let watermark = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    fs::File::options()
+        .write(true)
+        .open(dir1.join("drop.tmp"))?
+        .set_modified(watermark - Duration::from_secs(1))?;
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        modified_since: Some(watermark),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(2));
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![PathBuf::from("build/output.txt")])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_roots() -> Result<(), Box<dyn std::error::Error>> {
+    // The first side is split across two drives, the second side is a consolidated copy
+    let base_dir = std::env::temp_dir().join("test_dirs_multiple_roots");
+    let drive1 = base_dir.join("drive1");
+    let drive2 = base_dir.join("drive2");
+    let consolidated = base_dir.join("consolidated");
+    fs::create_dir_all(&drive1)?;
+    fs::create_dir_all(&drive2)?;
+    fs::create_dir_all(&consolidated)?;
+
+    create_file(&drive1.join("photo1.jpg"), "First photo")?;
+    create_file(&drive2.join("photo2.jpg"), "Second photo")?;
+    create_file(&drive2.join("missing.jpg"), "Not yet consolidated")?;
+    create_file(&consolidated.join("photo1.jpg"), "First photo")?;
+    create_file(&consolidated.join("photo2.jpg"), "Second photo")?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let roots1 = [drive1, drive2];
+    let roots2 = [consolidated];
+    let result = compare_multiple_roots(&roots1, &roots2, &options)?;
+
+    // Relative paths are stripped of the root they were found in
+    assert_eq!(
+        result.intersection,
+        Some(
+            ["photo1.jpg", "photo1.jpg", "photo2.jpg", "photo2.jpg"]
+                .map(PathBuf::from)
+                .to_vec()
+        )
+    );
+    assert_eq!(result.unique_dir1, Some(vec![PathBuf::from("missing.jpg")]));
+    assert_eq!(result.unique_dir2, Some(vec![]));
+
+    // A common ancestor keeps the roots apart
+    let options = CompareOptions {
+        relative_to1: Some(base_dir.clone()),
+        ..options
+    };
+    let result = compare_multiple_roots(&roots1, &roots2, &options)?;
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![PathBuf::from("drive2/missing.jpg")])
+    );
+
+    let options = CompareOptions {
+        relative_to1: Some(roots2[0].clone()),
+        ..options
+    };
+    let err = compare_multiple_roots(&roots1, &roots2, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // Absolute paths resolve `..` in the roots
+    let options = CompareOptions {
+        absolute: true,
+        relative: false,
+        relative_to1: None,
+        ..options
+    };
+    let roundabout = [roots1[0].clone(), roots1[1].join("..").join("drive2")];
+    let result = compare_multiple_roots(&roundabout, &roots2, &options)?;
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![fs::canonicalize(&roots1[1])?.join("missing.jpg")])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_export_cas() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_export_cas");
+    let dir = base_dir.join("dir");
+    let store = base_dir.join("store");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(dir.join("subdir"))?;
+    create_file(&dir.join("a.txt"), "Same")?;
+    create_file(&dir.join("subdir/b.txt"), "Same")?;
+    create_file(&dir.join("c.txt"), "Other")?;
+
+    // Identical files are stored once
+    let export = export_cas(&dir, &store, &CompareOptions::default(), None)?;
+    let same = blake3::hash(b"Same");
+    assert_eq!(
+        export.files,
+        vec![
+            (PathBuf::from("a.txt"), same),
+            (PathBuf::from("c.txt"), blake3::hash(b"Other")),
+            (PathBuf::from("subdir/b.txt"), same),
+        ]
+    );
+    assert_eq!(
+        (export.copied, export.copied_bytes, export.existing),
+        (2, 9, 0)
+    );
+    assert_eq!(fs::read(blob_path(&store, &same))?, b"Same");
+
+    // Exporting again only adds new contents
+    create_file(&dir.join("d.txt"), "New")?;
+    let export = export_cas(&dir, &store, &CompareOptions::default(), None)?;
+    assert_eq!(
+        (export.copied, export.copied_bytes, export.existing),
+        (1, 3, 2)
+    );
+    assert_eq!(export.files.len(), 4);
+
+    // Restoring gives back the tree, leaving out files whose blob is damaged or gone
+    let manifest: Vec<(PathBuf, String)> = export
+        .files
+        .iter()
+        .map(|(path, hash)| (path.clone(), hash.to_hex().to_string()))
+        .collect();
+    fs::write(blob_path(&store, &blake3::hash(b"Other")), "Damaged")?;
+    fs::remove_file(blob_path(&store, &blake3::hash(b"New")))?;
+    let restored = base_dir.join("restored");
+    let restore = restore_cas(&store, &manifest, &restored, None)?;
+    assert_eq!(
+        restore.restored,
+        vec![PathBuf::from("a.txt"), PathBuf::from("subdir/b.txt")]
+    );
+    assert_eq!(restore.corrupt, vec![PathBuf::from("c.txt")]);
+    assert_eq!(restore.missing, vec![PathBuf::from("d.txt")]);
+    assert_eq!(fs::read_to_string(restored.join("subdir/b.txt"))?, "Same");
+    assert!(!restored.join("c.txt").exists());
+
+    // Existing files are never overwritten
+    let err = restore_cas(&store, &manifest, &restored, None).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+    Ok(())
+}
+
+#[test]
+fn test_audit_log() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_audit_log");
+    let dir = base_dir.join("dir");
+    let store = base_dir.join("store");
+    let restored = base_dir.join("restored");
+    let log_path = base_dir.join("audit.jsonl");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir)?;
+    create_file(&dir.join("a.txt"), "First")?;
+    create_file(&dir.join("b.txt"), "Second")?;
+
+    // Every copy is logged with its hash and outcome
+    let mut log = AuditLog::open(&log_path)?;
+    let export = export_cas(&dir, &store, &CompareOptions::default(), Some(&mut log))?;
+    let manifest: Vec<(PathBuf, String)> = export
+        .files
+        .iter()
+        .map(|(path, hash)| (path.clone(), hash.to_hex().to_string()))
+        .collect();
+    restore_cas(&store, &manifest, &restored, Some(&mut log))?;
+    drop(log);
+
+    let entries = read_audit_log(BufReader::new(fs::File::open(&log_path)?))?;
+    assert_eq!(entries.len(), 4);
+    assert!(entries.iter().all(|entry| entry.error.is_none()));
+    assert_eq!(entries[2].hash, blake3::hash(b"First"));
+    assert_eq!(entries[2].size, 5);
+    assert_eq!(
+        entries[2].source,
+        std::path::absolute(blob_path(&store, &entries[2].hash))?
+    );
+    assert!(read_audit_log("not an entry\n".as_bytes()).is_err());
+
+    // Undoing removes the copies, except those modified since
+    create_file(&restored.join("b.txt"), "Edited")?;
+    let undone = undo(&entries)?;
+    assert_eq!(undone.removed.len(), 3);
+    assert_eq!(
+        undone.modified,
+        vec![std::path::absolute(restored.join("b.txt"))?]
+    );
+    assert!(!restored.join("a.txt").exists());
+    assert!(!blob_path(&store, &blake3::hash(b"Second")).exists());
+    assert!(dir.join("a.txt").exists());
+
+    // Undoing again finds the removed copies gone
+    assert_eq!(undo(&entries)?.gone.len(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_diff_sorted_manifests() -> Result<(), Box<dyn std::error::Error>> {
+    let manifest1 = "# sorted\n\
+                     aa  a.txt\n\
+                     bb  b.txt\n\
+                     bb  copy of b.txt\n\
+                     dd  d.txt\n";
+    let manifest2 = "bb  b2.txt\ncc  c.txt\nee  e.txt\n";
+
+    let mut emitted = Vec::new();
+    let diff = diff_sorted_manifests(
+        manifest1.as_bytes(),
+        manifest2.as_bytes(),
+        |category, path, digest| {
+            emitted.push((category, path.to_path_buf(), digest.to_string()));
+            Ok(())
+        },
+    )?;
+    assert_eq!(
+        diff,
+        ManifestDiff {
+            intersection: 3,
+            unique1: 2,
+            unique2: 2,
+        }
+    );
+    let paths: Vec<(Category, &str)> = emitted
+        .iter()
+        .map(|(category, path, _)| (*category, path.to_str().unwrap()))
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            (Category::UniqueDir1, "a.txt"),
+            (Category::Intersection, "b.txt"),
+            (Category::Intersection, "copy of b.txt"),
+            (Category::Intersection, "b2.txt"),
+            (Category::UniqueDir2, "c.txt"),
+            (Category::UniqueDir1, "d.txt"),
+            (Category::UniqueDir2, "e.txt"),
+        ]
+    );
+
+    // Manifests must be sorted by digest
+    let err = diff_sorted_manifests(
+        manifest1.as_bytes(),
+        "cc  c.txt\nbb  b2.txt\n".as_bytes(),
+        |_, _, _| Ok(()),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("manifest 2"));
+
+    Ok(())
+}
+
+#[test]
+fn test_files_identical() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_files_identical");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&base_dir)?;
+    let large = "0123456789abcdef".repeat(1024);
+    create_file(&base_dir.join("small1"), "Small")?;
+    create_file(&base_dir.join("small2"), "Small")?;
+    create_file(&base_dir.join("other"), "Other")?;
+    create_file(&base_dir.join("large1"), &large)?;
+    create_file(&base_dir.join("large2"), &large)?;
+    create_file(&base_dir.join("large3"), &large.replacen('0', "1", 1))?;
+
+    let identical = |a: &str, b: &str| files_identical(&base_dir.join(a), &base_dir.join(b));
+    assert!(identical("small1", "small2")?);
+    assert!(!identical("small1", "other")?);
+    assert!(!identical("small1", "large1")?);
+    assert!(identical("large1", "large2")?);
+    assert!(!identical("large1", "large3")?);
+    let err = identical("small1", "missing").unwrap_err();
+    assert!(err.to_string().contains("missing"));
+    assert_eq!(err.path(), Some(base_dir.join("missing").as_path()));
+
+    Ok(())
+}
+
+#[test]
+fn test_recheck_matches() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_recheck_matches");
+    let _ = fs::remove_dir_all(&base_dir);
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    let large = "0123456789abcdef".repeat(1024);
+    create_file(&dir1.join("small.txt"), "Same")?;
+    create_file(&dir2.join("small.txt"), "Same")?;
+    create_file(&dir1.join("large.txt"), &large)?;
+    create_file(&dir2.join("large.txt"), &large)?;
+    create_file(&dir1.join("unique.txt"), "Unique")?;
+
+    let options = CompareOptions {
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    let groups = result.groups.unwrap();
+
+    // Only contents on both sides are checked
+    let recheck = recheck_matches(&groups, 1.0, &options)?;
+    assert_eq!(recheck.checked, 2);
+    assert!(recheck.mismatched.is_empty());
+    assert_eq!(recheck_matches(&groups, 0.0, &options)?.checked, 0);
+
+    // Data that changed since the comparison shows up as a mismatch
+    create_file(&dir2.join("large.txt"), &large.replacen('0', "1", 1))?;
+    let recheck = recheck_matches(&groups, 1.0, &options)?;
+    assert_eq!(
+        recheck.mismatched,
+        vec![[dir1.join("large.txt"), dir2.join("large.txt")]]
+    );
+
+    fs::remove_file(dir2.join("small.txt"))?;
+    let err = recheck_matches(&groups, 1.0, &options).unwrap_err();
+    assert!(err.to_string().contains("small.txt"));
+
+    Ok(())
+}
+
+#[test]
+fn test_profiles() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_profiles");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&base_dir)?;
+    let file = base_dir.join("profiles");
+
+    // Without a file only the built-in profiles exist
+    let profiles = Profiles::load(&file)?;
+    assert_eq!(profiles, Profiles::builtin());
+    assert_eq!(
+        profiles.names().collect::<Vec<_>>(),
+        ["backup-verify", "code", "photos"]
+    );
+
+    fs::write(
+        &file,
+        "# Mine\n\nmusic = --strip-metadata  --intersection\nphotos = --sort\n",
+    )?;
+    let profiles = Profiles::load(&file)?;
+    assert_eq!(
+        profiles.get("music"),
+        Some(&["--strip-metadata".to_string(), "--intersection".to_string()][..])
+    );
+    assert_eq!(profiles.get("photos"), Some(&["--sort".to_string()][..]));
+    assert!(profiles.get("code").is_some());
+
+    // The options go before the selection, so later options take precedence
+    let args = [
+        "filematch",
+        "--profile",
+        "music",
+        "--json",
+        "--profile=photos",
+        "a",
+        "b",
+    ];
+    let expanded = profiles.expand(args.map(OsString::from))?;
+    let expected = [
+        "filematch",
+        "--strip-metadata",
+        "--intersection",
+        "--profile",
+        "music",
+        "--json",
+        "--sort",
+        "--profile",
+        "photos",
+        "a",
+        "b",
+    ];
+    assert_eq!(expanded, expected.map(OsString::from));
+    let args = ["filematch", "a", "--", "--profile", "music"];
+    assert_eq!(
+        profiles.expand(args.map(OsString::from))?,
+        args.map(OsString::from)
+    );
+    let err = profiles
+        .expand(["filematch", "--profile", "nope"].map(OsString::from))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    for text in ["music --sort\n", "= --sort\n", "loop = --profile music\n"] {
+        fs::write(&file, text)?;
+        let err = Profiles::load(&file).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("line 1"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_dedupe() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_dedupe");
+    let archive = base_dir.join("archive");
+    let downloads = base_dir.join("downloads");
+    let trash = base_dir.join("trash");
+    let setup = || -> Result<Vec<HashGroup>, Box<dyn std::error::Error>> {
+        let _ = fs::remove_dir_all(&base_dir);
+        fs::create_dir_all(archive.join("photos"))?;
+        fs::create_dir_all(downloads.join("new"))?;
+        create_file(&archive.join("photos/a.jpg"), "Photo")?;
+        create_file(&archive.join("b.txt"), "Text")?;
+        create_file(&downloads.join("new/a copy.jpg"), "Photo")?;
+        create_file(&downloads.join("b.txt"), "Text")?;
+        create_file(&downloads.join("c.txt"), "New")?;
+        let options = CompareOptions {
+            include_groups: true,
+            ..CompareOptions::default()
+        };
+        let result = compare_two_directories_with_options(&archive, &downloads, &options)?;
+        Ok(result.groups.unwrap())
+    };
+    let roots2 = [downloads.clone()];
+
+    let groups = setup()?;
+    let duplicates = cross_side_duplicates(&groups);
+    assert_eq!(duplicates.len(), 2);
+    let photo = duplicates
+        .iter()
+        .find(|duplicate| duplicate.size == 5)
+        .unwrap();
+    assert_eq!(photo.path, downloads.join("new/a copy.jpg"));
+    assert_eq!(photo.original, archive.join("photos/a.jpg"));
+
+    // A dry run changes nothing
+    let outcome = dedupe(&duplicates, DedupeAction::Remove, &roots2, None, true, None)?;
+    assert_eq!(outcome.done, duplicates);
+    assert_eq!(outcome.bytes(), 9);
+    assert!(downloads.join("b.txt").exists());
+
+    // Files changed since the comparison are left alone
+    create_file(&downloads.join("b.txt"), "Edited")?;
+    let outcome = dedupe(
+        &duplicates,
+        DedupeAction::Remove,
+        &roots2,
+        None,
+        false,
+        None,
+    )?;
+    assert_eq!(outcome.done, vec![photo.clone()]);
+    assert_eq!(outcome.changed.len(), 1);
+    assert!(!downloads.join("new/a copy.jpg").exists());
+    assert!(downloads.join("b.txt").exists());
+    assert!(downloads.join("c.txt").exists());
+    assert!(archive.join("photos/a.jpg").exists());
+
+    // Trashed files keep their paths below the root
+    let duplicates = cross_side_duplicates(&setup()?);
+    let err = dedupe(&duplicates, DedupeAction::Trash, &roots2, None, false, None).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let outcome = dedupe(
+        &duplicates,
+        DedupeAction::Trash,
+        &roots2,
+        Some(&trash),
+        false,
+        None,
+    )?;
+    assert_eq!(outcome.done.len(), 2);
+    assert_eq!(fs::read_to_string(trash.join("new/a copy.jpg"))?, "Photo");
+    assert!(!downloads.join("b.txt").exists());
+
+    // Links replace the files, and are left alone once they point to the original
+    let duplicates = cross_side_duplicates(&setup()?);
+    let outcome = dedupe(
+        &duplicates,
+        DedupeAction::Hardlink,
+        &roots2,
+        None,
+        false,
+        None,
+    )?;
+    assert_eq!(outcome.done.len(), 2);
+    assert_eq!(fs::read_to_string(downloads.join("b.txt"))?, "Text");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |path: &Path| fs::metadata(path).map(|metadata| metadata.ino());
+        assert_eq!(
+            inode(&downloads.join("b.txt"))?,
+            inode(&archive.join("b.txt"))?
+        );
+
+        let duplicates = cross_side_duplicates(&setup()?);
+        dedupe(
+            &duplicates,
+            DedupeAction::Symlink,
+            &roots2,
+            None,
+            false,
+            None,
+        )?;
+        assert!(fs::symlink_metadata(downloads.join("b.txt"))?.is_symlink());
+        let outcome = dedupe(
+            &duplicates,
+            DedupeAction::Remove,
+            &roots2,
+            None,
+            false,
+            None,
+        )?;
+        assert!(outcome.done.is_empty());
+        assert_eq!(outcome.same_file.len(), 2);
+        assert!(downloads.join("b.txt").exists());
+    }
+
+    // Every change is logged, and undoing it puts the files back
+    let mut actions = vec![
+        DedupeAction::Remove,
+        DedupeAction::Trash,
+        DedupeAction::Hardlink,
+    ];
+    if cfg!(unix) {
+        actions.push(DedupeAction::Symlink);
+    }
+    for action in actions {
+        let duplicates = cross_side_duplicates(&setup()?);
+        let log_path = base_dir.join("audit.jsonl");
+        let mut log = AuditLog::open(&log_path)?;
+        dedupe(
+            &duplicates,
+            action,
+            &roots2,
+            Some(&trash),
+            false,
+            Some(&mut log),
+        )?;
+        drop(log);
+
+        let entries = read_audit_log(BufReader::new(fs::File::open(&log_path)?))?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.error.is_none()));
+        assert_eq!(entries[0].operation.name(), action.name());
+        let undone = undo(&entries)?;
+        assert_eq!(undone.restored.len(), 2);
+        assert!(!fs::symlink_metadata(downloads.join("b.txt"))?.is_symlink());
+        assert_eq!(fs::read_to_string(downloads.join("b.txt"))?, "Text");
+        assert_eq!(
+            fs::read_to_string(downloads.join("new/a copy.jpg"))?,
+            "Photo"
+        );
+        assert_eq!(fs::read_to_string(archive.join("b.txt"))?, "Text");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let inode = |path: &Path| fs::metadata(path).map(|metadata| metadata.ino());
+            assert_ne!(
+                inode(&downloads.join("b.txt"))?,
+                inode(&archive.join("b.txt"))?
+            );
+        }
+
+        // Files put back are not put back twice
+        let undone = undo(&entries)?;
+        assert!(undone.restored.is_empty());
+        assert_eq!(undone.occupied.len() + undone.modified.len(), 2);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_apply_plan() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_apply_plan");
+    let _ = fs::remove_dir_all(&base_dir);
+    let archive = base_dir.join("archive");
+    let downloads = base_dir.join("downloads");
+    let trash = base_dir.join("trash");
+    fs::create_dir_all(&archive)?;
+    fs::create_dir_all(downloads.join("new"))?;
+    create_file(&archive.join("a.jpg"), "Photo")?;
+    create_file(&archive.join("b.txt"), "Text")?;
+    create_file(&downloads.join("new/a.jpg"), "Photo")?;
+    create_file(&downloads.join("b.txt"), "Text")?;
+    let options = CompareOptions {
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&archive, &downloads, &options)?;
+    let duplicates = cross_side_duplicates(&result.groups.unwrap());
+
+    // Plans survive a round trip through their file
+    let plan = dedupe_plan(
+        &duplicates,
+        DedupeAction::Trash,
+        std::slice::from_ref(&downloads),
+        Some(&trash),
+    )?;
+    assert_eq!(plan.steps.len(), 2);
+    assert!(plan.steps.iter().all(|step| step.path.is_absolute()));
+    let plan_file = base_dir.join("plan.json");
+    plan.write(&plan_file)?;
+    let plan = Plan::read(&plan_file)?;
+    assert!(plan
+        .steps
+        .iter()
+        .any(|step| step.target == Some(trash.join("new/a.jpg"))));
+    assert!(Plan::from_json(&serde_json::json!({ "version": 0, "steps": [] })).is_err());
+
+    // A step made before an interruption could record it is found done on resuming
+    let first = Plan {
+        steps: plan.steps[..1].to_vec(),
+    };
+    let applied = apply_plan(&first, None, None)?;
+    assert_eq!(applied.with_outcome(StepOutcome::Done).count(), 1);
+    create_file(&downloads.join("b.txt"), "Edited")?;
+    let journal = base_dir.join("plan.json.journal");
+    let applied = apply_plan(&plan, Some(&journal), None)?;
+    assert_eq!(applied.resumed, 0);
+    assert_eq!(applied.with_outcome(StepOutcome::Gone).count(), 1);
+    let applied = apply_plan(&plan, Some(&journal), None)?;
+    assert_eq!(applied.resumed, 2);
+    assert!(applied.steps.is_empty());
+
+    // A journal belongs to a single plan
+    let err = apply_plan(&first, Some(&journal), None).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    assert_eq!(fs::read_to_string(trash.join("new/a.jpg"))?, "Photo");
+    assert!(!downloads.join("new/a.jpg").exists());
+    assert_eq!(fs::read_to_string(downloads.join("b.txt"))?, "Edited");
+
+    // Changes are recorded in the audit log, but steps left alone are not
+    create_file(&downloads.join("c.txt"), "Text")?;
+    let plan = Plan {
+        steps: vec![
+            PlanStep {
+                action: DedupeAction::Remove,
+                path: downloads.join("c.txt"),
+                original: archive.join("b.txt"),
+                size: 4,
+                target: None,
+            },
+            PlanStep {
+                action: DedupeAction::Remove,
+                path: downloads.join("b.txt"),
+                original: archive.join("b.txt"),
+                size: 4,
+                target: None,
+            },
+        ],
+    };
+    let log_path = base_dir.join("audit.jsonl");
+    let mut log = AuditLog::open(&log_path)?;
+    let applied = apply_plan(&plan, None, Some(&mut log))?;
+    drop(log);
+    assert_eq!(applied.with_outcome(StepOutcome::Changed).count(), 1);
+    let entries = read_audit_log(BufReader::new(fs::File::open(&log_path)?))?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].target, downloads.join("c.txt"));
+    assert_eq!(entries[0].source, archive.join("b.txt"));
+    assert_eq!(undo(&entries)?.restored, vec![downloads.join("c.txt")]);
+    assert_eq!(fs::read_to_string(downloads.join("c.txt"))?, "Text");
+
+    Ok(())
+}
+
+#[test]
+fn test_comparison_result_json() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_comparison_result_json");
+    let _ = fs::remove_dir_all(&base_dir);
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same")?;
+    create_file(&dir2.join("same.txt"), "Same")?;
+    create_file(&dir2.join("new.txt"), "New")?;
+    create_file(&dir2.join(".hidden"), "Hidden")?;
+
+    let result = Comparison::new(&dir1, &dir2)
+        .options(CompareOptions {
+            include_excluded: true,
+            ..CompareOptions::default()
+        })
+        .relative(true)
+        .skip_hidden(true)
+        .sort(true)
+        .groups(true)
+        .run()?;
+    let counts = result.counts();
+    assert_eq!(counts.intersection, Some(2));
+    assert_eq!(counts.unique_dir2, Some(1));
+    assert_eq!(counts.skipped, 0);
+
+    let value = result.to_json();
+    assert_eq!(value["counts"]["unique_dir1"], 0);
+    assert_eq!(value["excluded"][0]["reason"], "hidden");
+    assert_eq!(value["excluded"][0]["side"], "directory2");
+    let read = ComparisonResult::from_json(&serde_json::from_str(&value.to_string())?)?;
+    assert_eq!(read, result);
+
+    let err = ComparisonResult::from_json(&serde_json::json!({ "errors": [] })).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // The summary for notifications lists the first unique files, with a text for chat services
+    let summary = summarize(&result, ["dir1", "dir2"], 10);
+    assert_eq!(summary["in_sync"], false);
+    assert_eq!(summary["counts"], value["counts"]);
+    assert_eq!(summary["unique_dir2"], serde_json::json!(["new.txt"]));
+    assert_eq!(
+        summary["text"],
+        "'dir1' and 'dir2' differ: 0 files only in 'dir1', 1 only in 'dir2'"
+    );
+    let summary = summarize(&result, ["dir1", "dir2"], 0);
+    assert_eq!(summary["unique_dir2"], serde_json::json!([]));
+
+    Ok(())
+}
+
+#[test]
+fn test_prefilter_sizes() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_prefilter_sizes");
+    let _ = fs::remove_dir_all(&base_dir);
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(dir1.join("sub"))?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same")?;
+    create_file(&dir2.join("same copy.txt"), "Same")?;
+    create_file(&dir1.join("sub/other.txt"), "Diff")?;
+    create_file(&dir1.join("sub/unique.txt"), "Only in dir1")?;
+    create_file(&dir2.join("unique.txt"), "Only in dir2, longer")?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let expected = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+
+    let mut options = CompareOptions {
+        prefilter_sizes: true,
+        sums: SumsPolicy::Update,
+        ..options
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection, expected.intersection);
+    assert_eq!(result.unique_dir1, expected.unique_dir1);
+    assert_eq!(result.unique_dir2, expected.unique_dir2);
+    assert_eq!(result.unique_dir2, Some(vec![PathBuf::from("unique.txt")]));
+
+    // Only the files of a size found on both sides were read, so only their hashes are recorded
+    let sums = fs::read_to_string(dir1.join("sub").join(SUMS_FILE_NAME))?;
+    assert!(sums.contains(" other.txt"));
+    assert!(!sums.contains("unique.txt"));
+    let sums = fs::read_to_string(dir2.join(SUMS_FILE_NAME))?;
+    assert!(sums.contains(" same copy.txt"));
+    assert!(!sums.contains("unique.txt"));
+
+    // Groups need the hash of every file, so every file is read
+    options.include_groups = true;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.groups.unwrap().len(), 4);
+    let sums = fs::read_to_string(dir1.join("sub").join(SUMS_FILE_NAME))?;
+    assert!(sums.contains(" unique.txt"));
+
+    Ok(())
+}
+
+#[test]
+fn test_collapse_case_aliases() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_collapse_case_aliases");
+    let _ = fs::remove_dir_all(&base_dir);
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("Photo.jpg"), "Photo")?;
+    create_file(&dir2.join("photo.jpg"), "Photo")?;
+    // Two spellings of one file, as a case-insensitive filesystem would show them
+    if fs::hard_link(dir1.join("Photo.jpg"), dir1.join("photo.jpg")).is_err() {
+        return Ok(());
+    }
+    // Separate files differing in case are kept
+    create_file(&dir1.join("NOTES.txt"), "Notes")?;
+    create_file(&dir1.join("notes.txt"), "Notes")?;
+
+    let mut options = CompareOptions {
+        relative: true,
+        sort: true,
+        include_excluded: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(3));
+
+    options.collapse_case_aliases = true;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![PathBuf::from("Photo.jpg"), PathBuf::from("photo.jpg")])
+    );
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![PathBuf::from("NOTES.txt"), PathBuf::from("notes.txt")])
+    );
+    let excluded = result.excluded.unwrap_or_default();
+    assert_eq!(excluded.len(), 1);
+    assert_eq!(excluded[0].path, PathBuf::from("photo.jpg"));
+    assert_eq!(excluded[0].side, Side::Dir1);
+    assert_eq!(excluded[0].reason, SkipReason::CaseAlias);
+
+    Ok(())
+}
+
+#[test]
+fn test_comparison_builder() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_comparison_builder");
+    let _ = fs::remove_dir_all(&base_dir);
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let extra = base_dir.join("extra");
+    for dir in [&dir1, &dir2, &extra] {
+        fs::create_dir_all(dir)?;
+    }
+    create_file(&dir1.join("a.txt"), "Same")?;
+    create_file(&dir1.join(".hidden"), "Hidden")?;
+    create_file(&dir2.join("b.txt"), "Same")?;
+    create_file(&dir2.join("c.txt"), "Only in dir2")?;
+    create_file(&extra.join("c.txt"), "Only in dir2")?;
+
+    let result = Comparison::new(&dir1, &dir2)
+        .relative(true)
+        .skip_hidden(true)
+        .sort(true)
+        .unique_dir2(false)
+        .run()?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")])
+    );
+    assert_eq!(result.unique_dir1, Some(vec![]));
+    assert_eq!(result.unique_dir2, None);
+
+    // The positional function gives the same result
+    let positional = compare_two_directories(&dir1, &dir2, true, true, true, true, true, false)?;
+    assert_eq!(positional, result);
+
+    // Further roots and options without a method of their own
+    let result = Comparison::new(&dir2, &dir1)
+        .options(CompareOptions {
+            include_groups: true,
+            ..CompareOptions::default()
+        })
+        .root1(&extra)
+        .relative(true)
+        .run()?;
+    assert_eq!(result.groups.unwrap().len(), 3);
+    assert_eq!(result.unique_dir1.map(|paths| paths.len()), Some(2));
+
+    Ok(())
+}
+
+#[test]
+fn test_double_check() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_double_check");
+    let _ = fs::remove_dir_all(&base_dir);
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Same")?;
+    create_file(&dir2.join("a.txt"), "Same")?;
+    create_file(&dir2.join("b.txt"), "Same")?;
+    create_file(&dir1.join("unique.txt"), "Unique")?;
+
+    let options = CompareOptions {
+        include_groups: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    let mut groups = result.groups.unwrap();
+
+    // Only the files of contents on both sides are digested
+    assert_eq!(double_check(&groups, Algorithm::Sha256, &options)?, 3);
+    let err = double_check(&groups, Algorithm::Blake3, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // A match the second digest disagrees with fails the check
+    create_file(&dir2.join("b.txt"), "Diff")?;
+    let shared = groups.iter_mut().find(|group| group.is_shared()).unwrap();
+    assert_eq!(shared.dir2, vec![dir2.join("a.txt"), dir2.join("b.txt")]);
+    let err = double_check(&groups, Algorithm::Md5, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("b.txt"));
+    assert!(err.to_string().contains("md5"));
+
+    // A file that cannot be read is named by the error
+    fs::remove_file(dir2.join("b.txt"))?;
+    let err = double_check(&groups, Algorithm::Md5, &options).unwrap_err();
+    assert_eq!(err.path(), Some(dir2.join("b.txt").as_path()));
+
+    Ok(())
+}
+
+#[test]
+fn test_keep_rules() -> Result<(), Box<dyn std::error::Error>> {
+    assert!(KeepRule::matches("*.jpg", Path::new("photos/2024/a.jpg")));
+    assert!(KeepRule::matches(
+        "originals/*",
+        Path::new("photos/originals/a.jpg")
+    ));
+    assert!(!KeepRule::matches(
+        "originals/*",
+        Path::new("originals/raw/a.jpg")
+    ));
+    assert!(KeepRule::matches(
+        "originals/**",
+        Path::new("originals/raw/a.jpg")
+    ));
+    assert!(KeepRule::matches(
+        "p?otos/**/a.*",
+        Path::new("photos/2024/x/a.jpg")
+    ));
+
+    let group = HashGroup {
+        hash: blake3::hash(b"Photo"),
+        size: 5,
+        dir1: vec![
+            PathBuf::from("dir1/import/tmp/a.jpg"),
+            PathBuf::from("dir1/originals/a.jpg"),
+        ],
+        dir2: vec![PathBuf::from("dir2/a.jpg")],
+    };
+    let keep = |rules: &KeepRules| rules.decide(&group).unwrap().keep.path;
+
+    // Without rules the first file of the first directory is kept
+    assert_eq!(keep(&KeepRules::new()), Path::new("dir1/import/tmp/a.jpg"));
+    assert_eq!(
+        keep(&KeepRules::new().shortest_path()),
+        Path::new("dir2/a.jpg")
+    );
+    // Later rules only break the ties of earlier ones
+    let rules = KeepRules::new()
+        .prefer_side(Side::Dir1)
+        .shortest_path()
+        .prefer_matching("import/**");
+    assert_eq!(keep(&rules), Path::new("dir1/originals/a.jpg"));
+    let rules: KeepRules = "avoid:tmp/*, dir2".parse()?;
+    assert_eq!(keep(&rules), Path::new("dir2/a.jpg"));
+    let decision = rules.decide(&group).unwrap();
+    assert_eq!(decision.duplicates.len(), 2);
+    assert_eq!(decision.duplicates[0].side, Side::Dir1);
+
+    assert!("largest".parse::<KeepRules>().is_err());
+    assert!("match:".parse::<KeepRules>().is_err());
+    let single = HashGroup {
+        dir2: Vec::new(),
+        dir1: vec![PathBuf::from("dir1/a.jpg")],
+        ..group
+    };
+    assert!(rules.decide(&single).is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_three_way_diff() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_three_way");
+    let _ = fs::remove_dir_all(&base_dir);
+    let [base, a, b] = ["base", "a", "b"].map(|name| base_dir.join(name));
+    for dir in [&base, &a, &b] {
+        fs::create_dir_all(dir)?;
+        for name in [
+            "same",
+            "edited_in_a",
+            "edited_in_b",
+            "edited_alike",
+            "conflict",
+            "removed",
+        ] {
+            create_file(&dir.join(name), "Original")?;
+        }
+    }
+    create_file(&a.join("edited_in_a"), "A")?;
+    create_file(&b.join("edited_in_b"), "B")?;
+    create_file(&a.join("edited_alike"), "Both")?;
+    create_file(&b.join("edited_alike"), "Both")?;
+    create_file(&a.join("conflict"), "A")?;
+    create_file(&b.join("conflict"), "B")?;
+    create_file(&a.join("added"), "New")?;
+    fs::remove_file(a.join("removed"))?;
+    create_file(&b.join("removed"), "B")?;
+
+    let diff = three_way_diff(&base, &a, &b, &CompareOptions::default())?;
+    assert_eq!(diff.unchanged, 1);
+    let paths = |status| {
+        diff.with_status(status)
+            .map(|change| {
+                (
+                    change.path.to_str().unwrap(),
+                    change.change_a(),
+                    change.change_b(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        paths(ThreeWayStatus::ChangedInA),
+        vec![
+            ("added", Some(Change::Added), None),
+            ("edited_in_a", Some(Change::Modified), None),
+        ]
+    );
+    assert_eq!(
+        paths(ThreeWayStatus::ChangedInB),
+        vec![("edited_in_b", None, Some(Change::Modified))]
+    );
+    assert_eq!(
+        paths(ThreeWayStatus::ChangedInBoth),
+        vec![(
+            "edited_alike",
+            Some(Change::Modified),
+            Some(Change::Modified)
+        )]
+    );
+    assert_eq!(
+        paths(ThreeWayStatus::Conflict),
+        vec![
+            ("conflict", Some(Change::Modified), Some(Change::Modified)),
+            ("removed", Some(Change::Removed), Some(Change::Modified)),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_exec() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_exec");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&base_dir)?;
+    let paths = vec![
+        create_file(&base_dir.join("plain.txt"), "Plain")?,
+        create_file(&base_dir.join("with 'quotes' and spaces.txt"), "Quoted")?,
+    ];
+
+    // Paths are passed as one argument each, whatever they contain
+    let template = ExecTemplate::parse("cp '{}' \"{}.copy\"")?;
+    let report = run_for_each(&template, &paths, 2)?;
+    assert_eq!(report.succeeded, 2);
+    assert!(report.failed.is_empty());
+    assert_eq!(
+        fs::read_to_string(base_dir.join("with 'quotes' and spaces.txt.copy"))?,
+        "Quoted"
+    );
+
+    // Without a placeholder the path is the last argument
+    let report = run_for_each(&ExecTemplate::parse("test -d")?, &paths, 1)?;
+    assert_eq!(report.succeeded, 0);
+    assert_eq!(
+        report
+            .failed
+            .iter()
+            .map(|failure| &failure.path)
+            .collect::<Vec<_>>(),
+        paths.iter().collect::<Vec<_>>()
+    );
+
+    let err = ExecTemplate::parse("echo 'unterminated").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(ExecTemplate::parse("  ").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_changes() {
+    let zfs = "M\t/tank/data/photos\n\
+               +\t/tank/data/photos/new\\0040file.jpg\n\
+               -\t/tank/data/old.txt\n\
+               R\t/tank/data/a.txt\t/tank/data/b.txt\n";
+    let changed = parse_zfs_diff(zfs, Path::new("/tank/data"));
+    let expected: HashSet<PathBuf> = ["photos", "photos/new file.jpg", "old.txt", "a.txt", "b.txt"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    assert_eq!(changed, expected);
+
+    let btrfs = "inode 257 file offset 0 len 4096 disk start 13631488 offset 0 gen 9 flags NONE docs/report 2.txt\n\
+                 inode 258 file offset 0 len 12 disk start 0 offset 0 gen 10 flags INLINE notes.txt\n\
+                 inode 258 file offset 12 len 12 disk start 0 offset 0 gen 10 flags INLINE notes.txt\n\
+                 transid marker was 10\n";
+    let changed = parse_btrfs_find_new(btrfs);
+    let expected: HashSet<PathBuf> = ["docs/report 2.txt", "notes.txt"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+    assert_eq!(changed, expected);
+}
+
+#[test]
+fn test_watch() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_watch");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("file.txt"), "Mirrored")?;
+
+    // The watcher reports the drift, then the repair
+    let mut events = Vec::new();
+    watch(
+        std::slice::from_ref(&dir1),
+        std::slice::from_ref(&dir2),
+        &CompareOptions::default(),
+        &WatchOptions {
+            interval: Duration::from_millis(10),
+            debounce: Duration::from_millis(10),
+            summary_interval: None,
+            changes: false,
+        },
+        |event| {
+            events.push(event.clone());
+            create_file(&dir2.join("file.txt"), "Mirrored").is_ok()
+                && *event
+                    == WatchEvent::Diverged {
+                        unique_dir1: 1,
+                        unique_dir2: 0,
+                    }
+        },
+    )?;
+    assert_eq!(
+        events,
+        vec![
+            WatchEvent::Diverged {
+                unique_dir1: 1,
+                unique_dir2: 0
+            },
+            WatchEvent::Reconverged,
+        ]
+    );
+
+    // With the changed files, only the files that changed are reported each time
+    create_file(&dir1.join("new.txt"), "New")?;
+    let mut events = Vec::new();
+    watch(
+        std::slice::from_ref(&dir1),
+        std::slice::from_ref(&dir2),
+        &CompareOptions::default(),
+        &WatchOptions {
+            interval: Duration::from_millis(10),
+            debounce: Duration::from_millis(10),
+            summary_interval: None,
+            changes: true,
+        },
+        |event| {
+            events.push(event.clone());
+            match event {
+                WatchEvent::Changed { diverged_dir1, .. } if !diverged_dir1.is_empty() => {
+                    create_file(&dir2.join("new.txt"), "New").is_ok()
+                }
+                WatchEvent::Changed { .. } => false,
+                _ => true,
+            }
+        },
+    )?;
+    assert_eq!(
+        events,
+        vec![
+            WatchEvent::Diverged {
+                unique_dir1: 1,
+                unique_dir2: 0
+            },
+            WatchEvent::Changed {
+                diverged_dir1: vec![dir1.join("new.txt")],
+                diverged_dir2: vec![],
+                converged_dir1: vec![],
+                converged_dir2: vec![],
+            },
+            WatchEvent::Reconverged,
+            WatchEvent::Changed {
+                diverged_dir1: vec![],
+                diverged_dir2: vec![],
+                converged_dir1: vec![dir1.join("new.txt")],
+                converged_dir2: vec![],
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_compare_against() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_compare_against");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&base_dir)?;
+    create_file(&base_dir.join("kept.txt"), "Kept")?;
+    create_file(&base_dir.join("new.txt"), "New")?;
+
+    let previous = format!(
+        r#"[
+            {{"hash": "{}", "size": 4, "directory1": [{{"path": "kept.txt"}}], "directory2": []}},
+            {{"hash": "{}", "size": 4, "directory1": [{{"path": "gone.txt"}}], "directory2": []}}
+        ]"#,
+        blake3::hash(b"Kept"),
+        blake3::hash(b"Gone")
+    );
+    let previous = read_grouped_json(previous.as_bytes(), Side::Dir1)?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_against(&base_dir, &previous, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![PathBuf::from("kept.txt"), PathBuf::from("kept.txt")])
+    );
+    assert_eq!(result.unique_dir1, Some(vec![PathBuf::from("new.txt")]));
+    assert_eq!(result.unique_dir2, Some(vec![PathBuf::from("gone.txt")]));
+
+    // Plain JSON output has no hashes
+    assert!(read_grouped_json(&br#"{"intersection": []}"#[..], Side::Dir1).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_hashdeep_audit() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_hashdeep");
+    let dir = base_dir.join("dir");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir)?;
+
+    let kept = create_file(&dir.join("kept.txt"), "Kept file")?;
+    let moved = create_file(&dir.join("moved.txt"), "Moved file")?;
+    let removed = create_file(&dir.join("removed, with comma.txt"), "Removed file")?;
+
+    // Write the known files and read them back
+    let algorithms = [Algorithm::Md5, Algorithm::Sha256];
+    let files = digest_tree(&dir, &algorithms, &CompareOptions::default())?;
+    let mut written = Vec::new();
+    write_hashdeep(&mut written, &algorithms, &files)?;
+    let known = read_hashdeep(written.as_slice())?;
+    assert_eq!(known.algorithms, algorithms);
+    assert_eq!(known.entries.len(), 3);
+    assert_eq!(known.entries[2].path, removed);
+
+    // Streaming the files with a queue of one gives the same files in any order
+    let options = CompareOptions {
+        max_in_flight: Some(1),
+        ..CompareOptions::default()
+    };
+    let mut streamed = Vec::new();
+    write_hashdeep_header(&mut streamed, &algorithms)?;
+    let count = for_each_digest(&dir, &algorithms, &options, |file| {
+        write_hashdeep_entry(&mut streamed, &file)
+    })?;
+    assert_eq!(count, 3);
+    let mut streamed = read_hashdeep(streamed.as_slice())?;
+    streamed.entries.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(streamed, known);
+
+    // Change the directory
+    let moved_to = dir.join("moved_to.txt");
+    fs::rename(&moved, &moved_to)?;
+    fs::remove_file(&removed)?;
+    let added = create_file(&dir.join("added.txt"), "Added file")?;
+
+    let report = audit(&dir, &known, &CompareOptions::default())?;
+    assert_eq!(report.matched, vec![kept]);
+    assert_eq!(report.moved, vec![(moved_to, moved)]);
+    assert_eq!(report.new, vec![added]);
+    assert_eq!(report.missing, vec![removed]);
+    assert!(!report.passed());
+
+    Ok(())
+}
+
+#[test]
+fn test_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("test_dirs_manifest");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub"))?;
+    create_file(&dir.join("a.txt"), "First")?;
+    create_file(&dir.join("sub/b c.txt"), "Second")?;
+    create_file(&dir.join("line\nbreak"), "Third")?;
+
+    // Every file is recorded by its relative path, hash, size and modification time
+    let manifest = generate_manifest(&dir)?;
+    let paths: Vec<_> = manifest.entries.iter().map(|entry| &entry.path).collect();
+    assert_eq!(
+        paths,
+        ["a.txt", "line\nbreak", "sub/b c.txt"]
+            .map(PathBuf::from)
+            .iter()
+            .collect::<Vec<_>>()
+    );
+    let entry = &manifest.entries[0];
+    assert_eq!(entry.hash, blake3::hash(b"First"));
+    assert_eq!(entry.stamp, FileStamp::read(&dir.join("a.txt"))?);
+
+    // Manifests are read back as written, paths with line breaks included
+    let mut written = Vec::new();
+    write_manifest(&mut written, &manifest)?;
+    let text = String::from_utf8(written.clone())?;
+    assert!(text.starts_with("# filematch manifest 1\n"));
+    assert_eq!(text.lines().count(), 4);
+    assert_eq!(read_manifest(written.as_slice())?, manifest);
+
+    let err = read_manifest("a.txt\n".as_bytes()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    let err = read_manifest("# filematch manifest 1\nnot a line\n".as_bytes()).unwrap_err();
+    assert!(err.to_string().starts_with("manifest line 2"));
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_verify_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("test_dirs_verify_manifest");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir)?;
+    create_file(&dir.join("kept.txt"), "Kept")?;
+    create_file(&dir.join("changed.txt"), "Before")?;
+    create_file(&dir.join("removed.txt"), "Removed")?;
+    let manifest = generate_manifest(&dir)?;
+
+    // An unchanged directory matches its manifest
+    let check = verify_manifest(&dir, &manifest, &CompareOptions::default())?;
+    assert!(check.passed());
+    assert_eq!(check.unchanged.len(), 3);
+
+    // Changed contents are found even with the same size and modification time
+    let stamp = FileStamp::read(&dir.join("changed.txt"))?;
+    create_file(&dir.join("changed.txt"), "Behind")?;
+    fs::File::options()
+        .write(true)
+        .open(dir.join("changed.txt"))?
+        .set_modified(UNIX_EPOCH + stamp.modified)?;
+    assert_eq!(FileStamp::read(&dir.join("changed.txt"))?, stamp);
+    fs::remove_file(dir.join("removed.txt"))?;
+    create_file(&dir.join("added.txt"), "Added")?;
+
+    let check = verify_manifest(&dir, &manifest, &CompareOptions::default())?;
+    assert!(!check.passed());
+    assert_eq!(check.unchanged, [PathBuf::from("kept.txt")]);
+    assert_eq!(check.changed, [PathBuf::from("changed.txt")]);
+    assert_eq!(check.missing, [PathBuf::from("removed.txt")]);
+    assert_eq!(check.added, [PathBuf::from("added.txt")]);
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_checksum_list() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("test_dirs_checksum_list");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub"))?;
+    create_file(&dir.join("a.txt"), "A")?;
+    create_file(&dir.join("sub/b.txt"), "B")?;
+
+    // Written checksum lines are read back with the algorithm told by the digest length
+    let list = generate_checksum_list(&dir, Algorithm::Sha256, &CompareOptions::default())?;
+    let mut written = Vec::new();
+    write_checksum_list(&mut written, &list)?;
+    assert!(
+        String::from_utf8(written.clone())?.starts_with(&format!("{}  a.txt\n", list.entries[0].1))
+    );
+    assert_eq!(read_checksum_list(written.as_slice(), None)?, list);
+    assert!(verify_checksum_list(&dir, &list, &CompareOptions::default())?.passed());
+
+    // Tagged lines name their algorithm, and other digests must be of the same one
+    let (path, digest) = &list.entries[1];
+    let tagged = format!("SHA256 ({}) = {digest}\n", path.display());
+    assert_eq!(
+        read_checksum_list(tagged.as_bytes(), None)?.entries,
+        [list.entries[1].clone()]
+    );
+    let mixed = format!("{tagged}{}  a.txt\n", "0".repeat(32));
+    assert!(read_checksum_list(mixed.as_bytes(), None).is_err());
+
+    // Files written on Windows end their lines with CRLF
+    let crlf = String::from_utf8(written.clone())?.replace('\n', "\r\n");
+    assert_eq!(read_checksum_list(crlf.as_bytes(), None)?, list);
+    let tagged_crlf = tagged.replace('\n', "\r\n");
+    assert_eq!(
+        read_checksum_list(tagged_crlf.as_bytes(), None)?.entries,
+        [list.entries[1].clone()]
+    );
+
+    // BLAKE3 digests have the length of SHA-256 ones, so their algorithm is given
+    let blake3 = generate_checksum_list(&dir, Algorithm::Blake3, &CompareOptions::default())?;
+    let mut written = Vec::new();
+    write_checksum_list(&mut written, &blake3)?;
+    let read = read_checksum_list(written.as_slice(), Some(Algorithm::Blake3))?;
+    create_file(&dir.join("a.txt"), "Changed")?;
+    let check = verify_checksum_list(&dir, &read, &CompareOptions::default())?;
+    assert_eq!(check.changed, [PathBuf::from("a.txt")]);
+    assert_eq!(check.unchanged, [PathBuf::from("sub/b.txt")]);
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_compare_sorted_manifests() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_compare_sorted_manifests");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same")?;
+    create_file(&dir2.join("renamed.txt"), "Same")?;
+    create_file(&dir1.join("changed.txt"), "Before")?;
+    create_file(&dir2.join("changed.txt"), "After")?;
+    create_file(&dir1.join("only1.txt"), "Only 1")?;
+
+    // Written manifests sorted by digest are compared without the directories
+    let sorted = |dir: &Path| -> std::io::Result<String> {
+        let mut written = Vec::new();
+        write_manifest(&mut written, &generate_manifest(dir)?)?;
+        let mut lines: Vec<&str> = std::str::from_utf8(&written).unwrap().lines().collect();
+        lines.sort_unstable();
+        Ok(lines.join("\n"))
+    };
+    let manifest1 = sorted(&dir1)?;
+    let manifest2 = sorted(&dir2)?;
+    fs::remove_dir_all(&base_dir)?;
+
+    let result = compare_sorted_manifests(manifest1.as_bytes(), manifest2.as_bytes())?;
+    let mut intersection = result.intersection.unwrap();
+    intersection.sort();
+    assert_eq!(
+        intersection,
+        [PathBuf::from("renamed.txt"), PathBuf::from("same.txt")]
+    );
+    let mut unique_dir1 = result.unique_dir1.unwrap();
+    unique_dir1.sort();
+    assert_eq!(
+        unique_dir1,
+        [PathBuf::from("changed.txt"), PathBuf::from("only1.txt")]
+    );
+    assert_eq!(result.unique_dir2.unwrap(), [PathBuf::from("changed.txt")]);
+    assert_eq!(result.modified.unwrap(), [PathBuf::from("changed.txt")]);
+
+    // The same content cannot have two sizes
+    let same = blake3::hash(b"Same");
+    let corrupt = format!("{MANIFEST_HEADER}\n{same} 5 0.000000000 same.txt\n");
+    let err = compare_sorted_manifests(manifest1.as_bytes(), corrupt.as_bytes()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    Ok(())
+}
+
+#[test]
+fn test_root_lost() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_root_lost");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let moved = base_dir.join("moved");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("file.txt"), "Same")?;
+    create_file(&dir2.join("file.txt"), "Same")?;
+
+    // A root replaced by another directory, like an unmounted mount point, is lost
+    let watch = RootWatch::new(std::slice::from_ref(&dir1), std::slice::from_ref(&dir2));
+    assert!(watch.lost_sides().is_empty());
+    fs::rename(&dir2, &moved)?;
+    fs::create_dir(&dir2)?;
+    assert!(watch.is_lost(Side::Dir2, &dir2.join("file.txt")));
+    assert!(!watch.is_lost(Side::Dir1, &dir1.join("file.txt")));
+    assert_eq!(watch.lost_sides(), [Side::Dir2]);
+    fs::remove_dir(&dir2)?;
+    fs::rename(&moved, &dir2)?;
+
+    // The second root disappears while it is walked, which leaves its side incomplete
+    let (root2, away) = (dir2.clone(), moved.clone());
+    let options = CompareOptions {
+        relative: true,
+        walk_filter: Some(WalkFilter::new(move |entry| {
+            if entry.path().starts_with(&root2) {
+                let _ = fs::rename(&root2, &away);
+            }
+            true
+        })),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.incomplete, [Side::Dir2]);
+    let json = ComparisonResult::from_json(&result.to_json())?;
+    assert_eq!(json.incomplete, [Side::Dir2]);
+    assert_eq!(result.unique_dir1.unwrap(), [PathBuf::from("file.txt")]);
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_bagit() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_bagit");
+    let bag1 = base_dir.join("bag1");
+    let bag2 = base_dir.join("bag2");
+    let _ = fs::remove_dir_all(&base_dir);
+
+    for bag in [&bag1, &bag2] {
+        fs::create_dir_all(bag.join("data"))?;
+        create_file(&bag.join("bagit.txt"), "BagIt-Version: 1.0\n")?;
+    }
+    create_file(&bag1.join("data/common.txt"), "Common file")?;
+    create_file(&bag1.join("data/unique1.txt"), "Unique file 1")?;
+    create_file(&bag2.join("data/renamed.txt"), "Common file")?;
+
+    let md5 = |content: &str| {
+        filematch::checksum::digest_reader(content.as_bytes(), &[Algorithm::Md5])
+            .map(|(_, digests)| digests[0].clone())
+    };
+    create_file(
+        &bag1.join("manifest-md5.txt"),
+        &format!(
+            "{}  data/common.txt\n{}  data/unique1.txt\n",
+            md5("Common file")?,
+            md5("Unique file 1")?
+        ),
+    )?;
+    create_file(
+        &bag2.join("manifest-md5.txt"),
+        &format!(
+            "{}  data/renamed.txt\n{}  data/unique2.txt\n",
+            md5("Common file")?,
+            md5("Unique file 2")?
+        ),
+    )?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+
+    let report = verify_bag(&bag1, &options)?;
+    assert!(report.passed());
+    assert_eq!(report.valid.len(), 2);
+
+    // The second bag lacks a listed file and has a corrupted one
+    fs::write(bag2.join("data/renamed.txt"), "Corrupted")?;
+    let report = verify_bag(&bag2, &options)?;
+    assert!(!report.passed());
+    assert_eq!(report.mismatched, vec![PathBuf::from("data/renamed.txt")]);
+    assert_eq!(report.missing, vec![PathBuf::from("data/unique2.txt")]);
+
+    // Comparing bags only uses the manifests
+    let result = compare_bags(&bag1, &bag2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![
+            PathBuf::from("data/common.txt"),
+            PathBuf::from("data/renamed.txt")
+        ])
+    );
+    assert_eq!(
+        result.unique_dir1,
+        Some(vec![PathBuf::from("data/unique1.txt")])
+    );
+    assert_eq!(
+        result.unique_dir2,
+        Some(vec![PathBuf::from("data/unique2.txt")])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_decompress() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let base_dir = std::env::temp_dir().join("test_dirs_decompress");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    let content = "Log line\n".repeat(1000);
+    create_file(&dir1.join("foo.log"), &content)?;
+
+    let mut gz = flate2::write::GzEncoder::new(
+        fs::File::create(dir2.join("foo.log.gz"))?,
+        flate2::Compression::default(),
+    );
+    gz.write_all(content.as_bytes())?;
+    gz.finish()?;
+    fs::write(
+        dir2.join("foo.log.zst"),
+        zstd::encode_all(content.as_bytes(), 0)?,
+    )?;
+
+    // Compressed files are compared as they are by default
+    let mut options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection, Some(vec![]));
+
+    options.decompress = vec![Compression::Gz, Compression::Zst];
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![
+            PathBuf::from("foo.log"),
+            PathBuf::from("foo.log.gz"),
+            PathBuf::from("foo.log.zst")
+        ])
+    );
+
+    // A corrupted archive fails the comparison
+    create_file(&dir2.join("corrupted.gz"), "Not gzip")?;
+    let err = compare_two_directories_with_options(&dir1, &dir2, &options).unwrap_err();
+    assert!(matches!(err, FileMatchError::Hash { .. }));
+    assert_eq!(err.path(), Some(dir2.join("corrupted.gz").as_path()));
+
+    // Unless read errors are collected
+    options.read_errors = ErrorPolicy::CollectAndContinue;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.read_errors.len(), 1);
+    assert_eq!(result.read_errors[0].path, PathBuf::from("corrupted.gz"));
+    assert_eq!(result.read_errors[0].side, Side::Dir2);
+    assert_eq!(result[Category::Error], [PathBuf::from("corrupted.gz")]);
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(3));
+
+    Ok(())
+}
+
+#[test]
+fn test_strip_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_strip_metadata");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    // A JPEG with an EXIF segment, a quantization table, and scan data
+    let jpeg = |exif: &[u8]| {
+        let mut bytes = vec![0xff, 0xd8, 0xff, 0xe1];
+        bytes.extend_from_slice(&u16::try_from(exif.len() + 2).unwrap().to_be_bytes());
+        bytes.extend_from_slice(exif);
+        bytes.extend_from_slice(&[0xff, 0xdb, 0x00, 0x04, 0x00, 0x01]);
+        bytes.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0x12, 0x34, 0x56, 0xff, 0xd9]);
+        bytes
+    };
+    fs::write(dir1.join("photo.jpg"), jpeg(b"Exif\0\0camera"))?;
+    fs::write(dir2.join("edited.jpg"), jpeg(b"Exif\0\0edited by someone"))?;
+
+    // An MP3 with ID3v2 and ID3v1 tags around the audio frames
+    let mp3 = |title: &[u8]| {
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        bytes.push(u8::try_from(title.len()).unwrap());
+        bytes.extend_from_slice(title);
+        bytes.extend_from_slice(&[0xff, 0xfb, 0x90, 0x00].repeat(100));
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.extend_from_slice(title);
+        id3v1.resize(128, 0);
+        bytes.extend_from_slice(&id3v1);
+        bytes
+    };
+    fs::write(dir1.join("song.mp3"), mp3(b"Title"))?;
+    fs::write(dir2.join("retagged.mp3"), mp3(b"Better title"))?;
+
+    let mut options = CompareOptions {
+        relative: true,
+        sort: true,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection, Some(vec![]));
+
+    options.strip_metadata = true;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection,
+        Some(vec![
+            PathBuf::from("edited.jpg"),
+            PathBuf::from("photo.jpg"),
+            PathBuf::from("retagged.mp3"),
+            PathBuf::from("song.mp3")
+        ])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_content_transform() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_content_transform");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("report.csv"), "generated 2024-01-01\na,b\n")?;
+    create_file(&dir2.join("report.csv"), "generated 2025-06-30\na,b\n")?;
+
+    // Leave out the first line, which holds a timestamp
+    let options = CompareOptions {
+        relative: true,
+        transform: Some(ContentTransform::new(|_, reader| {
+            let mut reader = BufReader::new(reader);
+            let mut header = String::new();
+            let _ = reader.read_line(&mut header);
+            Box::new(reader)
+        })),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.unique_dir1, Some(vec![]));
+    assert_eq!(result.unique_dir2, Some(vec![]));
+
+    let options = CompareOptions {
+        sums: SumsPolicy::Trust,
+        ..options
+    };
+    assert!(compare_two_directories_with_options(&dir1, &dir2, &options).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_unstable_files() -> Result<(), Box<dyn std::error::Error>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let base_dir = std::env::temp_dir().join("test_dirs_unstable_files");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    let growing = create_file(&dir1.join("growing.log"), "Line")?;
+    create_file(&dir2.join("growing.log"), "Line")?;
+
+    // Append to the file whenever it is opened, for the given number of times
+    let appender = |times: usize| {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let growing = growing.clone();
+        ContentTransform::new(move |path, reader| {
+            if path == growing && opened.fetch_add(1, Ordering::SeqCst) < times {
+                let contents = fs::read_to_string(path).unwrap();
+                fs::write(path, contents + "Line").unwrap();
+            }
+            reader
+        })
+    };
+
+    let mut options = CompareOptions {
+        relative: true,
+        transform: Some(appender(1)),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.skipped.len(), 1);
+    assert_eq!(result.skipped[0].path, PathBuf::from("growing.log"));
+    assert_eq!(result.skipped[0].reason, SkipReason::Unstable);
+
+    options.retry_unstable = true;
+    options.transform = Some(appender(1));
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert!(result.skipped.is_empty());
+    assert_eq!(result.unique_dir1, Some(vec![PathBuf::from("growing.log")]));
+
+    options.transform = Some(appender(2));
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.skipped.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_sums() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_sums");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Same")?;
+    create_file(&dir2.join("b.txt"), "Same")?;
+
+    let mut options = CompareOptions {
+        relative: true,
+        sums: SumsPolicy::Update,
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.intersection.unwrap().len(), 2);
+
+    // The sums files are written, and never compared themselves
+    let sums = fs::read_to_string(dir2.join(SUMS_FILE_NAME))?;
+    assert!(sums.contains(&format!("{} 4 ", blake3::hash(b"Same"))));
+    assert!(sums.ends_with(" b.txt\n"));
+
+    // A recorded hash is trusted while the file is unchanged
+    let other = blake3::hash(b"Other");
+    fs::write(
+        dir2.join(SUMS_FILE_NAME),
+        sums.replace(&blake3::hash(b"Same").to_string(), &other.to_string()),
+    )?;
+    options.sums = SumsPolicy::Trust;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.unique_dir2, Some(vec![PathBuf::from("b.txt")]));
+    assert_eq!(result.sums_check, SumsCheck::default());
+
+    // unless it is checked, which finds it stale
+    options.verify_sums = 1.0;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.unique_dir2, Some(vec![]));
+    assert_eq!(result.sums_check.checked, 2);
+    assert_eq!(result.sums_check.stale, vec![PathBuf::from("b.txt")]);
+    assert!((result.sums_check.error_rate() - 0.5).abs() < f64::EPSILON);
+    options.verify_sums = 0.0;
+
+    options.sums = SumsPolicy::Ignore;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.unique_dir2, Some(vec![]));
+
+    options.sums = SumsPolicy::Trust;
+    options.strip_metadata = true;
+    assert!(compare_two_directories_with_options(&dir1, &dir2, &options).is_err());
+    options.strip_metadata = false;
+
+    // Read-only comparisons may use the recorded hashes, but not record any
+    options.read_only = true;
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(result.unique_dir2, Some(vec![PathBuf::from("b.txt")]));
+    options.sums = SumsPolicy::Update;
+    let err = compare_two_directories_with_options(&dir1, &dir2, &options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+    // Precomputing records the hashes of a tree without comparing it
+    fs::remove_file(dir1.join(SUMS_FILE_NAME))?;
+    let report = precompute(std::slice::from_ref(&dir1), &CompareOptions::default())?;
+    assert_eq!(report.files, 1);
+    assert!(dir1.join(SUMS_FILE_NAME).is_file());
+
+    // The recorded hashes can be exported as b3sum lines
+    let exported = export_sums(&dir1, &CompareOptions::default())?;
+    assert!(exported.missing.is_empty());
+    let mut lines = Vec::new();
+    write_checksum_lines(&mut lines, &exported.hashes)?;
+    assert_eq!(
+        String::from_utf8(lines.clone())?,
+        format!("{}  a.txt\n", blake3::hash(b"Same"))
+    );
+
+    // and imported into the sums files of a copy
+    let copy = base_dir.join("copy");
+    fs::create_dir_all(&copy)?;
+    create_file(&copy.join("a.txt"), "Same")?;
+    let checksums = read_checksum_lines(lines.as_slice())?;
+    let report = import_sums(&copy, &checksums)?;
+    assert_eq!(report.imported, 1);
+    assert_eq!(export_sums(&copy, &CompareOptions::default())?, exported);
+
+    Ok(())
+}
+
+#[test]
+fn test_jobs() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_jobs");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let jobs = base_dir.join("jobs");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    let file1 = create_file(&dir1.join("a.txt"), "Same")?;
+    create_file(&dir2.join("b.txt"), "Same")?;
+
+    let spec = JobSpec {
+        directories1: vec![dir1.clone()],
+        directories2: vec![dir2.clone()],
+        relative: true,
+        ..JobSpec::default()
+    };
+    let job = create_job(&jobs, &spec)?;
+    assert_eq!(job.id, "1");
+    assert_eq!(create_job(&jobs, &spec)?.id, "2");
+    assert_eq!(load_job(&jobs, "1")?.state, JobState::Created);
+    assert_eq!(load_job(&jobs, "1")?.last_run, None);
+
+    let result = run_job(&jobs, "1", &CompareOptions::default())?;
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(2));
+    assert_eq!(list_jobs(&jobs)?[0].state, JobState::Finished);
+    assert!(list_jobs(&jobs)?[0].last_run.is_some());
+
+    // Files with an unchanged size and modification time are taken from the journal
+    let modified = fs::metadata(&file1)?.modified()?;
+    fs::write(&file1, "Diff")?;
+    fs::File::options()
+        .write(true)
+        .open(&file1)?
+        .set_modified(modified)?;
+    let result = run_job(&jobs, "1", &CompareOptions::default())?;
+    assert_eq!(result.intersection.map(|paths| paths.len()), Some(2));
+    let result = run_job(&jobs, "2", &CompareOptions::default())?;
+    assert_eq!(result.unique_dir1, Some(vec![PathBuf::from("a.txt")]));
+
+    Ok(())
+}
+
+#[test]
+fn test_self_test() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_self_test");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&base_dir)?;
+
+    let test = run_self_test(&base_dir, 1 << 20, 64 * 1024, 2)?;
+    assert!(test.hash_single > 0.0 && test.hash_multi > 0.0);
+    assert!(test.sequential_read > 0.0 && test.random_read > 0.0);
+    assert!(!base_dir.join(TEST_FILE_NAME).exists());
+
+    // Slow random reads suggest a single reader and just enough hashers
+    let disk = SelfTest {
+        hash_single: 1000.0,
+        hash_multi: 4000.0,
+        hash_threads: 4,
+        sequential_read: 1500.0,
+        random_read: 10.0,
+    };
+    let recommendation = disk.recommendation();
+    assert!(recommendation.seeks_slowly && !recommendation.adaptive_io);
+    assert_eq!(recommendation.io_threads, 1);
+    assert_eq!(recommendation.hash_threads, 2);
+    let ssd = SelfTest {
+        random_read: 1000.0,
+        ..disk
+    };
+    let recommendation = ssd.recommendation();
+    assert!(!recommendation.seeks_slowly && recommendation.adaptive_io);
+    assert_eq!(recommendation.io_threads, 16);
+    assert_eq!(recommendation.hash_threads, 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+    assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+    assert!(parse_duration("10").is_err());
+    assert!(parse_duration("fast").is_err());
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("512K"), Ok(512 * 1024));
+    assert_eq!(parse_size("1.5M"), Ok(3 * 512 * 1024));
+    assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+    assert_eq!(parse_size("1gb"), Ok(1 << 30));
+    assert!(parse_size("1X").is_err());
+    assert!(parse_size("big").is_err());
+}
+
+#[test]
+fn test_path_style() {
+    let path = Path::new("photos").join("2024").join("a.jpg");
+    assert_eq!(PathStyle::Unix.display(&path), "photos/2024/a.jpg");
+    assert_eq!(PathStyle::Windows.display(&path), "photos\\2024\\a.jpg");
+    assert_eq!(PathStyle::Native.display(&path), path.display().to_string());
+    // Absolute paths are only meaningful on their own platform
+    let absolute = std::env::temp_dir().join("a.jpg");
+    assert_eq!(
+        PathStyle::Windows.display(&absolute),
+        absolute.display().to_string()
+    );
+}
+
+#[test]
+fn test_open_file_limit() {
+    assert_eq!(max_open_files(1024), 960);
+    assert_eq!(max_open_files(10), 1);
+
+    #[cfg(unix)]
+    {
+        let limit = raise_open_file_limit().unwrap();
+        assert!(limit > 0);
+        // Raising again keeps the limit
+        assert_eq!(raise_open_file_limit(), Some(limit));
+
+        // The limit returned is the soft limit in effect, a number of files even when the hard
+        // limit is unlimited
+        let mut current = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `current` is a valid, writable `rlimit` for the call to fill.
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut current) },
+            0
+        );
+        #[allow(clippy::useless_conversion)]
+        let soft = u64::from(current.rlim_cur);
+        assert_eq!(soft, limit);
+        assert!(current.rlim_cur <= current.rlim_max);
+        if cfg!(target_os = "macos") || current.rlim_max != libc::RLIM_INFINITY {
+            assert_ne!(current.rlim_cur, libc::RLIM_INFINITY);
+        }
+    }
+}
+
+#[test]
+fn test_long_paths() {
+    let deep = PathBuf::from("restore")
+        .join("d".repeat(200))
+        .join("f".repeat(100));
+    let long_name = PathBuf::from("restore").join("n".repeat(MAX_NAME_LENGTH + 1));
+    // Four UTF-8 bytes, but two UTF-16 characters
+    let wide = PathBuf::from("😀".repeat(100));
+    let paths = [deep.as_path(), long_name.as_path(), wide.as_path()];
+
+    let windows = long_paths(paths, PathPlatform::Windows);
+    assert_eq!(windows.len(), 2);
+    assert_eq!(windows[0].path, deep);
+    assert_eq!((windows[0].length, windows[0].limit), (309, 259));
+    assert!(!windows[0].name);
+    assert_eq!(windows[1].path, long_name);
+    assert_eq!((windows[1].length, windows[1].limit), (264, 259));
+
+    let linux = long_paths(paths, PathPlatform::Linux);
+    assert_eq!(linux.len(), 2);
+    assert_eq!(linux[0].path, long_name);
+    assert_eq!((linux[0].length, linux[0].limit), (256, MAX_NAME_LENGTH));
+    assert!(linux[0].name);
+    assert_eq!(linux[1].path, wide);
+    assert_eq!(linux[1].length, 400);
+    assert!(linux[1].name);
+}
+
+#[test]
+fn test_mount_kind() {
+    let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+35 22 0:40 / /mnt/archive rw,relatime shared:20 - nfs4 server:/export rw,vers=4.2
+36 22 0:41 / /mnt/my\\040share rw,relatime shared:21 - cifs //server/share rw
+37 35 0:42 / /mnt/archive/cache rw,relatime shared:22 - tmpfs tmpfs rw
+";
+    let kind = |path: &str| mount_kind(mountinfo, Path::new(path));
+
+    assert_eq!(kind("/home/user"), Some("ext4".to_string()));
+    assert_eq!(kind("/mnt/archive/photos"), Some("nfs4".to_string()));
+    assert_eq!(kind("/mnt/archive/cache/x"), Some("tmpfs".to_string()));
+    assert_eq!(kind("/mnt/my share/docs"), Some("cifs".to_string()));
+    // Mount points match whole path components only
+    assert_eq!(kind("/mnt/archive2"), Some("ext4".to_string()));
+
+    let source = |path: &str| mount_source(mountinfo, Path::new(path));
+    assert_eq!(source("/home/user"), Some("/dev/sda1".to_string()));
+    assert_eq!(
+        source("/mnt/archive/photos"),
+        Some("server:/export".to_string())
+    );
+}
+
+#[test]
+fn test_parse_timestamp() {
+    let at = |seconds| Ok(UNIX_EPOCH + Duration::from_secs(seconds));
+    assert_eq!(parse_timestamp("@1714566600"), at(1_714_566_600));
+    assert_eq!(parse_timestamp("2024-05-01"), at(1_714_521_600));
+    assert_eq!(parse_timestamp("2024-05-01T12:30:00Z"), at(1_714_566_600));
+    assert_eq!(parse_timestamp("2024-05-01 12:30"), at(1_714_566_600));
+    assert_eq!(parse_timestamp("2024-02-29"), at(1_709_164_800));
+    assert!(parse_timestamp("2023-02-29").is_err());
+    assert!(parse_timestamp("1969-12-31").is_err());
+    assert!(parse_timestamp("yesterday").is_err());
+}
+
+#[test]
+fn test_file_identity() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_file_identity");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&base_dir)?;
+    let file = create_file(&base_dir.join("file.txt"), "Contents")?;
+    let copy = create_file(&base_dir.join("copy.txt"), "Contents")?;
+    let link = base_dir.join("link.txt");
+    fs::hard_link(&file, &link)?;
+
+    // Hard links share the identity of their file, copies and directories do not
+    assert_eq!(file_identity(&link)?, file_identity(&file)?);
+    assert_ne!(file_identity(&copy)?, file_identity(&file)?);
+    assert_ne!(file_identity(&base_dir)?, file_identity(&file)?);
+    assert!(same_file(&file, &link));
+    assert!(!same_file(&file, &copy));
+    assert!(file_identity(&base_dir.join("missing")).is_err());
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_labels() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_labels");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Shared")?;
+    create_file(&dir2.join("a.txt"), "Shared")?;
+    create_file(&dir1.join("b.txt"), "Only in dir1")?;
+
+    let filematch = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(["--label1", "NAS", "--label2", "USB-backup"])
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // The headers of the sections and of the rollups name the sides by their labels
+    let text = filematch(&[])?;
+    assert!(text.contains("Files both in 'NAS' and 'USB-backup':"));
+    assert!(text.contains("Files unique in 'NAS':"));
+    assert!(text.contains("Files unique in 'USB-backup':"));
+    let rollup = filematch(&["--rollup"])?;
+    assert!(rollup.contains("in 'NAS':") && rollup.contains("in 'USB-backup':"));
+
+    // JSON holds the labels, and summaries use them instead of the paths
+    let json: serde_json::Value = serde_json::from_str(&filematch(&["--json"])?)?;
+    assert_eq!(json["labels"]["directory1"], "NAS");
+    assert_eq!(json["labels"]["directory2"], "USB-backup");
+    let summary: serde_json::Value = serde_json::from_str(&filematch(&["--format", "summary"])?)?;
+    assert_eq!(summary["directory1"], "NAS");
+    assert_eq!(summary["directory2"], "USB-backup");
+    assert_eq!(
+        summary["text"],
+        "'NAS' and 'USB-backup' differ: 1 files only in 'NAS', 0 only in 'USB-backup'"
+    );
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_pagination() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_pagination");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    for i in 0..3 {
+        create_file(&dir1.join(format!("{i}.txt")), &format!("Shared {i}"))?;
+        create_file(&dir2.join(format!("{i}.txt")), &format!("Shared {i}"))?;
+    }
+
+    let filematch = |args: &[&str]| -> std::io::Result<std::process::Output> {
+        std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()
+    };
+
+    // An offset past the end displays nothing, and no page is left
+    let output = filematch(&["--counts", "--offset", "5"])?;
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(output.stderr.is_empty());
+
+    // A limit of 0 displays nothing, and the next page starts where it started
+    let output = filematch(&["--counts", "--limit", "0"])?;
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr)?,
+        "Displayed 0 of 3 contents, continue with --offset 0\n"
+    );
+
+    // The counts and the groups as JSON hold the selected page
+    let output = filematch(&["--counts", "--json", "--offset", "1", "--limit", "1"])?;
+    let counts: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(counts.as_array().map(Vec::len), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stderr)?,
+        "Displayed 1 of 3 contents, continue with --offset 2\n"
+    );
+    let output = filematch(&["--format", "json-grouped", "--offset", "2", "--limit", "5"])?;
+    let groups: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(groups.as_array().map(Vec::len), Some(1));
+    assert!(output.stderr.is_empty());
+
+    // Outputs that are not paginated reject the options
+    let output = filematch(&["--format", "json", "--offset", "1"])?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("--offset and --limit only apply"));
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_modified() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_modified");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("same.txt"), "Same")?;
+    create_file(&dir2.join("same.txt"), "Same")?;
+    create_file(&dir1.join("changed.txt"), "Old")?;
+    create_file(&dir2.join("changed.txt"), "New")?;
+
+    let filematch = |args: &[&str]| -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(["--format", "json-result"])
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()?;
+        assert!(output.status.success());
+        Ok(serde_json::from_slice(&output.stdout)?)
+    };
+
+    // The changed file is found with paths as found, relative paths, and outputs making the
+    // paths relative after the comparison
+    for args in [
+        &[][..],
+        &["--relative"],
+        &["--relative", "--double-check", "sha256"],
+    ] {
+        let result = filematch(args)?;
+        assert_eq!(result["modified"], serde_json::json!(["changed.txt"]));
+        assert_eq!(result["counts"]["modified"], 1);
+    }
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_counts() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_counts");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+    create_file(&dir2.join("only2.txt"), "Only in dir2")?;
+    create_file(&dir1.join("dup1.txt"), "Duplicate")?;
+    create_file(&dir1.join("dup2.txt"), "Duplicate")?;
+    create_file(&dir2.join("dup3.txt"), "Duplicate")?;
+    create_file(&dir1.join("shared.txt"), "Shared")?;
+    create_file(&dir2.join("shared.txt"), "Shared")?;
+
+    let filematch = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .arg("--counts")
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // Only the contents found on both sides are counted, in the order of their hashes
+    let duplicate = blake3::hash(b"Duplicate");
+    let shared = blake3::hash(b"Shared");
+    let mut expected = vec![
+        format!("{duplicate}: 2 in dir1, 1 in dir2"),
+        format!("{shared}: 1 in dir1, 1 in dir2"),
+    ];
+    if shared.as_bytes() < duplicate.as_bytes() {
+        expected.reverse();
+    }
+    assert_eq!(filematch(&[])?.lines().collect::<Vec<_>>(), expected);
+
+    let counts: serde_json::Value = serde_json::from_str(&filematch(&["--json"])?)?;
+    let counts = counts.as_array().unwrap();
+    assert_eq!(counts.len(), 2);
+    let count = |hash: blake3::Hash| {
+        counts
+            .iter()
+            .find(|count| count["hash"] == hash.to_hex().as_str())
+            .map(|count| (count["directory1"].clone(), count["directory2"].clone()))
+    };
+    assert_eq!(count(duplicate), Some((2.into(), 1.into())));
+    assert_eq!(count(shared), Some((1.into(), 1.into())));
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_json_grouped() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_json_grouped");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Duplicate")?;
+    create_file(&dir1.join("b.txt"), "Duplicate")?;
+    create_file(&dir2.join("c.txt"), "Duplicate")?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+        .args(["--format", "json-grouped", "--relative", "--sort"])
+        .arg(&dir1)
+        .arg(&dir2)
+        .output()?;
+    assert!(output.status.success());
+    let groups: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let groups = groups.as_array().unwrap();
+    assert_eq!(groups.len(), 2);
+
+    // Each content lists its files on both sides with their size and modification time
+    let paths = |files: &serde_json::Value| -> Vec<String> {
+        let files = files.as_array().unwrap();
+        for file in files {
+            assert!(file["modified"].is_u64());
+        }
+        files
+            .iter()
+            .map(|file| file["path"].as_str().unwrap().to_string())
+            .collect()
+    };
+    let duplicate = groups
+        .iter()
+        .find(|group| group["hash"] == blake3::hash(b"Duplicate").to_hex().as_str())
+        .unwrap();
+    assert_eq!(duplicate["size"], 9);
+    assert_eq!(duplicate["total_size"], 27);
+    assert_eq!(paths(&duplicate["directory1"]), ["a.txt", "b.txt"]);
+    assert_eq!(paths(&duplicate["directory2"]), ["c.txt"]);
+    assert_eq!(duplicate["directory1"][0]["size"], 9);
+
+    let unique = groups
+        .iter()
+        .find(|group| group["hash"] == blake3::hash(b"Only in dir1").to_hex().as_str())
+        .unwrap();
+    assert_eq!(unique["total_size"], 12);
+    assert_eq!(paths(&unique["directory1"]), ["only1.txt"]);
+    assert_eq!(paths(&unique["directory2"]), Vec::<String>::new());
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_mtime_delta() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_mtime_delta");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+
+    // Copies modified a day, two hours, three minutes and four seconds apart, and at once
+    let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    for (name, contents, modified1, modified2) in [
+        ("earlier.txt", "Earlier", 1_093_784, 1_000_000),
+        ("equal.txt", "Equal", 1_000_000, 1_000_000),
+        ("later.txt", "Later", 1_000_000, 1_093_784),
+        ("minutes.txt", "Minutes", 1_000_000, 1_000_065),
+    ] {
+        create_file(&dir1.join(name), contents)?;
+        create_file(&dir2.join(name), contents)?;
+        fs::File::options()
+            .write(true)
+            .open(dir1.join(name))?
+            .set_modified(at(modified1))?;
+        fs::File::options()
+            .write(true)
+            .open(dir2.join(name))?
+            .set_modified(at(modified2))?;
+    }
+
+    let filematch = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(["--mtime-delta", "--relative", "--sort", "--intersection"])
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // Copies in directory2 modified before the one in directory1 have a negative delta
+    assert_eq!(
+        filematch(&["--no-header"])?.lines().collect::<Vec<_>>(),
+        [
+            "earlier.txt\tearlier.txt\t-1d 2h 3m 4s",
+            "equal.txt\tequal.txt\t+0s",
+            "later.txt\tlater.txt\t+1d 2h 3m 4s",
+            "minutes.txt\tminutes.txt\t+1m 5s",
+        ]
+    );
+
+    // JSON holds the delta in seconds
+    let json: serde_json::Value = serde_json::from_str(&filematch(&["--json"])?)?;
+    assert_eq!(
+        json["intersection"],
+        serde_json::json!([
+            ["earlier.txt", "earlier.txt", -93_784],
+            ["equal.txt", "equal.txt", 0],
+            ["later.txt", "later.txt", 93_784],
+            ["minutes.txt", "minutes.txt", 65],
+        ])
+    );
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_no_header_and_prefix() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_no_header_and_prefix");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("shared.txt"), "Shared")?;
+    create_file(&dir2.join("shared.txt"), "Shared")?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+    create_file(&dir2.join("only2.txt"), "Only in dir2")?;
+
+    let filematch = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(["--relative", "--sort"])
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // Without headers the sections are only separated by empty lines
+    assert_eq!(
+        filematch(&["--no-header"])?,
+        "shared.txt\nshared.txt\n\nonly1.txt\n\nonly2.txt\n"
+    );
+
+    // With prefixes every line names its section instead
+    assert_eq!(
+        filematch(&["--prefix"])?,
+        "BOTH\tshared.txt\nBOTH\tshared.txt\nONLY1\tonly1.txt\nONLY2\tonly2.txt\n"
+    );
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_sections() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_sections");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("shared.txt"), "Shared")?;
+    create_file(&dir2.join("shared.txt"), "Shared")?;
+    create_file(&dir1.join("only1.txt"), "Only in dir1")?;
+    create_file(&dir2.join("only2.txt"), "Only in dir2")?;
+
+    let filematch = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(["--relative", "--sort"])
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()?;
+        assert!(output.status.success());
+        Ok(String::from_utf8(output.stdout)?)
+    };
+
+    // Only the selected sections are printed, in the order given
+    assert_eq!(
+        filematch(&["--sections", "dir2,intersection", "--no-header"])?,
+        "only2.txt\n\nshared.txt\nshared.txt\n"
+    );
+    assert_eq!(
+        filematch(&["--sections", "dir1", "--no-header"])?,
+        "only1.txt\n"
+    );
+
+    // JSON leaves out the other sections too
+    let json: serde_json::Value =
+        serde_json::from_str(&filematch(&["--sections", "dir1", "--json"])?)?;
+    assert_eq!(json["directory1"], serde_json::json!(["only1.txt"]));
+    assert!(json.get("intersection").is_none());
+    assert!(json.get("directory2").is_none());
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_priority() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_priority");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Shared")?;
+    create_file(&dir2.join("a.txt"), "Shared")?;
+
+    let filematch = |args: &[&str]| -> std::io::Result<std::process::Output> {
+        std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+            .args(args)
+            .arg(&dir1)
+            .arg(&dir2)
+            .output()
+    };
+
+    // Levels outside 1 to 19 and unknown classes are rejected before comparing
+    for (args, message) in [
+        (&["--nice=0"][..], "0 is not in 1..=19"),
+        (&["--nice=20"], "20 is not in 1..=19"),
+        (&["--nice=low"], "invalid value 'low'"),
+        (&["--ionice", "realtime"], "invalid value 'realtime'"),
+    ] {
+        let output = filematch(args)?;
+        assert_eq!(output.status.code(), Some(2));
+        assert!(output.stdout.is_empty());
+        assert!(String::from_utf8(output.stderr)?.contains(message));
+    }
+
+    // The level may be left out, and lowering the priority needs no privileges
+    for args in [&["--nice"][..], &["--nice=19", "--ionice", "idle"]] {
+        assert!(filematch(args)?.status.success());
+    }
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_cli_notify_without_curl() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_cli_notify_without_curl");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Shared")?;
+    create_file(&dir2.join("a.txt"), "Shared")?;
+
+    // Without curl on the path the comparison still succeeds, with a warning naming curl
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_filematch"))
+        .args(["--notify-webhook", "http://127.0.0.1:9/hook"])
+        .arg(&dir1)
+        .arg(&dir2)
+        .env("PATH", &base_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)?
+        .contains("cannot notify http://127.0.0.1:9/hook: curl not found"));
+
+    fs::remove_dir_all(&base_dir)?;
+    Ok(())
+}
+
 /// Helper function to create a file with specified content
 pub fn create_file(path: &Path, content: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
     let mut file = fs::File::create(path)?;
@@ -0,0 +1,86 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use filematch::bench::run_benchmark;
+use filematch::compare_two_directories::compare_two_directories_with_options;
+use filematch::options::CompareOptions;
+use filematch::testutil::{forced_collision, setup_benchmark_files};
+
+#[test]
+fn test_bench() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_bench");
+    let dir_a = base_dir.join("a");
+    let dir_b = base_dir.join("b");
+    let _ = fs::remove_dir_all(&base_dir);
+
+    // Two groups of 64 KiB each: 16 files of 4 KiB and 4 files of 16 KiB
+    let files = setup_benchmark_files(&dir_a, &dir_b, 128 * 1024, &[4096, 16 * 1024], 42)?;
+    assert_eq!(files.only_a.len(), 5 + 1);
+    assert_eq!(files.only_b.len(), 5 + 1);
+    assert_eq!(files.both.len(), 2 * (6 + 2));
+
+    // The same seed generates the same files
+    let again = setup_benchmark_files(&dir_a, &dir_b, 128 * 1024, &[4096, 16 * 1024], 42)?;
+    assert_eq!(files, again);
+
+    let durations = run_benchmark(
+        &dir_a,
+        &dir_b,
+        &files,
+        2,
+        &CompareOptions::default(),
+        |_, _| {},
+    )?;
+    assert_eq!(durations.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_forced_collision() -> Result<(), Box<dyn std::error::Error>> {
+    let base_dir = std::env::temp_dir().join("test_dirs_forced_collision");
+    let dir1 = base_dir.join("dir1");
+    let dir2 = base_dir.join("dir2");
+    let _ = fs::remove_dir_all(&base_dir);
+    fs::create_dir_all(&dir1)?;
+    fs::create_dir_all(&dir2)?;
+    create_file(&dir1.join("a.txt"), "Original")?;
+    create_file(&dir1.join("b.txt"), "Unrelated")?;
+    create_file(&dir2.join("c.txt"), "Different")?;
+
+    let options = CompareOptions {
+        relative: true,
+        sort: true,
+        include_groups: true,
+        transform: Some(forced_collision(&[
+            dir1.join("a.txt"),
+            base_dir.join("dir2/../dir2/c.txt"),
+        ])?),
+        ..CompareOptions::default()
+    };
+    let result = compare_two_directories_with_options(&dir1, &dir2, &options)?;
+    assert_eq!(
+        result.intersection.unwrap(),
+        vec![PathBuf::from("a.txt"), PathBuf::from("c.txt")]
+    );
+    assert_eq!(result.unique_dir1.unwrap(), vec![PathBuf::from("b.txt")]);
+
+    // The colliding group looks like a real collision: the hash and size of the first file
+    let groups = result.groups.unwrap();
+    let group = groups.iter().find(|group| group.dir2.len() == 1).unwrap();
+    assert_eq!(group.hash, blake3::hash(b"Original"));
+    assert_eq!(group.size, 8);
+    assert_eq!(group.dir1, vec![PathBuf::from("a.txt")]);
+
+    assert!(forced_collision(&[]).is_err());
+
+    Ok(())
+}
+
+/// Helper function to create a file with specified content
+pub fn create_file(path: &Path, content: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut file = fs::File::create(path)?;
+    use std::io::Write;
+    file.write_all(content.as_bytes())?;
+    Ok(path.to_path_buf())
+}